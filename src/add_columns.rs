@@ -0,0 +1,378 @@
+//! Adding computed columns to our output, for `--add-column`.
+//!
+//! A computed column is written as `NAME = EXPR`, where `EXPR` is a column
+//! name, a "string" or number literal, or a call to one of:
+//!
+//! - `concat(a, b, ...)`: Join values together.
+//! - `substr(s, start, len)`: Take `len` characters of `s`, starting at
+//!   `start`, counting from 1. `len` is optional.
+//! - `lower(s)`, `upper(s)`: Change the case of `s`.
+//! - `coalesce(a, b, ...)`: The first value which isn't empty.
+//!
+//! Like `--where`, column names with unusual characters can be written in
+//! `backquotes`.
+//!
+//! We also add constant columns, row numbers and the name of the source file
+//! this way, for `--add-constant-column`, `--add-row-number-column` and
+//! `--add-source-file-column`.
+
+use csv::ByteRecord;
+use std::{borrow::Cow, str::FromStr};
+
+use crate::errors::*;
+use crate::expr::{tokenize, Token};
+
+/// A function which we can call in an expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Func {
+    Concat,
+    Substr,
+    Lower,
+    Upper,
+    Coalesce,
+}
+
+impl Func {
+    /// Look up a function by name, returning it along with the minimum and
+    /// maximum number of arguments it takes.
+    fn from_name(name: &str) -> Option<(Func, usize, usize)> {
+        match name {
+            "concat" => Some((Func::Concat, 1, usize::MAX)),
+            "substr" => Some((Func::Substr, 2, 3)),
+            "lower" => Some((Func::Lower, 1, 1)),
+            "upper" => Some((Func::Upper, 1, 1)),
+            "coalesce" => Some((Func::Coalesce, 1, usize::MAX)),
+            _ => None,
+        }
+    }
+}
+
+/// An expression which computes a value, referring to columns using `C`.
+#[derive(Clone, Debug, PartialEq)]
+enum ValueExpr<C> {
+    Column(C),
+    Literal(Vec<u8>),
+    Call(Func, Vec<ValueExpr<C>>),
+    /// The number of the current data row, counting from 1.
+    RowNumber,
+    /// The name of the file we're reading.
+    SourceFile,
+}
+
+/// Parse an expression from `tokens`, starting at `*pos`.
+fn parse_value(tokens: &[Token], pos: &mut usize) -> Result<ValueExpr<String>> {
+    let token = tokens.get(*pos).cloned();
+    *pos += 1;
+    match token {
+        Some(Token::Column(name)) if tokens.get(*pos) == Some(&Token::LParen) => {
+            let (func, min_args, max_args) = Func::from_name(&name)
+                .ok_or_else(|| format_err!("unknown function {:?}", name))?;
+            *pos += 1;
+            let mut args = vec![];
+            if tokens.get(*pos) != Some(&Token::RParen) {
+                loop {
+                    args.push(parse_value(tokens, pos)?);
+                    match tokens.get(*pos) {
+                        Some(Token::Comma) => *pos += 1,
+                        _ => break,
+                    }
+                }
+            }
+            match tokens.get(*pos) {
+                Some(Token::RParen) => *pos += 1,
+                other => return Err(format_err!("expected \")\", found {:?}", other)),
+            }
+            if args.len() < min_args || args.len() > max_args {
+                return Err(format_err!(
+                    "wrong number of arguments to {}: {}",
+                    name,
+                    args.len()
+                ));
+            }
+            Ok(ValueExpr::Call(func, args))
+        }
+        Some(Token::Column(name)) => Ok(ValueExpr::Column(name)),
+        Some(Token::Str(s)) => Ok(ValueExpr::Literal(s.into_bytes())),
+        Some(Token::Num(n)) => Ok(ValueExpr::Literal(n.to_string().into_bytes())),
+        other => Err(format_err!(
+            "expected a column, string, number or function, found {:?}",
+            other
+        )),
+    }
+}
+
+/// Look up the columns used by `expr` in `hdr`.
+fn compile_value(
+    expr: &ValueExpr<String>,
+    hdr: &ByteRecord,
+) -> Result<ValueExpr<usize>> {
+    Ok(match expr {
+        ValueExpr::Column(name) => ValueExpr::Column(
+            hdr.iter()
+                .position(|col| col == name.as_bytes())
+                .ok_or_else(|| {
+                    format_err!("cannot find --add-column column {:?}", name)
+                })?,
+        ),
+        ValueExpr::Literal(value) => ValueExpr::Literal(value.clone()),
+        ValueExpr::Call(func, args) => ValueExpr::Call(
+            *func,
+            args.iter()
+                .map(|arg| compile_value(arg, hdr))
+                .collect::<Result<_>>()?,
+        ),
+        ValueExpr::RowNumber => ValueExpr::RowNumber,
+        ValueExpr::SourceFile => ValueExpr::SourceFile,
+    })
+}
+
+/// Where we are in our input, for `ValueExpr::RowNumber` and
+/// `ValueExpr::SourceFile`.
+#[derive(Clone, Copy, Debug)]
+struct Position<'a> {
+    row_number: u64,
+    source: &'a [u8],
+}
+
+/// Compute the value of `expr` for `row`.
+fn eval<'a>(
+    expr: &'a ValueExpr<usize>,
+    row: &'a [Cow<'a, [u8]>],
+    pos: Position<'a>,
+) -> Cow<'a, [u8]> {
+    match expr {
+        ValueExpr::Column(i) => Cow::Borrowed(&row[*i]),
+        ValueExpr::Literal(value) => Cow::Borrowed(value),
+        ValueExpr::RowNumber => Cow::Owned(pos.row_number.to_string().into_bytes()),
+        ValueExpr::SourceFile => Cow::Borrowed(pos.source),
+        ValueExpr::Call(Func::Concat, args) => Cow::Owned(
+            args.iter()
+                .flat_map(|arg| eval(arg, row, pos).into_owned())
+                .collect(),
+        ),
+        ValueExpr::Call(Func::Coalesce, args) => args
+            .iter()
+            .map(|arg| eval(arg, row, pos))
+            .find(|value| !value.is_empty())
+            .unwrap_or(Cow::Borrowed(b"")),
+        ValueExpr::Call(Func::Lower, args) => {
+            let value = eval(&args[0], row, pos);
+            Cow::Owned(String::from_utf8_lossy(&value).to_lowercase().into_bytes())
+        }
+        ValueExpr::Call(Func::Upper, args) => {
+            let value = eval(&args[0], row, pos);
+            Cow::Owned(String::from_utf8_lossy(&value).to_uppercase().into_bytes())
+        }
+        ValueExpr::Call(Func::Substr, args) => {
+            let value = eval(&args[0], row, pos);
+            let number = |arg: &'a ValueExpr<usize>| {
+                String::from_utf8_lossy(&eval(arg, row, pos))
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+            };
+            // Start at the beginning if `start` is missing or invalid, and
+            // take nothing if `len` is invalid.
+            let start = number(&args[1]).unwrap_or(1).max(1) - 1;
+            let len = args.get(2).map_or(Some(usize::MAX), number);
+            let substr = String::from_utf8_lossy(&value)
+                .chars()
+                .skip(start)
+                .take(len.unwrap_or(0))
+                .collect::<String>();
+            Cow::Owned(substr.into_bytes())
+        }
+    }
+}
+
+/// A column to add, from `NAME = EXPR`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddColumn {
+    /// The name of the new column.
+    name: String,
+    /// How to compute its value.
+    expr: ValueExpr<String>,
+}
+
+impl FromStr for AddColumn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AddColumn> {
+        let (name, expr) = s
+            .split_once('=')
+            .ok_or_else(|| format_err!("expected NAME = EXPR, found {:?}", s))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format_err!("missing column name in {:?}", s));
+        }
+        let tokens = tokenize(expr)?;
+        let mut pos = 0;
+        let expr = parse_value(&tokens, &mut pos)?;
+        if let Some(token) = tokens.get(pos) {
+            return Err(format_err!("unexpected {:?} in {:?}", token, s));
+        }
+        Ok(AddColumn {
+            name: name.to_owned(),
+            expr,
+        })
+    }
+}
+
+impl AddColumn {
+    /// A column containing the number of each data row, counting from 1.
+    pub fn row_number(name: &str) -> AddColumn {
+        AddColumn {
+            name: name.to_owned(),
+            expr: ValueExpr::RowNumber,
+        }
+    }
+
+    /// A column containing the name of the file we're reading.
+    pub fn source_file(name: &str) -> AddColumn {
+        AddColumn {
+            name: name.to_owned(),
+            expr: ValueExpr::SourceFile,
+        }
+    }
+}
+
+/// A column with the same value in every row, from `NAME=VALUE`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConstantColumn(pub AddColumn);
+
+impl FromStr for ConstantColumn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ConstantColumn> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| format_err!("expected NAME=VALUE, found {:?}", s))?;
+        if name.is_empty() {
+            return Err(format_err!("missing column name in {:?}", s));
+        }
+        Ok(ConstantColumn(AddColumn {
+            name: name.to_owned(),
+            expr: ValueExpr::Literal(value.as_bytes().to_owned()),
+        }))
+    }
+}
+
+/// Columns which we append to each row.
+#[derive(Debug)]
+pub struct AddedColumns {
+    exprs: Vec<ValueExpr<usize>>,
+    /// The name of the file we're reading, for `AddColumn::source_file`.
+    source: Vec<u8>,
+}
+
+impl AddedColumns {
+    /// Add `columns` to the header `hdr`. Each column can use the columns
+    /// before it, including other added columns. `source` is the name of the
+    /// file we're reading. Returns `None` if we have nothing to add.
+    pub fn new(
+        hdr: &mut ByteRecord,
+        columns: &[AddColumn],
+        source: &str,
+    ) -> Result<Option<AddedColumns>> {
+        if columns.is_empty() {
+            return Ok(None);
+        }
+        let mut exprs = vec![];
+        for column in columns {
+            exprs.push(compile_value(&column.expr, hdr)?);
+            if hdr.iter().any(|name| name == column.name.as_bytes()) {
+                return Err(format_err!("column {:?} already exists", column.name));
+            }
+            hdr.push_field(column.name.as_bytes());
+        }
+        Ok(Some(AddedColumns {
+            exprs,
+            source: source.as_bytes().to_owned(),
+        }))
+    }
+
+    /// We've started reading from the file named `source`.
+    pub fn set_source(&mut self, source: &str) {
+        self.source = source.as_bytes().to_owned();
+    }
+
+    /// Append our columns to `row`, which is data row number `row_number`,
+    /// counting from 1.
+    pub fn append(&self, row_number: u64, row: &mut Vec<Cow<[u8]>>) {
+        let pos = Position {
+            row_number,
+            source: &self.source,
+        };
+        for expr in &self.exprs {
+            let value = eval(expr, row, pos).into_owned();
+            row.push(Cow::Owned(value));
+        }
+    }
+}
+
+#[test]
+fn computes_columns() {
+    let mut hdr = ByteRecord::from(vec!["first name", "last", "nick"]);
+    let columns = [
+        "full = concat(`first name`, \" \", last)",
+        "initial = upper(substr(`first name`, 1, 1))",
+        "shout = upper(full)",
+        "quiet = lower(last)",
+        "called = coalesce(nick, full)",
+        "rest = substr(last, 2)",
+        "n = 1.5",
+    ]
+    .iter()
+    .map(|s| s.parse::<AddColumn>().unwrap())
+    .chain(vec![
+        "batch=42".parse::<ConstantColumn>().unwrap().0,
+        AddColumn::row_number("row"),
+        AddColumn::source_file("file"),
+    ])
+    .collect::<Vec<_>>();
+    let added = AddedColumns::new(&mut hdr, &columns, "in.csv")
+        .unwrap()
+        .unwrap();
+    assert_eq!(hdr.len(), 13);
+    let mut row = vec![
+        Cow::Borrowed(&b"ada"[..]),
+        Cow::Borrowed(&b"Lovelace"[..]),
+        Cow::Borrowed(&b""[..]),
+    ];
+    added.append(7, &mut row);
+    let added = row[3..]
+        .iter()
+        .map(|v| String::from_utf8_lossy(v))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        added,
+        vec![
+            "ada Lovelace",
+            "A",
+            "ADA LOVELACE",
+            "lovelace",
+            "ada Lovelace",
+            "ovelace",
+            "1.5",
+            "42",
+            "7",
+            "in.csv",
+        ],
+    );
+
+    for bad in &[
+        "x",
+        "= a",
+        "x = nope(a)",
+        "x = lower(a, b)",
+        "x = concat(a",
+        "x = a b",
+    ] {
+        assert!(bad.parse::<AddColumn>().is_err(), "{}", bad);
+    }
+    assert!("x".parse::<ConstantColumn>().is_err());
+    assert!("=x".parse::<ConstantColumn>().is_err());
+    let mut hdr = ByteRecord::from(vec!["a"]);
+    assert!(AddedColumns::new(&mut hdr, &["a = a".parse().unwrap()], "").is_err());
+    assert!(AddedColumns::new(&mut hdr, &["b = c".parse().unwrap()], "").is_err());
+}
@@ -0,0 +1,121 @@
+//! Saving the rows we reject, so that they can be inspected or fixed later.
+
+use csv::ByteRecord;
+use std::{fs, path::Path};
+
+use crate::errors::*;
+
+/// Why we rejected a row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BadRowReason {
+    /// We couldn't read part of the record.
+    Unparseable,
+    /// The record contained a quote outside of a quoted field.
+    BareQuote,
+    /// The header ended with a delimiter, but this row didn't.
+    MissingTrailingDelimiter,
+    /// The row had too many or too few columns.
+    WrongColumnCount,
+    /// A column listed in `--drop-row-if-null` was empty.
+    RequiredColumnNull,
+    /// A cell contained invalid UTF-8, and `--invalid-utf8` was "drop".
+    InvalidUtf8,
+    /// We kept another row with the same `--dedup-by` key.
+    DuplicateKey,
+    /// A schema rule with `severity: error` failed.
+    ValidationFailed,
+    /// A cell was empty, and `--drop-row-if-any-null` was passed.
+    AnyColumnNull,
+    /// Every cell was empty, and `--drop-row-if-all-null` was passed.
+    BlankRow,
+    /// A cell didn't have the type given by `--types`.
+    TypeMismatch,
+    /// A `--normalize-numbers` cell wasn't a number.
+    InvalidNumber,
+    /// A cell was longer than `--max-cell-bytes`, and `--long-cell-policy`
+    /// was "drop-row".
+    CellTooLong,
+    /// A row's key wasn't in a `--lookup` file with `miss=reject`.
+    LookupMiss,
+    /// A row was out of order for `--assert-sorted-by`, and
+    /// `--on-unsorted` was "reject".
+    OutOfOrder,
+}
+
+impl BadRowReason {
+    /// A machine-readable name for this reason.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BadRowReason::Unparseable => "unparseable",
+            BadRowReason::BareQuote => "bare_quote",
+            BadRowReason::MissingTrailingDelimiter => "missing_trailing_delimiter",
+            BadRowReason::WrongColumnCount => "wrong_column_count",
+            BadRowReason::RequiredColumnNull => "required_column_null",
+            BadRowReason::InvalidUtf8 => "invalid_utf8",
+            BadRowReason::DuplicateKey => "duplicate_key",
+            BadRowReason::ValidationFailed => "validation_failed",
+            BadRowReason::AnyColumnNull => "any_column_null",
+            BadRowReason::BlankRow => "blank_row",
+            BadRowReason::TypeMismatch => "type_mismatch",
+            BadRowReason::InvalidNumber => "invalid_number",
+            BadRowReason::CellTooLong => "cell_too_long",
+            BadRowReason::LookupMiss => "lookup_miss",
+            BadRowReason::OutOfOrder => "out_of_order",
+        }
+    }
+}
+
+/// Writes bad rows to a CSV file, exactly as we parsed them.
+pub struct BadRowWriter {
+    wtr: csv::Writer<fs::File>,
+    /// Should we add a line number and reason to each row?
+    annotate: bool,
+}
+
+impl BadRowWriter {
+    /// Create a bad row file at `path`, with the input header `hdr`. If
+    /// `annotate` is true, prepend two columns to each row, containing the
+    /// line the row started on and why we rejected it.
+    pub fn create(
+        path: &Path,
+        hdr: &ByteRecord,
+        annotate: bool,
+    ) -> Result<BadRowWriter> {
+        let file = fs::File::create(path)
+            .with_context(|_| format!("cannot create {}", path.display()))?;
+        // Bad rows may have any number of columns.
+        let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(file);
+        if annotate {
+            let mut annotated = ByteRecord::from(vec!["_line", "_reason"]);
+            annotated.extend(hdr);
+            wtr.write_byte_record(&annotated)
+        } else {
+            wtr.write_byte_record(hdr)
+        }
+        .context("cannot write bad row headers")?;
+        Ok(BadRowWriter { wtr, annotate })
+    }
+
+    /// Save a bad row.
+    pub fn write(&mut self, record: &ByteRecord, reason: BadRowReason) -> Result<()> {
+        if self.annotate {
+            // Rows which we split out of a runaway quoted field may not know
+            // where they came from.
+            let line = record
+                .position()
+                .map(|pos| pos.line().to_string())
+                .unwrap_or_default();
+            let mut annotated = ByteRecord::from(vec![&line[..], reason.as_str()]);
+            annotated.extend(record);
+            self.wtr.write_byte_record(&annotated)
+        } else {
+            self.wtr.write_byte_record(record)
+        }
+        .context("cannot write bad row")
+    }
+
+    /// Flush any buffered rows.
+    pub fn flush(&mut self) -> Result<()> {
+        self.wtr.flush().context("cannot write bad rows")
+    }
+}
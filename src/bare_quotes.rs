@@ -0,0 +1,211 @@
+//! Handle quotes which appear outside of quoted fields, like `a,b"c,d`, or
+//! `"a"b"` after the closing quote.
+//!
+//! The CSV parser keeps these as literal characters. That's often right, but
+//! sometimes we'd rather remove them or reject the row, and by the time the
+//! parser has handed us a record, we can no longer tell them apart from
+//! properly escaped quotes. So we look at the raw input bytes instead.
+
+use log::debug;
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    io::{self, prelude::*},
+    rc::Rc,
+    str::FromStr,
+};
+
+use crate::errors::*;
+
+/// What should we do with quotes outside of quoted fields?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BareQuotePolicy {
+    /// Keep them as part of the value.
+    Keep,
+    /// Remove them.
+    Strip,
+    /// Treat the row as bad.
+    Reject,
+}
+
+impl FromStr for BareQuotePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BareQuotePolicy> {
+        match s {
+            "keep" => Ok(BareQuotePolicy::Keep),
+            "strip" => Ok(BareQuotePolicy::Strip),
+            "reject" => Ok(BareQuotePolicy::Reject),
+            _ => Err(format_err!("unknown bare quote policy: '{}'", s)),
+        }
+    }
+}
+
+/// The bare quotes we've found. This is shared with the caller, so it can
+/// still be read after our reader has been handed to the CSV parser.
+#[derive(Debug, Default)]
+pub struct BareQuotes {
+    /// The number of bare quotes we've seen.
+    pub count: Cell<u64>,
+    /// With `BareQuotePolicy::Reject`, the 1-based line numbers of any bare
+    /// quotes which the caller hasn't dealt with yet.
+    pub lines: RefCell<VecDeque<u64>>,
+}
+
+/// Where we are in a field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// At the start of a field.
+    FieldStart,
+    /// Inside an unquoted field.
+    Unquoted,
+    /// Inside a quoted field.
+    Quoted,
+    /// Just after a quote inside a quoted field, which either closes the
+    /// field or is the first half of an escaped quote.
+    AfterQuote,
+}
+
+/// A reader which applies a `BareQuotePolicy`.
+pub struct BareQuoteReader<R: BufRead> {
+    /// The reader we wrap.
+    inner: R,
+    /// What we do with bare quotes.
+    policy: BareQuotePolicy,
+    /// Our field delimiter.
+    delimiter: u8,
+    /// Our quote character.
+    quote: u8,
+    /// The raw line we're working on.
+    line: Vec<u8>,
+    /// The processed version of `line`.
+    processed: Vec<u8>,
+    /// How much of `processed` we've already returned.
+    pos: usize,
+    /// The 1-based number of the current line.
+    line_number: u64,
+    /// Where we are at the end of the current line.
+    state: State,
+    /// The bare quotes we've found.
+    found: Rc<BareQuotes>,
+}
+
+impl<R: BufRead> BareQuoteReader<R> {
+    /// Create a new reader. Returns the reader and a record of the bare
+    /// quotes it finds.
+    pub fn new(
+        inner: R,
+        policy: BareQuotePolicy,
+        delimiter: u8,
+        quote: u8,
+    ) -> (BareQuoteReader<R>, Rc<BareQuotes>) {
+        let found = Rc::new(BareQuotes::default());
+        let rdr = BareQuoteReader {
+            inner,
+            policy,
+            delimiter,
+            quote,
+            line: vec![],
+            processed: vec![],
+            pos: 0,
+            line_number: 0,
+            state: State::FieldStart,
+            found: found.clone(),
+        };
+        (rdr, found)
+    }
+
+    /// Process `self.line` into `self.processed`.
+    fn process_line(&mut self) {
+        self.processed.clear();
+        self.pos = 0;
+        let mut found_on_line = false;
+        for &b in &self.line {
+            let ends_field = b == self.delimiter || b == b'\n' || b == b'\r';
+            let bare = match self.state {
+                State::FieldStart if b == self.quote => {
+                    self.state = State::Quoted;
+                    false
+                }
+                State::Quoted if b == self.quote => {
+                    self.state = State::AfterQuote;
+                    false
+                }
+                State::Quoted => false,
+                State::AfterQuote if b == self.quote => {
+                    self.state = State::Quoted;
+                    false
+                }
+                _ if ends_field => {
+                    self.state = State::FieldStart;
+                    false
+                }
+                _ => {
+                    self.state = State::Unquoted;
+                    b == self.quote
+                }
+            };
+            if bare {
+                debug!("line {}: found bare quote", self.line_number);
+                self.found.count.set(self.found.count.get() + 1);
+                found_on_line = true;
+                if self.policy == BareQuotePolicy::Strip {
+                    continue;
+                }
+            }
+            self.processed.push(b);
+        }
+        if found_on_line && self.policy == BareQuotePolicy::Reject {
+            self.found.lines.borrow_mut().push_back(self.line_number);
+        }
+    }
+}
+
+impl<R: BufRead> Read for BareQuoteReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.processed.len() {
+            self.line.clear();
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            self.line_number += 1;
+            self.process_line();
+        }
+        let count = buf.len().min(self.processed.len() - self.pos);
+        buf[..count].copy_from_slice(&self.processed[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+fn process(input: &str, policy: BareQuotePolicy) -> (String, u64, Vec<u64>) {
+    let (mut rdr, found) = BareQuoteReader::new(input.as_bytes(), policy, b',', b'"');
+    let mut out = String::new();
+    rdr.read_to_string(&mut out).unwrap();
+    let lines = found.lines.borrow().iter().cloned().collect();
+    (out, found.count.get(), lines)
+}
+
+#[test]
+fn handles_bare_quotes() {
+    let input =
+        "a,b\"c,\"ok \"\"x\"\"\"\n\"multi\nline\"\n\"Broken \" quotes\"\nfine\n";
+    assert_eq!(
+        process(input, BareQuotePolicy::Keep),
+        (input.to_owned(), 2, vec![]),
+    );
+    assert_eq!(
+        process(input, BareQuotePolicy::Strip),
+        (
+            "a,bc,\"ok \"\"x\"\"\"\n\"multi\nline\"\n\"Broken \" quotes\nfine\n"
+                .to_owned(),
+            2,
+            vec![],
+        ),
+    );
+    assert_eq!(
+        process(input, BareQuotePolicy::Reject),
+        (input.to_owned(), 2, vec![1, 4]),
+    );
+}
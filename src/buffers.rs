@@ -0,0 +1,157 @@
+//! Choosing I/O buffer sizes.
+
+use std::{fs, io, str::FromStr};
+
+use crate::errors::*;
+
+/// Use reasonably large input and output buffers by default. This seems to
+/// give us a performance boost of around 5-10% compared to the standard 8 KiB
+/// buffer used by `csv`.
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// What kind of thing are we reading from or writing to?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    /// A regular file.
+    File,
+    /// A pipe.
+    Pipe,
+    /// A socket.
+    Socket,
+    /// A terminal, or some other character device.
+    Terminal,
+    /// We couldn't tell.
+    Unknown,
+}
+
+impl StreamKind {
+    /// What kind of stream is `file`?
+    pub fn of_file(file: &fs::File) -> StreamKind {
+        match file.metadata() {
+            Ok(metadata) => StreamKind::of_file_type(metadata.file_type()),
+            Err(_) => StreamKind::Unknown,
+        }
+    }
+
+    /// What kind of stream is standard input?
+    pub fn of_stdin() -> StreamKind {
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsFd;
+            match io::stdin().as_fd().try_clone_to_owned() {
+                Ok(fd) => StreamKind::of_file(&fs::File::from(fd)),
+                Err(_) => StreamKind::Unknown,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            StreamKind::Unknown
+        }
+    }
+
+    /// What kind of stream is standard output?
+    pub fn of_stdout() -> StreamKind {
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsFd;
+            match io::stdout().as_fd().try_clone_to_owned() {
+                Ok(fd) => StreamKind::of_file(&fs::File::from(fd)),
+                Err(_) => StreamKind::Unknown,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            StreamKind::Unknown
+        }
+    }
+
+    fn of_file_type(file_type: fs::FileType) -> StreamKind {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return StreamKind::Pipe;
+            } else if file_type.is_socket() {
+                return StreamKind::Socket;
+            } else if file_type.is_char_device() {
+                return StreamKind::Terminal;
+            }
+        }
+        if file_type.is_file() {
+            StreamKind::File
+        } else {
+            StreamKind::Unknown
+        }
+    }
+}
+
+/// A buffer size specified on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferSize {
+    /// Pick a size based on the kind of stream.
+    Auto,
+    /// Use exactly this many bytes.
+    Bytes(usize),
+}
+
+impl BufferSize {
+    /// Get the size we should use for `kind`.
+    pub fn bytes_for(self, kind: StreamKind) -> usize {
+        match self {
+            BufferSize::Bytes(bytes) => bytes,
+            BufferSize::Auto => match kind {
+                // Big reads and writes mean fewer system calls.
+                StreamKind::File => 1024 * 1024,
+                // Linux pipes hold 64 KiB by default, so there's not much
+                // point in asking for more at once.
+                StreamKind::Pipe => 64 * 1024,
+                StreamKind::Socket => 128 * 1024,
+                // Somebody may be watching, so don't make them wait.
+                StreamKind::Terminal => 8 * 1024,
+                StreamKind::Unknown => DEFAULT_BUFFER_SIZE,
+            },
+        }
+    }
+}
+
+impl FromStr for BufferSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BufferSize> {
+        if s == "auto" {
+            return Ok(BufferSize::Auto);
+        }
+        let (digits, multiplier) = match s.as_bytes().last() {
+            Some(b'K' | b'k') => (&s[..s.len() - 1], 1024),
+            Some(b'M' | b'm') => (&s[..s.len() - 1], 1024 * 1024),
+            _ => (s, 1),
+        };
+        let bytes = digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .filter(|&bytes| bytes > 0)
+            .ok_or_else(|| format_err!("cannot parse buffer size: '{}'", s))?;
+        Ok(BufferSize::Bytes(bytes))
+    }
+}
+
+#[test]
+fn parses_buffer_sizes() {
+    assert_eq!(BufferSize::from_str("auto").unwrap(), BufferSize::Auto);
+    assert_eq!(
+        BufferSize::from_str("4096").unwrap(),
+        BufferSize::Bytes(4096)
+    );
+    assert_eq!(
+        BufferSize::from_str("64K").unwrap(),
+        BufferSize::Bytes(64 * 1024),
+    );
+    assert_eq!(
+        BufferSize::from_str("2m").unwrap(),
+        BufferSize::Bytes(2 * 1024 * 1024),
+    );
+    assert!(BufferSize::from_str("0").is_err());
+    assert!(BufferSize::from_str("K").is_err());
+    assert!(BufferSize::from_str("1G").is_err());
+}
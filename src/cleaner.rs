@@ -0,0 +1,366 @@
+//! The cleanups we apply to each cell of a good row.
+//!
+//! These don't depend on anything but the cell itself, so `--jobs` can run
+//! them on several threads at once. Each thread counts its own rule hits in
+//! a `CleanHits`, and we add them all up at the end.
+
+use regex::bytes::Regex;
+use std::{borrow::Cow, str, str::FromStr};
+
+use crate::cleanup;
+use crate::encoding::{replace_invalid_utf8, Utf8Fallback};
+use crate::errors::*;
+use crate::numbers;
+use crate::stats::{RuleHits, RuleId};
+use crate::unicode::{self, UnicodeForm};
+
+/// What to do with cells longer than `--max-cell-bytes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LongCellPolicy {
+    /// Cut the cell down to size.
+    Truncate,
+    /// Replace the cell with an empty value.
+    Null,
+    /// Reject the whole row. Our caller handles this, because it's not
+    /// something we can do one cell at a time.
+    DropRow,
+}
+
+impl FromStr for LongCellPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LongCellPolicy> {
+        match s {
+            "truncate" => Ok(LongCellPolicy::Truncate),
+            "null" => Ok(LongCellPolicy::Null),
+            "drop-row" => Ok(LongCellPolicy::DropRow),
+            _ => Err(format_err!("unknown --long-cell-policy: '{}'", s)),
+        }
+    }
+}
+
+/// Which cleanups to apply to each cell.
+#[derive(Debug, Default)]
+pub struct CellCleaner {
+    /// Convert values matching this regex to empty strings.
+    pub null_re: Option<Regex>,
+    /// Remove whitespace at the start and end of each value.
+    pub trim_whitespace: bool,
+    /// Replace runs of spaces and tabs with a single space.
+    pub collapse_whitespace: bool,
+    /// Reinterpret invalid UTF-8 using this encoding.
+    pub utf8_fallback: Option<Utf8Fallback>,
+    /// Replace any remaining invalid UTF-8 with U+FFFD.
+    pub replace_invalid_utf8: bool,
+    /// Normalize Unicode to this form.
+    pub normalize_unicode: Option<UnicodeForm>,
+    /// Replace curly quotes, dashes and non-breaking spaces with ASCII.
+    pub ascii_punctuation: bool,
+    /// Remove thousands separators from numbers.
+    pub strip_thousands_separators: bool,
+    /// Write numbers with a decimal comma.
+    pub decimal_comma_output: bool,
+    /// Replace newlines with spaces.
+    pub replace_newlines: bool,
+    /// Remove control characters, or replace them with this byte.
+    pub strip_control_chars: Option<Option<u8>>,
+    /// Shorten or clear cells longer than this many bytes.
+    pub max_cell_bytes: Option<(usize, LongCellPolicy)>,
+}
+
+/// The rules registered for each of our cleanups, if enabled.
+#[derive(Debug)]
+pub struct CleanRules {
+    null: Option<RuleId>,
+    trim: Option<RuleId>,
+    collapse: Option<RuleId>,
+    utf8_fallback: Option<RuleId>,
+    invalid_utf8: Option<RuleId>,
+    unicode: Option<RuleId>,
+    punctuation: Option<RuleId>,
+    thousands: Option<RuleId>,
+    decimal_comma: Option<RuleId>,
+    newlines: Option<RuleId>,
+    control_chars: Option<RuleId>,
+    long_cells: Option<RuleId>,
+}
+
+/// How many cells each of our cleanups changed.
+#[derive(Clone, Debug, Default)]
+pub struct CleanHits {
+    null: u64,
+    trim: u64,
+    collapse: u64,
+    utf8_fallback: u64,
+    invalid_utf8: u64,
+    unicode: u64,
+    punctuation: u64,
+    thousands: u64,
+    decimal_comma: u64,
+    newlines: u64,
+    control_chars: u64,
+    long_cells: u64,
+}
+
+impl CleanHits {
+    /// Add the hits in `other` to our own.
+    pub fn merge(&mut self, other: &CleanHits) {
+        self.null += other.null;
+        self.trim += other.trim;
+        self.collapse += other.collapse;
+        self.utf8_fallback += other.utf8_fallback;
+        self.invalid_utf8 += other.invalid_utf8;
+        self.unicode += other.unicode;
+        self.punctuation += other.punctuation;
+        self.thousands += other.thousands;
+        self.decimal_comma += other.decimal_comma;
+        self.newlines += other.newlines;
+        self.control_chars += other.control_chars;
+        self.long_cells += other.long_cells;
+    }
+
+    /// Add our hits to `rule_hits`.
+    pub fn record(&self, rules: &CleanRules, rule_hits: &RuleHits) {
+        let counts = [
+            (rules.null, self.null),
+            (rules.trim, self.trim),
+            (rules.collapse, self.collapse),
+            (rules.utf8_fallback, self.utf8_fallback),
+            (rules.invalid_utf8, self.invalid_utf8),
+            (rules.unicode, self.unicode),
+            (rules.punctuation, self.punctuation),
+            (rules.thousands, self.thousands),
+            (rules.decimal_comma, self.decimal_comma),
+            (rules.newlines, self.newlines),
+            (rules.control_chars, self.control_chars),
+            (rules.long_cells, self.long_cells),
+        ];
+        for (rule, count) in counts {
+            if let Some(rule) = rule {
+                rule_hits.add(rule, count);
+            }
+        }
+    }
+}
+
+impl CellCleaner {
+    /// Do we have any cleanups to apply?
+    pub fn is_noop(&self) -> bool {
+        self.null_re.is_none()
+            && !self.trim_whitespace
+            && !self.collapse_whitespace
+            && self.utf8_fallback.is_none()
+            && !self.replace_invalid_utf8
+            && self.normalize_unicode.is_none()
+            && !self.ascii_punctuation
+            && !self.strip_thousands_separators
+            && !self.decimal_comma_output
+            && !self.replace_newlines
+            && self.strip_control_chars.is_none()
+            && self.long_cell_limit().is_none()
+    }
+
+    /// The cell length limit we enforce ourselves, if any.
+    fn long_cell_limit(&self) -> Option<(usize, LongCellPolicy)> {
+        self.max_cell_bytes
+            .filter(|&(_, policy)| policy != LongCellPolicy::DropRow)
+    }
+
+    /// Register rules for each of our cleanups with `rule_hits`.
+    pub fn register_rules(&self, rule_hits: &mut RuleHits) -> CleanRules {
+        let mut register = |enabled: bool, name: &str| {
+            if enabled {
+                Some(rule_hits.register(name, "cells changed"))
+            } else {
+                None
+            }
+        };
+        CleanRules {
+            null: register(self.null_re.is_some(), "--null"),
+            trim: register(self.trim_whitespace, "--trim-whitespace"),
+            collapse: register(self.collapse_whitespace, "--collapse-whitespace"),
+            utf8_fallback: register(self.utf8_fallback.is_some(), "--utf8-fallback"),
+            invalid_utf8: register(self.replace_invalid_utf8, "--ensure-utf8"),
+            unicode: register(self.normalize_unicode.is_some(), "--normalize-unicode"),
+            punctuation: register(self.ascii_punctuation, "--ascii-punctuation"),
+            thousands: register(
+                self.strip_thousands_separators,
+                "--strip-thousands-separators",
+            ),
+            decimal_comma: register(
+                self.decimal_comma_output,
+                "--decimal-comma-output",
+            ),
+            newlines: register(self.replace_newlines, "--replace-newlines"),
+            control_chars: register(
+                self.strip_control_chars.is_some(),
+                "--strip-control-chars",
+            ),
+            long_cells: register(self.long_cell_limit().is_some(), "--max-cell-bytes"),
+        }
+    }
+
+    /// Clean a single value, counting what we did in `hits`.
+    pub fn clean<'a>(
+        &self,
+        original: &'a [u8],
+        hits: &mut CleanHits,
+    ) -> Cow<'a, [u8]> {
+        let mut val = original;
+
+        // Convert values matching `--null` regex to empty strings.
+        if let Some(ref null_re) = self.null_re {
+            if null_re.is_match(val) {
+                if !val.is_empty() {
+                    hits.null += 1;
+                }
+                val = &[]
+            }
+        }
+
+        // Remove whitespace from our cells.
+        if self.trim_whitespace {
+            let trimmed = cleanup::trim_whitespace(val);
+            if trimmed.len() != val.len() {
+                hits.trim += 1;
+            }
+            val = trimmed;
+        }
+
+        let mut val = Cow::Borrowed(val);
+        if self.collapse_whitespace {
+            if let Cow::Owned(collapsed) = cleanup::collapse_whitespace(&val) {
+                hits.collapse += 1;
+                val = Cow::Owned(collapsed);
+            }
+        }
+
+        // Fix up any invalid UTF-8.
+        if let Some(fallback) = self.utf8_fallback {
+            val = fallback.fix(val);
+            if let Cow::Owned(_) = val {
+                hits.utf8_fallback += 1;
+            }
+        }
+        if self.replace_invalid_utf8 && str::from_utf8(&val).is_err() {
+            val = replace_invalid_utf8(val);
+            hits.invalid_utf8 += 1;
+        }
+
+        // Clean up Unicode, if we have any.
+        if let Some(form) = self.normalize_unicode {
+            if let Some(fixed) = fix_str(&val, |s| unicode::normalize(s, form)) {
+                hits.unicode += 1;
+                val = Cow::Owned(fixed);
+            }
+        }
+        if self.ascii_punctuation {
+            if let Some(fixed) = fix_str(&val, unicode::ascii_punctuation) {
+                hits.punctuation += 1;
+                val = Cow::Owned(fixed);
+            }
+        }
+
+        // Fix up numbers.
+        if self.strip_thousands_separators {
+            let had_comma = val.contains(&b',');
+            val = numbers::strip_thousands_separators(val);
+            if had_comma && !val.contains(&b',') {
+                hits.thousands += 1;
+            }
+        }
+        if self.decimal_comma_output {
+            let had_point = val.contains(&b'.');
+            val = numbers::use_decimal_comma(val);
+            if had_point && !val.contains(&b'.') {
+                hits.decimal_comma += 1;
+            }
+        }
+
+        // Fix newlines.
+        if self.replace_newlines && (val.contains(&b'\n') || val.contains(&b'\r')) {
+            hits.newlines += 1;
+            val = Cow::Owned(cleanup::replace_newlines(&val).into_owned());
+        }
+
+        // Remove control characters.
+        if let Some(replacement) = self.strip_control_chars {
+            if let Cow::Owned(stripped) =
+                cleanup::strip_control_chars(&val, replacement)
+            {
+                hits.control_chars += 1;
+                val = Cow::Owned(stripped);
+            }
+        }
+
+        // Shorten long cells, once we know how long they'll be.
+        if let Some((max, policy)) = self.long_cell_limit() {
+            if val.len() > max {
+                hits.long_cells += 1;
+                val = match policy {
+                    LongCellPolicy::Null => Cow::Borrowed(&[]),
+                    _ => match val {
+                        Cow::Borrowed(val) => {
+                            Cow::Borrowed(cleanup::truncate_bytes(val, max))
+                        }
+                        Cow::Owned(val) => {
+                            Cow::Owned(cleanup::truncate_bytes(&val, max).to_owned())
+                        }
+                    },
+                };
+            }
+        }
+        val
+    }
+}
+
+/// Apply `fix` to `val` if it's valid UTF-8, returning the result if it
+/// changed anything.
+fn fix_str<F>(val: &[u8], fix: F) -> Option<Vec<u8>>
+where
+    F: for<'a> Fn(&'a str) -> Cow<'a, str>,
+{
+    match fix(str::from_utf8(val).ok()?) {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(fixed) => Some(fixed.into_bytes()),
+    }
+}
+
+#[test]
+fn cleans_cells_and_counts_hits() {
+    let cleaner = CellCleaner {
+        null_re: Some(Regex::new("^NULL$").unwrap()),
+        trim_whitespace: true,
+        replace_newlines: true,
+        ..CellCleaner::default()
+    };
+    let mut rule_hits = RuleHits::default();
+    let rules = cleaner.register_rules(&mut rule_hits);
+    let mut hits = CleanHits::default();
+    assert_eq!(cleaner.clean(b"NULL", &mut hits), &b""[..]);
+    assert_eq!(cleaner.clean(b" a\nb ", &mut hits), &b"a b"[..]);
+    assert_eq!(cleaner.clean(b"ok", &mut hits), &b"ok"[..]);
+    let mut total = CleanHits::default();
+    total.merge(&hits);
+    total.merge(&hits);
+    total.record(&rules, &rule_hits);
+    let counts = rule_hits.iter().map(|(_, _, n)| n).collect::<Vec<_>>();
+    assert_eq!(counts, vec![2, 2, 2]);
+}
+
+#[test]
+fn limits_cell_length() {
+    let mut cleaner = CellCleaner {
+        max_cell_bytes: Some((3, LongCellPolicy::Truncate)),
+        ..CellCleaner::default()
+    };
+    let mut hits = CleanHits::default();
+    assert_eq!(cleaner.clean(b"abc", &mut hits), &b"abc"[..]);
+    assert_eq!(cleaner.clean(b"abcd", &mut hits), &b"abc"[..]);
+    cleaner.max_cell_bytes = Some((3, LongCellPolicy::Null));
+    assert_eq!(cleaner.clean(b"abcd", &mut hits), &b""[..]);
+    assert_eq!(hits.long_cells, 2);
+    cleaner.max_cell_bytes = Some((3, LongCellPolicy::DropRow));
+    assert!(cleaner.is_noop());
+    assert_eq!(cleaner.clean(b"abcd", &mut hits), &b"abcd"[..]);
+}
@@ -0,0 +1,170 @@
+//! Cleanups which we apply to individual cells, and our policy for bad rows.
+//!
+//! These are shared by the `scrubcsv` command line tool and by `Scrubber`,
+//! so that both clean data in exactly the same way.
+
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use std::borrow::Cow;
+
+lazy_static! {
+    /// Either a CRLF newline, a LF newline, or a CR newline. Any of these
+    /// will break certain CSV parsers, including BigQuery's CSV importer.
+    static ref NEWLINE_RE: Regex = Regex::new(r#"\n|\r\n?"#)
+        .expect("regex in source code is unparseable");
+
+    /// Runs of spaces and tabs which aren't already a single space.
+    static ref WHITESPACE_RUN_RE: Regex = Regex::new(r#"[ \t]{2,}|\t"#)
+        .expect("regex in source code is unparseable");
+}
+
+/// Compile a `--null` pattern. It always has to match the whole value, even
+/// if it has alternatives like `NULL|\\N`.
+pub fn null_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+/// Remove ASCII whitespace from the beginning and end of `val`.
+///
+/// We do this manually, because the built-in `trim` only works on UTF-8
+/// strings, and we work on any "ASCII-compatible" encoding.
+pub fn trim_whitespace(val: &[u8]) -> &[u8] {
+    let first = val.iter().position(|c| !c.is_ascii_whitespace());
+    let last = val.iter().rposition(|c| !c.is_ascii_whitespace());
+    match (first, last) {
+        (Some(first), Some(last)) if first <= last => &val[first..=last],
+        (None, None) => &[],
+        _ => panic!(
+            "tried to trim {:?}, got impossible indices {:?} {:?}",
+            val, first, last,
+        ),
+    }
+}
+
+/// Replace any LF, CRLF or CR newlines in `val` with spaces.
+pub fn replace_newlines(val: &[u8]) -> Cow<'_, [u8]> {
+    NEWLINE_RE.replace_all(val, &b" "[..])
+}
+
+/// Replace each run of spaces and tabs in `val` with a single space.
+pub fn collapse_whitespace(val: &[u8]) -> Cow<'_, [u8]> {
+    WHITESPACE_RUN_RE.replace_all(val, &b" "[..])
+}
+
+/// Is `c` a C0 control character which we should remove? We keep tabs and
+/// newlines, which may be delimiters or record terminators, and which have
+/// cleanups of their own.
+fn is_strippable_control_char(c: u8) -> bool {
+    c < 0x20 && !matches!(c, b'\t' | b'\n' | b'\r')
+}
+
+/// Remove C0 control characters other than tabs and newlines from `val`, or
+/// replace each of them with `replacement`.
+pub fn strip_control_chars(val: &[u8], replacement: Option<u8>) -> Cow<'_, [u8]> {
+    if !val.iter().any(|&c| is_strippable_control_char(c)) {
+        return Cow::Borrowed(val);
+    }
+    let mut stripped = Vec::with_capacity(val.len());
+    for &c in val {
+        if !is_strippable_control_char(c) {
+            stripped.push(c);
+        } else if let Some(replacement) = replacement {
+            stripped.push(replacement);
+        }
+    }
+    Cow::Owned(stripped)
+}
+
+/// Cut `val` down to at most `max` bytes, without splitting a UTF-8
+/// character in half.
+pub fn truncate_bytes(val: &[u8], max: usize) -> &[u8] {
+    if val.len() <= max {
+        return val;
+    }
+    let mut end = max;
+    while end > 0 && val[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+    &val[..end]
+}
+
+/// How many bad rows we tolerate before deciding that something has gone
+/// horribly wrong.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BadRowPolicy {
+    /// Fail if more than this percentage of rows are bad.
+    pub max_percent: Option<f64>,
+    /// Fail if more than this many rows are bad.
+    pub max_count: Option<u64>,
+    /// Fail as soon as we see a bad row.
+    pub fail_fast: bool,
+}
+
+impl BadRowPolicy {
+    /// Are `bad_rows` out of `rows` too many?
+    pub fn is_exceeded(&self, bad_rows: u64, rows: u64) -> bool {
+        let too_many_pct = self
+            .max_percent
+            .is_some_and(|pct| bad_rows as f64 > rows as f64 * pct / 100.0);
+        let too_many_count = self.max_count.is_some_and(|max| bad_rows > max);
+        too_many_pct || too_many_count || (self.fail_fast && bad_rows > 0)
+    }
+}
+
+impl Default for BadRowPolicy {
+    /// By default, we allow up to 10% of rows to be bad.
+    fn default() -> Self {
+        BadRowPolicy {
+            max_percent: Some(10.0),
+            max_count: None,
+            fail_fast: false,
+        }
+    }
+}
+
+#[test]
+fn trims_and_replaces_newlines() {
+    assert_eq!(trim_whitespace(b" a b\t"), b"a b");
+    assert_eq!(trim_whitespace(b" \n "), b"");
+    assert_eq!(&replace_newlines(b"a\r\nb\nc\rd")[..], b"a b c d");
+    assert_eq!(&collapse_whitespace(b"a  b\tc \t d e")[..], b"a b c d e");
+    assert!(matches!(collapse_whitespace(b"a b"), Cow::Borrowed(_)));
+}
+
+#[test]
+fn strips_control_chars() {
+    assert_eq!(&strip_control_chars(b"a\x00b\x0bc\x0c", None)[..], b"abc");
+    assert_eq!(&strip_control_chars(b"a\x1fb", Some(b' '))[..], b"a b");
+    assert_eq!(&strip_control_chars(b"a\tb\r\n", None)[..], b"a\tb\r\n");
+}
+
+#[test]
+fn truncates_without_splitting_characters() {
+    assert_eq!(truncate_bytes(b"abc", 5), b"abc");
+    assert_eq!(truncate_bytes(b"abcdef", 3), b"abc");
+    assert_eq!(truncate_bytes("aé".as_bytes(), 2), b"a");
+    assert_eq!(truncate_bytes("aé".as_bytes(), 3), "aé".as_bytes());
+}
+
+#[test]
+fn bad_row_policy_checks_limits() {
+    let policy = BadRowPolicy::default();
+    assert!(!policy.is_exceeded(1, 10));
+    assert!(policy.is_exceeded(2, 10));
+    let policy = BadRowPolicy {
+        max_percent: None,
+        max_count: Some(1),
+        fail_fast: false,
+    };
+    assert!(!policy.is_exceeded(1, 2));
+    assert!(policy.is_exceeded(2, 100));
+}
+
+#[test]
+fn anchors_null_regexes() {
+    let re = null_regex(r"(?i)NULL|\\N").unwrap();
+    assert!(re.is_match(b"null"));
+    assert!(re.is_match(b"\\N"));
+    assert!(!re.is_match(b"Nullam dolor"));
+    assert!(!re.is_match(b"x\\N"));
+}
@@ -0,0 +1,104 @@
+//! Finding columns by name.
+//!
+//! These are shared by the `scrubcsv` command line tool and by `Scrubber`,
+//! so that both accept the same column names.
+
+use csv::ByteRecord;
+use std::{error, fmt};
+
+use crate::uniquifier::Uniquifier;
+
+/// Why we couldn't find a column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnLookupError {
+    /// No column had that name.
+    Missing,
+    /// More than one column loosely matched that name.
+    Ambiguous,
+}
+
+impl fmt::Display for ColumnLookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColumnLookupError::Missing => write!(f, "cannot find column"),
+            ColumnLookupError::Ambiguous => write!(f, "column is ambiguous"),
+        }
+    }
+}
+
+impl error::Error for ColumnLookupError {}
+
+/// Find the column `name` in `hdr`. A name like `@3` or `#3` refers to the
+/// third column, unless a column actually has that name.
+pub fn find_column(hdr: &ByteRecord, name: &str) -> Option<usize> {
+    hdr.iter()
+        .position(|col| col == name.as_bytes())
+        .or_else(|| {
+            name.strip_prefix(['@', '#'])
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|&n| n >= 1 && n <= hdr.len())
+                .map(|n| n - 1)
+        })
+}
+
+/// Like `find_column`, but if no column has exactly that name, we ignore
+/// case and surrounding whitespace, and then try the name
+/// `--clean-column-names` would give it. Fails if we find nothing, or more
+/// than one column.
+pub fn find_column_loosely(
+    hdr: &ByteRecord,
+    name: &str,
+) -> Result<usize, ColumnLookupError> {
+    let find = |matches: &dyn Fn(&str) -> bool| {
+        let found = hdr
+            .iter()
+            .enumerate()
+            .filter(|(_, col)| matches(&String::from_utf8_lossy(col)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        match found[..] {
+            [] => Ok(None),
+            [i] => Ok(Some(i)),
+            _ => Err(ColumnLookupError::Ambiguous),
+        }
+    };
+    let normalized = name.trim().to_lowercase();
+    let cleaned = Uniquifier::default()
+        .unique_id_for(name)
+        .map(|id| id.to_owned())
+        .ok();
+    if let Some(i) = find_column(hdr, name) {
+        Ok(i)
+    } else if let Some(i) = find(&|col| col.trim().to_lowercase() == normalized)? {
+        Ok(i)
+    } else if let Some(i) = find(&|col| Some(col) == cleaned.as_deref())? {
+        Ok(i)
+    } else {
+        Err(ColumnLookupError::Missing)
+    }
+}
+
+#[test]
+fn finds_columns() {
+    let hdr = ByteRecord::from(vec!["id", " Email ", "EMAIL", "zip_code"]);
+    let find = |name| find_column_loosely(&hdr, name).ok();
+    assert_eq!(find("id"), Some(0));
+    assert_eq!(find("#2"), Some(1));
+    assert_eq!(find(" ID"), Some(0));
+    assert_eq!(find("EMAIL"), Some(2));
+    assert_eq!(find("email"), None);
+    assert_eq!(
+        find_column_loosely(&hdr, "email"),
+        Err(ColumnLookupError::Ambiguous)
+    );
+    assert_eq!(find("Zip Code"), Some(3));
+    assert_eq!(find("state"), None);
+
+    let hdr = ByteRecord::from(vec!["", "@2", ""]);
+    assert_eq!(find_column(&hdr, "@1"), Some(0));
+    assert_eq!(find_column(&hdr, "#3"), Some(2));
+    assert_eq!(find_column(&hdr, "@2"), Some(1));
+    assert_eq!(find_column(&hdr, "@0"), None);
+    assert_eq!(find_column(&hdr, "@4"), None);
+    assert_eq!(find_column(&hdr, "3"), None);
+}
@@ -0,0 +1,86 @@
+//! Skipping comment lines anywhere in our input, for `--comment-char`.
+
+use log::debug;
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, prelude::*},
+    rc::Rc,
+};
+
+/// Where we copy the comment lines we skip, for `--comments-path`.
+pub type CommentSink = Rc<RefCell<io::BufWriter<fs::File>>>;
+
+/// A reader which discards lines starting with a comment character, unless
+/// they're inside a quoted value. We find quoted values by counting quotes,
+/// which works for both quoted and doubled quotes.
+pub struct CommentLineSkipper<R: BufRead> {
+    inner: R,
+    /// Lines starting with this character are comments.
+    comment: u8,
+    /// Our quote character, if we have one.
+    quote: Option<u8>,
+    /// Are we inside a quoted value at the end of the last line?
+    in_quotes: bool,
+    /// Where to copy the comments we skip.
+    sink: Option<CommentSink>,
+    /// The line we're returning now.
+    line: Vec<u8>,
+    /// How much of `line` we've already returned.
+    pos: usize,
+}
+
+impl<R: BufRead> CommentLineSkipper<R> {
+    /// Create a new reader.
+    pub fn new(
+        inner: R,
+        comment: u8,
+        quote: Option<u8>,
+        sink: Option<CommentSink>,
+    ) -> Self {
+        CommentLineSkipper {
+            inner,
+            comment,
+            quote,
+            in_quotes: false,
+            sink,
+            line: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Read for CommentLineSkipper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            if !self.in_quotes && self.line.first() == Some(&self.comment) {
+                debug!("skipping comment {:?}", String::from_utf8_lossy(&self.line));
+                if let Some(sink) = &self.sink {
+                    sink.borrow_mut().write_all(&self.line)?;
+                }
+                self.line.clear();
+            } else if let Some(quote) = self.quote {
+                let quotes = self.line.iter().filter(|&&b| b == quote).count();
+                self.in_quotes ^= quotes % 2 == 1;
+            }
+        }
+        let count = buf.len().min(self.line.len() - self.pos);
+        buf[..count].copy_from_slice(&self.line[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+#[test]
+fn skips_comment_lines() {
+    let input = "# exported\na,b\n1,\"x\n# not a comment\"\n#2,3\n4,5";
+    let mut rdr = CommentLineSkipper::new(input.as_bytes(), b'#', Some(b'"'), None);
+    let mut output = String::new();
+    rdr.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "a,b\n1,\"x\n# not a comment\"\n4,5");
+}
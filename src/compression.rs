@@ -0,0 +1,249 @@
+//! Reading compressed input.
+//!
+//! We support gzip, and, depending on which cargo features are enabled,
+//! zstd, bzip2 and xz. We recognize compressed input by its file extension
+//! or by the magic bytes at the start of the data.
+
+use flate2::read::MultiGzDecoder;
+use log::debug;
+use std::{
+    io::{self, prelude::*},
+    path::Path,
+};
+
+/// How our input is compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Not at all.
+    None,
+    /// With gzip.
+    Gzip,
+    /// With Zstandard.
+    Zstd,
+    /// With bzip2.
+    Bzip2,
+    /// With xz.
+    Xz,
+    /// We don't know yet, so look at the start of the data.
+    Detect,
+}
+
+/// Magic bytes which identify compression formats, except for bzip2.
+const MAGIC: &[(Compression, &[u8])] = &[
+    (Compression::Gzip, &[0x1f, 0x8b]),
+    (Compression::Zstd, &[0x28, 0xb5, 0x2f, 0xfd]),
+    (Compression::Xz, &[0xfd, b'7', b'z', b'X', b'Z', 0x00]),
+];
+
+/// bzip2 data starts with "BZh", a block size from '1' to '9', and then
+/// either a block header or the end of an empty stream. We check all of
+/// this, because "BZh" could easily be the start of a CSV file.
+const BZIP2_BLOCK_MAGIC: &[u8] = &[0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+const BZIP2_END_MAGIC: &[u8] = &[0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+
+/// The most bytes we need to look at to recognize a compression format.
+const MAX_MAGIC_LEN: usize = 10;
+
+impl Compression {
+    /// Decide how our input is compressed, based on whether `--gzip` was
+    /// passed and the name of our input file, if any.
+    pub fn for_input(gzip: bool, path: Option<&Path>) -> Compression {
+        if gzip {
+            return Compression::Gzip;
+        }
+        let ext = path
+            .and_then(|path| path.extension())
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            Some("xz") => Compression::Xz,
+            _ => Compression::Detect,
+        }
+    }
+
+    /// Recognize compressed data from its first few bytes. If we need to see
+    /// more bytes to be sure, and we're not at the end of our input, return
+    /// `None`.
+    fn from_magic(prefix: &[u8], eof: bool) -> Option<Compression> {
+        for &(compression, magic) in MAGIC {
+            if prefix.starts_with(magic) {
+                return Some(compression);
+            } else if !eof && magic.starts_with(prefix) {
+                return None;
+            }
+        }
+        let might_be_bzip2 = b"BZh".starts_with(&prefix[..prefix.len().min(3)])
+            && prefix.get(3).is_none_or(|b| (b'1'..=b'9').contains(b))
+            && prefix.get(4..).is_none_or(|rest| {
+                BZIP2_BLOCK_MAGIC.starts_with(rest)
+                    || BZIP2_END_MAGIC.starts_with(rest)
+            });
+        if might_be_bzip2 && prefix.len() == MAX_MAGIC_LEN {
+            Some(Compression::Bzip2)
+        } else if might_be_bzip2 && !eof {
+            None
+        } else {
+            Some(Compression::None)
+        }
+    }
+
+    /// Wrap `rdr` so that it returns decompressed data.
+    pub fn decompress<R>(self, rdr: R) -> Box<dyn Read + Send>
+    where
+        R: Read + Send + 'static,
+    {
+        // Most tools will happily read several compressed streams written
+        // back to back, so we do too.
+        match self {
+            Compression::None => Box::new(rdr),
+            Compression::Gzip => Box::new(MultiGzDecoder::new(rdr)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => match zstd::Decoder::new(rdr) {
+                Ok(decoder) => Box::new(decoder),
+                Err(err) => Box::new(FailingReader(Some(err))),
+            },
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(rdr)),
+            #[cfg(feature = "xz")]
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(rdr)),
+            #[allow(unreachable_patterns)]
+            Compression::Zstd | Compression::Bzip2 | Compression::Xz => {
+                Box::new(FailingReader(Some(io::Error::other(format!(
+                    "scrubcsv was built without support for {:?} compression",
+                    self,
+                )))))
+            }
+            Compression::Detect => Box::new(DetectingReader {
+                inner: Some(rdr),
+                decompressed: None,
+            }),
+        }
+    }
+}
+
+/// A reader which looks at the start of its input to see how it's
+/// compressed. We wait until we're first read from to do this, so that we
+/// don't block before our caller is ready.
+struct DetectingReader<R: Read + Send + 'static> {
+    /// Our input, until we've looked at it.
+    inner: Option<R>,
+    /// Our decompressed input, once we know how to decompress it.
+    decompressed: Option<Box<dyn Read + Send>>,
+}
+
+impl<R: Read + Send + 'static> Read for DetectingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.decompressed.is_none() {
+            let mut inner = self.inner.take().expect("should have input");
+            // Read just enough to decide, because the rest of our input may
+            // not have been written yet.
+            let mut prefix = vec![0; MAX_MAGIC_LEN];
+            let mut len = 0;
+            let compression = loop {
+                let count = match inner.read(&mut prefix[len..]) {
+                    Ok(count) => count,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                len += count;
+                if let Some(compression) =
+                    Compression::from_magic(&prefix[..len], count == 0)
+                {
+                    break compression;
+                }
+            };
+            prefix.truncate(len);
+            debug!("detected input compression {:?}", compression);
+            // Box this, so the compiler doesn't try to instantiate
+            // `decompress` for nested `Chain` types forever.
+            let rdr: Box<dyn Read + Send> =
+                Box::new(io::Cursor::new(prefix).chain(inner));
+            self.decompressed = Some(compression.decompress(rdr));
+        }
+        self.decompressed
+            .as_mut()
+            .expect("should have decompressed input")
+            .read(buf)
+    }
+}
+
+/// A reader which fails with an error.
+struct FailingReader(Option<io::Error>);
+
+impl Read for FailingReader {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.take() {
+            Some(err) => Err(err),
+            None => Ok(0),
+        }
+    }
+}
+
+#[test]
+fn detects_compression_from_path() {
+    let examples = &[
+        ("a.csv.GZ", Compression::Gzip),
+        ("a.csv.zst", Compression::Zstd),
+        ("a.csv.bz2", Compression::Bzip2),
+        ("a.csv.xz", Compression::Xz),
+        ("a.csv", Compression::Detect),
+    ];
+    for &(path, expected) in examples {
+        assert_eq!(
+            Compression::for_input(false, Some(Path::new(path))),
+            expected,
+        );
+    }
+    assert_eq!(Compression::for_input(true, None), Compression::Gzip);
+}
+
+#[test]
+fn decompresses_gzip() {
+    use flate2::{write::GzEncoder, Compression as Level};
+
+    // Write two gzip members back to back.
+    let mut data = vec![];
+    for chunk in ["a,b\n", "1,2\n"] {
+        let mut encoder = GzEncoder::new(vec![], Level::default());
+        encoder.write_all(chunk.as_bytes()).unwrap();
+        data.extend(encoder.finish().unwrap());
+    }
+    let mut decompressed = String::new();
+    Compression::Gzip
+        .decompress(std::io::Cursor::new(data))
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, "a,b\n1,2\n");
+}
+
+#[test]
+#[cfg(all(feature = "zstd", feature = "bzip2", feature = "xz"))]
+fn detects_compression_from_magic() {
+    let input = b"a,b\n1,2\n";
+    let mut bz = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+    bz.write_all(input).unwrap();
+    let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+    xz.write_all(input).unwrap();
+    let examples = vec![
+        input.to_vec(),
+        b"BZh".to_vec(),
+        zstd::encode_all(&input[..], 0).unwrap(),
+        bz.finish().unwrap(),
+        xz.finish().unwrap(),
+    ];
+    for data in examples {
+        let mut decompressed = vec![];
+        Compression::Detect
+            .decompress(std::io::Cursor::new(data.clone()))
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        if data.starts_with(b"BZh") && data.len() == 3 {
+            assert_eq!(decompressed, b"BZh");
+        } else {
+            assert_eq!(decompressed, input);
+        }
+    }
+}
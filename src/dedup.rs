@@ -0,0 +1,438 @@
+//! Removing duplicate rows.
+//!
+//! By default, we remember a 128-bit hash of every row we've kept, so
+//! memory use grows with the number of distinct rows. With a memory limit,
+//! once remembering our hashes would take more than that, we switch to a
+//! Bloom filter of a fixed size instead. Bloom filters never miss a real
+//! duplicate, but they sometimes think that a new row is a duplicate, and
+//! this gets more likely as they fill up.
+
+use csv::ByteRecord;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::Hasher,
+    str::FromStr,
+};
+
+use crate::errors::*;
+use crate::spill::{self, Decoder, Spill, SpillBuffer};
+use crate::util::{find_column, ByteSize};
+
+/// Roughly how many bytes each hash takes up in a `HashSet<u128>`,
+/// including the table's control bytes.
+const BYTES_PER_HASH: usize = 17;
+
+/// How we remember the rows we've seen.
+#[derive(Debug)]
+enum Seen {
+    /// Exactly, by remembering their hashes. We use 128-bit hashes, so that
+    /// even with billions of rows, we're very unlikely to drop a row which
+    /// isn't really a duplicate.
+    Exact(HashSet<u128>),
+    /// Approximately.
+    Bloom(BloomFilter),
+}
+
+/// Remembers the rows we've written, so that we can drop exact duplicates.
+#[derive(Debug)]
+pub struct Deduplicator {
+    /// The rows we've seen.
+    seen: Seen,
+    /// The most memory we may use, if any.
+    memory_limit: Option<ByteSize>,
+    /// How many duplicates we've found.
+    duplicates: u64,
+}
+
+impl Deduplicator {
+    /// Create a deduplicator which uses at most `memory_limit` to remember
+    /// rows, if specified.
+    pub fn new(memory_limit: Option<ByteSize>) -> Deduplicator {
+        Deduplicator {
+            seen: Seen::Exact(HashSet::new()),
+            memory_limit,
+            duplicates: 0,
+        }
+    }
+
+    /// Is `row` a duplicate of a row we've already seen? If not, remember it.
+    pub fn is_duplicate<'a, I>(&mut self, row: I) -> bool
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let hash = hash_row(row);
+        self.switch_to_bloom_if_needed();
+        let duplicate = match &mut self.seen {
+            Seen::Exact(seen) => !seen.insert(hash),
+            Seen::Bloom(bloom) => !bloom.insert(hash),
+        };
+        if duplicate {
+            self.duplicates += 1;
+        }
+        duplicate
+    }
+
+    /// If our exact set is about to grow past our memory limit, replace it
+    /// with a Bloom filter.
+    fn switch_to_bloom_if_needed(&mut self) {
+        if let (Seen::Exact(seen), Some(ByteSize(limit))) =
+            (&self.seen, self.memory_limit)
+        {
+            let full = seen.len() == seen.capacity();
+            if full && seen.capacity().max(1) * 2 * BYTES_PER_HASH > limit {
+                let mut bloom = BloomFilter::new(limit);
+                for &hash in seen {
+                    bloom.insert(hash);
+                }
+                self.seen = Seen::Bloom(bloom);
+            }
+        }
+    }
+
+    /// How many duplicate rows have we found?
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// If we switched to a Bloom filter, the chance that it would mistake a
+    /// new row for a duplicate right now.
+    pub fn false_positive_rate(&self) -> Option<f64> {
+        match &self.seen {
+            Seen::Exact(_) => None,
+            Seen::Bloom(bloom) => Some(bloom.false_positive_rate()),
+        }
+    }
+}
+
+/// A Bloom filter of row hashes.
+#[derive(Debug)]
+struct BloomFilter {
+    /// Our bits.
+    bits: Vec<u64>,
+    /// How many of our bits are set.
+    bits_set: u64,
+}
+
+impl BloomFilter {
+    /// How many bits we set for each hash. This is about right when we have
+    /// 10 bits per hash, which gives a false positive rate of about 1%.
+    const HASHES: u64 = 7;
+
+    /// Create a filter which uses `bytes` bytes.
+    fn new(bytes: usize) -> BloomFilter {
+        BloomFilter {
+            bits: vec![0; (bytes / 8).max(1)],
+            bits_set: 0,
+        }
+    }
+
+    /// Add `hash` to our filter. Returns false if it may have already been
+    /// present.
+    fn insert(&mut self, hash: u128) -> bool {
+        // Derive all the bits we need from our two 64-bit halves.
+        let bit_count = self.bits.len() as u64 * 64;
+        let (h1, h2) = ((hash >> 64) as u64, hash as u64);
+        let mut inserted = false;
+        for i in 0..Self::HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % bit_count;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                self.bits_set += 1;
+                inserted = true;
+            }
+        }
+        inserted
+    }
+
+    /// The chance that a new hash would look like it was already present.
+    fn false_positive_rate(&self) -> f64 {
+        let full = self.bits_set as f64 / (self.bits.len() as f64 * 64.0);
+        full.powi(Self::HASHES as i32)
+    }
+}
+
+/// Which row to keep when several rows have the same key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Keep {
+    /// Keep the first row with each key.
+    First,
+    /// Keep the last row with each key. We can't write any rows until we've
+    /// read all our input, so this holds every output row, spilling them to
+    /// disk past `--memory-limit`.
+    Last,
+}
+
+impl FromStr for Keep {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Keep> {
+        match s {
+            "first" => Ok(Keep::First),
+            "last" => Ok(Keep::Last),
+            _ => Err(format_err!("expected \"first\" or \"last\", found {:?}", s)),
+        }
+    }
+}
+
+/// A row which may still be replaced by a later row with the same key.
+#[derive(Debug)]
+pub struct HeldRow {
+    /// The values we'll write.
+    pub values: Vec<Vec<u8>>,
+    /// The row as we read it, in case we need to reject it.
+    pub input_record: ByteRecord,
+    /// Did cleaning or repair change the row?
+    pub changed: bool,
+}
+
+impl Spill for HeldRow {
+    fn memory_size(&self) -> usize {
+        spill::values_memory_size(self.values.iter().map(|v| &v[..]))
+            + spill::values_memory_size(self.input_record.iter())
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(u8::from(self.changed));
+        match self.input_record.position() {
+            Some(pos) => {
+                buf.push(1);
+                for n in [pos.byte(), pos.line(), pos.record()] {
+                    spill::encode_u64(buf, n);
+                }
+            }
+            None => buf.push(0),
+        }
+        spill::encode_values(buf, self.values.iter().map(|v| &v[..]));
+        spill::encode_values(buf, self.input_record.iter());
+    }
+
+    fn decode(buf: &[u8]) -> Option<HeldRow> {
+        let (&[changed, has_position], buf) = buf.split_first_chunk::<2>()?;
+        let mut decoder = Decoder::new(buf);
+        let position = if has_position == 1 {
+            let mut pos = csv::Position::new();
+            pos.set_byte(decoder.u64()?)
+                .set_line(decoder.u64()?)
+                .set_record(decoder.u64()?);
+            Some(pos)
+        } else {
+            None
+        };
+        let values = decoder.values()?;
+        let mut input_record = ByteRecord::from(decoder.values()?);
+        input_record.set_position(position);
+        decoder.finish()?;
+        Some(HeldRow {
+            values,
+            input_record,
+            changed: changed == 1,
+        })
+    }
+}
+
+/// What to do with a row, according to `KeyDeduplicator`.
+#[derive(Debug)]
+pub enum KeyCheck {
+    /// Write this row.
+    Write,
+    /// Reject this row, because we've already written a row with its key.
+    Duplicate,
+    /// We're holding on to this row until we know whether it's the last row
+    /// with its key. If we were holding an earlier row with the same key,
+    /// reject it.
+    Held {
+        /// The earlier row we were holding, if any.
+        replaced: Option<HeldRow>,
+    },
+}
+
+/// Keeps a single row for each key, for `--dedup-by`.
+#[derive(Debug)]
+pub struct KeyDeduplicator {
+    /// The columns which make up our key.
+    key_cols: Vec<usize>,
+    /// Which row to keep.
+    keep: Keep,
+    /// Hashes of every key we've seen, and where we're holding the row with
+    /// that key, if we're holding rows.
+    seen: HashMap<u128, usize>,
+    /// With `Keep::Last`, the rows we're holding, in order.
+    held: SpillBuffer<HeldRow>,
+}
+
+impl KeyDeduplicator {
+    /// Create a deduplicator for rows with the header `hdr`, using the
+    /// columns in `key_names` as our key. Any rows we hold past
+    /// `memory_limit` are spilled to disk.
+    pub fn new(
+        hdr: &ByteRecord,
+        key_names: &[String],
+        keep: Keep,
+        memory_limit: Option<ByteSize>,
+    ) -> Result<KeyDeduplicator> {
+        let key_cols = key_names
+            .iter()
+            .map(|name| {
+                find_column(hdr, name)
+                    .ok_or_else(|| format_err!("cannot find dedup column {:?}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(KeyDeduplicator {
+            key_cols,
+            keep,
+            seen: HashMap::new(),
+            held: SpillBuffer::new(memory_limit),
+        })
+    }
+
+    /// Decide what to do with the row `values`, which we read as
+    /// `input_record`.
+    pub fn check(
+        &mut self,
+        values: &[&[u8]],
+        input_record: &ByteRecord,
+        changed: bool,
+    ) -> Result<KeyCheck> {
+        let key = hash_row(self.key_cols.iter().map(|&i| values[i]));
+        match self.keep {
+            Keep::First if self.seen.contains_key(&key) => Ok(KeyCheck::Duplicate),
+            Keep::First => {
+                self.seen.insert(key, 0);
+                Ok(KeyCheck::Write)
+            }
+            Keep::Last => {
+                let idx = self.held.push(HeldRow {
+                    values: values.iter().map(|v| v.to_vec()).collect(),
+                    input_record: input_record.clone(),
+                    changed,
+                })?;
+                let replaced = match self.seen.insert(key, idx) {
+                    Some(i) => self.held.take(i)?,
+                    None => None,
+                };
+                Ok(KeyCheck::Held { replaced })
+            }
+        }
+    }
+
+    /// Return the rows we're still holding, in order.
+    pub fn into_held(self) -> impl Iterator<Item = Result<HeldRow>> {
+        self.held.into_rows()
+    }
+}
+
+/// Compute a 128-bit hash of `row`.
+fn hash_row<'a, I>(row: I) -> u128
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    // Combine two differently-initialized 64-bit hashes.
+    let mut hashers = [DefaultHasher::new(), DefaultHasher::new()];
+    hashers[1].write_u8(1);
+    for value in row {
+        for hasher in &mut hashers {
+            // Include the length, so that `a,bc` and `ab,c` hash differently.
+            hasher.write_usize(value.len());
+            hasher.write(value);
+        }
+    }
+    (u128::from(hashers[0].finish()) << 64) | u128::from(hashers[1].finish())
+}
+
+#[test]
+fn finds_duplicates() {
+    let mut dedup = Deduplicator::new(None);
+    let rows: &[&[&[u8]]] = &[&[b"a", b"bc"], &[b"ab", b"c"], &[b"a", b"bc"]];
+    let duplicates = rows
+        .iter()
+        .map(|row| dedup.is_duplicate(row.iter().copied()))
+        .collect::<Vec<_>>();
+    assert_eq!(duplicates, vec![false, false, true]);
+    assert_eq!(dedup.duplicates(), 1);
+    assert_eq!(dedup.false_positive_rate(), None);
+}
+
+#[test]
+fn switches_to_bloom_filter() {
+    let mut dedup = Deduplicator::new(Some(ByteSize::from_str("64K").unwrap()));
+    let row = |i: u32| vec![i.to_string().into_bytes()];
+    let new_rows = (0..20_000)
+        .filter(|&i| !dedup.is_duplicate(row(i).iter().map(|v| &v[..])))
+        .count();
+    let rate = dedup
+        .false_positive_rate()
+        .expect("should use Bloom filter");
+    // 20,000 rows in 512K bits should give us a false positive rate of
+    // about 0.1%.
+    assert!(rate < 0.01, "{}", rate);
+    assert!(new_rows > 19_900, "{}", new_rows);
+    // We never miss real duplicates.
+    assert!((0..20_000).all(|i| dedup.is_duplicate(row(i).iter().map(|v| &v[..]))));
+}
+
+#[test]
+fn keeps_one_row_per_key() {
+    let hdr = ByteRecord::from(vec!["id", "name"]);
+    let rows: &[&[&[u8]]] = &[&[b"1", b"a"], &[b"2", b"b"], &[b"1", b"c"]];
+    let input = ByteRecord::new();
+
+    let mut first =
+        KeyDeduplicator::new(&hdr, &["id".to_owned()], Keep::First, None).unwrap();
+    let checks = rows
+        .iter()
+        .map(|row| first.check(row, &input, false).unwrap())
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        checks[..],
+        [KeyCheck::Write, KeyCheck::Write, KeyCheck::Duplicate]
+    ));
+
+    let mut last =
+        KeyDeduplicator::new(&hdr, &["id".to_owned()], Keep::Last, None).unwrap();
+    for (i, row) in rows.iter().enumerate() {
+        match last.check(row, &input, false).unwrap() {
+            KeyCheck::Held { replaced } => {
+                let replaced = replaced.map(|held| held.values[1].clone());
+                assert_eq!(replaced, if i == 2 { Some(b"a".to_vec()) } else { None });
+            }
+            check => panic!("unexpected {:?}", check),
+        }
+    }
+    let kept = last
+        .into_held()
+        .map(|held| held.unwrap().values)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kept,
+        vec![
+            vec![b"2".to_vec(), b"b".to_vec()],
+            vec![b"1".to_vec(), b"c".to_vec()]
+        ]
+    );
+
+    assert!(
+        KeyDeduplicator::new(&hdr, &["nope".to_owned()], Keep::First, None).is_err()
+    );
+}
+
+#[test]
+fn spills_held_rows() {
+    let mut record = ByteRecord::from(vec![" a "]);
+    let mut pos = csv::Position::new();
+    pos.set_byte(10).set_line(3).set_record(2);
+    record.set_position(Some(pos.clone()));
+    let held = HeldRow {
+        values: vec![b"a".to_vec()],
+        input_record: record,
+        changed: true,
+    };
+    let mut buf = vec![];
+    held.encode(&mut buf);
+    let decoded = HeldRow::decode(&buf).unwrap();
+    assert_eq!(decoded.values, held.values);
+    assert_eq!(decoded.input_record, held.input_record);
+    assert_eq!(decoded.input_record.position(), Some(&pos));
+    assert!(decoded.changed);
+    assert!(HeldRow::decode(&buf[..buf.len() - 1]).is_none());
+}
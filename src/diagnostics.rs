@@ -0,0 +1,103 @@
+//! Human-readable diagnostics for bad rows, so that people can see what went
+//! wrong without re-running with `RUST_LOG=debug`.
+
+use csv::ByteRecord;
+
+/// Prints details about the first few bad rows we see.
+#[derive(Debug)]
+pub struct BadRowDiagnostics {
+    /// The delimiter to use when displaying rows.
+    delimiter: u8,
+    /// The maximum number of rows to show.
+    limit: usize,
+    /// The number of rows we've shown so far.
+    shown: usize,
+}
+
+impl BadRowDiagnostics {
+    /// Create a new reporter which shows at most `limit` rows.
+    pub fn new(delimiter: u8, limit: usize) -> BadRowDiagnostics {
+        BadRowDiagnostics {
+            delimiter,
+            limit,
+            shown: 0,
+        }
+    }
+
+    /// Report that `record` has the wrong number of columns, if we haven't
+    /// already shown enough rows. `row` is used when we don't know the
+    /// record's line number.
+    pub fn wrong_column_count(
+        &mut self,
+        row: u64,
+        record: &ByteRecord,
+        expected_cols: usize,
+    ) {
+        if self.shown >= self.limit {
+            return;
+        }
+        self.shown += 1;
+        eprint!("{}", self.describe(row, record, expected_cols));
+        if self.shown == self.limit {
+            eprintln!("(not showing any more bad rows)");
+        }
+    }
+
+    /// Describe a row with the wrong number of columns, with a caret pointing
+    /// at the delimiter which starts the first extra column, or at the end of a short row.
+    fn describe(&self, row: u64, record: &ByteRecord, expected_cols: usize) -> String {
+        let location = match record.position() {
+            Some(pos) => format!("line {}", pos.line()),
+            None => format!("row {}", row),
+        };
+
+        // Rebuild the row for display, keeping track of where the problem is.
+        let delimiter = char::from(self.delimiter).escape_default().to_string();
+        let mut line = String::new();
+        let mut caret = None;
+        for (i, val) in record.iter().enumerate() {
+            if i == expected_cols {
+                caret = Some(line.chars().count());
+            }
+            if i > 0 {
+                line.push_str(&delimiter);
+            }
+            for c in String::from_utf8_lossy(val).chars() {
+                match c {
+                    '\n' => line.push_str("\\n"),
+                    '\r' => line.push_str("\\r"),
+                    _ => line.push(c),
+                }
+            }
+        }
+        let (caret, note) = match caret {
+            Some(caret) => (caret, "unexpected delimiter"),
+            None => (line.chars().count(), "row ends here"),
+        };
+
+        format!(
+            "Bad row at {}: expected {} columns, found {}\n  {}\n  {}^ {}\n",
+            location,
+            expected_cols,
+            record.len(),
+            line,
+            " ".repeat(caret),
+            note,
+        )
+    }
+}
+
+#[test]
+fn describes_bad_rows() {
+    let diagnostics = BadRowDiagnostics::new(b',', 3);
+    let record = ByteRecord::from(vec!["1", "2", "3"]);
+    assert_eq!(
+        diagnostics.describe(7, &record, 2),
+        "Bad row at row 7: expected 2 columns, found 3\n  1,2,3\n     ^ unexpected delimiter\n",
+    );
+    let record = ByteRecord::from(vec!["a\nb"]);
+    assert_eq!(
+        diagnostics.describe(7, &record, 2),
+        "Bad row at row 7: expected 2 columns, found 1\n  a\\nb\n      ^ row ends here\n",
+    );
+}
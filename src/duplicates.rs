@@ -0,0 +1,104 @@
+//! Counting duplicate rows, without removing them.
+
+use csv::ByteRecord;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::Hasher,
+};
+
+use crate::errors::*;
+use crate::util::find_column;
+
+/// Counts duplicate rows and, optionally, duplicated keys.
+#[derive(Debug, Default)]
+pub struct DuplicateCounter {
+    /// Hashes of every row we've seen. We only keep 64-bit hashes to save
+    /// memory, so there's a tiny chance of counting a false duplicate.
+    seen: HashSet<u64>,
+    /// The number of rows which exactly matched an earlier row.
+    duplicate_rows: u64,
+    /// The columns which make up our key, if any.
+    key_cols: Vec<usize>,
+    /// How many times we've seen each key.
+    keys: HashMap<Vec<Vec<u8>>, u64>,
+}
+
+impl DuplicateCounter {
+    /// Create a counter for rows with the header `hdr`. If `key_names` isn't
+    /// empty, also count how often each key made up of those columns appears.
+    pub fn new(hdr: &ByteRecord, key_names: &[String]) -> Result<DuplicateCounter> {
+        let key_cols = key_names
+            .iter()
+            .map(|name| {
+                find_column(hdr, name)
+                    .ok_or_else(|| format_err!("cannot find key column {:?}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(DuplicateCounter {
+            key_cols,
+            ..DuplicateCounter::default()
+        })
+    }
+
+    /// Record a row.
+    pub fn observe_row<'a, I>(&mut self, row: I)
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut hasher = DefaultHasher::new();
+        let mut values = vec![];
+        for value in row {
+            // Include the length, so that `a,bc` and `ab,c` hash differently.
+            hasher.write_usize(value.len());
+            hasher.write(value);
+            if !self.key_cols.is_empty() {
+                values.push(value);
+            }
+        }
+        if !self.seen.insert(hasher.finish()) {
+            self.duplicate_rows += 1;
+        }
+        if !self.key_cols.is_empty() {
+            let key = self
+                .key_cols
+                .iter()
+                .map(|&i| values.get(i).copied().unwrap_or(b"").to_owned())
+                .collect();
+            *self.keys.entry(key).or_default() += 1;
+        }
+    }
+
+    /// How many rows were exact duplicates of earlier rows?
+    pub fn duplicate_rows(&self) -> u64 {
+        self.duplicate_rows
+    }
+
+    /// Return up to `n` keys which appeared more than once, most common
+    /// first, and the number of rows with each.
+    pub fn top_keys(&self, n: usize) -> Vec<(&[Vec<u8>], u64)> {
+        let mut top = self
+            .keys
+            .iter()
+            .filter(|&(_, &count)| count > 1)
+            .map(|(key, &count)| (&key[..], count))
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+#[test]
+fn counts_duplicates() {
+    let hdr = ByteRecord::from(vec!["id", "name"]);
+    let mut counter = DuplicateCounter::new(&hdr, &["id".to_owned()]).unwrap();
+    for row in &[["1", "a"], ["2", "b"], ["1", "a"], ["1", "c"], ["2", "b"]] {
+        counter.observe_row(row.iter().map(|v| v.as_bytes()));
+    }
+    assert_eq!(counter.duplicate_rows(), 2);
+    assert_eq!(
+        counter.top_keys(10),
+        vec![(&[b"1".to_vec()][..], 3), (&[b"2".to_vec()][..], 2)],
+    );
+    assert!(DuplicateCounter::new(&hdr, &["nope".to_owned()]).is_err());
+}
@@ -0,0 +1,154 @@
+//! Describing our output as a schema or a table definition, for
+//! `--emit-schema`.
+
+use serde_json::json;
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::profile::{ColumnProfile, Profile, ValueType};
+
+/// The kinds of schema we can write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchemaFormat {
+    /// A BigQuery JSON schema, as used by `bq load --schema`.
+    BigQuery,
+    /// A PostgreSQL `CREATE TABLE` statement.
+    Postgres,
+    /// A schema which we can read back with `--schema`.
+    Json,
+}
+
+impl SchemaFormat {
+    /// The extension we add to our output path to name the schema.
+    pub fn extension(self) -> &'static str {
+        match self {
+            SchemaFormat::BigQuery => "bigquery.json",
+            SchemaFormat::Postgres => "sql",
+            SchemaFormat::Json => "schema.json",
+        }
+    }
+
+    /// Describe the columns in `profile`. `table` is used to name the table,
+    /// if our format needs one.
+    pub fn emit(self, profile: &Profile, table: &str) -> Result<String> {
+        // If we've never seen a column empty, assume it's required. But if
+        // we've never seen any data, we don't know.
+        let required =
+            |column: &ColumnProfile| profile.rows > 0 && column.null_count == 0;
+        match self {
+            SchemaFormat::BigQuery => {
+                let fields = profile
+                    .columns
+                    .iter()
+                    .map(|column| {
+                        let field_type = match column.value_type {
+                            Some(ValueType::Boolean) => "BOOL",
+                            Some(ValueType::Integer) => "INT64",
+                            Some(ValueType::Decimal) => "FLOAT64",
+                            Some(ValueType::Date) => "DATE",
+                            Some(ValueType::String) | None => "STRING",
+                        };
+                        let mode = if required(column) { "REQUIRED" } else { "NULLABLE" };
+                        json!({ "name": column.name, "type": field_type, "mode": mode })
+                    })
+                    .collect::<Vec<_>>();
+                Ok(serde_json::to_string_pretty(&fields)? + "\n")
+            }
+            SchemaFormat::Postgres => {
+                let mut sql = format!("CREATE TABLE {} (\n", quote_identifier(table));
+                for (i, column) in profile.columns.iter().enumerate() {
+                    let column_type = match column.value_type {
+                        Some(ValueType::Boolean) => "BOOLEAN".to_owned(),
+                        Some(ValueType::Integer) => "BIGINT".to_owned(),
+                        Some(ValueType::Decimal) => "NUMERIC".to_owned(),
+                        Some(ValueType::Date) => "DATE".to_owned(),
+                        Some(ValueType::String) => {
+                            format!("VARCHAR({})", column.max_length)
+                        }
+                        None => "TEXT".to_owned(),
+                    };
+                    sql.push_str(&format!(
+                        "    {} {}{}{}\n",
+                        quote_identifier(&column.name),
+                        column_type,
+                        if required(column) { " NOT NULL" } else { "" },
+                        if i + 1 < profile.columns.len() {
+                            ","
+                        } else {
+                            ""
+                        },
+                    ));
+                }
+                sql.push_str(");\n");
+                Ok(sql)
+            }
+            SchemaFormat::Json => {
+                let columns = profile
+                    .columns
+                    .iter()
+                    .map(|column| {
+                        let mut schema_column = json!({
+                            "name": column.name,
+                            "type": column.value_type.unwrap_or(ValueType::String),
+                            "nullable": !required(column),
+                        });
+                        if column.value_type == Some(ValueType::String) {
+                            schema_column["max_length"] = json!(column.max_length);
+                        }
+                        schema_column
+                    })
+                    .collect::<Vec<_>>();
+                Ok(
+                    serde_json::to_string_pretty(&json!({ "columns": columns }))?
+                        + "\n",
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for SchemaFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SchemaFormat> {
+        match s {
+            "bigquery" => Ok(SchemaFormat::BigQuery),
+            "postgres" => Ok(SchemaFormat::Postgres),
+            "json" => Ok(SchemaFormat::Json),
+            _ => Err(format_err!("unknown schema format: '{}'", s)),
+        }
+    }
+}
+
+/// Quote `name` as a PostgreSQL identifier.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[test]
+fn emits_schemas() {
+    use crate::profile::Profiler;
+
+    let mut profiler = Profiler::new(vec![&b"id"[..], b"na\"me", b"when"], 0, 0);
+    profiler.observe_row(vec![&b"1"[..], b"Ann", b""]);
+    profiler.observe_row(vec![&b"2"[..], b"Bob", b"2024-01-02"]);
+    profiler.observe_row(vec![&b"3"[..], b"", b""]);
+    let profile = profiler.to_profile();
+    assert_eq!(
+        SchemaFormat::Postgres.emit(&profile, "people").unwrap(),
+        "CREATE TABLE \"people\" (\n    \"id\" BIGINT NOT NULL,\n    \
+         \"na\"\"me\" VARCHAR(3),\n    \"when\" DATE\n);\n",
+    );
+    let bigquery: serde_json::Value =
+        serde_json::from_str(&SchemaFormat::BigQuery.emit(&profile, "").unwrap())
+            .unwrap();
+    assert_eq!(
+        bigquery[0],
+        json!({ "name": "id", "type": "INT64", "mode": "REQUIRED" }),
+    );
+    let schema: crate::schema::Schema =
+        serde_json::from_str(&SchemaFormat::Json.emit(&profile, "").unwrap()).unwrap();
+    assert_eq!(schema.columns[1].max_length, Some(3));
+    assert!(!schema.columns[0].nullable);
+    assert!("oracle".parse::<SchemaFormat>().is_err());
+}
@@ -0,0 +1,182 @@
+//! Removing columns which never contain a value, for
+//! `--drop-empty-columns`.
+//!
+//! We can't know whether a column is empty until we've seen every row, so we
+//! spool our output to a temporary file, and then copy it to its real
+//! destination without the empty columns.
+
+use csv::ByteRecord;
+use log::debug;
+use std::{
+    env, fs,
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::output::FinishWrite;
+use crate::quoting::{
+    write_record_quoting_edge_whitespace, OutputFormat, SharedOutput,
+};
+
+/// Spools our output, and writes it to `inner` without any empty columns when
+/// we finish.
+pub struct EmptyColumnDropper<'a> {
+    /// Where our output should end up, until we finish.
+    inner: Option<Box<dyn FinishWrite + 'a>>,
+    /// Our spooled output.
+    spool: io::BufWriter<fs::File>,
+    /// The path of our spool file, which we remove when we're dropped.
+    spool_path: PathBuf,
+    /// Does our output start with a header?
+    header: bool,
+    /// How our output is formatted.
+    format: OutputFormat,
+    /// Should we quote values with leading or trailing whitespace?
+    quote_edge_whitespace: bool,
+}
+
+impl<'a> EmptyColumnDropper<'a> {
+    /// Spool our output before writing it to `inner`.
+    pub fn new(
+        inner: Box<dyn FinishWrite + 'a>,
+        header: bool,
+        format: OutputFormat,
+        quote_edge_whitespace: bool,
+    ) -> io::Result<EmptyColumnDropper<'a>> {
+        let spool_path =
+            env::temp_dir().join(format!("scrubcsv-{}-spool.csv", process::id()));
+        debug!("spooling output to {}", spool_path.display());
+        let spool = io::BufWriter::new(fs::File::create(&spool_path)?);
+        Ok(EmptyColumnDropper {
+            inner: Some(inner),
+            spool,
+            spool_path,
+            header,
+            format,
+            quote_edge_whitespace,
+        })
+    }
+}
+
+/// Read back the rows spooled to `path`, which were written in `format`.
+pub fn spooled_records(
+    path: &Path,
+    format: OutputFormat,
+) -> io::Result<impl Iterator<Item = io::Result<ByteRecord>>> {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.has_headers(false);
+    format.configure_reader(&mut builder);
+    let rdr = builder.from_reader(fs::File::open(path)?);
+    Ok(rdr
+        .into_byte_records()
+        .map(|record| record.map_err(|err| io::Error::other(err.to_string()))))
+}
+
+/// Which columns of `records` contain a value, not counting the header if we
+/// `skip` it? If there are no rows, we keep every column.
+fn nonempty_columns(
+    records: impl Iterator<Item = io::Result<ByteRecord>>,
+    skip: bool,
+) -> io::Result<Vec<bool>> {
+    let mut nonempty: Option<Vec<bool>> = None;
+    let mut width = 0;
+    for (i, record) in records.enumerate() {
+        let record = record?;
+        width = record.len();
+        if skip && i == 0 {
+            continue;
+        }
+        let nonempty = nonempty.get_or_insert_with(|| vec![false; record.len()]);
+        for (keep, value) in nonempty.iter_mut().zip(record.iter()) {
+            *keep |= !value.is_empty();
+        }
+    }
+    Ok(nonempty.unwrap_or_else(|| vec![true; width]))
+}
+
+impl Write for EmptyColumnDropper<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.spool.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}
+
+impl FinishWrite for EmptyColumnDropper<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.spool.flush()?;
+        let format = self.format;
+        let keep =
+            nonempty_columns(spooled_records(&self.spool_path, format)?, self.header)?;
+        debug!(
+            "dropping {} empty columns",
+            keep.iter().filter(|k| !**k).count()
+        );
+
+        let records = spooled_records(&self.spool_path, format)?;
+        let quote_edge_whitespace = self.quote_edge_whitespace;
+        let mut inner = self.inner.take().expect("should only finish once");
+        let mut output = SharedOutput::new(&mut inner);
+        let mut wtr_builder = csv::WriterBuilder::new();
+        format.configure(&mut wtr_builder);
+        let mut wtr = wtr_builder.from_writer(output.clone());
+        for record in records {
+            let record = record?;
+            let kept = record
+                .iter()
+                .zip(&keep)
+                .filter(|(_, &k)| k)
+                .map(|(value, _)| value);
+            if quote_edge_whitespace {
+                write_record_quoting_edge_whitespace(
+                    &mut wtr,
+                    &mut output,
+                    format,
+                    kept,
+                )
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            } else {
+                wtr.write_record(kept)?;
+            }
+        }
+        wtr.flush()?;
+        drop(wtr);
+        drop(output);
+        inner.finish()
+    }
+}
+
+impl Drop for EmptyColumnDropper<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.spool_path);
+    }
+}
+
+#[test]
+fn finds_nonempty_columns() {
+    let rows = |rows: &[&[&str]]| {
+        rows.iter()
+            .map(|row| Ok(ByteRecord::from(row.to_vec())))
+            .collect::<Vec<_>>()
+            .into_iter()
+    };
+    assert_eq!(
+        nonempty_columns(
+            rows(&[&["a", "b", "c"], &["1", "", ""], &["", "", "3"]]),
+            true
+        )
+        .unwrap(),
+        vec![true, false, true],
+    );
+    assert_eq!(
+        nonempty_columns(rows(&[&["a", "b"]]), true).unwrap(),
+        vec![true, true],
+    );
+    assert_eq!(
+        nonempty_columns(rows(&[&["", "b"]]), false).unwrap(),
+        vec![false, true],
+    );
+}
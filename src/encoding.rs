@@ -0,0 +1,189 @@
+//! Transcoding our input, and fixing up cells which aren't valid UTF-8.
+
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use std::{borrow::Cow, fmt, io::prelude::*, str, str::FromStr};
+
+use crate::errors::*;
+
+/// The UTF-8 byte order mark.
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The character set of our input, if it isn't UTF-8 or some other
+/// ASCII-compatible encoding.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct InputEncoding(&'static encoding_rs::Encoding);
+
+impl InputEncoding {
+    /// Wrap `rdr` so that it converts our input to UTF-8. Any invalid
+    /// sequences are replaced with U+FFFD.
+    pub fn decode<'a, R: Read + 'a>(self, rdr: R) -> Box<dyn Read + 'a> {
+        Box::new(
+            DecodeReaderBytesBuilder::new()
+                .encoding(Some(self.0))
+                .build(rdr),
+        )
+    }
+}
+
+impl fmt::Debug for InputEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InputEncoding({})", self.0.name())
+    }
+}
+
+impl FromStr for InputEncoding {
+    type Err = Error;
+
+    /// Parse any encoding label recognized by web browsers, like "latin1",
+    /// "windows-1252" or "utf-16le".
+    fn from_str(s: &str) -> Result<InputEncoding> {
+        encoding_rs::Encoding::for_label(s.as_bytes())
+            .map(InputEncoding)
+            .ok_or_else(|| format_err!("unknown input encoding: '{}'", s))
+    }
+}
+
+/// How should we interpret bytes which aren't valid UTF-8?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Utf8Fallback {
+    /// Treat them as Windows-1252, which is what most "UTF-8" files with
+    /// stray bytes like `0x92` actually contain.
+    Windows1252,
+}
+
+impl FromStr for Utf8Fallback {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Utf8Fallback> {
+        match s {
+            "windows-1252" | "cp1252" => Ok(Utf8Fallback::Windows1252),
+            _ => Err(format_err!("unknown UTF-8 fallback encoding: '{}'", s)),
+        }
+    }
+}
+
+/// What `--ensure-utf8` should do with invalid UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Replace invalid sequences with U+FFFD.
+    Replace,
+    /// Reject the row.
+    Drop,
+    /// Stop with an error.
+    Fail,
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<InvalidUtf8> {
+        match s {
+            "replace" => Ok(InvalidUtf8::Replace),
+            "drop" => Ok(InvalidUtf8::Drop),
+            "fail" => Ok(InvalidUtf8::Fail),
+            _ => Err(format_err!("unknown --invalid-utf8 policy: '{}'", s)),
+        }
+    }
+}
+
+/// Replace any invalid UTF-8 sequences in `val` with U+FFFD.
+pub fn replace_invalid_utf8(val: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+    match String::from_utf8_lossy(&val) {
+        Cow::Borrowed(_) => val,
+        Cow::Owned(fixed) => Cow::Owned(fixed.into_bytes()),
+    }
+}
+
+/// The characters for Windows-1252 bytes `0x80` to `0x9F`. The rest of the
+/// upper half is identical to Latin-1. Like web browsers, we map the five
+/// unassigned bytes to the matching C1 control characters.
+const WINDOWS_1252_80_TO_9F: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}',
+    'Ž', '\u{8F}', '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›',
+    'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+/// Convert a single Windows-1252 byte to a character.
+fn windows_1252_char(b: u8) -> char {
+    match b {
+        0x80..=0x9F => WINDOWS_1252_80_TO_9F[usize::from(b - 0x80)],
+        _ => char::from(b),
+    }
+}
+
+impl Utf8Fallback {
+    /// If `val` contains invalid UTF-8, reinterpret just the invalid bytes
+    /// using our fallback encoding, leaving any valid UTF-8 alone.
+    pub fn fix<'a>(self, val: Cow<'a, [u8]>) -> Cow<'a, [u8]> {
+        if str::from_utf8(&val).is_ok() {
+            return val;
+        }
+        let mut fixed = String::with_capacity(val.len() + 8);
+        let mut rest = &val[..];
+        loop {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    fixed.push_str(valid);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    fixed.push_str(
+                        str::from_utf8(valid).expect("should be valid UTF-8"),
+                    );
+                    let bad_len = err.error_len().unwrap_or(invalid.len());
+                    for &b in &invalid[..bad_len] {
+                        fixed.push(windows_1252_char(b));
+                    }
+                    rest = &invalid[bad_len..];
+                }
+            }
+        }
+        Cow::Owned(fixed.into_bytes())
+    }
+}
+
+#[test]
+fn falls_back_to_windows_1252() {
+    let examples: &[(&[u8], &str)] = &[
+        (b"plain", "plain"),
+        ("caf\u{e9}".as_bytes(), "caf\u{e9}"),
+        (b"don\x92t", "don’t"),
+        (b"\x93quoted\x94 caf\xe9", "“quoted” café"),
+        (b"\x81", "\u{81}"),
+        (b"\xe2\x80\x99 and \x92", "’ and ’"),
+    ];
+    for &(input, expected) in examples {
+        assert_eq!(
+            Utf8Fallback::Windows1252.fix(Cow::Borrowed(input)),
+            expected.as_bytes(),
+        );
+    }
+}
+
+#[test]
+fn replaces_invalid_utf8() {
+    assert_eq!(
+        replace_invalid_utf8(Cow::Borrowed(b"caf\xe9!")),
+        "caf\u{fffd}!".as_bytes(),
+    );
+    assert!(matches!(
+        replace_invalid_utf8(Cow::Borrowed("café".as_bytes())),
+        Cow::Borrowed(_),
+    ));
+}
+
+#[test]
+fn transcodes_input() {
+    let examples: &[(&str, &[u8], &str)] = &[
+        ("latin1", b"caf\xe9,\x93x\x94\n", "café,“x”\n"),
+        ("utf-16le", b"a\x00,\x00\xe9\x00\n\x00", "a,é\n"),
+    ];
+    for &(label, input, expected) in examples {
+        let encoding = InputEncoding::from_str(label).unwrap();
+        let mut output = String::new();
+        encoding.decode(input).read_to_string(&mut output).unwrap();
+        assert_eq!(output, expected);
+    }
+    assert!(InputEncoding::from_str("klingon").is_err());
+}
@@ -14,16 +14,60 @@ pub type Error = Box<dyn error::Error + 'static>;
 /// Our custom `Result` type. Defaults the `E` parameter to our error type.
 pub type Result<T, E = Error> = result::Result<T, E>;
 
+/// Where in our input something happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    /// The line number, starting at 1.
+    pub line: u64,
+    /// The record number, starting at 0 for the header.
+    pub record: u64,
+    /// The byte offset from the start of the input.
+    pub byte: u64,
+}
+
+impl From<&csv::Position> for Position {
+    fn from(pos: &csv::Position) -> Position {
+        Position {
+            line: pos.line(),
+            record: pos.record(),
+            byte: pos.byte(),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, record {}, byte offset {}",
+            self.line, self.record, self.byte
+        )
+    }
+}
+
 /// Human-readable context for another error.
 #[derive(Debug)]
 pub struct Context {
     context: String,
+    /// Where in our input the error occurred, if we know.
+    position: Option<Position>,
     source: Error,
 }
 
+impl Context {
+    /// Where in our input did this error occur?
+    #[cfg(test)]
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+}
+
 impl fmt::Display for Context {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.context.fmt(f)
+        match &self.position {
+            Some(position) => write!(f, "{} (at {})", self.context, position),
+            None => self.context.fmt(f),
+        }
     }
 }
 
@@ -49,6 +93,12 @@ pub trait ResultExt<T, E>: Sized {
     where
         C: Into<String>,
         F: FnOnce(&E) -> C;
+
+    /// If this result is an error, wrap that error with `context`, noting that
+    /// it occurred at `position` in our input.
+    fn at_position<C>(self, context: C, position: Position) -> Result<T>
+    where
+        C: Into<String>;
 }
 
 impl<T, E: error::Error + 'static> ResultExt<T, E> for Result<T, E> {
@@ -60,6 +110,20 @@ impl<T, E: error::Error + 'static> ResultExt<T, E> for Result<T, E> {
         self.map_err(|err| {
             Box::new(Context {
                 context: build_context(&err).into(),
+                position: None,
+                source: Box::new(err),
+            }) as Error
+        })
+    }
+
+    fn at_position<C>(self, context: C, position: Position) -> Result<T>
+    where
+        C: Into<String>,
+    {
+        self.map_err(|err| {
+            Box::new(Context {
+                context: context.into(),
+                position: Some(position),
                 source: Box::new(err),
             }) as Error
         })
@@ -79,3 +143,23 @@ macro_rules! format_err {
         err
     });
 }
+
+#[test]
+fn context_includes_position() {
+    let result: Result<(), std::io::Error> =
+        Err(std::io::Error::other("disk on fire"));
+    let position = Position {
+        line: 12,
+        record: 10,
+        byte: 345,
+    };
+    let err = result
+        .at_position("cannot read record", position)
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "cannot read record (at line 12, record 10, byte offset 345)"
+    );
+    let context = err.downcast_ref::<Context>().unwrap();
+    assert_eq!(context.position(), Some(position));
+}
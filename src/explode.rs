@@ -0,0 +1,135 @@
+//! Turning cells with several values into several rows, for `--explode`.
+
+use csv::ByteRecord;
+use std::{borrow::Cow, str::FromStr};
+
+use crate::errors::*;
+use crate::util::{find_column, parse_option_words};
+
+/// A column to explode, written as `COL on=SEP`.
+#[derive(Clone, Debug)]
+pub struct Explode {
+    /// The column to explode.
+    column: String,
+    /// The separator between values.
+    on: Vec<u8>,
+}
+
+impl FromStr for Explode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Explode> {
+        match &parse_option_words(s)?[..] {
+            [(column, None), (key, Some(on))] if key == "on" && !on.is_empty() => {
+                Ok(Explode {
+                    column: column.to_owned(),
+                    on: on.as_bytes().to_owned(),
+                })
+            }
+            _ => Err(format_err!("expected COL on=SEP, found {:?}", s)),
+        }
+    }
+}
+
+/// Explodes rows.
+pub struct Exploder {
+    /// Our columns, and the separators we split them on.
+    columns: Vec<(usize, Vec<u8>)>,
+}
+
+impl Exploder {
+    /// Prepare to explode rows with the header `hdr`. Returns `None` if we
+    /// have no columns to explode.
+    pub fn new(hdr: &ByteRecord, explodes: &[Explode]) -> Result<Option<Exploder>> {
+        if explodes.is_empty() {
+            return Ok(None);
+        }
+        let columns = explodes
+            .iter()
+            .map(|explode| {
+                let idx = find_column(hdr, &explode.column).ok_or_else(|| {
+                    format_err!("cannot find --explode column {:?}", explode.column)
+                })?;
+                Ok((idx, explode.on.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Exploder { columns }))
+    }
+
+    /// Turn `row` into one row for each value in our columns, skipping empty
+    /// values. If we explode more than one column, we output every
+    /// combination of their values. Rows where a column has no values are
+    /// kept, with that column left empty.
+    pub fn explode_row<'a>(&self, row: Vec<Cow<'a, [u8]>>) -> Vec<Vec<Cow<'a, [u8]>>> {
+        let mut rows = vec![row];
+        for (idx, on) in &self.columns {
+            let mut exploded = vec![];
+            for row in rows {
+                let values = split_on(&row[*idx], on)
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_owned())
+                    .collect::<Vec<_>>();
+                if values.len() <= 1 {
+                    exploded.push(row);
+                    continue;
+                }
+                for value in values {
+                    let mut new_row = row.clone();
+                    new_row[*idx] = Cow::Owned(value);
+                    exploded.push(new_row);
+                }
+            }
+            rows = exploded;
+        }
+        rows
+    }
+}
+
+/// Split `val` on each occurrence of `sep`.
+fn split_on<'a>(val: &'a [u8], sep: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+    let mut rest = Some(val);
+    std::iter::from_fn(move || {
+        let current = rest?;
+        match current.windows(sep.len()).position(|w| w == sep) {
+            Some(i) => {
+                rest = Some(&current[i + sep.len()..]);
+                Some(&current[..i])
+            }
+            None => {
+                rest = None;
+                Some(current)
+            }
+        }
+    })
+}
+
+#[test]
+fn explodes_rows() {
+    let hdr = ByteRecord::from(vec!["id", "tags", "codes"]);
+    let explodes = vec![
+        r#"tags on=";""#.parse().unwrap(),
+        "codes on=||".parse().unwrap(),
+    ];
+    let exploder = Exploder::new(&hdr, &explodes).unwrap().unwrap();
+    let row = |values: &[&'static str]| {
+        values
+            .iter()
+            .map(|v| Cow::Borrowed(v.as_bytes()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        exploder.explode_row(row(&["1", "a;;b", "x||y"])),
+        vec![
+            row(&["1", "a", "x"]),
+            row(&["1", "a", "y"]),
+            row(&["1", "b", "x"]),
+            row(&["1", "b", "y"]),
+        ]
+    );
+    assert_eq!(
+        exploder.explode_row(row(&["2", "", "z"])),
+        vec![row(&["2", "", "z"])]
+    );
+    assert!("tags".parse::<Explode>().is_err());
+    assert!(Exploder::new(&hdr, &["nope on=,".parse().unwrap()]).is_err());
+}
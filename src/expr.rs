@@ -0,0 +1,378 @@
+//! Filter expressions for `--where`, like `age >= 18 && state != ""`.
+//!
+//! An expression compares columns, strings and numbers using `==`, `!=`,
+//! `<`, `<=`, `>` and `>=`, and combines comparisons with `&&`, `||`, `!`
+//! and parentheses. A column on its own is true if it isn't empty. Column
+//! names which aren't simple identifiers can be written in backquotes.
+//!
+//! If either side of a comparison is a number, or both sides are columns
+//! containing numbers, we compare numerically. In a numeric comparison, a
+//! value which isn't a number is never equal to, less than or greater than
+//! anything. Otherwise, we compare bytes.
+
+use csv::ByteRecord;
+use std::{cmp::Ordering, str::FromStr};
+
+use crate::errors::*;
+
+/// A comparison operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    /// Does `ordering` satisfy this operator? `None` means the values
+    /// couldn't be compared.
+    fn accepts(self, ordering: Option<Ordering>) -> bool {
+        match (self, ordering) {
+            (CompareOp::Ne, None) => true,
+            (_, None) => false,
+            (CompareOp::Eq, Some(ord)) => ord == Ordering::Equal,
+            (CompareOp::Ne, Some(ord)) => ord != Ordering::Equal,
+            (CompareOp::Lt, Some(ord)) => ord == Ordering::Less,
+            (CompareOp::Le, Some(ord)) => ord != Ordering::Greater,
+            (CompareOp::Gt, Some(ord)) => ord == Ordering::Greater,
+            (CompareOp::Ge, Some(ord)) => ord != Ordering::Less,
+        }
+    }
+}
+
+/// A token in an expression. `--add-column` uses these, too.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Column(String),
+    Str(String),
+    Num(f64),
+    Compare(CompareOp),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    Comma,
+}
+
+/// Split `s` into tokens.
+pub fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let chars = s.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Compare(CompareOp::Eq), 2),
+            ('!', Some('=')) => (Token::Compare(CompareOp::Ne), 2),
+            ('<', Some('=')) => (Token::Compare(CompareOp::Le), 2),
+            ('>', Some('=')) => (Token::Compare(CompareOp::Ge), 2),
+            ('<', _) => (Token::Compare(CompareOp::Lt), 1),
+            ('>', _) => (Token::Compare(CompareOp::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            (',', _) => (Token::Comma, 1),
+            ('"', _) | ('`', _) => {
+                // Read a quoted string or column name, with backslash escapes.
+                let mut value = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        None => {
+                            return Err(format_err!("unterminated {} in {:?}", c, s))
+                        }
+                        Some(&end) if end == c => break,
+                        Some('\\') if j + 1 < chars.len() => {
+                            value.push(chars[j + 1]);
+                            j += 2;
+                        }
+                        Some(&other) => {
+                            value.push(other);
+                            j += 1;
+                        }
+                    }
+                }
+                let token = if c == '"' {
+                    Token::Str(value)
+                } else {
+                    Token::Column(value)
+                };
+                (token, j + 1 - i)
+            }
+            (c, _) if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let len = chars[i..]
+                    .iter()
+                    .enumerate()
+                    .take_while(|&(j, &c)| {
+                        c.is_ascii_digit() || c == '.' || (j == 0 && c == '-')
+                    })
+                    .count();
+                let number = chars[i..i + len].iter().collect::<String>();
+                let number = number
+                    .parse::<f64>()
+                    .map_err(|_| format_err!("cannot parse number {:?}", number))?;
+                (Token::Num(number), len)
+            }
+            (c, _) if c.is_alphanumeric() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_alphanumeric() || **c == '_')
+                    .count();
+                (Token::Column(chars[i..i + len].iter().collect()), len)
+            }
+            (c, _) => return Err(format_err!("unexpected {:?} in {:?}", c, s)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+/// Something we can compare.
+#[derive(Clone, Debug, PartialEq)]
+enum Operand<C> {
+    Column(C),
+    Str(Vec<u8>),
+    Num(f64),
+}
+
+/// A filter expression, referring to columns using `C`.
+#[derive(Clone, Debug, PartialEq)]
+enum Node<C> {
+    Or(Box<Node<C>>, Box<Node<C>>),
+    And(Box<Node<C>>, Box<Node<C>>),
+    Not(Box<Node<C>>),
+    Compare(Operand<C>, CompareOp, Operand<C>),
+    NotEmpty(Operand<C>),
+}
+
+/// Parses a list of tokens.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Node<String>> {
+        let mut node = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            node = Node::Or(Box::new(node), Box::new(self.parse_and()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_and(&mut self) -> Result<Node<String>> {
+        let mut node = self.parse_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            node = Node::And(Box::new(node), Box::new(self.parse_not()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_not(&mut self) -> Result<Node<String>> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Node::Not(Box::new(self.parse_not()?)))
+            }
+            Some(Token::LParen) => {
+                self.next();
+                let node = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(node),
+                    other => Err(format_err!("expected \")\", found {:?}", other)),
+                }
+            }
+            _ => self.parse_compare(),
+        }
+    }
+
+    fn parse_compare(&mut self) -> Result<Node<String>> {
+        let left = self.parse_operand()?;
+        if let Some(&Token::Compare(op)) = self.peek() {
+            self.next();
+            let right = self.parse_operand()?;
+            Ok(Node::Compare(left, op, right))
+        } else {
+            Ok(Node::NotEmpty(left))
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand<String>> {
+        match self.next() {
+            Some(Token::Column(name)) => Ok(Operand::Column(name)),
+            Some(Token::Str(s)) => Ok(Operand::Str(s.into_bytes())),
+            Some(Token::Num(n)) => Ok(Operand::Num(n)),
+            other => Err(format_err!(
+                "expected a column, string or number, found {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// A parsed `--where` expression, which doesn't yet know which columns its
+/// names refer to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WhereExpr(Node<String>);
+
+impl FromStr for WhereExpr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<WhereExpr> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let node = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format_err!("unexpected {:?} in {:?}", token, s));
+        }
+        Ok(WhereExpr(node))
+    }
+}
+
+impl WhereExpr {
+    /// Look up the columns we use in `hdr`.
+    pub fn compile(&self, hdr: &ByteRecord) -> Result<CompiledWhere> {
+        Ok(CompiledWhere(compile_node(&self.0, hdr)?))
+    }
+}
+
+fn compile_node(node: &Node<String>, hdr: &ByteRecord) -> Result<Node<usize>> {
+    let compile = |node| compile_node(node, hdr).map(Box::new);
+    Ok(match node {
+        Node::Or(a, b) => Node::Or(compile(a)?, compile(b)?),
+        Node::And(a, b) => Node::And(compile(a)?, compile(b)?),
+        Node::Not(a) => Node::Not(compile(a)?),
+        Node::Compare(a, op, b) => {
+            Node::Compare(compile_operand(a, hdr)?, *op, compile_operand(b, hdr)?)
+        }
+        Node::NotEmpty(a) => Node::NotEmpty(compile_operand(a, hdr)?),
+    })
+}
+
+fn compile_operand(
+    operand: &Operand<String>,
+    hdr: &ByteRecord,
+) -> Result<Operand<usize>> {
+    Ok(match operand {
+        Operand::Column(name) => Operand::Column(
+            hdr.iter()
+                .position(|col| col == name.as_bytes())
+                .ok_or_else(|| format_err!("cannot find --where column {:?}", name))?,
+        ),
+        Operand::Str(s) => Operand::Str(s.clone()),
+        Operand::Num(n) => Operand::Num(*n),
+    })
+}
+
+/// A `--where` expression, ready to evaluate.
+#[derive(Clone, Debug)]
+pub struct CompiledWhere(Node<usize>);
+
+impl CompiledWhere {
+    /// Does `row` match this expression?
+    pub fn matches(&self, row: &[&[u8]]) -> bool {
+        eval(&self.0, row)
+    }
+}
+
+fn eval(node: &Node<usize>, row: &[&[u8]]) -> bool {
+    match node {
+        Node::Or(a, b) => eval(a, row) || eval(b, row),
+        Node::And(a, b) => eval(a, row) && eval(b, row),
+        Node::Not(a) => !eval(a, row),
+        Node::NotEmpty(a) => !bytes(a, row).is_empty(),
+        Node::Compare(a, op, b) => {
+            let numeric = matches!(a, Operand::Num(_))
+                || matches!(b, Operand::Num(_))
+                || (number(a, row).is_some() && number(b, row).is_some());
+            let ordering = if numeric {
+                match (number(a, row), number(b, row)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => None,
+                }
+            } else {
+                Some(bytes(a, row).cmp(bytes(b, row)))
+            };
+            op.accepts(ordering)
+        }
+    }
+}
+
+/// The bytes of `operand`. Numbers have no bytes, but we only use this for
+/// non-numeric comparisons.
+fn bytes<'a>(operand: &'a Operand<usize>, row: &[&'a [u8]]) -> &'a [u8] {
+    match operand {
+        Operand::Column(i) => row[*i],
+        Operand::Str(s) => s,
+        Operand::Num(_) => b"",
+    }
+}
+
+/// The value of `operand` as a number, if it is one.
+fn number(operand: &Operand<usize>, row: &[&[u8]]) -> Option<f64> {
+    match operand {
+        Operand::Column(i) => std::str::from_utf8(row[*i])
+            .ok()
+            .and_then(|s| s.trim().parse::<f64>().ok())
+            .filter(|n| !n.is_nan()),
+        Operand::Str(_) => None,
+        Operand::Num(n) => Some(*n),
+    }
+}
+
+#[test]
+fn evaluates_where_expressions() {
+    let hdr = ByteRecord::from(vec!["age", "state", "first name"]);
+    let examples: &[(&str, &[&[u8]], bool)] = &[
+        ("age >= 18 && state != \"\"", &[b"18", b"NY", b""], true),
+        ("age >= 18 && state != \"\"", &[b"17", b"NY", b""], false),
+        ("age >= 18 && state != \"\"", &[b"30", b"", b""], false),
+        ("age >= 18", &[b"", b"", b""], false),
+        ("age != 18", &[b"n/a", b"", b""], true),
+        ("age > 9", &[b"10", b"", b""], true),
+        ("state == \"NY\" || !(age < 21)", &[b"25", b"MA", b""], true),
+        ("state", &[b"1", b"", b""], false),
+        (
+            "`first name` == \"A \\\"B\\\"\"",
+            &[b"1", b"", b"A \"B\""],
+            true,
+        ),
+        ("state < \"N\"", &[b"1", b"MA", b""], true),
+        ("age == -1.5", &[b"-1.5", b"", b""], true),
+    ];
+    for &(expr, row, expected) in examples {
+        let compiled = expr.parse::<WhereExpr>().unwrap().compile(&hdr).unwrap();
+        assert_eq!(compiled.matches(row), expected, "{} on {:?}", expr, row);
+    }
+    for bad in &["age >=", "(age", "age # 1", "\"open", "age 1"] {
+        assert!(bad.parse::<WhereExpr>().is_err(), "{}", bad);
+    }
+    let expr = "missing == 1".parse::<WhereExpr>().unwrap();
+    assert!(expr.compile(&hdr).is_err());
+}
@@ -0,0 +1,147 @@
+//! Choosing which good rows to keep, based on their contents.
+
+use csv::ByteRecord;
+use regex::bytes::Regex;
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::expr::{CompiledWhere, WhereExpr};
+use crate::stats::{RuleHits, RuleId};
+use crate::util::find_column;
+
+/// A regex which should match a column, from `COL=REGEX`.
+#[derive(Clone, Debug)]
+pub struct ColumnMatch {
+    /// The name of the column.
+    column: String,
+    /// The regex to match against it.
+    regex: Regex,
+}
+
+impl FromStr for ColumnMatch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ColumnMatch> {
+        let (column, regex) = s
+            .split_once('=')
+            .ok_or_else(|| format_err!("expected COL=REGEX, found {:?}", s))?;
+        let regex = Regex::new(regex)
+            .with_context(|_| format!("cannot parse regex {:?}", regex))?;
+        Ok(ColumnMatch {
+            column: column.to_owned(),
+            regex,
+        })
+    }
+}
+
+/// Decides which rows to keep.
+#[derive(Debug)]
+pub struct RowFilter {
+    /// Drop rows where any of these columns match.
+    drop: Vec<(usize, Regex)>,
+    /// Keep only rows where all of these columns match.
+    keep: Vec<(usize, Regex)>,
+    /// Keep only rows where this expression is true.
+    where_expr: Option<CompiledWhere>,
+    /// Counts rows dropped by `drop`, if we have any.
+    drop_rule: Option<RuleId>,
+    /// Counts rows dropped by `keep`, if we have any.
+    keep_rule: Option<RuleId>,
+    /// Counts rows dropped by `where_expr`, if we have one.
+    where_rule: Option<RuleId>,
+}
+
+impl RowFilter {
+    /// Create a filter for rows with the header `hdr`, registering its rules
+    /// with `hits`. Returns `None` if there's nothing to filter.
+    pub fn new(
+        hdr: &ByteRecord,
+        drop: &[ColumnMatch],
+        keep: &[ColumnMatch],
+        where_expr: Option<&WhereExpr>,
+        hits: &mut RuleHits,
+    ) -> Result<Option<RowFilter>> {
+        if drop.is_empty() && keep.is_empty() && where_expr.is_none() {
+            return Ok(None);
+        }
+        let resolve = |matches: &[ColumnMatch]| {
+            matches
+                .iter()
+                .map(|m| {
+                    let idx = find_column(hdr, &m.column).ok_or_else(|| {
+                        format_err!("cannot find filter column {:?}", m.column)
+                    })?;
+                    Ok((idx, m.regex.clone()))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let drop_rule = if !drop.is_empty() {
+            Some(hits.register("--drop-row-if-match", "rows filtered"))
+        } else {
+            None
+        };
+        let keep_rule = if !keep.is_empty() {
+            Some(hits.register("--keep-row-if-match", "rows filtered"))
+        } else {
+            None
+        };
+        let where_rule = if where_expr.is_some() {
+            Some(hits.register("--where", "rows filtered"))
+        } else {
+            None
+        };
+        Ok(Some(RowFilter {
+            drop: resolve(drop)?,
+            keep: resolve(keep)?,
+            where_expr: where_expr.map(|expr| expr.compile(hdr)).transpose()?,
+            drop_rule,
+            keep_rule,
+            where_rule,
+        }))
+    }
+
+    /// Should we keep `row`? Counts any rows we filter out in `hits`.
+    pub fn keeps(&self, row: &[&[u8]], hits: &RuleHits) -> bool {
+        if self.drop.iter().any(|(i, re)| re.is_match(row[*i])) {
+            hits.hit(self.drop_rule.expect("should have drop rule"));
+            false
+        } else if !self.keep.iter().all(|(i, re)| re.is_match(row[*i])) {
+            hits.hit(self.keep_rule.expect("should have keep rule"));
+            false
+        } else if !self
+            .where_expr
+            .as_ref()
+            .is_none_or(|expr| expr.matches(row))
+        {
+            hits.hit(self.where_rule.expect("should have where rule"));
+            false
+        } else {
+            true
+        }
+    }
+}
+
+#[test]
+fn filters_rows() {
+    let hdr = ByteRecord::from(vec!["email", "country"]);
+    let drop = vec!["email=@example\\.com$".parse::<ColumnMatch>().unwrap()];
+    let keep = vec!["country=^US$".parse::<ColumnMatch>().unwrap()];
+    let mut hits = RuleHits::default();
+    let filter = RowFilter::new(&hdr, &drop, &keep, None, &mut hits)
+        .unwrap()
+        .unwrap();
+    assert!(filter.keeps(&[b"a@b.org", b"US"], &hits));
+    assert!(!filter.keeps(&[b"test@example.com", b"US"], &hits));
+    assert!(!filter.keeps(&[b"a@b.org", b"CA"], &hits));
+    assert!(!filter.keeps(&[b"a@b.org", b"USA"], &hits));
+    let counts = hits.iter().map(|(_, _, n)| n).collect::<Vec<_>>();
+    assert_eq!(counts, vec![1, 2]);
+
+    assert!("nope".parse::<ColumnMatch>().is_err());
+    assert!(RowFilter::new(&hdr, &[], &[], None, &mut hits)
+        .unwrap()
+        .is_none());
+    assert!(
+        RowFilter::new(&hdr, &[], &["x=y".parse().unwrap()], None, &mut hits).is_err()
+    );
+}
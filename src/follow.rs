@@ -0,0 +1,70 @@
+//! Keep reading a file as it grows, like `tail -f`.
+
+use std::{
+    io::{self, prelude::*},
+    thread,
+    time::Duration,
+};
+
+/// How long to wait before checking a followed file for more data.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A reader which never reaches the end of its input. Whenever `inner` runs
+/// out of data, we wait a while and try again.
+pub struct FollowReader<R: Read> {
+    inner: R,
+    poll_interval: Duration,
+}
+
+impl<R: Read> FollowReader<R> {
+    /// Follow `inner`, checking for new data every `poll_interval`.
+    pub fn new(inner: R, poll_interval: Duration) -> FollowReader<R> {
+        FollowReader {
+            inner,
+            poll_interval,
+        }
+    }
+}
+
+impl<R: Read> Read for FollowReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.inner.read(buf)? {
+                0 => thread::sleep(self.poll_interval),
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+/// A reader which returns each of its chunks in turn, with an "end of file"
+/// before each one.
+struct SlowReader(Vec<&'static [u8]>, bool);
+
+#[cfg(test)]
+impl Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.1 = !self.1;
+        if self.1 || self.0.is_empty() {
+            return Ok(0);
+        }
+        let chunk = self.0.remove(0);
+        buf[..chunk.len()].copy_from_slice(chunk);
+        Ok(chunk.len())
+    }
+}
+
+#[test]
+fn waits_for_more_data() {
+    let inner = SlowReader(vec![b"a,b\n", b"1,2\n"], false);
+    let mut rdr = FollowReader::new(inner, Duration::from_millis(1));
+    let mut buf = [0; 8];
+    assert_eq!(rdr.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"a,b\n");
+    assert_eq!(rdr.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"1,2\n");
+}
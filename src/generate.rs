@@ -0,0 +1,194 @@
+//! Generate synthetic dirty CSV data, for benchmarking and for reproducing bug
+//! reports without sharing customer data.
+
+use std::io::{self, prelude::*};
+use structopt::StructOpt;
+
+use crate::errors::*;
+
+/// Options for `scrubcsv generate`.
+#[derive(Clone, Debug, StructOpt)]
+pub struct GenerateOpt {
+    /// Number of data rows to generate.
+    #[structopt(value_name = "N", long = "rows", default_value = "1000")]
+    rows: u64,
+
+    /// Number of columns to generate.
+    #[structopt(value_name = "M", long = "cols", default_value = "5")]
+    cols: usize,
+
+    /// Seed for our random number generator. The same seed and options
+    /// always generate the same output.
+    #[structopt(value_name = "SEED", long = "seed", default_value = "1")]
+    seed: u64,
+
+    /// Fraction of rows with too few or too many columns.
+    #[structopt(value_name = "RATE", long = "ragged-rate", default_value = "0.01")]
+    ragged_rate: f64,
+
+    /// Fraction of cells containing a stray, unescaped quote.
+    #[structopt(
+        value_name = "RATE",
+        long = "stray-quote-rate",
+        default_value = "0.01"
+    )]
+    stray_quote_rate: f64,
+
+    /// Fraction of cells containing an embedded newline.
+    #[structopt(value_name = "RATE", long = "newline-rate", default_value = "0.01")]
+    newline_rate: f64,
+
+    /// Fraction of cells containing Latin-1 bytes instead of UTF-8.
+    #[structopt(value_name = "RATE", long = "latin1-rate", default_value = "0.01")]
+    latin1_rate: f64,
+}
+
+/// Words used to build cell values.
+const WORDS: &[&str] = &[
+    "apple",
+    "Paris",
+    "NULL",
+    "42",
+    "3.14",
+    "north",
+    "  padded  ",
+    "",
+    "blue",
+    "2024-01-03",
+    "Smith",
+    "x",
+];
+
+/// A tiny "xorshift64*" random number generator. We don't need anything
+/// cryptographic, just something fast and reproducible across platforms.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // The state must never be zero.
+        Rng((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Return a value in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Return true with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Write generated data to standard output.
+pub fn run(opt: &GenerateOpt) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    generate(opt, &mut out).context("cannot write generated data")?;
+    out.flush().context("cannot write generated data")?;
+    Ok(())
+}
+
+/// Write generated data to `out`.
+fn generate(opt: &GenerateOpt, out: &mut dyn Write) -> io::Result<()> {
+    let mut rng = Rng::new(opt.seed);
+
+    // Write a clean header.
+    let header = (1..=opt.cols)
+        .map(|i| format!("col{}", i))
+        .collect::<Vec<_>>()
+        .join(",");
+    writeln!(out, "{}", header)?;
+
+    for _ in 0..opt.rows {
+        // Decide how many columns this row gets.
+        let mut cols = opt.cols;
+        if rng.chance(opt.ragged_rate) {
+            if cols > 1 && rng.chance(0.5) {
+                cols -= 1;
+            } else {
+                cols += 1;
+            }
+        }
+
+        for col in 0..cols {
+            if col > 0 {
+                out.write_all(b",")?;
+            }
+            write_cell(opt, &mut rng, out)?;
+        }
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Write a single generated cell to `out`.
+fn write_cell(
+    opt: &GenerateOpt,
+    rng: &mut Rng,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut cell = WORDS[rng.below(WORDS.len())].as_bytes().to_owned();
+    if rng.chance(opt.latin1_rate) {
+        // "é" in Latin-1, which is not valid UTF-8.
+        cell.extend_from_slice(b"caf\xE9");
+    }
+    if rng.chance(opt.stray_quote_rate) {
+        // Just like real life: a quote in the middle of an unquoted field.
+        cell.extend_from_slice(b" \"broken");
+        return out.write_all(&cell);
+    }
+    if rng.chance(opt.newline_rate) {
+        cell.extend_from_slice(b"\nline 2");
+    }
+    if cell.contains(&b'\n') || cell.contains(&b',') {
+        out.write_all(b"\"")?;
+        out.write_all(&cell)?;
+        out.write_all(b"\"")
+    } else {
+        out.write_all(&cell)
+    }
+}
+
+#[test]
+fn generates_reproducible_output() {
+    let opt = GenerateOpt::from_iter(&["generate", "--rows", "50", "--seed", "7"]);
+    let mut first = vec![];
+    generate(&opt, &mut first).unwrap();
+    let mut second = vec![];
+    generate(&opt, &mut second).unwrap();
+    assert_eq!(first, second);
+    assert!(first.starts_with(b"col1,col2,col3,col4,col5\n"));
+}
+
+#[test]
+fn generates_clean_output_when_asked() {
+    let opt = GenerateOpt::from_iter(&[
+        "generate",
+        "--rows",
+        "20",
+        "--cols",
+        "3",
+        "--ragged-rate",
+        "0",
+        "--stray-quote-rate",
+        "0",
+        "--newline-rate",
+        "0",
+        "--latin1-rate",
+        "0",
+    ]);
+    let mut out = vec![];
+    generate(&opt, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 21);
+    assert!(lines.iter().all(|l| l.matches(',').count() == 2));
+}
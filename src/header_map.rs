@@ -0,0 +1,104 @@
+//! Saved column renames, so that every file from a feed gets the same column
+//! names.
+
+use csv::ByteRecord;
+use std::{fs, path::Path};
+
+use crate::errors::*;
+
+/// A list of original column names and the names we output for them, stored
+/// as a CSV file with the columns `original` and `cleaned`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    /// Our `(original, cleaned)` pairs, in column order.
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl HeaderMap {
+    /// Build a map from the `original` header to the `cleaned` one.
+    pub fn new(original: &ByteRecord, cleaned: &ByteRecord) -> HeaderMap {
+        HeaderMap {
+            entries: original
+                .iter()
+                .zip(cleaned.iter())
+                .map(|(o, c)| (o.to_owned(), c.to_owned()))
+                .collect(),
+        }
+    }
+
+    /// Read a header map from a CSV file.
+    pub fn read(path: &Path) -> Result<HeaderMap> {
+        let mut rdr = csv::Reader::from_path(path)
+            .with_context(|_| format!("cannot open header map {}", path.display()))?;
+        let mut entries = vec![];
+        for record in rdr.byte_records() {
+            let record = record.with_context(|_| {
+                format!("cannot read header map {}", path.display())
+            })?;
+            if record.len() != 2 {
+                return Err(format_err!(
+                    "expected 2 columns in header map {}, found {}",
+                    path.display(),
+                    record.len()
+                ));
+            }
+            entries.push((record[0].to_owned(), record[1].to_owned()));
+        }
+        Ok(HeaderMap { entries })
+    }
+
+    /// Write our header map to a CSV file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.write_record(["original", "cleaned"])?;
+        for (original, cleaned) in &self.entries {
+            wtr.write_record([original, cleaned])?;
+        }
+        let data = wtr.into_inner().context("cannot write header map")?;
+        fs::write(path, data).with_context(|_| {
+            format!("cannot write header map to {}", path.display())
+        })?;
+        Ok(())
+    }
+
+    /// Rename the columns in `hdr`. If a name appears more than once, its
+    /// occurrences are matched up with the map's entries in order. Fails if
+    /// any column isn't in the map, because we'd rather stop than guess.
+    pub fn apply(&self, hdr: &ByteRecord) -> Result<ByteRecord> {
+        let mut used = vec![false; self.entries.len()];
+        let mut new_hdr = ByteRecord::default();
+        for col in hdr.iter() {
+            let idx = self
+                .entries
+                .iter()
+                .enumerate()
+                .position(|(i, (original, _))| !used[i] && &original[..] == col)
+                .ok_or_else(|| {
+                    format_err!(
+                        "column {:?} is not in the header map",
+                        String::from_utf8_lossy(col)
+                    )
+                })?;
+            used[idx] = true;
+            new_hdr.push_field(&self.entries[idx].1);
+        }
+        Ok(new_hdr)
+    }
+}
+
+#[test]
+fn applies_header_maps() {
+    let map = HeaderMap::new(
+        &ByteRecord::from(vec!["Name", "Name", "Zip Code"]),
+        &ByteRecord::from(vec!["name", "name_2", "zip"]),
+    );
+    assert_eq!(
+        map.apply(&ByteRecord::from(vec!["Zip Code", "Name", "Name"]))
+            .unwrap(),
+        ByteRecord::from(vec!["zip", "name", "name_2"]),
+    );
+    assert!(map.apply(&ByteRecord::from(vec!["Email"])).is_err());
+    assert!(map
+        .apply(&ByteRecord::from(vec!["Zip Code", "Zip Code"]))
+        .is_err());
+}
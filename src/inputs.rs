@@ -0,0 +1,254 @@
+//! Reading more than one input file as if it were a single file.
+
+use csv::ByteRecord;
+use regex::Regex;
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::encoding::InputEncoding;
+use crate::errors::*;
+use crate::util::{project_record, CharSpecifier, DelimiterSpecifier};
+
+/// Does `s` contain any glob wildcards?
+fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// Build a regex matching the file names matched by the glob `pattern`,
+/// which may contain `*`, `?` and `[...]`.
+fn glob_regex(pattern: &str) -> Result<Regex> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                let mut class = chars.by_ref().take_while(|&c| c != ']').peekable();
+                if class.peek() == Some(&'!') {
+                    class.next();
+                    re.push('^');
+                }
+                for c in class {
+                    if c == '\\' || c == '[' || c == '^' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).with_context(|_| format!("cannot parse glob {:?}", pattern))
+}
+
+/// Expand any globs in `paths` which don't name existing files, in sorted
+/// order. Globs can be used in any part of the path, but never match hidden
+/// files.
+pub fn expand_globs(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut expanded = vec![];
+    for path in paths {
+        if path.exists() || !is_glob(&path.to_string_lossy()) {
+            expanded.push(path.to_owned());
+            continue;
+        }
+        let mut matches = vec![PathBuf::new()];
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_string_lossy(),
+                other => {
+                    for m in &mut matches {
+                        m.push(other);
+                    }
+                    continue;
+                }
+            };
+            if !is_glob(&name) {
+                for m in &mut matches {
+                    m.push(&*name);
+                }
+                continue;
+            }
+            let re = glob_regex(&name)?;
+            let mut next = vec![];
+            for dir in &matches {
+                let dir_path = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                // Skip anything we can't list, like plain files.
+                let entries = match fs::read_dir(dir_path) {
+                    Ok(entries) => entries,
+                    Err(_) => continue,
+                };
+                for entry in entries {
+                    let entry = entry.with_context(|_| {
+                        format!("cannot list {}", dir_path.display())
+                    })?;
+                    let file_name = entry.file_name();
+                    let file_name = file_name.to_string_lossy();
+                    if !file_name.starts_with('.') && re.is_match(&file_name) {
+                        next.push(dir.join(&*file_name));
+                    }
+                }
+            }
+            next.sort();
+            matches = next;
+        }
+        if matches.is_empty() {
+            return Err(format_err!("no files match {}", path.display()));
+        }
+        expanded.extend(matches);
+    }
+    Ok(expanded)
+}
+
+/// Parser settings for a single input file, which take the place of our
+/// command-line options for that file. These are written after the path,
+/// like `vendor.csv;delimiter=pipe;quote=none;encoding=latin1`.
+#[derive(Clone, Debug, Default)]
+pub struct InputSettings {
+    /// Used instead of `--delimiter`.
+    pub delimiter: Option<DelimiterSpecifier>,
+    /// Used instead of `--quote`.
+    pub quote: Option<CharSpecifier>,
+    /// Used instead of `--input-encoding`.
+    pub encoding: Option<InputEncoding>,
+}
+
+impl InputSettings {
+    /// Split any settings off the end of `path`. Paths which name existing
+    /// files are left alone, even if they contain `;`.
+    pub fn split_path(path: &Path) -> Result<(PathBuf, InputSettings)> {
+        let (file, settings) = match path.to_str().and_then(|s| s.split_once(';')) {
+            Some(split) if !path.exists() => split,
+            _ => return Ok((path.to_owned(), InputSettings::default())),
+        };
+        let mut result = InputSettings::default();
+        for setting in settings.split(';') {
+            let (key, value) = setting.split_once('=').ok_or_else(|| {
+                format_err!("expected KEY=VALUE in {:?}, found {:?}", path, setting)
+            })?;
+            match key {
+                "delimiter" => result.delimiter = Some(value.parse()?),
+                "quote" => result.quote = Some(value.parse()?),
+                "encoding" => result.encoding = Some(value.parse()?),
+                _ => {
+                    return Err(format_err!(
+                        "unknown input setting {:?} (expected delimiter, quote or encoding)",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok((PathBuf::from(file), result))
+    }
+}
+
+/// Combine the headers of several files, keeping the columns of the first
+/// file in order, followed by any new columns from later files.
+pub fn union_headers(hdrs: &[ByteRecord]) -> ByteRecord {
+    let mut union = ByteRecord::new();
+    for hdr in hdrs {
+        for col in hdr {
+            if !union.iter().any(|c| c == col) {
+                union.push_field(col);
+            }
+        }
+    }
+    union
+}
+
+/// Turns rows from one input file into rows with the columns from
+/// `union_headers`, filling in any missing columns with empty values.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnionProjection {
+    /// How many columns this file has.
+    input_cols: usize,
+    /// Where to find each output column.
+    projection: Vec<Option<usize>>,
+}
+
+impl UnionProjection {
+    /// Project rows with the header `hdr` onto `union`. Returns `None` if no
+    /// changes are needed.
+    pub fn new(union: &ByteRecord, hdr: &ByteRecord) -> Option<UnionProjection> {
+        if union == hdr {
+            return None;
+        }
+        Some(UnionProjection {
+            input_cols: hdr.len(),
+            projection: union
+                .iter()
+                .map(|col| hdr.iter().position(|c| c == col))
+                .collect(),
+        })
+    }
+
+    /// Project `record`. Records with the wrong number of columns are left
+    /// alone, so that we can reject them later.
+    pub fn apply(&self, record: ByteRecord) -> ByteRecord {
+        if record.len() != self.input_cols {
+            return record;
+        }
+        let mut projected = project_record(&record, &self.projection);
+        projected.set_position(record.position().cloned());
+        projected
+    }
+}
+
+#[test]
+fn translates_globs() {
+    let re = glob_regex("data-[0-9]?.c*").unwrap();
+    assert!(re.is_match("data-12.csv"));
+    assert!(!re.is_match("data-x2.csv"));
+    assert!(!re.is_match("xdata-12.csv"));
+    let re = glob_regex("[!a]+.csv").unwrap();
+    assert!(re.is_match("b+.csv"));
+    assert!(!re.is_match("a+.csv"));
+    assert!(!re.is_match("bb.csv"));
+}
+
+#[test]
+fn splits_input_settings() {
+    let (path, settings) =
+        InputSettings::split_path(Path::new("a.csv;delimiter=pipe;quote=none"))
+            .unwrap();
+    assert_eq!(path, Path::new("a.csv"));
+    assert!(matches!(
+        settings.delimiter,
+        Some(DelimiterSpecifier::Char(c)) if c.char() == Some(b'|')
+    ));
+    assert_eq!(settings.quote.map(|q| q.char()), Some(None));
+    assert!(settings.encoding.is_none());
+
+    let (path, settings) = InputSettings::split_path(Path::new("b.csv")).unwrap();
+    assert_eq!(path, Path::new("b.csv"));
+    assert!(settings.delimiter.is_none() && settings.quote.is_none());
+
+    assert!(InputSettings::split_path(Path::new("a.csv;delim=|")).is_err());
+    assert!(InputSettings::split_path(Path::new("a.csv;encoding=nope")).is_err());
+    assert!(InputSettings::split_path(Path::new("a.csv;")).is_err());
+}
+
+#[test]
+fn unions_headers() {
+    let a = ByteRecord::from(vec!["id", "name"]);
+    let b = ByteRecord::from(vec!["email", "id"]);
+    let union = union_headers(&[a.clone(), b.clone()]);
+    assert_eq!(union, ByteRecord::from(vec!["id", "name", "email"]));
+    let from_b = UnionProjection::new(&union, &b).unwrap();
+    assert_eq!(
+        from_b.apply(ByteRecord::from(vec!["a@b.c", "1"])),
+        ByteRecord::from(vec!["1", "", "a@b.c"]),
+    );
+    let short = ByteRecord::from(vec!["x"]);
+    assert_eq!(from_b.apply(short.clone()), short);
+    assert_eq!(UnionProjection::new(&a, &a), None);
+}
@@ -0,0 +1,130 @@
+//! Cleaning rows on several threads, for `--jobs`.
+//!
+//! We read and check rows on our main thread, collect the good ones into
+//! batches, clean each batch on a pool of worker threads, and then finish and
+//! write the rows in their original order.
+
+use csv::ByteRecord;
+use rayon::prelude::*;
+
+use crate::cleaner::{CellCleaner, CleanHits};
+use crate::errors::*;
+
+/// How many rows we clean at once.
+pub const BATCH_ROWS: usize = 4096;
+
+/// How many rows each worker cleans at a time.
+const CHUNK_ROWS: usize = 256;
+
+/// A row which we're cleaning in a batch.
+pub struct BatchRow {
+    /// The number of this row in our input, counting the header.
+    pub row_number: u64,
+    /// The row itself, with only the columns we want to output.
+    pub record: ByteRecord,
+    /// The row as we read it, if different from `record`.
+    pub input_record: Option<ByteRecord>,
+    /// Did we repair this row before cleaning it?
+    pub repaired: bool,
+    /// The cleaned values, once we're done.
+    pub cleaned: Vec<Vec<u8>>,
+    /// Did cleaning or repair change the row?
+    pub changed: bool,
+}
+
+impl BatchRow {
+    /// Prepare to clean `record`.
+    pub fn new(
+        row_number: u64,
+        record: ByteRecord,
+        input_record: Option<ByteRecord>,
+        repaired: bool,
+    ) -> BatchRow {
+        BatchRow {
+            row_number,
+            record,
+            input_record,
+            repaired,
+            cleaned: vec![],
+            changed: repaired,
+        }
+    }
+}
+
+/// A pool of threads for cleaning rows.
+pub struct ParallelCleaner {
+    pool: rayon::ThreadPool,
+}
+
+impl ParallelCleaner {
+    /// Create a pool with `jobs` threads.
+    pub fn new(jobs: usize) -> Result<ParallelCleaner> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .thread_name(|i| format!("scrubcsv-job-{}", i))
+            .build()
+            .context("cannot create worker threads")?;
+        Ok(ParallelCleaner { pool })
+    }
+
+    /// Clean `rows` using `cleaner`, adding any rule hits to `hits`. Returns
+    /// the rows in their original order.
+    pub fn clean(
+        &self,
+        cleaner: &CellCleaner,
+        mut rows: Vec<BatchRow>,
+        hits: &mut CleanHits,
+    ) -> Vec<BatchRow> {
+        let batch_hits = self.pool.install(|| {
+            rows.par_chunks_mut(CHUNK_ROWS)
+                .map(|chunk| {
+                    let mut hits = CleanHits::default();
+                    for row in chunk {
+                        let mut changed = row.changed;
+                        row.cleaned = row
+                            .record
+                            .iter()
+                            .map(|original| {
+                                let val = cleaner.clean(original, &mut hits);
+                                if val[..] != *original {
+                                    changed = true;
+                                }
+                                val.into_owned()
+                            })
+                            .collect();
+                        row.changed = changed;
+                    }
+                    hits
+                })
+                .reduce(CleanHits::default, |mut total, hits| {
+                    total.merge(&hits);
+                    total
+                })
+        });
+        hits.merge(&batch_hits);
+        rows
+    }
+}
+
+#[test]
+fn cleans_in_parallel_and_keeps_order() {
+    let cleaner = CellCleaner {
+        trim_whitespace: true,
+        ..CellCleaner::default()
+    };
+    let rows = (0..1000)
+        .map(|i| {
+            let record = ByteRecord::from(vec![format!(" {} ", i), i.to_string()]);
+            BatchRow::new(i, record, None, false)
+        })
+        .collect::<Vec<_>>();
+    let mut hits = CleanHits::default();
+    let rows = ParallelCleaner::new(4)
+        .unwrap()
+        .clean(&cleaner, rows, &mut hits);
+    for (i, row) in rows.iter().enumerate() {
+        assert_eq!(row.row_number, i as u64);
+        assert_eq!(row.cleaned[0], i.to_string().as_bytes());
+        assert!(row.changed);
+    }
+}
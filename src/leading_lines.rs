@@ -0,0 +1,81 @@
+//! Skipping titles, metadata and other junk before the real header.
+
+use log::debug;
+use regex::bytes::Regex;
+use std::io::{self, prelude::*};
+
+/// A reader which discards lines at the start of its input. We discard the
+/// first `count` lines, and then any lines matching `pattern`, until we find
+/// a line that we want to keep.
+pub struct LeadingLineSkipper<R: BufRead> {
+    inner: R,
+    /// How many more lines we should skip unconditionally.
+    count: usize,
+    /// Skip any leading lines matching this pattern.
+    pattern: Option<Regex>,
+    /// Are we still looking for the first line to keep?
+    skipping: bool,
+    /// The first line we kept.
+    line: Vec<u8>,
+    /// How much of `line` we've already returned.
+    pos: usize,
+}
+
+impl<R: BufRead> LeadingLineSkipper<R> {
+    /// Create a new reader.
+    pub fn new(inner: R, count: usize, pattern: Option<Regex>) -> Self {
+        LeadingLineSkipper {
+            inner,
+            count,
+            pattern,
+            skipping: true,
+            line: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Should we skip `line`?
+    fn should_skip(&mut self, line: &[u8]) -> bool {
+        if self.count > 0 {
+            self.count -= 1;
+            return true;
+        }
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        self.pattern.as_ref().is_some_and(|re| re.is_match(line))
+    }
+}
+
+impl<R: BufRead> Read for LeadingLineSkipper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.skipping {
+            let mut line = vec![];
+            if self.inner.read_until(b'\n', &mut line)? == 0 {
+                return Ok(0);
+            }
+            if self.should_skip(&line) {
+                debug!("skipping leading line {:?}", String::from_utf8_lossy(&line));
+            } else {
+                self.line = line;
+                self.skipping = false;
+            }
+        }
+        if self.pos < self.line.len() {
+            let count = buf.len().min(self.line.len() - self.pos);
+            buf[..count].copy_from_slice(&self.line[self.pos..self.pos + count]);
+            self.pos += count;
+            return Ok(count);
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[test]
+fn skips_leading_lines() {
+    let input = "Report generated 2024-01-03\n\r\nsep=,\na,b\n\n1,2\n";
+    let pattern = Regex::new("^(sep=.*)?$").unwrap();
+    let mut rdr = LeadingLineSkipper::new(input.as_bytes(), 1, Some(pattern));
+    let mut output = String::new();
+    rdr.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "a,b\n\n1,2\n");
+}
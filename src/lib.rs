@@ -0,0 +1,75 @@
+//! Library support for `scrubcsv`, so that other tools can clean data the
+//! same way we do. The `scrubcsv` command line tool is a thin wrapper around
+//! `Scrubber`.
+
+#![warn(clippy::all)]
+#![forbid(unsafe_code)]
+
+// Modules defined in separate files.
+#[macro_use]
+mod errors;
+mod add_columns;
+mod bad_rows;
+mod bare_quotes;
+mod buffers;
+mod cleaner;
+pub mod cleanup;
+pub mod columns;
+mod comments;
+mod compression;
+mod dedup;
+mod diagnostics;
+mod duplicates;
+mod emit_schema;
+mod empty_columns;
+mod encoding;
+mod explode;
+mod expr;
+mod filter;
+mod follow;
+mod generate;
+mod header_map;
+mod inputs;
+mod jobs;
+mod leading_lines;
+mod long_delimiters;
+mod lookup;
+mod melt;
+mod merge_columns;
+mod merge_delimiters;
+mod numbers;
+mod options;
+mod output;
+mod overflow;
+mod pipeline;
+mod preset;
+mod profile;
+mod quote_repair;
+mod quoting;
+mod raw;
+mod recover;
+mod rename;
+mod replace;
+mod report;
+mod schema;
+pub mod scrubber;
+mod skip;
+mod sniff;
+mod sort;
+mod spill;
+mod split;
+mod split_columns;
+mod stats;
+mod threads;
+mod timeout;
+mod trailing_lines;
+mod tui;
+mod types;
+mod unicode;
+pub mod uniquifier;
+mod util;
+mod validate;
+mod window;
+
+pub use crate::options::Opt;
+pub use crate::scrubber::{ScrubError, ScrubStats, Scrubber};
@@ -0,0 +1,131 @@
+//! Support for delimiters longer than one byte, like `||` or `~|~`.
+//!
+//! The `csv` parser only supports single-byte delimiters, so we rewrite the
+//! raw input before it sees it, replacing each delimiter outside of quotes
+//! with a single byte.
+
+use std::io::{self, prelude::*};
+
+/// The byte we replace long delimiters with. This is the ASCII "unit
+/// separator", which should never appear in text. If it does, it will split
+/// fields, too.
+pub const SEQUENCE_DELIMITER: u8 = 0x1F;
+
+/// A reader which replaces a delimiter sequence outside of quotes with a
+/// single byte.
+pub struct DelimiterTranslatingReader<R: BufRead> {
+    /// The reader we wrap.
+    inner: R,
+    /// The delimiter we look for.
+    delimiter: Vec<u8>,
+    /// The single delimiter we output in its place.
+    output_delimiter: u8,
+    /// Our quote character, if any.
+    quote: Option<u8>,
+    /// The raw line we're working on.
+    line: Vec<u8>,
+    /// The rewritten version of `line`.
+    translated: Vec<u8>,
+    /// How much of `translated` we've already returned.
+    pos: usize,
+    /// Are we inside a quoted field at the end of the current line?
+    in_quotes: bool,
+}
+
+impl<R: BufRead> DelimiterTranslatingReader<R> {
+    /// Create a new reader which replaces `delimiter` with
+    /// `output_delimiter`.
+    pub fn new(
+        inner: R,
+        delimiter: &[u8],
+        output_delimiter: u8,
+        quote: Option<u8>,
+    ) -> DelimiterTranslatingReader<R> {
+        assert!(!delimiter.is_empty(), "delimiter should not be empty");
+        DelimiterTranslatingReader {
+            inner,
+            delimiter: delimiter.to_owned(),
+            output_delimiter,
+            quote,
+            line: vec![],
+            translated: vec![],
+            pos: 0,
+            in_quotes: false,
+        }
+    }
+
+    /// Rewrite `self.line` into `self.translated`.
+    fn translate_line(&mut self) {
+        self.translated.clear();
+        self.pos = 0;
+        let mut at_field_start = !self.in_quotes;
+        let mut i = 0;
+        while i < self.line.len() {
+            let b = self.line[i];
+            if self.in_quotes {
+                if Some(b) == self.quote {
+                    // If the next byte is another quote, this was an escaped
+                    // quote and we'll go right back into quotes.
+                    self.in_quotes = false;
+                    at_field_start = true;
+                }
+                self.translated.push(b);
+            } else if self.line[i..].starts_with(&self.delimiter) {
+                self.translated.push(self.output_delimiter);
+                at_field_start = true;
+                i += self.delimiter.len();
+                continue;
+            } else if b == b'\n' || b == b'\r' {
+                at_field_start = true;
+                self.translated.push(b);
+            } else {
+                if Some(b) == self.quote && at_field_start {
+                    self.in_quotes = true;
+                }
+                at_field_start = false;
+                self.translated.push(b);
+            }
+            i += 1;
+        }
+    }
+}
+
+impl<R: BufRead> Read for DelimiterTranslatingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.translated.len() {
+            self.line.clear();
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            self.translate_line();
+        }
+        let count = buf.len().min(self.translated.len() - self.pos);
+        buf[..count].copy_from_slice(&self.translated[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+fn translate(input: &str, delimiter: &str) -> String {
+    let mut rdr = DelimiterTranslatingReader::new(
+        input.as_bytes(),
+        delimiter.as_bytes(),
+        b';',
+        Some(b'"'),
+    );
+    let mut out = String::new();
+    rdr.read_to_string(&mut out).unwrap();
+    out
+}
+
+#[test]
+fn translates_delimiters() {
+    assert_eq!(translate("a||b|c||\n", "||"), "a;b|c;\n");
+    assert_eq!(
+        translate("a~|~\"x~|~\ny\"~|~b\n", "~|~"),
+        "a;\"x~|~\ny\";b\n"
+    );
+    assert_eq!(translate("\"x\"\"||y\"||b\n", "||"), "\"x\"\"||y\";b\n");
+    assert_eq!(translate("a|||b\n", "||"), "a;|b\n");
+}
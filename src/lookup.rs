@@ -0,0 +1,347 @@
+//! Adding columns from a reference CSV file, for `--lookup`, and filtering
+//! rows using one, for `--drop-if-in` and `--keep-if-in`.
+
+use csv::ByteRecord;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::errors::*;
+use crate::stats::{RuleHits, RuleId};
+use crate::util::{find_column, parse_option_words};
+
+/// What to do with rows whose key isn't in our lookup file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnMiss {
+    /// Keep the row, leaving our columns empty.
+    Keep,
+    /// Filter out the row.
+    Drop,
+    /// Reject the row as bad.
+    Reject,
+}
+
+/// A lookup, written as `FILE on=COL add=COL,... miss=POLICY`.
+#[derive(Clone, Debug)]
+pub struct LookupSpec {
+    /// The file to read.
+    path: PathBuf,
+    /// The key column in our input.
+    on: String,
+    /// The key column in the lookup file, if different.
+    lookup_on: String,
+    /// The columns to add, or all of them if empty.
+    add: Vec<String>,
+    /// What to do with rows we can't find.
+    miss: OnMiss,
+}
+
+impl FromStr for LookupSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LookupSpec> {
+        let words = parse_option_words(s)?;
+        let path = match words.first() {
+            Some((path, None)) => PathBuf::from(path),
+            _ => return Err(format_err!("expected FILE on=COL, found {:?}", s)),
+        };
+        let mut on = None;
+        let mut add = vec![];
+        let mut miss = OnMiss::Keep;
+        for (key, value) in &words[1..] {
+            match (key.as_str(), value.as_deref()) {
+                ("on", Some(col)) => on = Some(col.to_owned()),
+                ("add", Some(cols)) => {
+                    add = cols.split(',').map(str::to_owned).collect()
+                }
+                ("miss", Some("keep")) => miss = OnMiss::Keep,
+                ("miss", Some("drop")) => miss = OnMiss::Drop,
+                ("miss", Some("reject")) => miss = OnMiss::Reject,
+                _ => return Err(format_err!("unknown --lookup option {:?}", key)),
+            }
+        }
+        let on = on.ok_or_else(|| format_err!("--lookup {:?} needs on=COL", s))?;
+        let (on, lookup_on) = split_key(&on);
+        Ok(LookupSpec {
+            path,
+            on,
+            lookup_on,
+            add,
+            miss,
+        })
+    }
+}
+
+/// `on=a:b` matches our column `a` to the reference file's column `b`, and
+/// `on=a` matches columns with the same name.
+fn split_key(on: &str) -> (String, String) {
+    match on.split_once(':') {
+        Some((on, file_on)) => (on.to_owned(), file_on.to_owned()),
+        None => (on.to_owned(), on.to_owned()),
+    }
+}
+
+/// A lookup file, loaded into memory.
+pub struct Lookup {
+    /// Our key column in our input.
+    key: usize,
+    /// The values to add for each key.
+    values: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    /// How many columns we add.
+    width: usize,
+    /// What to do with rows we can't find.
+    miss: OnMiss,
+    /// The name of our lookup file, for our rules.
+    name: String,
+    /// Counts rows we can't find, once we've registered it.
+    miss_rule: Option<RuleId>,
+}
+
+impl Lookup {
+    /// Load the lookup file for `spec`, and add its columns to `hdr`.
+    pub fn new(hdr: &mut ByteRecord, spec: &LookupSpec) -> Result<Lookup> {
+        let key = find_column(hdr, &spec.on)
+            .ok_or_else(|| format_err!("cannot find --lookup column {:?}", spec.on))?;
+        let path = &spec.path;
+        let mut rdr = csv::Reader::from_path(path)
+            .with_context(|_| format!("cannot open lookup file {}", path.display()))?;
+        let lookup_hdr = rdr
+            .byte_headers()
+            .with_context(|_| format!("cannot read lookup file {}", path.display()))?
+            .clone();
+        let find = |name: &str| {
+            find_column(&lookup_hdr, name).ok_or_else(|| {
+                format_err!("cannot find column {:?} in {}", name, path.display())
+            })
+        };
+        let lookup_key = find(&spec.lookup_on)?;
+        let added = if spec.add.is_empty() {
+            (0..lookup_hdr.len()).filter(|&i| i != lookup_key).collect()
+        } else {
+            spec.add
+                .iter()
+                .map(|name| find(name))
+                .collect::<Result<Vec<_>>>()?
+        };
+        for &i in &added {
+            if hdr.iter().any(|name| name == &lookup_hdr[i]) {
+                return Err(format_err!(
+                    "column {:?} already exists",
+                    String::from_utf8_lossy(&lookup_hdr[i])
+                ));
+            }
+            hdr.push_field(&lookup_hdr[i]);
+        }
+
+        let mut values = HashMap::new();
+        for record in rdr.byte_records() {
+            let record = record.with_context(|_| {
+                format!("cannot read lookup file {}", path.display())
+            })?;
+            let row = added.iter().map(|&i| record[i].to_owned()).collect();
+            if values.insert(record[lookup_key].to_owned(), row).is_some() {
+                return Err(format_err!(
+                    "duplicate key {:?} in lookup file {}",
+                    String::from_utf8_lossy(&record[lookup_key]),
+                    path.display()
+                ));
+            }
+        }
+        Ok(Lookup {
+            key,
+            values,
+            width: added.len(),
+            miss: spec.miss,
+            name: path.display().to_string(),
+            miss_rule: None,
+        })
+    }
+
+    /// Register our rules with `hits`.
+    pub fn register_rules(&mut self, hits: &mut RuleHits) {
+        self.miss_rule =
+            Some(hits.register(format!("--lookup {}", self.name), "rows not found"));
+    }
+
+    /// What we do with rows we can't find.
+    pub fn on_miss(&self) -> OnMiss {
+        self.miss
+    }
+
+    /// Add our columns to `row`. If we can't find its key, we add empty
+    /// values, count it in `hits`, and return `false`.
+    pub fn lookup_row(&self, row: &mut Vec<Cow<[u8]>>, hits: &RuleHits) -> bool {
+        match self.values.get(&row[self.key][..]) {
+            Some(values) => {
+                row.extend(values.iter().map(|v| Cow::Owned(v.clone())));
+                true
+            }
+            None => {
+                hits.hit(self.miss_rule.expect("should have registered rules"));
+                row.extend((0..self.width).map(|_| Cow::Borrowed(&b""[..])));
+                false
+            }
+        }
+    }
+}
+
+/// A file of keys, written as `FILE on=COL ignore-case`.
+#[derive(Clone, Debug)]
+pub struct KeyListSpec {
+    /// The file to read.
+    path: PathBuf,
+    /// The key column in our input.
+    on: String,
+    /// The key column in the file, if different.
+    list_on: String,
+    /// Should we ignore the case of ASCII letters?
+    ignore_case: bool,
+}
+
+impl FromStr for KeyListSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<KeyListSpec> {
+        let words = parse_option_words(s)?;
+        let path = match words.first() {
+            Some((path, None)) => PathBuf::from(path),
+            _ => return Err(format_err!("expected FILE on=COL, found {:?}", s)),
+        };
+        let mut on = None;
+        let mut ignore_case = false;
+        for (key, value) in &words[1..] {
+            match (key.as_str(), value) {
+                ("on", Some(col)) => on = Some(split_key(col)),
+                ("ignore-case", None) => ignore_case = true,
+                _ => return Err(format_err!("unknown key file option {:?}", key)),
+            }
+        }
+        let (on, list_on) = on.ok_or_else(|| format_err!("{:?} needs on=COL", s))?;
+        Ok(KeyListSpec {
+            path,
+            on,
+            list_on,
+            ignore_case,
+        })
+    }
+}
+
+/// Filters rows using the keys in a file.
+pub struct KeyFilter {
+    /// Our key column in our input.
+    key: usize,
+    /// The keys in our file.
+    keys: HashSet<Vec<u8>>,
+    /// Do we keep rows whose keys are in our file, instead of dropping them?
+    keep: bool,
+    /// Should we ignore the case of ASCII letters?
+    ignore_case: bool,
+    /// Counts the rows we filter out.
+    rule: RuleId,
+}
+
+impl KeyFilter {
+    /// Load the keys for `spec`, to filter rows with the header `hdr`. If
+    /// `keep` is true, we keep only rows whose keys are in the file, and
+    /// otherwise we drop them. Registers our rule with `hits`.
+    pub fn new(
+        hdr: &ByteRecord,
+        spec: &KeyListSpec,
+        keep: bool,
+        hits: &mut RuleHits,
+    ) -> Result<KeyFilter> {
+        let option = if keep { "--keep-if-in" } else { "--drop-if-in" };
+        let key = find_column(hdr, &spec.on).ok_or_else(|| {
+            format_err!("cannot find {} column {:?}", option, spec.on)
+        })?;
+        let path = &spec.path;
+        let mut rdr = csv::Reader::from_path(path)
+            .with_context(|_| format!("cannot open key file {}", path.display()))?;
+        let list_hdr = rdr
+            .byte_headers()
+            .with_context(|_| format!("cannot read key file {}", path.display()))?;
+        let list_key = find_column(list_hdr, &spec.list_on).ok_or_else(|| {
+            format_err!(
+                "cannot find column {:?} in {}",
+                spec.list_on,
+                path.display()
+            )
+        })?;
+        let mut keys = HashSet::new();
+        for record in rdr.byte_records() {
+            let record = record.with_context(|_| {
+                format!("cannot read key file {}", path.display())
+            })?;
+            keys.insert(
+                normalize_key(&record[list_key], spec.ignore_case).into_owned(),
+            );
+        }
+        Ok(KeyFilter {
+            key,
+            keys,
+            keep,
+            ignore_case: spec.ignore_case,
+            rule: hits
+                .register(format!("{} {}", option, path.display()), "rows filtered"),
+        })
+    }
+
+    /// Should we keep `row`? Counts any rows we filter out in `hits`.
+    pub fn keeps(&self, row: &[Cow<[u8]>], hits: &RuleHits) -> bool {
+        let key = normalize_key(&row[self.key], self.ignore_case);
+        let keeps = self.keys.contains(&key[..]) == self.keep;
+        if !keeps {
+            hits.hit(self.rule);
+        }
+        keeps
+    }
+}
+
+/// Lowercase `key` if we're ignoring case.
+fn normalize_key(key: &[u8], ignore_case: bool) -> Cow<'_, [u8]> {
+    if ignore_case && key.iter().any(u8::is_ascii_uppercase) {
+        Cow::Owned(key.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+#[test]
+fn parses_key_list_specs() {
+    let spec = "suppress.csv on=email:address ignore-case"
+        .parse::<KeyListSpec>()
+        .unwrap();
+    assert_eq!(
+        (spec.on.as_str(), spec.list_on.as_str()),
+        ("email", "address")
+    );
+    assert!(spec.ignore_case);
+    assert!("suppress.csv".parse::<KeyListSpec>().is_err());
+    assert!("suppress.csv on=email add=x"
+        .parse::<KeyListSpec>()
+        .is_err());
+    assert_eq!(normalize_key(b"A@b.C", true), &b"a@b.c"[..]);
+    assert_eq!(normalize_key(b"A@b.C", false), &b"A@b.C"[..]);
+}
+
+#[test]
+fn parses_lookup_specs() {
+    let spec = "zips.csv on=zip:postal add=city,state miss=reject"
+        .parse::<LookupSpec>()
+        .unwrap();
+    assert_eq!(spec.path, PathBuf::from("zips.csv"));
+    assert_eq!(
+        (spec.on.as_str(), spec.lookup_on.as_str()),
+        ("zip", "postal")
+    );
+    assert_eq!(spec.add, vec!["city", "state"]);
+    assert_eq!(spec.miss, OnMiss::Reject);
+    let spec = "zips.csv on=zip".parse::<LookupSpec>().unwrap();
+    assert_eq!(spec.lookup_on, "zip");
+    assert_eq!(spec.miss, OnMiss::Keep);
+    assert!("zips.csv".parse::<LookupSpec>().is_err());
+    assert!("zips.csv on=zip miss=maybe".parse::<LookupSpec>().is_err());
+}
@@ -0,0 +1,94 @@
+//! Turning wide files into long ones, for `--melt`.
+
+use csv::ByteRecord;
+use std::borrow::Cow;
+
+use crate::errors::*;
+use crate::util::find_column;
+
+/// Turns each row into one row per non-ID column.
+pub struct Melter {
+    /// The columns we copy to every row.
+    id_columns: Vec<usize>,
+    /// The columns we turn into rows, and their names.
+    value_columns: Vec<(usize, Vec<u8>)>,
+}
+
+impl Melter {
+    /// Prepare to melt rows with the header `hdr`, replacing it with the
+    /// header of our output: `id_columns`, followed by `variable_name` and
+    /// `value_name`.
+    pub fn new(
+        hdr: &mut ByteRecord,
+        id_columns: &[String],
+        variable_name: &str,
+        value_name: &str,
+    ) -> Result<Melter> {
+        let id_columns = id_columns
+            .iter()
+            .map(|col| {
+                find_column(hdr, col).ok_or_else(|| {
+                    format_err!("cannot find --id-columns column {:?}", col)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let value_columns = hdr
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !id_columns.contains(i))
+            .map(|(i, name)| (i, name.to_owned()))
+            .collect::<Vec<_>>();
+        let mut melted_hdr = id_columns
+            .iter()
+            .map(|&i| hdr[i].to_owned())
+            .collect::<Vec<_>>();
+        for name in [variable_name, value_name] {
+            if melted_hdr.iter().any(|n| n == name.as_bytes()) {
+                return Err(format_err!("column {:?} already exists", name));
+            }
+            melted_hdr.push(name.as_bytes().to_owned());
+        }
+        *hdr = ByteRecord::from(melted_hdr);
+        Ok(Melter {
+            id_columns,
+            value_columns,
+        })
+    }
+
+    /// Turn `row` into one row for each of our value columns.
+    pub fn melt_row<'a>(&self, row: &[Cow<'a, [u8]>]) -> Vec<Vec<Cow<'a, [u8]>>> {
+        self.value_columns
+            .iter()
+            .map(|(i, name)| {
+                let mut melted = Vec::with_capacity(self.id_columns.len() + 2);
+                melted.extend(self.id_columns.iter().map(|&id| row[id].clone()));
+                melted.push(Cow::Owned(name.clone()));
+                melted.push(row[*i].clone());
+                melted
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn melts_rows() {
+    let mut hdr = ByteRecord::from(vec!["temp", "id", "humidity"]);
+    let melter = Melter::new(&mut hdr, &["id".to_owned()], "metric", "value").unwrap();
+    assert_eq!(hdr, ByteRecord::from(vec!["id", "metric", "value"]));
+    let row = [
+        Cow::Borrowed(&b"20"[..]),
+        Cow::Borrowed(&b"1"[..]),
+        Cow::Borrowed(&b""[..]),
+    ];
+    assert_eq!(
+        melter.melt_row(&row),
+        vec![
+            vec![&b"1"[..], b"temp", b"20"],
+            vec![&b"1"[..], b"humidity", b""],
+        ]
+    );
+
+    let mut hdr = ByteRecord::from(vec!["id", "x"]);
+    assert!(Melter::new(&mut hdr, &["id".to_owned()], "id", "value").is_err());
+    assert!(Melter::new(&mut hdr, &["nope".to_owned()], "variable", "value").is_err());
+}
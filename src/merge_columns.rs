@@ -0,0 +1,191 @@
+//! Joining several columns into one, for `--merge-columns`.
+
+use csv::ByteRecord;
+use std::{borrow::Cow, str::FromStr};
+
+use crate::errors::*;
+use crate::util::{find_column, parse_option_words};
+
+/// Columns to merge, written as `NEW=COL1,COL2,... sep=SEP drop`.
+#[derive(Clone, Debug)]
+pub struct ColumnMerge {
+    /// The name of our new column.
+    name: String,
+    /// The columns to merge.
+    columns: Vec<String>,
+    /// What to put between values.
+    sep: Vec<u8>,
+    /// Should we remove the columns we merged?
+    drop: bool,
+}
+
+impl FromStr for ColumnMerge {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ColumnMerge> {
+        let words = parse_option_words(s)?;
+        let mut merge = match words.first() {
+            Some((name, Some(columns))) => ColumnMerge {
+                name: name.to_owned(),
+                columns: columns.split(',').map(str::to_owned).collect(),
+                sep: b" ".to_vec(),
+                drop: false,
+            },
+            _ => {
+                return Err(format_err!(
+                "expected NEW=COL1,COL2,... followed by sep=SEP or drop, found {:?}",
+                s
+            ))
+            }
+        };
+        for (key, value) in &words[1..] {
+            match (key.as_str(), value) {
+                ("sep", Some(sep)) => merge.sep = sep.as_bytes().to_owned(),
+                ("drop", None) => merge.drop = true,
+                _ => {
+                    return Err(format_err!(
+                        "unknown --merge-columns option {:?}",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(merge)
+    }
+}
+
+/// A merge, resolved against a header.
+struct ResolvedMerge {
+    /// The columns to merge.
+    sources: Vec<usize>,
+    /// Where to put our new column, after dropping any sources.
+    target: usize,
+    /// The separator to use.
+    sep: Vec<u8>,
+    /// Should we remove the columns we merged?
+    drop: bool,
+}
+
+/// Merges columns in each row.
+pub struct ColumnMerger {
+    merges: Vec<ResolvedMerge>,
+}
+
+impl ColumnMerger {
+    /// Apply `merges` to `hdr`, in order, and prepare to do the same to each
+    /// row. A merged column replaces the first of its sources if we're
+    /// dropping them, and is added at the end otherwise. Returns `None` if
+    /// we have nothing to merge.
+    pub fn new(
+        hdr: &mut ByteRecord,
+        merges: &[ColumnMerge],
+    ) -> Result<Option<ColumnMerger>> {
+        if merges.is_empty() {
+            return Ok(None);
+        }
+        let mut resolved = vec![];
+        for merge in merges {
+            let sources = merge
+                .columns
+                .iter()
+                .map(|col| {
+                    find_column(hdr, col).ok_or_else(|| {
+                        format_err!("cannot find --merge-columns column {:?}", col)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut names = hdr.iter().map(|name| name.to_owned()).collect::<Vec<_>>();
+            let target = if merge.drop {
+                let first = *sources.iter().min().expect("should have a column");
+                let target = first - sources.iter().filter(|&&i| i < first).count();
+                names = remove_indices(names, &sources);
+                target
+            } else {
+                names.len()
+            };
+            if names.iter().any(|n| n == merge.name.as_bytes()) {
+                return Err(format_err!("column {:?} already exists", merge.name));
+            }
+            names.insert(target, merge.name.as_bytes().to_owned());
+            *hdr = ByteRecord::from(names);
+            resolved.push(ResolvedMerge {
+                sources,
+                target,
+                sep: merge.sep.clone(),
+                drop: merge.drop,
+            });
+        }
+        Ok(Some(ColumnMerger { merges: resolved }))
+    }
+
+    /// Merge the columns of `row`. Empty values are skipped, so that we don't
+    /// output stray separators.
+    pub fn merge_row<'a>(&self, row: &mut Vec<Cow<'a, [u8]>>) {
+        for merge in &self.merges {
+            let mut merged = vec![];
+            for &idx in &merge.sources {
+                if row[idx].is_empty() {
+                    continue;
+                }
+                if !merged.is_empty() {
+                    merged.extend_from_slice(&merge.sep);
+                }
+                merged.extend_from_slice(&row[idx]);
+            }
+            if merge.drop {
+                *row = remove_indices(std::mem::take(row), &merge.sources);
+            }
+            row.insert(merge.target, Cow::Owned(merged));
+        }
+    }
+}
+
+/// Remove the items at `indices` from `items`.
+fn remove_indices<T>(items: Vec<T>, indices: &[usize]) -> Vec<T> {
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !indices.contains(i))
+        .map(|(_, item)| item)
+        .collect()
+}
+
+#[test]
+fn merges_columns() {
+    let merges = vec![
+        r#"address=street,city sep=", " drop"#.parse().unwrap(),
+        "all=id,address".parse().unwrap(),
+    ];
+    let mut hdr = ByteRecord::from(vec!["id", "street", "note", "city"]);
+    let merger = ColumnMerger::new(&mut hdr, &merges).unwrap().unwrap();
+    assert_eq!(hdr, ByteRecord::from(vec!["id", "address", "note", "all"]));
+    let mut row = vec![
+        Cow::Borrowed(&b"1"[..]),
+        Cow::Borrowed(&b"1 Main St"[..]),
+        Cow::Borrowed(&b"x"[..]),
+        Cow::Borrowed(&b"Springfield"[..]),
+    ];
+    merger.merge_row(&mut row);
+    assert_eq!(
+        row,
+        vec![
+            &b"1"[..],
+            b"1 Main St, Springfield",
+            b"x",
+            b"1 1 Main St, Springfield"
+        ]
+    );
+    let mut row = vec![
+        Cow::Borrowed(&b"2"[..]),
+        Cow::Borrowed(&b""[..]),
+        Cow::Borrowed(&b""[..]),
+        Cow::Borrowed(&b"Shelbyville"[..]),
+    ];
+    merger.merge_row(&mut row);
+    assert_eq!(row[1], &b"Shelbyville"[..]);
+
+    assert!("address".parse::<ColumnMerge>().is_err());
+    assert!("a=b,c nope=1".parse::<ColumnMerge>().is_err());
+    let mut hdr = ByteRecord::from(vec!["id", "street", "city", "address"]);
+    assert!(ColumnMerger::new(&mut hdr, &merges).is_err());
+}
@@ -0,0 +1,128 @@
+//! Treat runs of delimiters as a single separator, the way `awk` splits
+//! fields on whitespace by default.
+//!
+//! The `csv` parser can't do this, so we rewrite the raw input before it sees
+//! it.
+
+use std::io::{self, prelude::*};
+
+/// A reader which collapses runs of delimiter bytes outside of quotes into a
+/// single delimiter, and removes delimiters at the start and end of lines.
+pub struct DelimiterMergingReader<R: BufRead> {
+    /// The reader we wrap.
+    inner: R,
+    /// Bytes which count as delimiters on input.
+    delimiters: Vec<u8>,
+    /// The single delimiter we output in their place.
+    output_delimiter: u8,
+    /// Our quote character, if any.
+    quote: Option<u8>,
+    /// The raw line we're working on.
+    line: Vec<u8>,
+    /// The rewritten version of `line`.
+    merged: Vec<u8>,
+    /// How much of `merged` we've already returned.
+    pos: usize,
+    /// Are we inside a quoted field at the end of the current line?
+    in_quotes: bool,
+}
+
+impl<R: BufRead> DelimiterMergingReader<R> {
+    /// Create a new reader which replaces runs of any of `delimiters` with
+    /// `output_delimiter`.
+    pub fn new(
+        inner: R,
+        delimiters: &[u8],
+        output_delimiter: u8,
+        quote: Option<u8>,
+    ) -> DelimiterMergingReader<R> {
+        DelimiterMergingReader {
+            inner,
+            delimiters: delimiters.to_owned(),
+            output_delimiter,
+            quote,
+            line: vec![],
+            merged: vec![],
+            pos: 0,
+            in_quotes: false,
+        }
+    }
+
+    /// Rewrite `self.line` into `self.merged`.
+    fn merge_line(&mut self) {
+        self.merged.clear();
+        self.pos = 0;
+        let mut at_field_start = !self.in_quotes;
+        let mut at_line_start = !self.in_quotes;
+        let mut pending_delimiter = false;
+        for &b in &self.line {
+            if self.in_quotes {
+                if Some(b) == self.quote {
+                    // If the next byte is another quote, this was an escaped
+                    // quote and we'll go right back into quotes.
+                    self.in_quotes = false;
+                    at_field_start = true;
+                }
+                self.merged.push(b);
+            } else if self.delimiters.contains(&b) {
+                // Don't output anything until we know this isn't leading or
+                // trailing.
+                pending_delimiter = !at_line_start;
+                at_field_start = true;
+            } else if b == b'\n' || b == b'\r' {
+                pending_delimiter = false;
+                at_field_start = true;
+                at_line_start = true;
+                self.merged.push(b);
+            } else {
+                if pending_delimiter {
+                    self.merged.push(self.output_delimiter);
+                    pending_delimiter = false;
+                }
+                if Some(b) == self.quote && at_field_start {
+                    self.in_quotes = true;
+                }
+                at_field_start = false;
+                at_line_start = false;
+                self.merged.push(b);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for DelimiterMergingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.merged.len() {
+            self.line.clear();
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            self.merge_line();
+        }
+        let count = buf.len().min(self.merged.len() - self.pos);
+        buf[..count].copy_from_slice(&self.merged[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+fn merge(input: &str, delimiters: &[u8], output_delimiter: u8) -> String {
+    let mut rdr = DelimiterMergingReader::new(
+        input.as_bytes(),
+        delimiters,
+        output_delimiter,
+        Some(b'"'),
+    );
+    let mut out = String::new();
+    rdr.read_to_string(&mut out).unwrap();
+    out
+}
+
+#[test]
+fn merges_delimiters() {
+    assert_eq!(merge("a;;b;;;c\n", b";", b';'), "a;b;c\n");
+    assert_eq!(merge(";a;b;\r\n", b";", b';'), "a;b\r\n");
+    assert_eq!(merge("a;\"x;;\ny\";b\n", b";", b';'), "a;\"x;;\ny\";b\n");
+    assert_eq!(merge("\"x\"\";;y\";;b\n", b";", b';'), "\"x\"\";;y\";b\n");
+}
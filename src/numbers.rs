@@ -0,0 +1,254 @@
+//! Locale-aware tweaks for numeric cells.
+//!
+//! We only touch values which look _exactly_ like plain decimal numbers, so
+//! that things like ZIP codes, dates and free text pass through unchanged.
+
+use csv::ByteRecord;
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use std::borrow::Cow;
+
+use crate::errors::*;
+use crate::stats::{RuleHits, RuleId};
+use crate::util::find_column;
+
+lazy_static! {
+    /// A number using `,` as a thousands separator, like `-1,234,567.89`.
+    static ref THOUSANDS_RE: Regex = Regex::new(r#"^[-+]?\d{1,3}(?:,\d{3})+(?:\.\d+)?$"#)
+        .expect("regex in source code is unparseable");
+
+    /// A number with a decimal point, like `-1234.56`.
+    static ref DECIMAL_POINT_RE: Regex = Regex::new(r#"^[-+]?\d+\.\d+$"#)
+        .expect("regex in source code is unparseable");
+}
+
+/// If `val` is a number with `,` thousands separators, remove them.
+pub fn strip_thousands_separators(val: Cow<[u8]>) -> Cow<[u8]> {
+    if THOUSANDS_RE.is_match(&val) {
+        Cow::Owned(val.iter().cloned().filter(|&b| b != b',').collect())
+    } else {
+        val
+    }
+}
+
+#[test]
+fn strips_thousands_separators() {
+    let examples: &[(&[u8], &[u8])] = &[
+        (b"1,234", b"1234"),
+        (b"-1,234,567.89", b"-1234567.89"),
+        (b"1234", b"1234"),
+        (b"12,34", b"12,34"),
+        (b"Paris, France", b"Paris, France"),
+    ];
+    for &(input, expected) in examples {
+        assert_eq!(
+            &strip_thousands_separators(Cow::Borrowed(input))[..],
+            expected
+        );
+    }
+}
+
+/// If `val` is a number with a decimal point, replace the point with a comma,
+/// the way most European systems expect.
+pub fn use_decimal_comma(val: Cow<[u8]>) -> Cow<[u8]> {
+    if DECIMAL_POINT_RE.is_match(&val) {
+        Cow::Owned(
+            val.iter()
+                .map(|&b| if b == b'.' { b',' } else { b })
+                .collect(),
+        )
+    } else {
+        val
+    }
+}
+
+#[test]
+fn converts_to_decimal_comma() {
+    let examples: &[(&[u8], &[u8])] = &[
+        (b"1234.56", b"1234,56"),
+        (b"-0.5", b"-0,5"),
+        (b"1234", b"1234"),
+        (b"1.2.3", b"1.2.3"),
+        (b"1,234.56", b"1,234.56"),
+    ];
+    for &(input, expected) in examples {
+        assert_eq!(&use_decimal_comma(Cow::Borrowed(input))[..], expected);
+    }
+}
+
+/// Currency symbols which we remove from numbers.
+const CURRENCY_SYMBOLS: &[char] = &['$', '€', '£', '¥', '₹', '¢'];
+
+/// Convert a number like `$ 1,234.56` or `(1,234.56)` to a plain number like
+/// `1234.56` or `-1234.56`. If `decimal_comma` is set, we expect numbers like
+/// `1.234,56` instead. Returns `None` if `val` isn't a number.
+pub fn normalize_number(val: &[u8], decimal_comma: bool) -> Option<Vec<u8>> {
+    let trim = |s: &str| -> String {
+        s.trim_matches(|c: char| c.is_whitespace() || CURRENCY_SYMBOLS.contains(&c))
+            .to_owned()
+    };
+    let mut s = trim(std::str::from_utf8(val).ok()?);
+    let mut negative = false;
+    if let Some(inner) = s.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+        negative = true;
+        s = trim(inner);
+    }
+    if let Some(rest) = s.strip_prefix('-') {
+        if negative {
+            return None;
+        }
+        negative = true;
+        s = trim(rest);
+    } else if let Some(rest) = s.strip_prefix('+') {
+        s = trim(rest);
+    }
+
+    let (separator, point) = if decimal_comma {
+        ('.', ',')
+    } else {
+        (',', '.')
+    };
+    let (whole, frac) = match s.split_once(point) {
+        Some((whole, frac)) => (whole, Some(frac)),
+        None => (&s[..], None),
+    };
+    let all_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    let mut groups = whole.split(separator);
+    let first = groups.next().expect("split always returns a value");
+    let rest = groups.collect::<Vec<_>>();
+    let grouped_ok = rest.is_empty() || (!first.is_empty() && first.len() <= 3);
+    if !grouped_ok
+        || !all_digits(first)
+        || !rest.iter().all(|g| g.len() == 3 && all_digits(g))
+        || !frac.is_none_or(|f| !f.is_empty() && all_digits(f))
+        || (whole.is_empty() && frac.is_none())
+    {
+        return None;
+    }
+
+    let mut normalized = vec![];
+    if negative {
+        normalized.push(b'-');
+    }
+    if whole.is_empty() {
+        normalized.push(b'0');
+    }
+    normalized.extend(whole.bytes().filter(|&b| b != separator as u8));
+    if let Some(frac) = frac {
+        normalized.push(b'.');
+        normalized.extend_from_slice(frac.as_bytes());
+    }
+    Some(normalized)
+}
+
+#[test]
+fn normalizes_numbers() {
+    let examples: &[(&str, bool, Option<&str>)] = &[
+        (" 1,234.56 ", false, Some("1234.56")),
+        ("$1,234", false, Some("1234")),
+        ("(1,234.56)", false, Some("-1234.56")),
+        ("-€ 5", false, Some("-5")),
+        ("£.5", false, Some("0.5")),
+        ("+12", false, Some("12")),
+        ("1.234,56 €", true, Some("1234.56")),
+        ("(0,5)", true, Some("-0.5")),
+        ("1,234.56", true, None),
+        ("12,34", false, None),
+        ("1,2345", false, None),
+        ("-(5)", false, None),
+        ("5.", false, None),
+        ("$", false, None),
+        ("12 apples", false, None),
+    ];
+    for &(input, decimal_comma, expected) in examples {
+        assert_eq!(
+            normalize_number(input.as_bytes(), decimal_comma),
+            expected.map(|e| e.as_bytes().to_vec()),
+            "{:?}",
+            input,
+        );
+    }
+}
+
+/// Normalizes the numbers in some of our columns, for `--normalize-numbers`.
+#[derive(Debug)]
+pub struct NumberNormalizer {
+    /// Our columns, and the IDs we use to count values which aren't numbers.
+    columns: Vec<(usize, RuleId)>,
+    /// Do our inputs use decimal commas?
+    decimal_comma: bool,
+    /// Should we write numbers with decimal commas?
+    decimal_comma_output: bool,
+    /// Counts the values we changed.
+    normalized_rule: RuleId,
+}
+
+impl NumberNormalizer {
+    /// Prepare to normalize `columns`, using the header `hdr`, and registering
+    /// our rules with `hits`. Returns `None` if we have no columns.
+    pub fn new(
+        hdr: &ByteRecord,
+        columns: &[String],
+        decimal_comma: bool,
+        decimal_comma_output: bool,
+        hits: &mut RuleHits,
+    ) -> Result<Option<NumberNormalizer>> {
+        if columns.is_empty() {
+            return Ok(None);
+        }
+        let columns = columns
+            .iter()
+            .map(|name| {
+                let idx = find_column(hdr, name).ok_or_else(|| {
+                    format_err!("cannot find --normalize-numbers column {:?}", name)
+                })?;
+                let id = hits.register(
+                    format!("--normalize-numbers {}", name),
+                    "cells rejected",
+                );
+                Ok((idx, id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let normalized_rule = hits.register("--normalize-numbers", "cells normalized");
+        Ok(Some(NumberNormalizer {
+            columns,
+            decimal_comma,
+            decimal_comma_output,
+            normalized_rule,
+        }))
+    }
+
+    /// Normalize the numbers in `row`, counting them in `hits`. Returns
+    /// `false` if a value isn't a number. Returns `true` and sets `changed` if
+    /// we changed anything. Empty values are always allowed.
+    pub fn normalize_row(
+        &self,
+        row: &mut [Cow<[u8]>],
+        changed: &mut bool,
+        hits: &RuleHits,
+    ) -> bool {
+        for &(idx, id) in &self.columns {
+            if row[idx].is_empty() {
+                continue;
+            }
+            match normalize_number(&row[idx], self.decimal_comma) {
+                Some(mut normalized) => {
+                    if self.decimal_comma_output {
+                        normalized =
+                            use_decimal_comma(Cow::Owned(normalized)).into_owned();
+                    }
+                    if normalized != *row[idx] {
+                        hits.hit(self.normalized_rule);
+                        row[idx] = Cow::Owned(normalized);
+                        *changed = true;
+                    }
+                }
+                None => {
+                    hits.hit(id);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
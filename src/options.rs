@@ -0,0 +1,1104 @@
+//! Our command-line options.
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+use crate::add_columns::{AddColumn, ConstantColumn};
+use crate::bare_quotes::BareQuotePolicy;
+use crate::buffers::BufferSize;
+use crate::cleaner::LongCellPolicy;
+use crate::dedup::Keep;
+use crate::emit_schema::SchemaFormat;
+use crate::encoding::{InputEncoding, InvalidUtf8, Utf8Fallback};
+use crate::explode::Explode;
+use crate::expr::WhereExpr;
+use crate::filter::ColumnMatch;
+use crate::generate::GenerateOpt;
+use crate::lookup::{KeyListSpec, LookupSpec};
+use crate::merge_columns::ColumnMerge;
+use crate::output::OutputTemplate;
+use crate::preset::Preset;
+use crate::quote_repair::QuoteRepair;
+use crate::quoting::OutputEscape;
+use crate::rename::Rename;
+use crate::replace::Replacement;
+use crate::report::ReportFormat;
+use crate::schema::OnSchemaChange;
+use crate::sort::{OnUnsorted, SortKey};
+use crate::split_columns::ColumnSplit;
+use crate::tui::TuiOpt;
+use crate::types::ColumnType;
+use crate::unicode::UnicodeForm;
+use crate::util::{ByteSize, CharSpecifier, DelimiterSpecifier};
+
+/// Our command-line arguments.
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(
+    name = "scrubcsv",
+    about = "Clean and normalize a CSV file.",
+    after_help = "Read a CSV file, normalize the \"good\" lines, and print them to standard
+output.  Discard any lines with the wrong number of columns.
+
+Options which take column names also accept positions like @3 or #3, counting
+from 1, for columns with duplicate, empty or unreadable names.
+
+Regular expressions use Rust syntax, as described here:
+https://doc.rust-lang.org/regex/regex/index.html#syntax
+
+scrubcsv should work with any ASCII-compatible encoding. To convert other
+encodings, like UTF-16, to UTF-8, use --input-encoding.
+
+Exit code:
+    0 on success
+    1 on error
+    2 if more than 10% of rows were bad
+    3 if the number of good rows was outside --assert-min-rows and
+      --assert-max-rows
+    4 if --fail-if-unchanged or --fail-if-changed-over was triggered
+    5 if --fail-on-drift found drift from --baseline-profile
+    6 if the columns did not match --expect-schema and --on-schema-change
+      was \"fail\", or --expect-columns, --require-columns or
+      --fail-on-extra-columns failed
+    7 if --stdin-timeout expired"
+)]
+pub struct Opt {
+    /// Subcommands which do something other than scrubbing.
+    #[structopt(subcommand)]
+    pub(crate) cmd: Option<Command>,
+
+    /// Input files (uses stdin if omitted). With more than one file, we
+    /// output all their rows with a single header, and their headers must
+    /// match unless --union-columns is passed. Quoted globs like "data/*.csv"
+    /// are expanded. A file can have its own delimiter, quote or encoding,
+    /// like 'vendor.csv;delimiter=pipe;encoding=latin1'.
+    pub(crate) inputs: Vec<PathBuf>,
+
+    /// Turn on the options needed to load our output into DATABASE:
+    /// "bigquery", "redshift", "snowflake" or "postgres". All of these make
+    /// sure our output is valid UTF-8 and treat "NULL" and "\N" as empty,
+    /// unless --null is passed. "bigquery" also replaces newlines in values,
+    /// and "redshift" and "snowflake" quote values with leading or trailing
+    /// whitespace.
+    #[structopt(value_name = "DATABASE", long = "preset")]
+    pub(crate) preset: Option<Preset>,
+
+    /// Character used to separate fields in a row (must be a single ASCII
+    /// byte, a name like "tab", "comma", "semicolon", "pipe" or "caret", a
+    /// byte in hex like "0x1F", "auto" to guess from the start of the
+    /// input, or "whitespace" to split on runs of spaces and tabs). For a
+    /// longer delimiter, add "seq:", like "seq:||" or "seq:~|~". If the
+    /// input starts with a line like "sep=;", which Excel uses to give the
+    /// delimiter, we always remove it, and use its delimiter instead of ","
+    /// or "auto".
+    #[structopt(
+        value_name = "CHAR",
+        short = "d",
+        long = "delimiter",
+        default_value = ","
+    )]
+    pub(crate) delimiter: DelimiterSpecifier,
+
+    /// Byte which ends each input record, like "0x1E" for ASCII-delimited
+    /// files or "\0" for NUL-terminated streams. By default, records end
+    /// with "\n", "\r" or "\r\n". Output records always end with "\n".
+    /// Options that work on raw input lines still split them on "\n".
+    #[structopt(value_name = "CHAR", long = "record-terminator")]
+    pub(crate) record_terminator: Option<CharSpecifier>,
+
+    /// Treat runs of the delimiter as a single separator, and ignore
+    /// delimiters at the start and end of lines.
+    #[structopt(long = "merge-delimiters")]
+    pub(crate) merge_delimiters: bool,
+
+    /// Maximum number of bytes to examine when guessing the delimiter with
+    /// "--delimiter auto". Nothing else we detect reads a sample: compression
+    /// is detected from the first few bytes, and --emit-schema looks at every
+    /// row.
+    #[structopt(
+        value_name = "BYTES",
+        long = "detect-sample-bytes",
+        default_value = "65536"
+    )]
+    pub(crate) detect_sample_bytes: usize,
+
+    /// Maximum number of lines to examine when guessing the delimiter with
+    /// "--delimiter auto".
+    #[structopt(
+        value_name = "ROWS",
+        long = "detect-sample-rows",
+        default_value = "100"
+    )]
+    pub(crate) detect_sample_rows: usize,
+
+    /// Convert values matching NULL_REGEX to an empty string. For a case-insensitive
+    /// match, use `(?i)`: `--null '(?i)NULL'`.
+    #[structopt(value_name = "NULL_REGEX", short = "n", long = "null")]
+    pub(crate) null: Option<String>,
+
+    /// Replace LF and CRLF sequences in values with spaces. This should improve
+    /// compatibility with systems like BigQuery that don't expect newlines
+    /// inside escaped strings.
+    #[structopt(long = "replace-newlines")]
+    pub(crate) replace_newlines: bool,
+
+    /// Normalize Unicode in each cell to "nfc" or "nfkc", so that strings
+    /// which look the same are encoded the same. We handle accented Latin
+    /// letters and common compatibility characters, not the full Unicode
+    /// tables.
+    #[structopt(value_name = "FORM", long = "normalize-unicode")]
+    pub(crate) normalize_unicode: Option<UnicodeForm>,
+
+    /// Replace curly quotes with straight quotes, en and em dashes with
+    /// hyphens, and non-breaking spaces with spaces.
+    #[structopt(long = "ascii-punctuation")]
+    pub(crate) ascii_punctuation: bool,
+
+    /// Remove control characters other than tabs and newlines from each
+    /// cell. These break some CSV parsers, even inside quotes.
+    #[structopt(long = "strip-control-chars")]
+    pub(crate) strip_control_chars: bool,
+
+    /// With --strip-control-chars, replace each control character with a
+    /// space instead of removing it.
+    #[structopt(long = "control-chars-to-spaces", requires = "strip-control-chars")]
+    pub(crate) control_chars_to_spaces: bool,
+
+    /// Limit cells to this many bytes, after any other cleanups. See
+    /// --long-cell-policy.
+    #[structopt(value_name = "N", long = "max-cell-bytes")]
+    pub(crate) max_cell_bytes: Option<usize>,
+
+    /// With --max-cell-bytes, what to do with longer cells: "truncate" them
+    /// (the default), replace them with "null", or "drop-row".
+    #[structopt(
+        value_name = "POLICY",
+        long = "long-cell-policy",
+        requires = "max-cell-bytes"
+    )]
+    pub(crate) long_cell_policy: Option<LongCellPolicy>,
+
+    /// Remove whitespace at beginning and end of each cell.
+    #[structopt(long = "trim-whitespace")]
+    pub(crate) trim_whitespace: bool,
+
+    /// Replace each run of spaces and tabs inside a cell with a single space.
+    #[structopt(long = "collapse-whitespace")]
+    pub(crate) collapse_whitespace: bool,
+
+    /// Quote any values which start or end with whitespace, because some CSV
+    /// parsers strip unquoted whitespace. Only useful without
+    /// --trim-whitespace.
+    #[structopt(long = "quote-leading-whitespace")]
+    pub(crate) quote_leading_whitespace: bool,
+
+    /// How to escape quotes inside quoted output values: "doubled" (standard
+    /// CSV) or "backslash" (for some Hive and Spark readers).
+    #[structopt(
+        value_name = "STYLE",
+        long = "output-escape",
+        default_value = "doubled"
+    )]
+    pub(crate) output_escape: OutputEscape,
+
+    /// Character used to separate output values, like "tab" or ";". Defaults
+    /// to ",".
+    #[structopt(value_name = "CHAR", long = "out-delimiter")]
+    pub(crate) out_delimiter: Option<CharSpecifier>,
+
+    /// End output records with "\r\n" instead of "\n".
+    #[structopt(long = "out-crlf")]
+    pub(crate) out_crlf: bool,
+
+    /// Remove `,` thousands separators from numbers like `1,234.56`.
+    #[structopt(long = "strip-thousands-separators")]
+    pub(crate) strip_thousands_separators: bool,
+
+    /// Write numbers like `1234.56` using a decimal comma (`1234,56`), as
+    /// expected by many European systems.
+    #[structopt(long = "decimal-comma-output")]
+    pub(crate) decimal_comma_output: bool,
+
+    /// Replace matches of a regex in a column, written as
+    /// COL=/PATTERN/REPLACEMENT/. The replacement may use "$1" or "${name}"
+    /// for capture groups, and any character may be used instead of "/". Can
+    /// be passed more than once, and replacements run in order. Uses the
+    /// cleaned form of column names and values.
+    #[structopt(
+        value_name = "COL=/PATTERN/REPLACEMENT/",
+        long = "replace",
+        number_of_values = 1
+    )]
+    pub(crate) replace: Vec<Replacement>,
+
+    /// Normalize numbers in these columns, removing whitespace, currency
+    /// symbols and thousands separators, and turning accounting negatives
+    /// like "(1,234.56)" into "-1234.56". Rows where these columns contain
+    /// anything else are bad. Empty values are always allowed. Uses the
+    /// cleaned form of column names.
+    #[structopt(
+        value_name = "COL,...",
+        long = "normalize-numbers",
+        use_delimiter = true,
+        require_delimiter = true
+    )]
+    pub(crate) normalize_numbers: Vec<String>,
+
+    /// With --normalize-numbers, expect numbers like "1.234,56", which use
+    /// "," as a decimal point.
+    #[structopt(long = "decimal-comma", requires = "normalize-numbers")]
+    pub(crate) decimal_comma: bool,
+
+    /// Convert our input from ENCODING to UTF-8. Accepts any label which web
+    /// browsers do, like "latin1", "windows-1252" or "utf-16le".
+    #[structopt(value_name = "ENCODING", long = "input-encoding")]
+    pub(crate) input_encoding: Option<InputEncoding>,
+
+    /// When a cell contains invalid UTF-8, reinterpret just the invalid bytes
+    /// using this encoding (currently only "windows-1252") and convert them
+    /// to UTF-8.
+    #[structopt(value_name = "ENCODING", long = "utf8-fallback")]
+    pub(crate) utf8_fallback: Option<Utf8Fallback>,
+
+    /// Make sure every cell of our output is valid UTF-8, after any
+    /// --utf8-fallback. See --invalid-utf8.
+    #[structopt(long = "ensure-utf8")]
+    pub(crate) ensure_utf8: bool,
+
+    /// With --ensure-utf8, what to do with invalid UTF-8: "replace" it with
+    /// U+FFFD (the default), "drop" the row, or "fail".
+    #[structopt(
+        value_name = "POLICY",
+        long = "invalid-utf8",
+        requires = "ensure-utf8"
+    )]
+    pub(crate) invalid_utf8: Option<InvalidUtf8>,
+
+    /// How to repair stray quotes inside quoted fields, like `"Broken "
+    /// quotes"`. "smart" treats them as embedded quotes if the field is closed
+    /// properly later on the same line, "escape" always treats them as
+    /// embedded quotes, and "strip" removes them.
+    #[structopt(value_name = "STRATEGY", long = "quote-repair")]
+    pub(crate) quote_repair: Option<QuoteRepair>,
+
+    /// What to do with quotes outside of quoted fields, like `a"b` or the
+    /// last quote in `"a"b"`: "keep" them, "strip" them, or "reject" the row.
+    #[structopt(
+        value_name = "POLICY",
+        long = "bare-quote-policy",
+        default_value = "keep"
+    )]
+    pub(crate) bare_quote_policy: BareQuotePolicy,
+
+    /// Try to recover from unbalanced quotes which swallow the following
+    /// lines, by closing the quoted field at its first line break and
+    /// re-parsing the rest.
+    #[structopt(long = "recover-runaway-quotes")]
+    pub(crate) recover_runaway_quotes: bool,
+
+    /// When using --recover-runaway-quotes, treat any field spanning at least N
+    /// line breaks as a runaway, even if its row has the right number of
+    /// columns.
+    #[structopt(value_name = "N", long = "runaway-quote-lines", default_value = "10")]
+    pub(crate) runaway_quote_lines: usize,
+
+    /// If the header ends with a delimiter, strip the resulting empty column,
+    /// and require every row to end with a delimiter, too.
+    #[structopt(long = "allow-trailing-delimiter")]
+    pub(crate) allow_trailing_delimiter: bool,
+
+    /// Instead of rejecting rows with more columns than the header, discard
+    /// the extra fields at the end.
+    #[structopt(long = "truncate-long-rows")]
+    pub(crate) truncate_long_rows: bool,
+
+    /// Instead of rejecting rows with more columns than the header, join the
+    /// last column and any extra fields back together using the delimiter.
+    #[structopt(
+        long = "merge-overflow-into-last",
+        conflicts_with = "truncate-long-rows"
+    )]
+    pub(crate) merge_overflow_into_last: bool,
+
+    /// Discard this many lines at the start of our input, before looking for
+    /// a header.
+    #[structopt(value_name = "N", long = "skip-lines", default_value = "0")]
+    pub(crate) skip_lines: usize,
+
+    /// After any --skip-lines, also discard lines matching REGEX until we
+    /// find one which doesn't match. "^$" skips blank lines.
+    #[structopt(value_name = "REGEX", long = "skip-lines-matching")]
+    pub(crate) skip_lines_matching: Option<String>,
+
+    /// Discard this many lines at the end of our input, like totals or
+    /// "exported by" lines. Discarded lines aren't counted as bad rows.
+    #[structopt(value_name = "N", long = "skip-footer", conflicts_with = "follow")]
+    pub(crate) skip_footer: Option<usize>,
+
+    /// Before any --skip-footer lines, also discard lines at the end of our
+    /// input which match REGEX. "^(Total.*)?$" skips a totals line and any
+    /// blank lines.
+    #[structopt(
+        value_name = "REGEX",
+        long = "drop-trailer-matching",
+        conflicts_with = "follow"
+    )]
+    pub(crate) drop_trailer_matching: Option<String>,
+
+    /// Skip lines starting with CHAR anywhere in our input, unless they're
+    /// inside a quoted value. Skipped lines aren't counted as rows.
+    #[structopt(value_name = "CHAR", long = "comment-char")]
+    pub(crate) comment_char: Option<CharSpecifier>,
+
+    /// With --comment-char, copy the comment lines we skip to this file.
+    #[structopt(
+        value_name = "PATH",
+        long = "comments-path",
+        requires = "comment-char",
+        parse(from_os_str)
+    )]
+    pub(crate) comments_path: Option<PathBuf>,
+
+    /// Discard this many data rows after the header. Unlike --skip-lines,
+    /// this understands quoted newlines. Skipped rows aren't counted, unless
+    /// they're bad.
+    #[structopt(value_name = "N", long = "skip-rows", default_value = "0")]
+    pub(crate) skip_rows: u64,
+
+    /// Only output the first N good rows, and stop reading once we have
+    /// them.
+    #[structopt(value_name = "N", long = "head", conflicts_with = "keep")]
+    pub(crate) head: Option<u64>,
+
+    /// Only output the last N good rows, after any --head. We hold these
+    /// rows until we've read our input, in memory up to --memory-limit.
+    /// Earlier rows are counted as filtered out.
+    #[structopt(value_name = "N", long = "tail", conflicts_with = "keep")]
+    pub(crate) tail: Option<usize>,
+
+    /// Merge our input files, each of which must already be sorted by these
+    /// columns, written like --sort-by, so that our output is sorted, too.
+    /// Rows are compared before cleaning, using the input's column names.
+    /// Every input is opened at once.
+    #[structopt(
+        value_name = "COLS",
+        long = "merge-sorted-by",
+        use_delimiter = true,
+        require_delimiter = true,
+        conflicts_with_all = &["follow", "union-columns"]
+    )]
+    pub(crate) merge_sorted_by: Vec<SortKey>,
+
+    /// Treat the first row of our input as data, not as a header. Columns are
+    /// named c1, c2, etc., for options which refer to columns by name.
+    #[structopt(long = "no-headers")]
+    pub(crate) no_headers: bool,
+
+    /// With more than one input file, output every column found in any of
+    /// them, filling in missing cells with empty strings. Columns are
+    /// matched by name.
+    #[structopt(long = "union-columns", conflicts_with = "no-headers")]
+    pub(crate) union_columns: bool,
+
+    /// With --no-headers, write our synthesized column names as a header.
+    #[structopt(long = "add-header", requires = "no-headers")]
+    pub(crate) add_header: bool,
+
+    /// Fail with exit code 6 before writing any data unless our columns are
+    /// exactly these, in this order, separated by commas. Uses the cleaned
+    /// form of column names.
+    #[structopt(
+        value_name = "COLS",
+        long = "expect-columns",
+        use_delimiter = true,
+        require_delimiter = true
+    )]
+    pub(crate) expect_columns: Vec<String>,
+
+    /// Fail with exit code 6 before writing any data unless our input has
+    /// all of these columns, separated by commas. Uses the cleaned form of
+    /// column names.
+    #[structopt(
+        value_name = "COLS",
+        long = "require-columns",
+        use_delimiter = true,
+        require_delimiter = true
+    )]
+    pub(crate) require_columns: Vec<String>,
+
+    /// Rename the column OLD to NEW, before any other changes to column names.
+    /// Can be passed more than once. Columns which aren't in our input are
+    /// ignored.
+    #[structopt(value_name = "OLD=NEW", long = "rename", number_of_values = 1)]
+    pub(crate) rename: Vec<Rename>,
+
+    /// Rename columns using a CSV file with a header row, where each row
+    /// contains an old and a new column name. Works like --rename, which
+    /// takes priority.
+    #[structopt(value_name = "PATH", long = "rename-file", parse(from_os_str))]
+    pub(crate) rename_file: Option<PathBuf>,
+
+    /// Make sure column names are unique, and use only lowercase letters, numbers
+    /// and underscores.
+    #[structopt(long = "clean-column-names")]
+    pub(crate) clean_column_names: bool,
+
+    /// Write the original and final name of each column to a CSV file, so
+    /// that the same names can be reused with --apply-header-map.
+    #[structopt(value_name = "PATH", long = "write-header-map", parse(from_os_str))]
+    pub(crate) write_header_map: Option<PathBuf>,
+
+    /// Rename columns using a CSV file written by --write-header-map,
+    /// instead of cleaning them. Fails if a column isn't in the map.
+    #[structopt(
+        value_name = "PATH",
+        long = "apply-header-map",
+        parse(from_os_str),
+        conflicts_with = "clean-column-names"
+    )]
+    pub(crate) apply_header_map: Option<PathBuf>,
+
+    /// A YAML or JSON schema listing the columns we expect to see, after any
+    /// cleaning. Each column may have a type, which works like --types, and
+    /// may limit nullability, length and allowed values. Rows which break
+    /// these rules are bad. See --on-schema-change.
+    #[structopt(
+        value_name = "PATH",
+        long = "expect-schema",
+        alias = "schema",
+        parse(from_os_str)
+    )]
+    pub(crate) expect_schema: Option<PathBuf>,
+
+    /// What to do if the columns don't match --expect-schema: "warn" and
+    /// continue, "fail" before writing any data, or "adapt" by outputting the
+    /// schema's columns, filling in missing or renamed columns where possible.
+    #[structopt(
+        value_name = "POLICY",
+        long = "on-schema-change",
+        default_value = "warn"
+    )]
+    pub(crate) on_schema_change: OnSchemaChange,
+
+    /// Remove the column named COL from our output. Can be passed more than
+    /// once. Matches either the original or the cleaned column name.
+    #[structopt(value_name = "COL", long = "drop-column", number_of_values = 1)]
+    pub(crate) drop_column: Vec<String>,
+
+    /// Remove any columns whose original or cleaned names match REGEX. Can be
+    /// passed more than once.
+    #[structopt(
+        value_name = "REGEX",
+        long = "drop-column-matching",
+        number_of_values = 1
+    )]
+    pub(crate) drop_column_matching: Vec<String>,
+
+    /// Output only these columns, in this order, separated by commas. Uses
+    /// the cleaned form of column names.
+    #[structopt(
+        value_name = "COLS",
+        long = "select",
+        use_delimiter = true,
+        require_delimiter = true
+    )]
+    pub(crate) select: Vec<String>,
+
+    /// Output columns sorted by name (after any cleaning or renaming), so
+    /// that the layout doesn't change when the input's columns are reordered.
+    #[structopt(long = "sort-columns")]
+    pub(crate) sort_columns: bool,
+
+    /// Output exactly these columns, in this order, separated by commas.
+    /// Columns missing from our input are left empty, and columns not listed
+    /// are dropped. Any added columns follow these. Uses the cleaned form of
+    /// column names.
+    #[structopt(
+        value_name = "COLS",
+        long = "output-columns",
+        use_delimiter = true,
+        require_delimiter = true,
+        conflicts_with_all = &["select", "sort-columns"]
+    )]
+    pub(crate) output_columns: Vec<String>,
+
+    /// With --output-columns, fail with exit code 6 if our input has any
+    /// columns which aren't listed.
+    #[structopt(long = "fail-on-extra-columns", requires = "output-columns")]
+    pub(crate) fail_on_extra_columns: bool,
+
+    /// Drop any rows where the specified column is empty or NULL. Can be passed
+    /// more than once. Useful for cleaning primary key columns before
+    /// upserting. Uses the cleaned form of column names. If no column has
+    /// exactly this name, ignores case and surrounding whitespace, and then
+    /// tries cleaning the name. Fails if no column matches.
+    #[structopt(value_name = "COL", long = "drop-row-if-null", number_of_values = 1)]
+    pub(crate) drop_row_if_null: Vec<String>,
+
+    /// Drop any rows where any column is empty or NULL. Added columns aren't
+    /// checked.
+    #[structopt(long = "drop-row-if-any-null")]
+    pub(crate) drop_row_if_any_null: bool,
+
+    /// Drop any rows where every column is empty or NULL, like the blank rows
+    /// which spreadsheets often leave at the end of a file. Added columns
+    /// aren't checked.
+    #[structopt(long = "drop-row-if-all-null")]
+    pub(crate) drop_row_if_all_null: bool,
+
+    /// Remove any columns which are empty in every row. We need to see every
+    /// row before writing anything, so our output is spooled to a temporary
+    /// file.
+    #[structopt(
+        long = "drop-empty-columns",
+        conflicts_with_all = &["follow", "preserve-formatting", "output-template"]
+    )]
+    pub(crate) drop_empty_columns: bool,
+
+    /// Sort our output by these columns, separated by commas. Values are
+    /// compared byte by byte, unless a column is followed by ":num" to
+    /// compare numbers. Add ":desc" to put the largest values first. Rows
+    /// with the same values keep their order. We spool our output to
+    /// temporary files, so it doesn't need to fit in memory. Requires a
+    /// header.
+    #[structopt(
+        value_name = "COLS",
+        long = "sort-by",
+        use_delimiter = true,
+        require_delimiter = true,
+        conflicts_with_all = &["follow", "preserve-formatting", "output-template"]
+    )]
+    pub(crate) sort_by: Vec<SortKey>,
+
+    /// With --sort-by, sort about SIZE bytes of rows at a time in memory
+    /// (like "512M" or "2G"). Larger outputs are sorted in pieces on disk,
+    /// and merged. Defaults to --memory-limit, or 256M.
+    #[structopt(value_name = "SIZE", long = "sort-memory", requires = "sort-by")]
+    pub(crate) sort_memory: Option<ByteSize>,
+
+    /// Keep about SIZE bytes (like "512M" or "2G") of the rows we have to
+    /// hold before writing or rejecting them, for "--dedup-by --keep last"
+    /// and --tail, in memory. Any more are written to a temporary file.
+    #[structopt(value_name = "SIZE", long = "memory-limit")]
+    pub(crate) memory_limit: Option<ByteSize>,
+
+    /// Check that our output is already sorted by these columns, written
+    /// like --sort-by, without sorting it. Rows with the same values are
+    /// allowed. See --on-unsorted.
+    #[structopt(
+        value_name = "COLS",
+        long = "assert-sorted-by",
+        use_delimiter = true,
+        require_delimiter = true
+    )]
+    pub(crate) assert_sorted_by: Vec<SortKey>,
+
+    /// What to do with rows which are out of order for --assert-sorted-by:
+    /// "fail" with an error (the default), or "reject" them as bad rows.
+    /// Later rows are compared to the last row which was in order.
+    #[structopt(
+        value_name = "POLICY",
+        long = "on-unsorted",
+        requires = "assert-sorted-by"
+    )]
+    pub(crate) on_unsorted: Option<OnUnsorted>,
+
+    /// Filter out any rows where a column matches a regex, written as
+    /// COL=REGEX. Can be passed more than once. Uses the cleaned form of
+    /// column names and values. Filtered rows aren't counted as bad.
+    #[structopt(
+        value_name = "COL=REGEX",
+        long = "drop-row-if-match",
+        number_of_values = 1
+    )]
+    pub(crate) drop_row_if_match: Vec<ColumnMatch>,
+
+    /// Filter out any rows whose key is listed in a CSV file, loaded into
+    /// memory. Written as 'FILE on=COL ignore-case', where "on" may be
+    /// COL:FILE_COL if the key has a different name in FILE, and
+    /// "ignore-case" ignores the case of ASCII letters. Can be passed more
+    /// than once.
+    #[structopt(value_name = "SPEC", long = "drop-if-in", number_of_values = 1)]
+    pub(crate) drop_if_in: Vec<KeyListSpec>,
+
+    /// Like --drop-if-in, but keep only rows whose key is listed.
+    #[structopt(value_name = "SPEC", long = "keep-if-in", number_of_values = 1)]
+    pub(crate) keep_if_in: Vec<KeyListSpec>,
+
+    /// Filter out any rows where a column doesn't match a regex, written as
+    /// COL=REGEX. If passed more than once, rows must match all of them.
+    #[structopt(
+        value_name = "COL=REGEX",
+        long = "keep-row-if-match",
+        number_of_values = 1
+    )]
+    pub(crate) keep_row_if_match: Vec<ColumnMatch>,
+
+    /// Filter out any rows where EXPR is false, as in `age >= 18 && state !=
+    /// ""`. EXPR can compare columns, strings and numbers using ==, !=, <,
+    /// <=, > and >=, and combine comparisons with &&, ||, ! and
+    /// parentheses. Write column names with unusual characters in
+    /// `backquotes`. Uses the cleaned form of column names and values.
+    #[structopt(value_name = "EXPR", long = "where")]
+    pub(crate) where_expr: Option<WhereExpr>,
+
+    /// Check that columns have the expected types, written as
+    /// COL:TYPE,COL:TYPE. TYPE may be int, decimal, date, bool or string.
+    /// Values are converted to a standard form, such as YYYY-MM-DD for dates
+    /// and "true" or "false" for booleans. Rows with values of the wrong type
+    /// are bad. Empty values are always allowed. Uses the cleaned form of
+    /// column names and values.
+    #[structopt(
+        value_name = "COL:TYPE,...",
+        long = "types",
+        use_delimiter = true,
+        require_delimiter = true
+    )]
+    pub(crate) types: Vec<ColumnType>,
+
+    /// Fail with exit code 3 if fewer than N good data rows were written.
+    #[structopt(value_name = "N", long = "assert-min-rows")]
+    pub(crate) assert_min_rows: Option<u64>,
+
+    /// Fail with exit code 3 if more than N good data rows were written.
+    #[structopt(value_name = "N", long = "assert-max-rows")]
+    pub(crate) assert_max_rows: Option<u64>,
+
+    /// Fail with exit code 4 if cleanup didn't change any rows.
+    #[structopt(long = "fail-if-unchanged")]
+    pub(crate) fail_if_unchanged: bool,
+
+    /// Fail with exit code 4 if cleanup changed more than RATIO of the good
+    /// rows (between 0.0 and 1.0).
+    #[structopt(value_name = "RATIO", long = "fail-if-changed-over")]
+    pub(crate) fail_if_changed_over: Option<f64>,
+
+    /// Write a JSON profile of the output columns to PATH. This includes
+    /// each column's type, number of empty values, approximate number of
+    /// distinct values, value lengths, numeric range and mean, and a few
+    /// sample values.
+    #[structopt(value_name = "PATH", long = "profile", parse(from_os_str))]
+    pub(crate) profile: Option<PathBuf>,
+
+    /// Guess the type, nullability and maximum length of each output column,
+    /// and write them as FORMAT: a "bigquery" JSON schema, a "postgres"
+    /// CREATE TABLE statement, or a "json" schema for --schema. Written next
+    /// to --output unless --emit-schema-path is passed.
+    #[structopt(value_name = "FORMAT", long = "emit-schema")]
+    pub(crate) emit_schema: Option<SchemaFormat>,
+
+    /// Write the schema from --emit-schema to PATH.
+    #[structopt(
+        value_name = "PATH",
+        long = "emit-schema-path",
+        requires = "emit-schema",
+        parse(from_os_str)
+    )]
+    pub(crate) emit_schema_path: Option<PathBuf>,
+
+    /// Include the K most frequent values of each column in the profile.
+    /// For columns with many distinct values, each count is a lower bound,
+    /// and its `error` says how much higher it could be.
+    #[structopt(value_name = "K", long = "top-values", default_value = "0")]
+    pub(crate) top_values: usize,
+
+    /// Include up to N different sample values of each column in the
+    /// profile.
+    #[structopt(value_name = "N", long = "sample-values", default_value = "5")]
+    pub(crate) sample_values: usize,
+
+    /// Compare a profile of this run against a profile previously written
+    /// with --profile, and report any significant drift.
+    #[structopt(value_name = "PATH", long = "baseline-profile", parse(from_os_str))]
+    pub(crate) baseline_profile: Option<PathBuf>,
+
+    /// When using --baseline-profile, report columns whose fraction of empty
+    /// values changed by more than RATIO.
+    #[structopt(
+        value_name = "RATIO",
+        long = "max-null-rate-change",
+        default_value = "0.1"
+    )]
+    pub(crate) max_null_rate_change: f64,
+
+    /// Fail with exit code 5 if --baseline-profile found any drift.
+    #[structopt(long = "fail-on-drift")]
+    pub(crate) fail_on_drift: bool,
+
+    /// Append a column named COL containing the number of non-empty cells in
+    /// each row.
+    #[structopt(value_name = "COL", long = "add-completeness-column")]
+    pub(crate) add_completeness_column: Option<String>,
+
+    /// With --add-completeness-column, output the fraction of cells which are
+    /// non-empty, instead of the count.
+    #[structopt(
+        long = "completeness-as-fraction",
+        requires = "add-completeness-column"
+    )]
+    pub(crate) completeness_as_fraction: bool,
+
+    /// Append a column computed from other columns, written as NAME = EXPR,
+    /// as in `full_name = concat(first_name, " ", last_name)`. EXPR can use
+    /// columns, "strings", numbers, concat(...), substr(s, start, len),
+    /// lower(s), upper(s) and coalesce(...). Can be passed more than once.
+    /// Uses the cleaned form of column names and values.
+    #[structopt(
+        value_name = "NAME = EXPR",
+        long = "add-column",
+        number_of_values = 1
+    )]
+    pub(crate) add_column: Vec<AddColumn>,
+
+    /// Replace a column with several new ones, written as
+    /// 'COL=NEW1,NEW2,... on=SEP' to split on a separator, or as
+    /// 'COL=NEW1,NEW2,... re=REGEX' to split on a regex or, if it has capture
+    /// groups, to use those. Quote SEP or REGEX with "..." if it contains
+    /// spaces. The last new column gets anything left over, and missing
+    /// pieces are left empty. Can be passed more than once. Runs after
+    /// --select, using the cleaned form of column names and values.
+    #[structopt(value_name = "SPEC", long = "split-column", number_of_values = 1)]
+    pub(crate) split_column: Vec<ColumnSplit>,
+
+    /// Join several columns into a new one, written as
+    /// 'NEW=COL1,COL2,... sep=SEP drop'. Empty values are skipped, and SEP
+    /// defaults to a space. With "drop", the new column replaces the columns
+    /// it was made from; otherwise, it's added at the end. Can be passed more
+    /// than once, and runs after --split-column.
+    #[structopt(value_name = "SPEC", long = "merge-columns", number_of_values = 1)]
+    pub(crate) merge_columns: Vec<ColumnMerge>,
+
+    /// Output one row for each value in a column, written as 'COL on=SEP',
+    /// copying the other columns. Empty values are skipped. Can be passed
+    /// more than once, giving every combination of values. Runs after
+    /// --merge-columns, and before any rows are filtered or checked.
+    #[structopt(value_name = "SPEC", long = "explode", number_of_values = 1)]
+    pub(crate) explode: Vec<Explode>,
+
+    /// Turn each row into one row per column, except for the --id-columns,
+    /// which are copied to every row. The other columns are replaced by
+    /// a column containing the name of the original column, and a column
+    /// containing its value. Runs after --merge-columns and before
+    /// --explode.
+    #[structopt(long = "melt")]
+    pub(crate) melt: bool,
+
+    /// With --melt, the columns to copy to every row.
+    #[structopt(
+        value_name = "COL,...",
+        long = "id-columns",
+        use_delimiter = true,
+        require_delimiter = true,
+        requires = "melt"
+    )]
+    pub(crate) id_columns: Vec<String>,
+
+    /// With --melt, the name of the column containing the original column
+    /// names. Defaults to "variable".
+    #[structopt(value_name = "NAME", long = "variable-name", requires = "melt")]
+    pub(crate) variable_name: Option<String>,
+
+    /// With --melt, the name of the column containing the values. Defaults
+    /// to "value".
+    #[structopt(value_name = "NAME", long = "value-name", requires = "melt")]
+    pub(crate) value_name: Option<String>,
+
+    /// Add columns from a CSV file, written as
+    /// 'FILE on=COL add=COL,... miss=POLICY'. Rows are matched using the
+    /// column given by "on", which may be written as COL:LOOKUP_COL if it has
+    /// a different name in FILE. "add" chooses which columns of FILE to add,
+    /// defaulting to all of them. Rows which aren't found are kept with empty
+    /// values ("miss=keep", the default), filtered out ("miss=drop"), or
+    /// rejected ("miss=reject"). FILE is loaded into memory, and added
+    /// columns come after all other added columns. Can be passed more than
+    /// once.
+    #[structopt(value_name = "SPEC", long = "lookup", number_of_values = 1)]
+    pub(crate) lookup: Vec<LookupSpec>,
+
+    /// Append a column named NAME containing VALUE in every row, written as
+    /// NAME=VALUE. Can be passed more than once.
+    #[structopt(
+        value_name = "NAME=VALUE",
+        long = "add-constant-column",
+        number_of_values = 1
+    )]
+    pub(crate) add_constant_column: Vec<ConstantColumn>,
+
+    /// Append a column named NAME containing the number of each data row in
+    /// our input, counting from 1. Bad rows are still counted.
+    #[structopt(value_name = "NAME", long = "add-row-number-column")]
+    pub(crate) add_row_number_column: Option<String>,
+
+    /// Append a column named NAME containing the path of our input file, or
+    /// an empty string when reading standard input.
+    #[structopt(value_name = "NAME", long = "add-source-file-column")]
+    pub(crate) add_source_file_column: Option<String>,
+
+    /// Report how many output rows are exact duplicates of earlier rows,
+    /// without removing them.
+    #[structopt(long = "count-duplicates")]
+    pub(crate) count_duplicates: bool,
+
+    /// With --count-duplicates, also report the most duplicated values of
+    /// this column. Can be passed more than once to use several columns as
+    /// the key. Uses the cleaned form of column names.
+    #[structopt(
+        value_name = "COL",
+        long = "duplicate-key",
+        requires = "count-duplicates",
+        number_of_values = 1
+    )]
+    pub(crate) duplicate_key: Vec<String>,
+
+    /// Drop rows which exactly match an earlier row, after cleaning. Unlike
+    /// `sort -u`, this keeps our rows in their original order.
+    #[structopt(long = "dedup")]
+    pub(crate) dedup: bool,
+
+    /// With --dedup, report how many duplicate rows we dropped.
+    #[structopt(long = "dedup-report", requires = "dedup")]
+    pub(crate) dedup_report: bool,
+
+    /// With --dedup, use at most SIZE bytes (like "512M" or "2G") to
+    /// remember rows. Once remembering each row exactly would take more than
+    /// this, we switch to a Bloom filter, which may wrongly drop a few new
+    /// rows as duplicates. At 10 bits of SIZE per distinct row, this happens
+    /// to about 1% of new rows, and we print an estimate when we're done.
+    #[structopt(value_name = "SIZE", long = "dedup-memory-limit", requires = "dedup")]
+    pub(crate) dedup_memory_limit: Option<ByteSize>,
+
+    /// Keep only one row for each distinct value of these columns, separated
+    /// by commas, and reject the rest. Uses the cleaned form of column names.
+    #[structopt(
+        value_name = "COLS",
+        long = "dedup-by",
+        use_delimiter = true,
+        require_delimiter = true
+    )]
+    pub(crate) dedup_by: Vec<String>,
+
+    /// With --dedup-by, keep the "first" (the default) or "last" row with
+    /// each key. With "last", we can't write any rows until we've read all
+    /// our input, so we hold them in memory up to --memory-limit.
+    #[structopt(value_name = "WHICH", long = "keep", requires = "dedup-by")]
+    pub(crate) keep: Option<Keep>,
+
+    /// Write any rows we reject to this CSV file, as we parsed them, with the
+    /// input's header.
+    #[structopt(value_name = "PATH", long = "bad-rows-path", parse(from_os_str))]
+    pub(crate) bad_rows_path: Option<PathBuf>,
+
+    /// With --bad-rows-path, add "_line" and "_reason" columns to the start
+    /// of each bad row, containing the line it started on and a
+    /// machine-readable reason like "wrong_column_count".
+    #[structopt(long = "annotate-bad-rows", requires = "bad-rows-path")]
+    pub(crate) annotate_bad_rows: bool,
+
+    /// If a record can't be read, report it, count it as a bad row, and
+    /// continue with the next line, instead of failing.
+    #[structopt(long = "skip-unparseable")]
+    pub(crate) skip_unparseable: bool,
+
+    /// Show details about the first N rows with the wrong number of columns
+    /// on standard error (unless --quiet is given).
+    #[structopt(value_name = "N", long = "show-bad-rows", default_value = "3")]
+    pub(crate) show_bad_rows: usize,
+
+    /// Keep reading the input file as it grows, like `tail -f`, and write out
+    /// each new row as soon as we see it. Runs until interrupted.
+    #[structopt(long = "follow")]
+    pub(crate) follow: bool,
+
+    /// Fail with exit code 7 if standard input doesn't send us any data for
+    /// SECS seconds.
+    #[structopt(
+        value_name = "SECS",
+        long = "stdin-timeout",
+        conflicts_with = "inputs"
+    )]
+    pub(crate) stdin_timeout: Option<f64>,
+
+    /// Start our output with a UTF-8 byte order mark, so that Excel displays
+    /// accented characters correctly when the file is double-clicked.
+    #[structopt(long = "excel-friendly")]
+    pub(crate) excel_friendly: bool,
+
+    /// Start our output with a UTF-8 byte order mark. Any byte order mark at
+    /// the start of our input is always removed.
+    #[structopt(long = "write-bom")]
+    pub(crate) write_bom: bool,
+
+    /// With --excel-friendly, also write a line like `sep=,` before the header,
+    /// for versions of Excel which would otherwise guess the delimiter from
+    /// the system locale. Other CSV tools will treat this line as data.
+    #[structopt(long = "excel-sep-line", requires = "excel-friendly")]
+    pub(crate) excel_sep_line: bool,
+
+    /// Size of our input buffers, in bytes, with an optional K or M suffix.
+    /// "auto" picks a size based on whether we're reading from a file, a
+    /// pipe or a socket.
+    #[structopt(value_name = "SIZE", long = "read-buffer", default_value = "256K")]
+    pub(crate) read_buffer: BufferSize,
+
+    /// Size of our output buffer, in bytes, with an optional K or M suffix,
+    /// or "auto".
+    #[structopt(value_name = "SIZE", long = "write-buffer", default_value = "256K")]
+    pub(crate) write_buffer: BufferSize,
+
+    /// Write our output to PATH instead of standard output. We write to a
+    /// temporary file, and rename it to PATH once we're done. If PATH ends in
+    /// ".gz", we compress our output with gzip.
+    #[structopt(
+        value_name = "PATH",
+        short = "o",
+        long = "output",
+        parse(from_os_str)
+    )]
+    pub(crate) output: Option<PathBuf>,
+
+    /// How to name our output files with --split-rows or --split-bytes, like
+    /// "out-{:04}.csv", or with --partition-by, like "out/{value}.csv". "{}"
+    /// is replaced with the file number, starting from 0, and "{:0N}" pads it
+    /// to N digits. "{value}" is replaced with the partition column's value,
+    /// replacing any characters other than ASCII letters, digits, "-", "_"
+    /// and "." with "_". Files ending in ".gz" are gzipped.
+    #[structopt(value_name = "TEMPLATE", long = "output-template")]
+    pub(crate) output_template: Option<OutputTemplate>,
+
+    /// Start a new output file after every N rows. Each file gets a copy of
+    /// our header. Requires --output-template.
+    #[structopt(
+        value_name = "N",
+        long = "split-rows",
+        requires = "output-template",
+        conflicts_with = "output"
+    )]
+    pub(crate) split_rows: Option<u64>,
+
+    /// Start a new output file once the current one reaches SIZE bytes
+    /// before compression, with an optional K, M or G suffix. Each file gets
+    /// a copy of our header. Requires --output-template.
+    #[structopt(
+        value_name = "SIZE",
+        long = "split-bytes",
+        requires = "output-template",
+        conflicts_with = "output"
+    )]
+    pub(crate) split_bytes: Option<ByteSize>,
+
+    /// Write each row to a file named after its value in COL, creating files
+    /// as we need them. Each file gets a copy of our header. Requires
+    /// --output-template.
+    #[structopt(
+        value_name = "COL",
+        long = "partition-by",
+        requires = "output-template",
+        conflicts_with_all = &["output", "split-rows", "split-bytes"]
+    )]
+    pub(crate) partition_by: Option<String>,
+
+    /// With --partition-by, keep at most N output files open at once,
+    /// closing the least recently used ones and reopening them as needed
+    /// (default 128).
+    #[structopt(value_name = "N", long = "max-open-files", requires = "partition-by")]
+    pub(crate) max_open_files: Option<usize>,
+
+    /// Decompress gzipped input. This happens automatically for input which
+    /// starts with the gzip header, or files ending in ".gz". Input
+    /// compressed with zstd, bzip2 or xz is also decompressed automatically.
+    #[structopt(long = "gzip")]
+    pub(crate) gzip: bool,
+
+    /// Read our input and write our output on background threads, so that
+    /// I/O overlaps with parsing and cleaning.
+    #[structopt(long = "io-threads", conflicts_with = "follow")]
+    pub(crate) io_threads: bool,
+
+    /// Clean rows using N worker threads. Output rows stay in order, but
+    /// --bad-rows-path may list rows out of order.
+    #[structopt(value_name = "N", long = "jobs", conflicts_with = "follow")]
+    pub(crate) jobs: Option<usize>,
+
+    /// Don't flush our output after writing the header. This may be slightly
+    /// faster, but consumers won't see the header until we've written more
+    /// data.
+    #[structopt(long = "no-early-flush")]
+    pub(crate) no_early_flush: bool,
+
+    /// When a row needs no cleaning, copy it to our output exactly as we
+    /// read it, without changing its quoting. This only works for
+    /// comma-separated input with standard quoting, and is much faster.
+    #[structopt(long = "preserve-formatting")]
+    pub(crate) preserve_formatting: bool,
+
+    /// Fail with exit code 2 if more than PCT percent of rows are bad. This
+    /// defaults to 10, unless --max-bad-rows-count is passed.
+    #[structopt(value_name = "PCT", long = "max-bad-rows")]
+    pub(crate) max_bad_rows: Option<f64>,
+
+    /// Fail with exit code 2 if more than N rows are bad.
+    #[structopt(value_name = "N", long = "max-bad-rows-count")]
+    pub(crate) max_bad_rows_count: Option<u64>,
+
+    /// Stop with exit code 2 as soon as we find a bad row.
+    #[structopt(long = "fail-fast")]
+    pub(crate) fail_fast: bool,
+
+    /// Write a JSON report on this run to PATH, for use by other programs.
+    #[structopt(value_name = "PATH", long = "report-path", parse(from_os_str))]
+    pub(crate) report_path: Option<PathBuf>,
+
+    /// Print a report on this run to standard error in FORMAT. Only "json" is
+    /// supported.
+    #[structopt(value_name = "FORMAT", long = "report")]
+    pub(crate) report: Option<ReportFormat>,
+
+    /// Do not print performance information.
+    #[structopt(short = "q", long = "quiet")]
+    pub(crate) quiet: bool,
+
+    /// Report how long we spend reading, cleaning and writing, both on
+    /// standard error and in `--report`. This slows us down a bit, because
+    /// we check the clock several times per row.
+    #[structopt(long = "stats")]
+    pub(crate) stats: bool,
+
+    /// Character used to quote entries. May be set to "none" to ignore all
+    /// quoting.
+    #[structopt(value_name = "CHAR", long = "quote", default_value = "\"")]
+    pub(crate) quote: CharSpecifier,
+
+    /// Character used to escape quotes inside quoted entries, like "\" for
+    /// MySQL dumps which write `\"`. By default, quotes can only be escaped
+    /// by doubling them.
+    #[structopt(
+        value_name = "CHAR",
+        long = "escape",
+        conflicts_with = "quote-repair"
+    )]
+    pub(crate) escape: Option<CharSpecifier>,
+
+    /// Should doubled quotes inside quoted entries, like `""`, be treated as
+    /// a single quote? Set this to "false" with --escape if your input never
+    /// doubles quotes.
+    #[structopt(
+        value_name = "BOOL",
+        long = "double-quote",
+        default_value = "true",
+        parse(try_from_str)
+    )]
+    pub(crate) double_quote: bool,
+}
+
+// Our subcommands. (A doc comment here would replace our `--help` text.)
+#[derive(Clone, Debug, StructOpt)]
+pub(crate) enum Command {
+    /// Generate synthetic dirty CSV data on standard output, for benchmarking
+    /// and for reproducing bugs.
+    #[structopt(name = "generate")]
+    Generate(GenerateOpt),
+
+    /// Interactively preview how a file parses, experiment with delimiter,
+    /// quote and cleanup options, and print the matching command line.
+    #[structopt(name = "tui")]
+    Tui(TuiOpt),
+}
@@ -0,0 +1,286 @@
+//! Where we write our output.
+
+use flate2::write::GzEncoder;
+use log::debug;
+use std::{
+    fs,
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+use crate::errors::*;
+
+/// An output stream which needs to be explicitly finished, so that we can
+/// report any errors.
+pub trait FinishWrite: Write {
+    /// Write any remaining data and close the stream.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl FinishWrite for io::Stdout {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl FinishWrite for io::StdoutLock<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Any other writer, like one passed to `Scrubber::scrub`.
+impl FinishWrite for Box<dyn Write + '_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// The file we're actually writing to.
+enum FileWriter {
+    Plain(io::BufWriter<fs::File>),
+    Gzip(GzEncoder<io::BufWriter<fs::File>>),
+}
+
+/// An output file which is written to a temporary file, and then renamed into
+/// place once we're done. If we fail, the temporary file is removed and
+/// nothing is left at our destination.
+pub struct OutputFile {
+    /// Our output, or `None` if we've finished or suspended it.
+    wtr: Option<FileWriter>,
+    /// Have we finished writing?
+    finished: bool,
+    /// Should we gzip our output?
+    gzip: bool,
+    /// The temporary file we're writing.
+    tmp_path: PathBuf,
+    /// Where our output should end up.
+    path: PathBuf,
+}
+
+impl OutputFile {
+    /// Create a new output file, which will be gzipped if `path` ends in
+    /// ".gz".
+    pub fn create(path: &Path) -> Result<OutputFile> {
+        let file_name = path.file_name().ok_or_else(|| {
+            format_err!("output path has no file name: {}", path.display())
+        })?;
+        let mut tmp_name = file_name.to_owned();
+        tmp_name.push(format!(".tmp-{}", process::id()));
+        let tmp_path = path.with_file_name(tmp_name);
+        debug!("writing output to {}", tmp_path.display());
+        let gzip = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+        let wtr = open_writer(&tmp_path, gzip, false)
+            .with_context(|_| format!("cannot create {}", tmp_path.display()))?;
+        Ok(OutputFile {
+            wtr: Some(wtr),
+            finished: false,
+            gzip,
+            tmp_path,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Close our temporary file for now, so that we don't keep too many files
+    /// open. We'll reopen it the next time we write to it. Gzipped output
+    /// will contain one gzip stream for each time we reopen it, which gzip
+    /// readers handle.
+    pub fn suspend(&mut self) -> io::Result<()> {
+        if let Some(wtr) = self.wtr.take() {
+            close_writer(wtr)?;
+        }
+        Ok(())
+    }
+
+    fn wtr(&mut self) -> io::Result<&mut dyn Write> {
+        assert!(!self.finished, "output file already finished");
+        if self.wtr.is_none() {
+            self.wtr = Some(open_writer(&self.tmp_path, self.gzip, true)?);
+        }
+        Ok(match self.wtr.as_mut().expect("should have writer") {
+            FileWriter::Plain(wtr) => wtr,
+            FileWriter::Gzip(wtr) => wtr,
+        })
+    }
+}
+
+/// Open `path` for writing, either truncating it or appending to it.
+fn open_writer(path: &Path, gzip: bool, append: bool) -> io::Result<FileWriter> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    let file = io::BufWriter::new(file);
+    Ok(if gzip {
+        FileWriter::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+    } else {
+        FileWriter::Plain(file)
+    })
+}
+
+/// Finish writing `wtr`, returning the underlying file.
+fn close_writer(wtr: FileWriter) -> io::Result<fs::File> {
+    let buffered = match wtr {
+        FileWriter::Plain(wtr) => wtr,
+        FileWriter::Gzip(wtr) => wtr.finish()?,
+    };
+    buffered.into_inner().map_err(|err| err.into_error())
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wtr()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wtr()?.flush()
+    }
+}
+
+impl FinishWrite for OutputFile {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        assert!(!self.finished, "output file already finished");
+        let result = (|| {
+            let file = match self.wtr.take() {
+                Some(wtr) => close_writer(wtr)?,
+                None => fs::File::open(&self.tmp_path)?,
+            };
+            file.sync_all()?;
+            fs::rename(&self.tmp_path, &self.path)
+        })();
+        self.finished = true;
+        if result.is_err() {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+        result
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        // If we were never finished, don't leave a partial file behind.
+        if !self.finished {
+            debug!("removing incomplete {}", self.tmp_path.display());
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// A pattern for naming a series of output files, like "out-{:04}.csv" or
+/// "out/{value}.csv".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputTemplate {
+    /// The text before our placeholder.
+    before: String,
+    /// What to replace our placeholder with.
+    placeholder: Placeholder,
+    /// The text after our placeholder.
+    after: String,
+}
+
+/// The part of an `OutputTemplate` which changes from file to file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    /// A file number, padded to `width` digits.
+    Number { width: usize },
+    /// A column value.
+    Value,
+}
+
+impl OutputTemplate {
+    /// Does this template use a file number, instead of a column value?
+    pub fn is_numbered(&self) -> bool {
+        matches!(self.placeholder, Placeholder::Number { .. })
+    }
+
+    /// The path of file number `number`.
+    pub fn path(&self, number: u64) -> PathBuf {
+        let width = match self.placeholder {
+            Placeholder::Number { width } => width,
+            Placeholder::Value => panic!("output template needs a value"),
+        };
+        let name = format!("{}{:0width$}{}", self.before, number, self.after);
+        PathBuf::from(name)
+    }
+
+    /// The path of the file for the column value `value`. To keep this
+    /// inside our output directory, we replace any characters other than
+    /// ASCII letters, digits, "-", "_" and "." with "_", as well as any
+    /// leading ".". An empty value becomes "_".
+    pub fn value_path(&self, value: &[u8]) -> PathBuf {
+        assert_eq!(self.placeholder, Placeholder::Value);
+        let mut name = String::from_utf8_lossy(value)
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        if name.is_empty() || name.starts_with('.') {
+            name.replace_range(..name.len().min(1), "_");
+        }
+        PathBuf::from(format!("{}{}{}", self.before, name, self.after))
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputTemplate> {
+        let missing = || {
+            format_err!("output template needs {{}}, {{:0N}} or {{value}}: {:?}", s)
+        };
+        let start = s.find('{').ok_or_else(missing)?;
+        let end = start + s[start..].find('}').ok_or_else(missing)?;
+        let placeholder = match &s[start + 1..end] {
+            "" => Placeholder::Number { width: 0 },
+            "value" => Placeholder::Value,
+            spec => spec
+                .strip_prefix(":0")
+                .and_then(|digits| digits.parse::<usize>().ok())
+                .map(|width| Placeholder::Number { width })
+                .ok_or_else(|| {
+                    format_err!("cannot parse {{{}}} in output template", spec)
+                })?,
+        };
+        let (before, after) = (&s[..start], &s[end + 1..]);
+        if after.contains(['{', '}']) || before.contains('}') {
+            return Err(format_err!("output template has extra braces: {:?}", s));
+        }
+        Ok(OutputTemplate {
+            before: before.to_owned(),
+            placeholder,
+            after: after.to_owned(),
+        })
+    }
+}
+
+#[test]
+fn parses_output_templates() {
+    let template = "out/part-{:04}.csv.gz".parse::<OutputTemplate>().unwrap();
+    assert!(template.is_numbered());
+    assert_eq!(template.path(7), PathBuf::from("out/part-0007.csv.gz"));
+    assert_eq!(template.path(12345), PathBuf::from("out/part-12345.csv.gz"));
+    let template = "{}.csv".parse::<OutputTemplate>().unwrap();
+    assert_eq!(template.path(3), PathBuf::from("3.csv"));
+    let template = "out/{value}.csv".parse::<OutputTemplate>().unwrap();
+    assert!(!template.is_numbered());
+    assert_eq!(template.value_path(b"US"), PathBuf::from("out/US.csv"));
+    assert_eq!(
+        template.value_path(b"../x y"),
+        PathBuf::from("out/_._x_y.csv")
+    );
+    assert_eq!(template.value_path(b""), PathBuf::from("out/_.csv"));
+    for bad in &["out.csv", "out-{:4}.csv", "out-{}-{}.csv", "out-{.csv"] {
+        assert!(bad.parse::<OutputTemplate>().is_err(), "{}", bad);
+    }
+}
@@ -0,0 +1,58 @@
+//! Repairing rows which have more fields than the header.
+
+use csv::ByteRecord;
+
+/// How to repair a row with too many fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowRepair {
+    /// Discard any extra fields at the end of the row.
+    Truncate,
+    /// Join the last column and any extra fields using `delimiter`, on the
+    /// theory that somebody forgot to quote a value containing delimiters.
+    MergeIntoLast { delimiter: u8 },
+}
+
+impl OverflowRepair {
+    /// Return a copy of `record` with exactly `expected_cols` fields. We
+    /// should only be called when `record` has more fields than that.
+    pub fn repair(self, record: &ByteRecord, expected_cols: usize) -> ByteRecord {
+        debug_assert!(record.len() > expected_cols);
+        if expected_cols == 0 {
+            return ByteRecord::new();
+        }
+        let mut repaired =
+            ByteRecord::with_capacity(record.as_slice().len(), expected_cols);
+        for field in record.iter().take(expected_cols - 1) {
+            repaired.push_field(field);
+        }
+        match self {
+            OverflowRepair::Truncate => {
+                repaired.push_field(&record[expected_cols - 1])
+            }
+            OverflowRepair::MergeIntoLast { delimiter } => {
+                let mut last = vec![];
+                for (i, field) in record.iter().skip(expected_cols - 1).enumerate() {
+                    if i > 0 {
+                        last.push(delimiter);
+                    }
+                    last.extend_from_slice(field);
+                }
+                repaired.push_field(&last);
+            }
+        }
+        repaired
+    }
+}
+
+#[test]
+fn repairs_overlong_rows() {
+    let record = ByteRecord::from(vec!["a", "b", "c", "d"]);
+    assert_eq!(
+        OverflowRepair::Truncate.repair(&record, 2),
+        ByteRecord::from(vec!["a", "b"]),
+    );
+    assert_eq!(
+        OverflowRepair::MergeIntoLast { delimiter: b';' }.repair(&record, 2),
+        ByteRecord::from(vec!["a", "b;c;d"]),
+    );
+}
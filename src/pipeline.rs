@@ -0,0 +1,2441 @@
+//! Reading, cleaning and writing our rows.
+
+use csv::ByteRecord;
+use humansize::{file_size_opts, FileSize};
+use log::debug;
+use regex::bytes::Regex;
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    fs,
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+
+use crate::add_columns::{AddColumn, AddedColumns};
+use crate::bad_rows::{BadRowReason, BadRowWriter};
+use crate::bare_quotes::{BareQuotePolicy, BareQuoteReader, BareQuotes};
+use crate::buffers::StreamKind;
+use crate::cleaner::{CellCleaner, CleanHits, LongCellPolicy};
+use crate::cleanup::{null_regex, BadRowPolicy};
+use crate::columns::{self, ColumnLookupError};
+use crate::comments::{CommentLineSkipper, CommentSink};
+use crate::compression::Compression;
+use crate::dedup::{Deduplicator, Keep, KeyCheck, KeyDeduplicator};
+use crate::diagnostics::BadRowDiagnostics;
+use crate::duplicates::DuplicateCounter;
+use crate::empty_columns::EmptyColumnDropper;
+use crate::encoding::{InvalidUtf8, UTF8_BOM};
+use crate::errors::*;
+use crate::explode::Exploder;
+use crate::filter::RowFilter;
+use crate::follow::{self, FollowReader};
+use crate::header_map::HeaderMap;
+use crate::inputs::{expand_globs, union_headers, InputSettings, UnionProjection};
+use crate::jobs::{BatchRow, ParallelCleaner, BATCH_ROWS};
+use crate::leading_lines::LeadingLineSkipper;
+use crate::long_delimiters::{DelimiterTranslatingReader, SEQUENCE_DELIMITER};
+use crate::lookup::{KeyFilter, Lookup, OnMiss};
+use crate::melt::Melter;
+use crate::merge_columns::ColumnMerger;
+use crate::merge_delimiters::DelimiterMergingReader;
+use crate::numbers::NumberNormalizer;
+use crate::options::Opt;
+use crate::output::{FinishWrite, OutputFile};
+use crate::overflow::OverflowRepair;
+use crate::profile::{Profile, Profiler};
+use crate::quote_repair::QuoteRepairReader;
+use crate::quoting::{
+    write_record_quoting_edge_whitespace, OutputFormat, SharedOutput,
+};
+use crate::raw::{RawRecorder, RawRecords, RawWriter};
+use crate::recover::RunawayQuoteRecovery;
+use crate::rename::{apply_renames, read_renames};
+use crate::replace::Replacer;
+use crate::report::{Report, ReportFormat, RuleReport};
+use crate::schema::{check_columns, OnSchemaChange, Schema};
+use crate::scrubber::{ScrubError, ScrubStats};
+use crate::skip::{SkipUnparseableReader, SkippedErrors};
+use crate::sniff::{strip_prologue, Sample};
+use crate::sort::{
+    check_sort_keys, ExternalSorter, OnUnsorted, SortChecker, SortedMerge,
+    DEFAULT_SORT_MEMORY,
+};
+use crate::split::{SplitBy, Splitter, DEFAULT_MAX_OPEN_FILES};
+use crate::split_columns::ColumnSplitter;
+use crate::stats::{RuleHits, Stage, StageTimes};
+use crate::threads::{ThreadedReader, ThreadedWriter};
+use crate::timeout::TimeoutReader;
+use crate::trailing_lines::TrailingLineSkipper;
+use crate::types::{ColumnType, TypeCoercer};
+use crate::uniquifier::Uniquifier;
+use crate::util::{
+    compose_projection, copy_record, find_column, find_column_loosely, now,
+    project_record, select_columns, ByteSize, CharSpecifier, DelimiterSpecifier,
+};
+use crate::validate::Validator;
+use crate::window::{Admit, RowWindow};
+
+/// An input file, ready for us to read records from.
+struct Input<'a> {
+    /// Our CSV reader.
+    rdr: csv::Reader<Box<dyn Read + 'a>>,
+    /// The delimiter we're using, which we may have guessed.
+    delimiter: u8,
+    /// The quote character we're using, if any.
+    quote: Option<u8>,
+    /// How many stray quotes we've repaired, with `--quote-repair`.
+    quote_repairs: Option<Rc<Cell<u64>>>,
+    /// Where we've found bare quotes, with `--bare-quote-policy`.
+    bare_quotes: Option<Rc<BareQuotes>>,
+    /// Input we couldn't parse, with `--skip-unparseable`.
+    skipped_errors: Option<SkippedErrors>,
+    /// Our raw input, with `--preserve-formatting`.
+    raw_records: Option<RawRecords>,
+}
+
+/// What we've read from input files we've finished with.
+#[derive(Debug, Default)]
+struct InputTotals {
+    bytes: u64,
+    quote_repairs: u64,
+    bare_quotes: u64,
+}
+
+impl InputTotals {
+    /// Add what we've read from `input`.
+    fn add(&mut self, input: &Input<'_>) {
+        self.bytes += input.rdr.position().byte();
+        if let Some(quote_repairs) = &input.quote_repairs {
+            self.quote_repairs += quote_repairs.get();
+        }
+        if let Some(bare_quotes) = &input.bare_quotes {
+            self.bare_quotes += bare_quotes.count.get();
+        }
+    }
+}
+
+/// Open `path`, or `reader`, or standard input if we have neither, and set up
+/// everything we need to read CSV records from it. Any of its own `settings`
+/// take the place of our options. If we already know which `delimiter` to
+/// use, we use that instead of `--delimiter`. Any comments we skip are copied
+/// to `comments`.
+fn open_input<'a>(
+    opt: &Opt,
+    path: Option<&Path>,
+    reader: Option<Box<dyn Read + 'a>>,
+    settings: &InputSettings,
+    delimiter: Option<u8>,
+    comments: Option<&CommentSink>,
+) -> Result<Input<'a>> {
+    let delimiter_spec = settings.delimiter.as_ref().unwrap_or(&opt.delimiter);
+    let quote = settings.quote.as_ref().unwrap_or(&opt.quote).char();
+    let input_encoding = settings.encoding.or(opt.input_encoding);
+    // A file with its own delimiter doesn't need to match our first file.
+    let delimiter = delimiter.filter(|_| settings.delimiter.is_none());
+
+    // Fetch our input from either standard input or a file.  The only tricky
+    // detail here is that we use a `Box<dyn Read>` to represent "some object
+    // implementing `Read`, stored on the heap."  This allows us to do runtime
+    // dispatch (as if Rust were object oriented).  But because `csv` wraps a
+    // `BufReader` around the box, we only do that dispatch once per buffer
+    // flush, not on every tiny write.
+    //
+    // We decompress any input which looks compressed. With `--io-threads`,
+    // we read and decompress on a background thread, which needs input that
+    // it can own. We can't do either with a `reader` we were given, because
+    // it might not be safe to send to another thread.
+    let compression = Compression::for_input(opt.gzip, path);
+    let in_background = |rdr: Box<dyn Read + Send>, kind| -> Box<dyn Read> {
+        if opt.io_threads {
+            Box::new(ThreadedReader::new(rdr, opt.read_buffer.bytes_for(kind)))
+        } else {
+            rdr
+        }
+    };
+    let (mut input, input_kind): (Box<dyn Read + 'a>, _) = if let Some(path) = path {
+        let file = fs::File::open(path)
+            .with_context(|_| format!("cannot open {}", path.display()))?;
+        let kind = StreamKind::of_file(&file);
+        (in_background(compression.decompress(file), kind), kind)
+    } else if let Some(reader) = reader {
+        (reader, StreamKind::Unknown)
+    } else if let Some(secs) = opt.stdin_timeout {
+        // This already reads on a background thread.
+        let rdr = TimeoutReader::new(
+            compression.decompress(io::stdin()),
+            Duration::from_secs_f64(secs),
+        );
+        (Box::new(rdr) as Box<dyn Read>, StreamKind::of_stdin())
+    } else {
+        let kind = StreamKind::of_stdin();
+        (
+            in_background(compression.decompress(io::stdin()), kind),
+            kind,
+        )
+    };
+    let read_buffer = opt.read_buffer.bytes_for(input_kind);
+    debug!(
+        "input is {:?}, using {} byte buffers",
+        input_kind, read_buffer
+    );
+
+    // Convert our input to UTF-8, if we were asked to. Everything after
+    // this point only needs to understand ASCII-compatible encodings. The
+    // decoder removes any byte order mark for us.
+    if let Some(encoding) = input_encoding {
+        input = encoding.decode(input);
+    }
+
+    // Remove any byte order mark and Excel `sep=` line, which come before
+    // everything else.
+    let (sep_line_delimiter, rest) = strip_prologue(input, input_encoding.is_none())?;
+    input = rest;
+    if let Some(sep) = sep_line_delimiter {
+        debug!("found sep= line with delimiter {:?}", char::from(sep));
+    }
+
+    // If we were asked to skip junk before our header, do that before anybody
+    // else looks at our input.
+    if opt.skip_lines > 0 || opt.skip_lines_matching.is_some() {
+        let pattern = opt
+            .skip_lines_matching
+            .as_ref()
+            .map(|re| Regex::new(re).context("can't compile regular expression"))
+            .transpose()?;
+        input = Box::new(LeadingLineSkipper::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            opt.skip_lines,
+            pattern,
+        ));
+    }
+    if opt.skip_footer.is_some() || opt.drop_trailer_matching.is_some() {
+        let pattern = opt
+            .drop_trailer_matching
+            .as_ref()
+            .map(|re| Regex::new(re).context("can't compile regular expression"))
+            .transpose()?;
+        input = Box::new(TrailingLineSkipper::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            opt.skip_footer.unwrap_or(0),
+            pattern,
+        ));
+    }
+
+    // Skip any comments, wherever they are.
+    if let Some(comment) = opt.comment_char.as_ref().and_then(CharSpecifier::char) {
+        input = Box::new(CommentLineSkipper::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            comment,
+            quote,
+            comments.cloned(),
+        ));
+    }
+
+    // If we need to guess anything about our input, read a sample from the
+    // beginning.
+    let sample = if let (DelimiterSpecifier::Auto, None, None) =
+        (delimiter_spec, delimiter, sep_line_delimiter)
+    {
+        let (sample, rest) =
+            Sample::read(input, opt.detect_sample_bytes, opt.detect_sample_rows)?;
+        input = rest;
+        Some(sample)
+    } else {
+        None
+    };
+
+    // If we're following a growing file, never stop reading.
+    if opt.follow {
+        if path.is_none() {
+            return Err(format_err!("--follow requires an input file"));
+        }
+        input = Box::new(FollowReader::new(input, follow::POLL_INTERVAL));
+    }
+
+    // Create our CSV reader.
+    let mut rdr_builder = csv::ReaderBuilder::new();
+    // Set a reasonable buffer size.
+    rdr_builder.buffer_capacity(read_buffer);
+    // We need headers so that we can honor --drop-row-if-null. If our input
+    // doesn't have any, we make some up below.
+    rdr_builder.has_headers(!opt.no_headers);
+    // Allow records with the wrong number of columns.
+    rdr_builder.flexible(true);
+    // Configure how our records end.
+    if let Some(terminator) = &opt.record_terminator {
+        let terminator = terminator
+            .char()
+            .ok_or_else(|| format_err!("--record-terminator cannot be \"none\""))?;
+        rdr_builder.terminator(csv::Terminator::Any(terminator));
+    }
+    // Configure our delimiter.
+    let delimiter = match (delimiter_spec, &sample) {
+        _ if delimiter.is_some() => delimiter.expect("checked above"),
+        (DelimiterSpecifier::Auto, _) if sep_line_delimiter.is_some() => {
+            sep_line_delimiter.expect("checked above")
+        }
+        (DelimiterSpecifier::Char(c), _)
+            if c.char() == Some(b',') && sep_line_delimiter.is_some() =>
+        {
+            sep_line_delimiter.expect("checked above")
+        }
+        (DelimiterSpecifier::Char(c), _) => c
+            .char()
+            .ok_or_else(|| format_err!("field delimiter is required"))?,
+        (DelimiterSpecifier::Auto, Some(sample)) => {
+            let delimiter = sample.guess_delimiter(quote);
+            debug!("guessed delimiter {:?}", char::from(delimiter));
+            delimiter
+        }
+        (DelimiterSpecifier::Auto, None) => {
+            unreachable!("should have sampled input to guess delimiter")
+        }
+        // Outside of quotes, tabs can only be separators, so we turn each run
+        // of whitespace into a single tab below.
+        (DelimiterSpecifier::Whitespace, _) => b'\t',
+        // We replace long delimiters with a single byte below.
+        (DelimiterSpecifier::Sequence(_), _) => SEQUENCE_DELIMITER,
+    };
+    rdr_builder.delimiter(delimiter);
+
+    // If our delimiter is too long for the CSV parser, replace it.
+    if let DelimiterSpecifier::Sequence(sequence) = delimiter_spec {
+        input = Box::new(DelimiterTranslatingReader::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            sequence,
+            delimiter,
+            quote,
+        ));
+    }
+
+    // If we were asked to merge repeated delimiters, do it before the CSV
+    // parser sees them.
+    if let DelimiterSpecifier::Whitespace = delimiter_spec {
+        input = Box::new(DelimiterMergingReader::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            b" \t",
+            delimiter,
+            quote,
+        ));
+    } else if opt.merge_delimiters {
+        input = Box::new(DelimiterMergingReader::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            &[delimiter],
+            delimiter,
+            quote,
+        ));
+    }
+
+    // If we were asked to repair stray quotes, do it before the CSV parser
+    // sees them.
+    let mut quote_repairs = None;
+    if let (Some(strategy), Some(quote)) = (opt.quote_repair, quote) {
+        let (repairer, repairs) = QuoteRepairReader::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            strategy,
+            delimiter,
+            quote,
+        );
+        input = Box::new(repairer);
+        quote_repairs = Some(repairs);
+    }
+    // If we were asked to handle quotes outside of quoted fields, look for
+    // those, too.
+    let mut bare_quotes = None;
+    if let (BareQuotePolicy::Strip | BareQuotePolicy::Reject, Some(quote)) =
+        (opt.bare_quote_policy, quote)
+    {
+        let (rdr, found) = BareQuoteReader::new(
+            io::BufReader::with_capacity(read_buffer, input),
+            opt.bare_quote_policy,
+            delimiter,
+            quote,
+        );
+        input = Box::new(rdr);
+        bare_quotes = Some(found);
+    }
+    // Configure our quote character.
+    if let Some(quote) = quote {
+        rdr_builder.quote(quote);
+    } else {
+        rdr_builder.quoting(false);
+    }
+    // Configure how quotes are escaped inside quoted values.
+    rdr_builder.escape(opt.escape.as_ref().and_then(CharSpecifier::char));
+    rdr_builder.double_quote(opt.double_quote);
+
+    // If we were asked to skip over input we can't read, set that up. This
+    // needs to be the last wrapper before our CSV reader, so that it agrees
+    // with the reader about byte offsets.
+    let mut skipped_errors = None;
+    if opt.skip_unparseable {
+        let (skipper, errors) = SkipUnparseableReader::new(input, quote);
+        input = Box::new(skipper);
+        skipped_errors = Some(errors);
+    }
+    // With `--preserve-formatting`, keep a copy of our raw input. This needs
+    // to agree with our CSV reader about byte offsets, too.
+    let mut raw_records = None;
+    if opt.preserve_formatting {
+        let (recorder, records) = RawRecorder::new(input);
+        input = Box::new(recorder);
+        raw_records = Some(records);
+    }
+    Ok(Input {
+        rdr: rdr_builder.from_reader(input),
+        delimiter,
+        quote,
+        quote_repairs,
+        bare_quotes,
+        skipped_errors,
+        raw_records,
+    })
+}
+
+/// Where we read our rows from.
+pub enum Source<'a> {
+    /// The input files in our options, or standard input if there aren't
+    /// any.
+    Inputs,
+    /// A reader we were given, instead of any input files.
+    Reader(Box<dyn Read + 'a>),
+}
+
+/// Where we write our rows.
+pub enum Sink<'a> {
+    /// `--output`, the files we split our output into, or standard output.
+    Output,
+    /// A writer we were given, instead of `--output`.
+    Writer(Box<dyn Write + 'a>),
+}
+
+/// Read rows from `source`, clean them as `opt` asks, and write them to
+/// `sink`. If we're given a `null_re`, we use it instead of `--null`.
+///
+/// If one of our checks fails, we return a `ScrubError` describing it, without
+/// finishing our output, so that we never replace an existing output file.
+pub fn run<'a>(
+    opt: &Opt,
+    null_re: Option<&Regex>,
+    source: Source<'a>,
+    sink: Sink<'a>,
+) -> Result<ScrubStats> {
+    // Remember the time we started.
+    let start_time = now();
+
+    // Build a regex containing our `--null` value.
+    let null_re = match (null_re, &opt.null) {
+        (Some(null_re), _) => Some(null_re.clone()),
+        (None, Some(null_re_str)) => {
+            Some(null_regex(null_re_str).context("can't compile regular expression")?)
+        }
+        (None, None) => None,
+    };
+
+    // Expand any globs in our input paths, and open our first input. We open
+    // any others once we've finished reading it.
+    let (reader, paths) = match source {
+        Source::Inputs => (None, &opt.inputs[..]),
+        Source::Reader(reader) => (Some(reader), &[][..]),
+    };
+    let mut inputs = vec![];
+    let mut input_settings = vec![];
+    for path in paths {
+        let (path, settings) = InputSettings::split_path(path)?;
+        for path in expand_globs(&[path])? {
+            inputs.push(path);
+            input_settings.push(settings.clone());
+        }
+    }
+    if opt.follow && inputs.len() > 1 {
+        return Err(format_err!("--follow only works with a single input file"));
+    }
+    let comments = opt
+        .comments_path
+        .as_ref()
+        .map(|path| -> Result<CommentSink> {
+            let file = fs::File::create(path)
+                .with_context(|_| format!("cannot create {}", path.display()))?;
+            Ok(Rc::new(RefCell::new(io::BufWriter::new(file))))
+        })
+        .transpose()?;
+    let mut input = open_input(
+        opt,
+        inputs.first().map(PathBuf::as_path),
+        reader,
+        input_settings.first().unwrap_or(&InputSettings::default()),
+        None,
+        comments.as_ref(),
+    )?;
+    let delimiter = input.delimiter;
+    let mut remaining_inputs = inputs.iter().zip(&input_settings).skip(1);
+
+    // Write to our output file, if we have one, or to `stdout`. We lock
+    // `stdout`, giving us exclusive access. In the past, this has made an
+    // enormous difference in performance. With `--io-threads`, we write on a
+    // background thread instead.
+    //
+    // With `--split-rows`, `--split-bytes` or `--partition-by`, we write to
+    // a series of files, always on this thread.
+    let stdout = io::stdout();
+    let split_by = if let Some(col) = &opt.partition_by {
+        if opt
+            .output_template
+            .as_ref()
+            .is_some_and(|t| t.is_numbered())
+        {
+            return Err(format_err!(
+                "--partition-by {} needs {{value}} in --output-template",
+                col
+            ));
+        }
+        Some(SplitBy::Value {
+            max_open_files: opt.max_open_files.unwrap_or(DEFAULT_MAX_OPEN_FILES),
+        })
+    } else if opt.split_rows.is_some() || opt.split_bytes.is_some() {
+        if opt
+            .output_template
+            .as_ref()
+            .is_some_and(|t| !t.is_numbered())
+        {
+            return Err(format_err!("--split-rows and --split-bytes need {{}} or {{:0N}} in --output-template"));
+        }
+        Some(SplitBy::Size {
+            max_rows: opt.split_rows,
+            max_bytes: opt.split_bytes.map(|ByteSize(bytes)| bytes as u64),
+        })
+    } else if opt.output_template.is_some() {
+        return Err(format_err!(
+            "--output-template needs --split-rows, --split-bytes or --partition-by"
+        ));
+    } else {
+        None
+    };
+    let (output_kind, output_file) = match (&sink, &opt.output) {
+        (Sink::Writer(_), _) if split_by.is_some() => {
+            return Err(format_err!("cannot split output written to a writer"));
+        }
+        (Sink::Writer(_), _) => (StreamKind::Unknown, None),
+        (Sink::Output, Some(path)) => {
+            (StreamKind::File, Some(OutputFile::create(path)?))
+        }
+        (Sink::Output, None) if split_by.is_some() => (StreamKind::File, None),
+        (Sink::Output, None) => (StreamKind::of_stdout(), None),
+    };
+    let write_buffer = opt.write_buffer.bytes_for(output_kind);
+    let mut splitter = None;
+    let mut output: Box<dyn FinishWrite + 'a> =
+        match (sink, output_file, opt.io_threads) {
+            // Like our readers, we can't write to a writer we were given on
+            // another thread.
+            (Sink::Writer(wtr), _, _) => Box::new(wtr),
+            (Sink::Output, None, _) if split_by.is_some() => {
+                let template = opt
+                    .output_template
+                    .clone()
+                    .expect("splitting should require --output-template");
+                let (split_splitter, writer) =
+                    Splitter::new(template, split_by.expect("checked above"))?;
+                splitter = Some(split_splitter);
+                Box::new(writer)
+            }
+            (Sink::Output, Some(file), true) => {
+                Box::new(ThreadedWriter::new(file, write_buffer))
+            }
+            (Sink::Output, Some(file), false) => Box::new(file),
+            (Sink::Output, None, true) => {
+                Box::new(ThreadedWriter::new(io::stdout(), write_buffer))
+            }
+            (Sink::Output, None, false) => Box::new(stdout.lock()),
+        };
+
+    // Decide how to format our output.
+    let output_format = OutputFormat {
+        escape: opt.output_escape,
+        delimiter: match &opt.out_delimiter {
+            Some(delimiter) => delimiter
+                .char()
+                .ok_or_else(|| format_err!("--out-delimiter cannot be \"none\""))?,
+            None => b',',
+        },
+        crlf: opt.out_crlf,
+    };
+
+    // If a human is going to open our output in Excel, tell it what encoding
+    // and delimiter we're using.
+    if opt.excel_friendly || opt.write_bom {
+        output
+            .write_all(UTF8_BOM)
+            .context("cannot write byte order mark")?;
+    }
+    if opt.excel_sep_line {
+        output
+            .write_all(b"sep=")
+            .and_then(|()| output.write_all(&[output_format.delimiter]))
+            .and_then(|()| output.write_all(b"\r\n"))
+            .context("cannot write sep= line")?;
+    }
+
+    // If we need to drop empty columns, we can't write anything until we've
+    // seen every row.
+    if opt.drop_empty_columns {
+        output = Box::new(
+            EmptyColumnDropper::new(
+                output,
+                !opt.no_headers || opt.add_header,
+                output_format,
+                opt.quote_leading_whitespace,
+            )
+            .context("cannot create spool file")?,
+        );
+    }
+
+    // Likewise if we need to sort our output. We sort before dropping empty
+    // columns, so that we can sort by any column.
+    if !opt.sort_by.is_empty() {
+        if opt.no_headers && !opt.add_header {
+            return Err(format_err!("--sort-by needs a header, like --add-header"));
+        }
+        output = Box::new(
+            ExternalSorter::new(
+                output,
+                opt.sort_by.clone(),
+                opt.sort_memory
+                    .or(opt.memory_limit)
+                    .map_or(DEFAULT_SORT_MEMORY, |ByteSize(bytes)| bytes),
+                output_format,
+                opt.quote_leading_whitespace,
+            )
+            .context("cannot create spool file")?,
+        );
+    }
+
+    // Create our CSV writer.  Note that we _don't_ allow variable numbers
+    // of columns or other nonsense: We want our output to be highly
+    // normalized, with only the delimiter and line endings configurable.
+    //
+    // We share our output with `write_record_quoting_edge_whitespace`, which
+    // sometimes needs to bypass `wtr`.
+    let mut shared_output = SharedOutput::new(output);
+    let mut wtr_builder = csv::WriterBuilder::new();
+    wtr_builder.buffer_capacity(write_buffer);
+    output_format.configure(&mut wtr_builder);
+    let mut wtr = wtr_builder.from_writer(shared_output.clone());
+
+    // Get our header and, if we were asked, make sure all the column names are unique.
+    let first_hdr = input
+        .rdr
+        .byte_headers()
+        .context("cannot read headers")?
+        .to_owned();
+    // With `--union-columns`, read the headers of all our inputs up front, so
+    // that we know every column we'll output.
+    let union_hdr = if opt.union_columns {
+        let mut hdrs = vec![first_hdr.clone()];
+        for (path, settings) in inputs.iter().zip(&input_settings).skip(1) {
+            let mut rdr =
+                open_input(opt, Some(path), None, settings, Some(delimiter), None)?
+                    .rdr;
+            let hdr = rdr.byte_headers().with_context(|_| {
+                format!("cannot read headers of {}", path.display())
+            })?;
+            hdrs.push(hdr.to_owned());
+        }
+        Some(union_headers(&hdrs))
+    } else {
+        None
+    };
+    let mut union_projection = union_hdr
+        .as_ref()
+        .and_then(|union_hdr| UnionProjection::new(union_hdr, &first_hdr));
+    let input_hdr = union_hdr.clone().unwrap_or_else(|| first_hdr.clone());
+    // If our input has no header, its first row is data, so make up names
+    // for its columns.
+    let mut hdr = if opt.no_headers {
+        (1..=input_hdr.len())
+            .map(|i| format!("c{}", i))
+            .collect::<Vec<_>>()
+            .into()
+    } else {
+        input_hdr.clone()
+    };
+
+    // If we were asked to save our bad rows, set that up.
+    let mut bad_row_output = opt
+        .bad_rows_path
+        .as_ref()
+        .map(|path| BadRowWriter::create(path, &hdr, opt.annotate_bad_rows))
+        .transpose()?;
+
+    // If every line ends with a delimiter, our header will have an extra
+    // empty column at the end. If we were asked to, get rid of it.
+    let trailing_delimiter = opt.allow_trailing_delimiter
+        && input_hdr.len() > 1
+        && input_hdr.get(input_hdr.len() - 1) == Some(&b""[..]);
+    if trailing_delimiter {
+        hdr.truncate(hdr.len() - 1);
+    }
+
+    // With `--merge-sorted-by`, open all our inputs now, so that we can read
+    // from whichever one has the next row.
+    let mut sorted_merge = if !opt.merge_sorted_by.is_empty() {
+        let others = remaining_inputs
+            .by_ref()
+            .map(|(path, settings)| {
+                let mut other = open_input(
+                    opt,
+                    Some(path),
+                    None,
+                    settings,
+                    Some(delimiter),
+                    comments.as_ref(),
+                )?;
+                let other_hdr = other.rdr.byte_headers().with_context(|_| {
+                    format!("cannot read headers of {}", path.display())
+                })?;
+                if !opt.no_headers && other_hdr != &first_hdr {
+                    return Err(format_err!(
+                        "columns of {} do not match {}",
+                        path.display(),
+                        inputs[0].display()
+                    ));
+                }
+                Ok(other)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Some(SortedMerge::new(
+            &hdr,
+            &opt.merge_sorted_by,
+            others,
+            |input: &mut Input, record| input.rdr.read_byte_record(record),
+        )?)
+    } else {
+        None
+    };
+
+    // Apply any renames before we clean our column names.
+    let mut renames = opt.rename.clone();
+    if let Some(path) = &opt.rename_file {
+        renames.extend(read_renames(path)?);
+    }
+    if !renames.is_empty() {
+        hdr = apply_renames(&hdr, &renames);
+    }
+
+    let original_hdr = hdr.clone();
+    if let Some(path) = &opt.apply_header_map {
+        hdr = HeaderMap::read(path)?.apply(&hdr)?;
+    } else if opt.clean_column_names {
+        let mut uniquifier = Uniquifier::default();
+        let mut new_hdr = ByteRecord::default();
+        for col in hdr.into_iter() {
+            // Convert from bytes to UTF-8, make unique (and clean), and convert back to bytes.
+            let col = String::from_utf8_lossy(col);
+            let col = uniquifier.unique_id_for(&col)?.to_owned();
+            new_hdr.push_field(col.as_bytes());
+        }
+        hdr = new_hdr;
+    }
+    if let Some(path) = &opt.write_header_map {
+        HeaderMap::new(&original_hdr, &hdr).write(path)?;
+    }
+
+    // Make sure our input has the columns we were told to expect.
+    if !opt.expect_columns.is_empty() || !opt.require_columns.is_empty() {
+        let names = hdr
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect::<Vec<_>>();
+        let problems =
+            check_columns(&names, &opt.expect_columns, &opt.require_columns);
+        if !problems.is_empty() {
+            let message = problems
+                .iter()
+                .map(|problem| format!("Column check failed: {}", problem))
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(Box::new(ScrubError::UnexpectedColumns(message)));
+        }
+    }
+
+    // Calculate the number of expected columns, both before and after we
+    // strip any trailing delimiter.
+    let expected_cols = hdr.len();
+    let expected_input_cols = expected_cols + usize::from(trailing_delimiter);
+
+    // If we were asked to drop any columns, figure out which ones to keep.
+    // We match both the original and the cleaned names, so that this works
+    // the same way with or without `--clean-column-names`.
+    let mut projection = None;
+    if !opt.drop_column.is_empty() || !opt.drop_column_matching.is_empty() {
+        let drop_res = opt
+            .drop_column_matching
+            .iter()
+            .map(|re| Regex::new(re).context("can't compile regular expression"))
+            .collect::<Result<Vec<_>>>()?;
+        let should_drop = |name: &[u8]| drop_res.iter().any(|re| re.is_match(name));
+        let dropped_positions = opt
+            .drop_column
+            .iter()
+            .flat_map(|n| [find_column(&hdr, n), find_column(&original_hdr, n)])
+            .flatten()
+            .collect::<Vec<_>>();
+        let required_positions = opt
+            .drop_row_if_null
+            .iter()
+            .filter_map(|n| find_column_loosely(&hdr, n, "--drop-row-if-null").ok())
+            .collect::<Vec<_>>();
+        let mut kept = vec![];
+        for (i, (name, original)) in hdr.iter().zip(original_hdr.iter()).enumerate() {
+            if !dropped_positions.contains(&i)
+                && !should_drop(name)
+                && !should_drop(original)
+            {
+                kept.push(i);
+            } else if required_positions.contains(&i) {
+                return Err(format_err!(
+                    "cannot drop column {:?}, which is used by --drop-row-if-null",
+                    String::from_utf8_lossy(name)
+                ));
+            } else {
+                debug!("dropping column {:?}", String::from_utf8_lossy(name));
+            }
+        }
+        projection = Some(compose_projection(None, &kept));
+        hdr = select_columns(&hdr, &kept);
+    }
+
+    // If we have an expected schema, check our columns against it. If we're
+    // adapting to changes, figure out which input columns we want to output.
+    let schema = opt
+        .expect_schema
+        .as_ref()
+        .map(|path| Schema::read(path))
+        .transpose()?;
+    if let (Some(path), Some(schema)) = (&opt.expect_schema, &schema) {
+        let names = hdr
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect::<Vec<_>>();
+        let changes = schema.changes(&names);
+        for change in &changes {
+            eprintln!("Schema change: {}", change);
+        }
+        if !changes.is_empty() {
+            match opt.on_schema_change {
+                OnSchemaChange::Warn => {}
+                OnSchemaChange::Fail => {
+                    return Err(Box::new(ScrubError::UnexpectedColumns(format!(
+                        "Columns do not match schema {}",
+                        path.display()
+                    ))));
+                }
+                OnSchemaChange::Adapt => {
+                    let schema_projection = schema.projection(&names);
+                    projection = Some(match &projection {
+                        Some(projection) => schema_projection
+                            .iter()
+                            .map(|idx| idx.and_then(|idx| projection[idx]))
+                            .collect(),
+                        None => schema_projection,
+                    });
+                    hdr = schema
+                        .columns
+                        .iter()
+                        .map(|c| c.name.as_str())
+                        .collect::<Vec<_>>()
+                        .into();
+                }
+            }
+        }
+    }
+
+    // If we were asked to output only certain columns, pick those out.
+    if !opt.select.is_empty() {
+        let selected = opt
+            .select
+            .iter()
+            .map(|name| {
+                find_column(&hdr, name).ok_or_else(|| {
+                    format_err!("cannot select missing column {:?}", name)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        projection = Some(compose_projection(projection.as_deref(), &selected));
+        hdr = select_columns(&hdr, &selected);
+    }
+
+    // If we were asked to sort our columns by name, do that after everything
+    // else, so that the order doesn't depend on how the input was arranged.
+    if opt.sort_columns {
+        let mut order = (0..hdr.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| hdr[a].cmp(&hdr[b]));
+        projection = Some(compose_projection(projection.as_deref(), &order));
+        hdr = select_columns(&hdr, &order);
+    }
+
+    // If we were given a fixed layout, match our columns to it.
+    if !opt.output_columns.is_empty() {
+        let layout = opt
+            .output_columns
+            .iter()
+            .map(|name| match columns::find_column_loosely(&hdr, name) {
+                Ok(idx) => Ok(Some(idx)),
+                Err(ColumnLookupError::Missing) => Ok(None),
+                Err(ColumnLookupError::Ambiguous) => Err(format_err!(
+                    "--output-columns column {:?} is ambiguous",
+                    name
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let extras = hdr
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !layout.contains(&Some(*i)))
+            .map(|(_, col)| String::from_utf8_lossy(col).into_owned())
+            .collect::<Vec<_>>();
+        if opt.fail_on_extra_columns && !extras.is_empty() {
+            return Err(Box::new(ScrubError::UnexpectedColumns(format!(
+                "Unexpected columns not in --output-columns: {:?}",
+                extras
+            ))));
+        }
+        projection = Some(
+            layout
+                .iter()
+                .map(|idx| {
+                    idx.and_then(|idx| match &projection {
+                        Some(projection) => projection[idx],
+                        None => Some(idx),
+                    })
+                })
+                .collect(),
+        );
+        // Positions like "@2" are replaced by the name of that column, but
+        // otherwise we use the names we were given.
+        hdr = opt
+            .output_columns
+            .iter()
+            .map(|name| match find_column(&hdr, name) {
+                Some(idx) => hdr[idx].to_owned(),
+                None => name.as_bytes().to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .into();
+    }
+
+    // Split any columns we were asked to split.
+    let column_splitter = ColumnSplitter::new(&mut hdr, &opt.split_column)?;
+    let column_merger = ColumnMerger::new(&mut hdr, &opt.merge_columns)?;
+    let melter = if opt.melt {
+        Some(Melter::new(
+            &mut hdr,
+            &opt.id_columns,
+            opt.variable_name.as_deref().unwrap_or("variable"),
+            opt.value_name.as_deref().unwrap_or("value"),
+        )?)
+    } else {
+        None
+    };
+    let exploder = Exploder::new(&hdr, &opt.explode)?;
+
+    // Add our completeness column, if we have one.
+    let completeness_cols = hdr.len();
+    if let Some(col) = &opt.add_completeness_column {
+        if hdr.iter().any(|name| name == col.as_bytes()) {
+            return Err(format_err!("column {:?} already exists", col));
+        }
+        hdr.push_field(col.as_bytes());
+    }
+
+    // Add any constant, metadata and computed columns. Computed columns come
+    // last, so that they can use the others.
+    let mut add_columns = opt
+        .add_constant_column
+        .iter()
+        .map(|c| c.0.clone())
+        .collect::<Vec<_>>();
+    add_columns.extend(
+        opt.add_row_number_column
+            .as_deref()
+            .map(AddColumn::row_number),
+    );
+    add_columns.extend(
+        opt.add_source_file_column
+            .as_deref()
+            .map(AddColumn::source_file),
+    );
+    add_columns.extend(opt.add_column.iter().cloned());
+    let source = inputs
+        .first()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+    let mut added_columns = AddedColumns::new(&mut hdr, &add_columns, &source)?;
+
+    // Load any lookup files, and add their columns.
+    let mut lookups = opt
+        .lookup
+        .iter()
+        .map(|spec| Lookup::new(&mut hdr, spec))
+        .collect::<Result<Vec<_>>>()?;
+
+    // With `--partition-by`, find the column which chooses each row's file.
+    let partition_col = opt
+        .partition_by
+        .as_ref()
+        .map(|col| {
+            find_column(&hdr, col).ok_or_else(|| {
+                format_err!("cannot find --partition-by column {:?}", col)
+            })
+        })
+        .transpose()?;
+    check_sort_keys(&hdr, &opt.sort_by)?;
+
+    // Write our header to our output.
+    let write_header = !opt.no_headers || opt.add_header;
+    if write_header && opt.quote_leading_whitespace {
+        write_record_quoting_edge_whitespace(
+            &mut wtr,
+            &mut shared_output,
+            output_format,
+            &hdr,
+        )?;
+    } else if write_header {
+        wtr.write_byte_record(&hdr)
+            .context("cannot write headers")?;
+    }
+    if write_header && !opt.no_early_flush {
+        // Streaming consumers may want to look at our header right away.
+        wtr.flush().context("cannot write headers")?;
+    }
+    if let Some(splitter) = &splitter {
+        // Every file we split our output into needs the same header.
+        wtr.flush().context("cannot write headers")?;
+        splitter.end_preamble();
+    }
+
+    // Keep track of how often each of our rules does something.
+    let mut rule_hits = RuleHits::default();
+    for lookup in &mut lookups {
+        lookup.register_rules(&mut rule_hits);
+    }
+    let wrong_cols_rule =
+        rule_hits.register("wrong number of columns", "rows rejected");
+    let trailing_delimiter_rule = if trailing_delimiter {
+        Some(rule_hits.register("--allow-trailing-delimiter", "rows rejected"))
+    } else {
+        None
+    };
+    let unparseable_rule = if opt.skip_unparseable {
+        Some(rule_hits.register("--skip-unparseable", "rows rejected"))
+    } else {
+        None
+    };
+    let bare_quote_rule = if opt.bare_quote_policy == BareQuotePolicy::Reject {
+        Some(rule_hits.register("--bare-quote-policy reject", "rows rejected"))
+    } else {
+        None
+    };
+    let invalid_utf8 = if opt.ensure_utf8 {
+        Some(opt.invalid_utf8.unwrap_or(InvalidUtf8::Replace))
+    } else {
+        None
+    };
+    let cleaner = CellCleaner {
+        null_re,
+        trim_whitespace: opt.trim_whitespace,
+        collapse_whitespace: opt.collapse_whitespace,
+        utf8_fallback: opt.utf8_fallback,
+        replace_invalid_utf8: invalid_utf8 == Some(InvalidUtf8::Replace),
+        normalize_unicode: opt.normalize_unicode,
+        ascii_punctuation: opt.ascii_punctuation,
+        strip_thousands_separators: opt.strip_thousands_separators,
+        decimal_comma_output: opt.decimal_comma_output,
+        replace_newlines: opt.replace_newlines,
+        strip_control_chars: if opt.strip_control_chars {
+            Some(if opt.control_chars_to_spaces {
+                Some(b' ')
+            } else {
+                None
+            })
+        } else {
+            None
+        },
+        max_cell_bytes: opt.max_cell_bytes.map(|max| {
+            (
+                max,
+                opt.long_cell_policy.unwrap_or(LongCellPolicy::Truncate),
+            )
+        }),
+    };
+    let drop_rows_with_cells_over = match cleaner.max_cell_bytes {
+        Some((max, LongCellPolicy::DropRow)) => Some(max),
+        _ => None,
+    };
+    let long_cell_rule = if drop_rows_with_cells_over.is_some() {
+        Some(rule_hits.register("--max-cell-bytes", "rows rejected"))
+    } else {
+        None
+    };
+    let clean_rules = cleaner.register_rules(&mut rule_hits);
+    let invalid_utf8_rule = if invalid_utf8 == Some(InvalidUtf8::Drop) {
+        Some(rule_hits.register("--ensure-utf8", "rows rejected"))
+    } else {
+        None
+    };
+    let overflow_rule = if opt.truncate_long_rows {
+        Some(rule_hits.register("--truncate-long-rows", "rows repaired"))
+    } else if opt.merge_overflow_into_last {
+        Some(rule_hits.register("--merge-overflow-into-last", "rows repaired"))
+    } else {
+        None
+    };
+    let drop_row_if_null_rule = if !opt.drop_row_if_null.is_empty() {
+        Some(rule_hits.register("--drop-row-if-null", "rows rejected"))
+    } else {
+        None
+    };
+    let drop_row_if_any_null_rule = if opt.drop_row_if_any_null {
+        Some(rule_hits.register("--drop-row-if-any-null", "rows rejected"))
+    } else {
+        None
+    };
+    let drop_row_if_all_null_rule = if opt.drop_row_if_all_null {
+        Some(rule_hits.register("--drop-row-if-all-null", "rows rejected"))
+    } else {
+        None
+    };
+    let dedup_by_rule = if !opt.dedup_by.is_empty() {
+        Some(rule_hits.register("--dedup-by", "rows rejected"))
+    } else {
+        None
+    };
+
+    // If we were asked to filter rows, prepare to do that.
+    let row_filter = RowFilter::new(
+        &hdr,
+        &opt.drop_row_if_match,
+        &opt.keep_row_if_match,
+        opt.where_expr.as_ref(),
+        &mut rule_hits,
+    )?;
+    let key_filters = opt
+        .drop_if_in
+        .iter()
+        .map(|spec| (spec, false))
+        .chain(opt.keep_if_in.iter().map(|spec| (spec, true)))
+        .map(|(spec, keep)| KeyFilter::new(&hdr, spec, keep, &mut rule_hits))
+        .collect::<Result<Vec<_>>>()?;
+    let mut filtered_rows: u64 = 0;
+
+    // If we were asked to output only some rows, prepare to do that.
+    let mut row_window = RowWindow::new(opt.head, opt.tail, opt.memory_limit);
+    let mut skipped_rows: u64 = 0;
+
+    // If we were asked to check our sort order, prepare to do that.
+    let mut sort_checker = if !opt.assert_sorted_by.is_empty() {
+        Some(SortChecker::new(
+            &hdr,
+            &opt.assert_sorted_by,
+            opt.on_unsorted.unwrap_or(OnUnsorted::Fail),
+            &mut rule_hits,
+        )?)
+    } else {
+        None
+    };
+
+    // Keep track of how many rows --melt and --explode added.
+    let mut extra_rows: u64 = 0;
+
+    // If our schema has validation rules, prepare to check them.
+    let validator = if let Some(schema) = &schema {
+        let names = hdr
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect::<Vec<_>>();
+        Validator::new(schema, &names, &mut rule_hits)?
+    } else {
+        None
+    };
+    let mut validation_warnings: u64 = 0;
+
+    // If we were given --replace, prepare to apply it.
+    let replacer = Replacer::new(&hdr, &opt.replace, &mut rule_hits)?;
+
+    // If we were given --normalize-numbers, prepare to fix them.
+    let number_normalizer = NumberNormalizer::new(
+        &hdr,
+        &opt.normalize_numbers,
+        opt.decimal_comma,
+        opt.decimal_comma_output,
+        &mut rule_hits,
+    )?;
+
+    // If we were given --types, or our schema has column types, prepare to
+    // check them.
+    let mut types = opt.types.clone();
+    if let Some(schema) = &schema {
+        for column in &schema.columns {
+            if let Some(value_type) = column.value_type {
+                if hdr.iter().any(|name| name == column.name.as_bytes()) {
+                    types.push(ColumnType::new(&column.name, value_type));
+                }
+            }
+        }
+    }
+    let type_coercer = TypeCoercer::new(&hdr, &types, &mut rule_hits)?;
+
+    // Just in case --drop-row-if-null was passed, precompute which columns are
+    // required to contain a value.
+    let mut required_cols = vec![false; hdr.len()];
+    for name in &opt.drop_row_if_null {
+        required_cols[find_column_loosely(&hdr, name, "--drop-row-if-null")?] = true;
+    }
+
+    // Decide where to write our schema, and what to call our table.
+    let emit_schema = opt
+        .emit_schema
+        .map(|format| -> Result<_> {
+            let path = match (&opt.emit_schema_path, &opt.output) {
+                (Some(path), _) => path.clone(),
+                (None, Some(output)) => output.with_extension(format.extension()),
+                (None, None) => {
+                    return Err(format_err!(
+                        "--emit-schema needs --output or --emit-schema-path"
+                    ))
+                }
+            };
+            let table = opt
+                .output
+                .as_ref()
+                .unwrap_or(&path)
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .and_then(|name| name.split('.').next().map(|s| s.to_owned()))
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| "data".to_owned());
+            Ok((format, path, table))
+        })
+        .transpose()?;
+
+    // If we were asked for a profile, collect statistics about our output.
+    let mut profiler = if opt.profile.is_some()
+        || opt.baseline_profile.is_some()
+        || opt.emit_schema.is_some()
+    {
+        Some(Profiler::new(&hdr, opt.top_values, opt.sample_values))
+    } else {
+        None
+    };
+
+    // If we were asked to, count duplicate output rows.
+    let mut duplicates = if opt.count_duplicates {
+        Some(DuplicateCounter::new(&hdr, &opt.duplicate_key)?)
+    } else {
+        None
+    };
+
+    // If we were asked to, drop duplicate output rows.
+    let mut dedup = if opt.dedup {
+        Some(Deduplicator::new(opt.dedup_memory_limit))
+    } else {
+        None
+    };
+
+    // If we were asked to, keep only one row for each key.
+    let mut dedup_by = if opt.dedup_by.is_empty() {
+        None
+    } else {
+        Some(KeyDeduplicator::new(
+            &hdr,
+            &opt.dedup_by,
+            opt.keep.unwrap_or(Keep::First),
+            opt.memory_limit,
+        )?)
+    };
+
+    // Keep track of total rows and malformed rows seen. We count the header as
+    // a row for backwards compatibility.
+    let header_rows = u64::from(!opt.no_headers);
+    let mut rows: u64 = header_rows;
+    let mut bad_rows: u64 = 0;
+    let mut diagnostics = BadRowDiagnostics::new(
+        delimiter,
+        if opt.quiet { 0 } else { opt.show_bad_rows },
+    );
+
+    // If we were asked to recover from unbalanced quotes, set that up. Any
+    // records we rescue are queued up here and processed normally.
+    let recovery = if opt.recover_runaway_quotes {
+        Some(RunawayQuoteRecovery {
+            delimiter,
+            quote: input.quote,
+            max_field_lines: opt.runaway_quote_lines,
+        })
+    } else {
+        None
+    };
+    let mut rescued: VecDeque<ByteRecord> = VecDeque::new();
+    let mut runaway_quotes: u64 = 0;
+
+    // If we were asked to repair rows with too many columns, decide how.
+    let overflow_repair = if opt.truncate_long_rows {
+        Some(OverflowRepair::Truncate)
+    } else if opt.merge_overflow_into_last {
+        Some(OverflowRepair::MergeIntoLast { delimiter })
+    } else {
+        None
+    };
+
+    // Keep track of how many rows we've written in a different form than we
+    // read them.
+    let mut changed_rows: u64 = 0;
+
+    // Can we use the fast path and copy the data through unchanged? Or do we
+    // need to clean up emebedded newlines in our data? (These break BigQuery,
+    // for example.)
+    let use_fast_path = cleaner.is_noop()
+        && opt.add_completeness_column.is_none()
+        && added_columns.is_none()
+        && column_splitter.is_none()
+        && column_merger.is_none()
+        && melter.is_none()
+        && exploder.is_none()
+        && lookups.is_empty()
+        && key_filters.is_empty()
+        && sort_checker.is_none()
+        && row_window.is_none()
+        && replacer.is_none()
+        && number_normalizer.is_none()
+        && type_coercer.is_none()
+        && opt.drop_row_if_null.is_empty()
+        && !opt.drop_row_if_any_null
+        && !opt.drop_row_if_all_null
+        && drop_rows_with_cells_over.is_none();
+
+    // With `--preserve-formatting`, we copy rows on the fast path straight
+    // from our input, if our input is already formatted the way we write
+    // our output.
+    let mut raw_writer = None;
+    if let Some(records) = input.raw_records.take() {
+        if use_fast_path
+            && delimiter == b','
+            && input.quote == Some(b'"')
+            && output_format == OutputFormat::default()
+            && !opt.quote_leading_whitespace
+            && !trailing_delimiter
+            && projection.is_none()
+            && opt.keep != Some(Keep::Last)
+            && inputs.len() <= 1
+        {
+            // Anything we write with `wtr` needs to come before our raw rows.
+            wtr.flush().context("cannot write headers")?;
+            raw_writer = Some(RawWriter::new(records, write_buffer));
+        } else {
+            debug!("cannot preserve formatting with these options");
+        }
+    }
+
+    // Keep track of how many cells our cleanups changed.
+    let mut clean_hits = CleanHits::default();
+
+    // With `--jobs`, we clean batches of rows in parallel, and then finish
+    // them one at a time. There's nothing to do in parallel on the fast path.
+    let parallel = match opt.jobs {
+        Some(jobs) if jobs > 1 && !use_fast_path => Some(ParallelCleaner::new(jobs)?),
+        _ => None,
+    };
+    let mut batch: Vec<BatchRow> = Vec::with_capacity(BATCH_ROWS);
+    let mut ready: VecDeque<BatchRow> = VecDeque::new();
+
+    // Keep track of where we spend our time.
+    let mut stage_times = StageTimes::new(opt.stats);
+
+    // Iterate over all the rows, checking to make sure they look reasonable.
+    //
+    // If we use the lowest-level, zero-copy API for `csv`, we can process about
+    // 225 MB/s.  But it turns out we can't do that, because we need to count
+    // all the row's fields before deciding whether or not to write it out.
+    let mut last_line = None;
+    let mut totals = InputTotals::default();
+    // We read each record into `scratch`, and then copy it into a record of
+    // exactly the right size, just like `csv`'s own `byte_records`. This is
+    // much faster than letting `csv` grow a fresh record a bit at a time.
+    let mut scratch = ByteRecord::new();
+    'next_row: loop {
+        // With `--head`, stop once we have enough rows.
+        if row_window.as_ref().is_some_and(RowWindow::is_full) {
+            break 'next_row;
+        }
+
+        // With `--fail-fast`, stop at our first bad row.
+        if opt.fail_fast && bad_rows > 0 {
+            if let Some(bad_row_output) = &mut bad_row_output {
+                bad_row_output.flush()?;
+            }
+            return Err(Box::new(ScrubError::BadRow {
+                line: last_line.unwrap_or(0),
+            }));
+        }
+
+        // If we're following a file, we may wait a long time for the next
+        // record, so make sure that everything we've written so far is
+        // visible.
+        if opt.follow {
+            if let Some(raw_writer) = &mut raw_writer {
+                raw_writer
+                    .write_buffered(&mut shared_output)
+                    .context("error writing records")?;
+            }
+            wtr.flush().context("error writing records")?;
+        }
+
+        // Where we read this row from, if we can copy it straight to our
+        // output.
+        let mut raw_range = None;
+
+        // With `--jobs`, finish any rows we've already cleaned before reading
+        // more.
+        let (row_number, record, input_record, repaired, precleaned) = if let Some(
+            row,
+        ) =
+            ready.pop_front()
+        {
+            let precleaned = Some((row.cleaned, row.changed));
+            (
+                row.row_number,
+                row.record,
+                row.input_record,
+                row.repaired,
+                precleaned,
+            )
+        } else {
+            // Get our next record, either one we rescued or a fresh one.
+            stage_times.start(Stage::Read);
+            let (mut record, was_rescued) = if let Some(record) = rescued.pop_front() {
+                (record, true)
+            } else {
+                let read = match &mut sorted_merge {
+                    Some(sorted_merge) => {
+                        let previous = sorted_merge.current();
+                        let read = sorted_merge.read_byte_record(
+                            &mut input,
+                            &mut scratch,
+                            |input, record| input.rdr.read_byte_record(record),
+                        );
+                        if let (Some(added_columns), true) =
+                            (&mut added_columns, sorted_merge.current() != previous)
+                        {
+                            let path = &inputs[sorted_merge.current()];
+                            added_columns.set_source(&path.display().to_string());
+                        }
+                        read
+                    }
+                    None => input.rdr.read_byte_record(&mut scratch),
+                };
+                match read {
+                    Ok(true) => {
+                        let record = copy_record(&scratch);
+                        match &union_projection {
+                            Some(union_projection) => {
+                                (union_projection.apply(record), false)
+                            }
+                            None => (record, false),
+                        }
+                    }
+                    Ok(false) if batch.is_empty() => {
+                        // Move on to our next input, if we have one.
+                        let (path, settings) = match remaining_inputs.next() {
+                            Some(next) => next,
+                            None => break 'next_row,
+                        };
+                        totals.add(&input);
+                        input = open_input(
+                            opt,
+                            Some(path),
+                            None,
+                            settings,
+                            Some(delimiter),
+                            comments.as_ref(),
+                        )?;
+                        if !opt.no_headers {
+                            let hdr = input
+                                .rdr
+                                .byte_headers()
+                                .with_context(|_| {
+                                    format!(
+                                        "cannot read headers of {}",
+                                        path.display()
+                                    )
+                                })?
+                                .to_owned();
+                            if let Some(union_hdr) = &union_hdr {
+                                union_projection =
+                                    UnionProjection::new(union_hdr, &hdr);
+                            } else if hdr != first_hdr {
+                                return Err(format_err!(
+                                            "columns of {} do not match {} (try --union-columns)",
+                                            path.display(),
+                                            inputs[0].display()
+                                        ));
+                            }
+                        }
+                        if let Some(added_columns) = &mut added_columns {
+                            added_columns.set_source(&path.display().to_string());
+                        }
+                        continue 'next_row;
+                    }
+                    Ok(false) => {
+                        // Clean any rows left in our last batch.
+                        let parallel = parallel.as_ref().expect("should have jobs");
+                        let pending = std::mem::take(&mut batch);
+                        ready.extend(parallel.clean(
+                            &cleaner,
+                            pending,
+                            &mut clean_hits,
+                        ));
+                        continue 'next_row;
+                    }
+                    Err(err) => {
+                        let position = Position::from(
+                            err.position().unwrap_or(input.rdr.position()),
+                        );
+                        return Err(err).at_position("cannot read record", position);
+                    }
+                }
+            };
+            stage_times.start(Stage::Clean);
+            if let Some(position) = record.position() {
+                last_line = Some(position.line());
+            }
+            if let (Some(raw_writer), false) = (&raw_writer, was_rescued) {
+                let start = record.position().expect("should have position").byte();
+                raw_writer.discard_to(start);
+                raw_range = Some((start, input.rdr.position().byte()));
+            }
+
+            // If this record contains input we couldn't read, reject it.
+            if let (Some(skipped_errors), false) = (&input.skipped_errors, was_rescued)
+            {
+                let end = input.rdr.position().byte();
+                let skipped =
+                    skipped_errors.borrow_mut().pop_front_if(|e| e.byte < end);
+                if let Some(skipped) = skipped {
+                    rows += 1;
+                    bad_rows += 1;
+                    rule_hits
+                        .hit(unparseable_rule.expect("should have unparseable rule"));
+                    if !opt.quiet {
+                        let position = record.position().map(Position::from);
+                        eprintln!(
+                            "Skipping unparseable record at {}: {}",
+                            position.expect("fresh record should have position"),
+                            skipped.error,
+                        );
+                    }
+                    if let Some(bad_row_output) = &mut bad_row_output {
+                        bad_row_output.write(&record, BadRowReason::Unparseable)?;
+                    }
+                    continue 'next_row;
+                }
+            }
+
+            // If this record has any bare quotes we're supposed to reject, do so.
+            if let (Some(rule), Some(bare_quotes), false) =
+                (bare_quote_rule, &input.bare_quotes, was_rescued)
+            {
+                let end_line = input.rdr.position().line();
+                let mut lines = bare_quotes.lines.borrow_mut();
+                let mut found = false;
+                while lines.pop_front_if(|&mut line| line < end_line).is_some() {
+                    found = true;
+                }
+                if found {
+                    rows += 1;
+                    bad_rows += 1;
+                    rule_hits.hit(rule);
+                    if let Some(bad_row_output) = &mut bad_row_output {
+                        bad_row_output.write(&record, BadRowReason::BareQuote)?;
+                    }
+                    debug!("row {}: found bare quote", rows);
+                    continue 'next_row;
+                }
+            }
+
+            // If this looks like an unbalanced quote swallowed other rows, split
+            // it up and try again.
+            if let (Some(recovery), false) = (&recovery, was_rescued) {
+                if let Some(idx) =
+                    recovery.find_runaway_field(&record, expected_input_cols)
+                {
+                    runaway_quotes += 1;
+                    let split = recovery.split_runaway_field(&record, idx)?;
+                    debug!(
+                        "row {}: closed runaway quote in column {}, rescued {} rows",
+                        rows + 1,
+                        idx + 1,
+                        split.len() - 1,
+                    );
+                    for (i, r) in split.into_iter().enumerate() {
+                        rescued.insert(i, r);
+                    }
+                    continue 'next_row;
+                }
+            }
+
+            // Skip any rows at the start of our data, if we were asked to.
+            if skipped_rows < opt.skip_rows {
+                skipped_rows += 1;
+                continue 'next_row;
+            }
+
+            // Keep track of how many rows we've seen.
+            rows += 1;
+
+            // Strip any trailing delimiter. If the header had one, every row must.
+            if trailing_delimiter {
+                if record.len() == expected_input_cols
+                    && record.get(expected_cols) == Some(&b""[..])
+                {
+                    record.truncate(expected_cols);
+                } else {
+                    bad_rows += 1;
+                    if let Some(bad_row_output) = &mut bad_row_output {
+                        bad_row_output
+                            .write(&record, BadRowReason::MissingTrailingDelimiter)?;
+                    }
+                    if let Some(rule) = trailing_delimiter_rule {
+                        rule_hits.hit(rule);
+                    }
+                    debug!("row {}: expected trailing delimiter", rows);
+                    continue 'next_row;
+                }
+            }
+
+            // Repair rows with too many columns, if we were asked to.
+            let mut repaired = false;
+            if let Some(overflow_repair) = overflow_repair {
+                if record.len() > expected_cols {
+                    debug!(
+                        "row {}: repairing {} columns to {}",
+                        rows,
+                        record.len(),
+                        expected_cols,
+                    );
+                    record = overflow_repair.repair(&record, expected_cols);
+                    rule_hits.hit(overflow_rule.expect("should have overflow rule"));
+                    repaired = true;
+                }
+            }
+
+            // Check if we have the right number of columns in this row.
+            if record.len() != expected_cols {
+                bad_rows += 1;
+                if let Some(bad_row_output) = &mut bad_row_output {
+                    bad_row_output.write(&record, BadRowReason::WrongColumnCount)?;
+                }
+                rule_hits.hit(wrong_cols_rule);
+                diagnostics.wrong_column_count(rows, &record, expected_cols);
+                debug!(
+                    "row {}: expected {} columns, found {}",
+                    rows,
+                    expected_cols,
+                    record.len(),
+                );
+                continue 'next_row;
+            }
+
+            // Drop rows or stop if we have invalid UTF-8 that nobody will fix.
+            if let (Some(policy @ (InvalidUtf8::Drop | InvalidUtf8::Fail)), None) =
+                (invalid_utf8, opt.utf8_fallback)
+            {
+                if record.iter().any(|val| std::str::from_utf8(val).is_err()) {
+                    let line = record.position().map(|pos| pos.line()).unwrap_or(0);
+                    if policy == InvalidUtf8::Fail {
+                        return Err(format_err!(
+                            "invalid UTF-8 in row at line {}",
+                            line
+                        ));
+                    }
+                    bad_rows += 1;
+                    if let Some(bad_row_output) = &mut bad_row_output {
+                        bad_row_output.write(&record, BadRowReason::InvalidUtf8)?;
+                    }
+                    rule_hits.hit(invalid_utf8_rule.expect("should have UTF-8 rule"));
+                    debug!("row {}: invalid UTF-8", rows);
+                    continue 'next_row;
+                }
+            }
+
+            // Pick out the columns we want to output, keeping the original in
+            // case it turns out to be a bad row.
+            let input_record = if let Some(projection) = &projection {
+                let projected = project_record(&record, projection);
+                Some(std::mem::replace(&mut record, projected))
+            } else {
+                None
+            };
+
+            // With `--jobs`, queue this row up to be cleaned in parallel.
+            if let Some(parallel) = &parallel {
+                batch.push(BatchRow::new(rows, record, input_record, repaired));
+                if batch.len() >= BATCH_ROWS {
+                    let pending = std::mem::take(&mut batch);
+                    ready.extend(parallel.clean(&cleaner, pending, &mut clean_hits));
+                }
+                continue 'next_row;
+            }
+            (rows, record, input_record, repaired, None)
+        };
+
+        // Decide how to handle this row.
+        if use_fast_path {
+            // We don't need to do anything fancy, so just pass it through.
+            // I'm not sure how much this actually buys us in current Rust
+            // versions, but it seemed like a good idea at the time.
+            if let Some(row_filter) = &row_filter {
+                let values = record.iter().collect::<Vec<_>>();
+                if !row_filter.keeps(&values, &rule_hits) {
+                    filtered_rows += 1;
+                    continue 'next_row;
+                }
+            }
+            if let Some(validator) = &validator {
+                let values = record.iter().collect::<Vec<_>>();
+                let validation = validator.validate(row_number, &values, &rule_hits);
+                validation_warnings += validation.warnings;
+                if validation.errors > 0 {
+                    bad_rows += 1;
+                    if let Some(bad_row_output) = &mut bad_row_output {
+                        bad_row_output.write(
+                            input_record.as_ref().unwrap_or(&record),
+                            BadRowReason::ValidationFailed,
+                        )?;
+                    }
+                    continue 'next_row;
+                }
+            }
+            if let Some(dedup) = &mut dedup {
+                if dedup.is_duplicate(&record) {
+                    continue 'next_row;
+                }
+            }
+            if let Some(dedup_by) = &mut dedup_by {
+                let values = record.iter().collect::<Vec<_>>();
+                let input = input_record.as_ref().unwrap_or(&record);
+                let (write_now, rejected) =
+                    match dedup_by.check(&values, input, repaired)? {
+                        KeyCheck::Write => (true, None),
+                        KeyCheck::Duplicate => (false, Some(input.clone())),
+                        KeyCheck::Held { replaced } => {
+                            (false, replaced.map(|held| held.input_record))
+                        }
+                    };
+                if let Some(rejected) = rejected {
+                    bad_rows += 1;
+                    if let Some(bad_row_output) = &mut bad_row_output {
+                        bad_row_output.write(&rejected, BadRowReason::DuplicateKey)?;
+                    }
+                    rule_hits.hit(dedup_by_rule.expect("should have --dedup-by rule"));
+                    debug!("row {}: duplicate --dedup-by key", row_number);
+                }
+                if !write_now {
+                    continue 'next_row;
+                }
+            }
+            stage_times.start(Stage::Write);
+            if let Some(splitter) = &mut splitter {
+                splitter.before_row(
+                    &mut wtr,
+                    raw_writer.as_mut(),
+                    &mut shared_output,
+                    partition_col.map(|col| &record[col]),
+                )?;
+            }
+            match (&mut raw_writer, raw_range) {
+                (Some(raw_writer), Some((start, end))) if !repaired => {
+                    raw_writer
+                        .write_record(start, end, &mut shared_output)
+                        .context("cannot write record")?;
+                }
+                (Some(raw_writer), _) => {
+                    // We can't copy this row, so write it normally, making
+                    // sure it stays in order with our raw rows.
+                    raw_writer
+                        .write_buffered(&mut shared_output)
+                        .context("cannot write record")?;
+                    wtr.write_record(&record).context("cannot write record")?;
+                    wtr.flush().context("cannot write record")?;
+                }
+                (None, _) if opt.quote_leading_whitespace => {
+                    write_record_quoting_edge_whitespace(
+                        &mut wtr,
+                        &mut shared_output,
+                        output_format,
+                        &record,
+                    )?;
+                }
+                (None, _) => {
+                    wtr.write_record(&record).context("cannot write record")?;
+                }
+            }
+            if let Some(profiler) = &mut profiler {
+                profiler.observe_row(&record);
+            }
+            if let Some(duplicates) = &mut duplicates {
+                duplicates.observe_row(&record);
+            }
+            if repaired {
+                changed_rows += 1;
+            }
+        } else {
+            // We need to apply one or more cleanups, so run the slow path.
+            let row_changed = Cell::new(repaired);
+            let cleaned = record.into_iter().map(|original: &[u8]| -> Cow<[u8]> {
+                let val = cleaner.clean(original, &mut clean_hits);
+                // Remember whether we changed anything.
+                if val[..] != *original {
+                    row_changed.set(true);
+                }
+                val
+            });
+            if opt.drop_row_if_null.is_empty()
+                && !opt.drop_row_if_any_null
+                && !opt.drop_row_if_all_null
+                && drop_rows_with_cells_over.is_none()
+                && profiler.is_none()
+                && duplicates.is_none()
+                && dedup.is_none()
+                && dedup_by.is_none()
+                && row_filter.is_none()
+                && opt.add_completeness_column.is_none()
+                && added_columns.is_none()
+                && validator.is_none()
+                && column_splitter.is_none()
+                && column_merger.is_none()
+                && melter.is_none()
+                && exploder.is_none()
+                && lookups.is_empty()
+                && key_filters.is_empty()
+                && sort_checker.is_none()
+                && row_window.is_none()
+                && replacer.is_none()
+                && number_normalizer.is_none()
+                && type_coercer.is_none()
+                && !opt.quote_leading_whitespace
+                && precleaned.is_none()
+                && partition_col.is_none()
+            {
+                // Still somewhat fast! Our cleanups run lazily as we write, so
+                // we count them as writing.
+                stage_times.start(Stage::Write);
+                if let Some(splitter) = &mut splitter {
+                    splitter.before_row(
+                        &mut wtr,
+                        raw_writer.as_mut(),
+                        &mut shared_output,
+                        None,
+                    )?;
+                }
+                wtr.write_record(cleaned).context("cannot write record")?;
+            } else {
+                // We need to rebuild the record, check for null columns,
+                // and only output the record if everything's OK.
+                let mut row = match precleaned {
+                    Some((values, changed)) => {
+                        row_changed.set(changed);
+                        values.into_iter().map(Cow::Owned).collect::<Vec<_>>()
+                    }
+                    None => cleaned.collect::<Vec<Cow<[u8]>>>(),
+                };
+                if let Some(column_splitter) = &column_splitter {
+                    column_splitter.split_row(&mut row);
+                    row_changed.set(true);
+                }
+                if let Some(column_merger) = &column_merger {
+                    column_merger.merge_row(&mut row);
+                    row_changed.set(true);
+                }
+                // With --melt or --explode, this row may turn into several,
+                // which we check and write one at a time.
+                let mut pieces = match &melter {
+                    Some(melter) => {
+                        row_changed.set(true);
+                        melter.melt_row(&row)
+                    }
+                    None => vec![row],
+                };
+                if let Some(exploder) = &exploder {
+                    let count = pieces.len();
+                    pieces = pieces
+                        .into_iter()
+                        .flat_map(|row| exploder.explode_row(row))
+                        .collect();
+                    if pieces.len() > count {
+                        row_changed.set(true);
+                    }
+                }
+                // Count each extra piece as a row, so that our totals add up.
+                match pieces.len() {
+                    0 => filtered_rows += 1,
+                    n => extra_rows += n as u64 - 1,
+                }
+                'next_piece: for mut row in pieces {
+                    if opt.add_completeness_column.is_some() {
+                        let filled = row.iter().filter(|v| !v.is_empty()).count();
+                        let completeness = if opt.completeness_as_fraction {
+                            format!("{:.3}", filled as f64 / completeness_cols as f64)
+                        } else {
+                            filled.to_string()
+                        };
+                        row.push(Cow::Owned(completeness.into_bytes()));
+                    }
+                    if let Some(added_columns) = &added_columns {
+                        added_columns.append(row_number - header_rows, &mut row);
+                    }
+                    for lookup in &lookups {
+                        if lookup.lookup_row(&mut row, &rule_hits) {
+                            continue;
+                        }
+                        match lookup.on_miss() {
+                            OnMiss::Keep => {}
+                            OnMiss::Drop => {
+                                filtered_rows += 1;
+                                continue 'next_piece;
+                            }
+                            OnMiss::Reject => {
+                                bad_rows += 1;
+                                if let Some(bad_row_output) = &mut bad_row_output {
+                                    bad_row_output.write(
+                                        input_record.as_ref().unwrap_or(&record),
+                                        BadRowReason::LookupMiss,
+                                    )?;
+                                }
+                                debug!("row {}: not found in lookup file", row_number);
+                                continue 'next_piece;
+                            }
+                        }
+                    }
+                    if let Some(replacer) = &replacer {
+                        let mut changed = false;
+                        replacer.replace_row(&mut row, &mut changed, &rule_hits);
+                        if changed {
+                            row_changed.set(true);
+                        }
+                    }
+                    if let Some(number_normalizer) = &number_normalizer {
+                        let mut changed = false;
+                        if !number_normalizer.normalize_row(
+                            &mut row,
+                            &mut changed,
+                            &rule_hits,
+                        ) {
+                            bad_rows += 1;
+                            if let Some(bad_row_output) = &mut bad_row_output {
+                                bad_row_output.write(
+                                    input_record.as_ref().unwrap_or(&record),
+                                    BadRowReason::InvalidNumber,
+                                )?;
+                            }
+                            debug!("row {}: value is not a number", row_number);
+                            continue 'next_piece;
+                        }
+                        if changed {
+                            row_changed.set(true);
+                        }
+                    }
+                    if let Some(type_coercer) = &type_coercer {
+                        let mut changed = false;
+                        if !type_coercer.coerce_row(&mut row, &mut changed, &rule_hits)
+                        {
+                            bad_rows += 1;
+                            if let Some(bad_row_output) = &mut bad_row_output {
+                                bad_row_output.write(
+                                    input_record.as_ref().unwrap_or(&record),
+                                    BadRowReason::TypeMismatch,
+                                )?;
+                            }
+                            debug!("row {}: value has the wrong type", row_number);
+                            continue 'next_piece;
+                        }
+                        if changed {
+                            row_changed.set(true);
+                        }
+                    }
+                    for (value, &is_required_col) in
+                        row.iter().zip(required_cols.iter())
+                    {
+                        // If the column is NULL but shouldn't be, bail on this row.
+                        if is_required_col && value.is_empty() {
+                            bad_rows += 1;
+                            if let Some(bad_row_output) = &mut bad_row_output {
+                                bad_row_output.write(
+                                    input_record.as_ref().unwrap_or(&record),
+                                    BadRowReason::RequiredColumnNull,
+                                )?;
+                            }
+                            if let Some(rule) = drop_row_if_null_rule {
+                                rule_hits.hit(rule);
+                            }
+                            debug!("row {}: required column is empty", row_number);
+                            continue 'next_piece;
+                        }
+                    }
+                    if let Some(max) = drop_rows_with_cells_over {
+                        if row.iter().any(|v| v.len() > max) {
+                            bad_rows += 1;
+                            if let Some(bad_row_output) = &mut bad_row_output {
+                                bad_row_output.write(
+                                    input_record.as_ref().unwrap_or(&record),
+                                    BadRowReason::CellTooLong,
+                                )?;
+                            }
+                            rule_hits.hit(
+                                long_cell_rule.expect("should have long cell rule"),
+                            );
+                            debug!("row {}: cell is too long", row_number);
+                            continue 'next_piece;
+                        }
+                    }
+                    let input_values = &row[..completeness_cols];
+                    let null_check = if opt.drop_row_if_all_null
+                        && input_values.iter().all(|v| v.is_empty())
+                    {
+                        Some((BadRowReason::BlankRow, drop_row_if_all_null_rule))
+                    } else if opt.drop_row_if_any_null
+                        && input_values.iter().any(|v| v.is_empty())
+                    {
+                        Some((BadRowReason::AnyColumnNull, drop_row_if_any_null_rule))
+                    } else {
+                        None
+                    };
+                    if let Some((reason, rule)) = null_check {
+                        bad_rows += 1;
+                        if let Some(bad_row_output) = &mut bad_row_output {
+                            bad_row_output.write(
+                                input_record.as_ref().unwrap_or(&record),
+                                reason,
+                            )?;
+                        }
+                        if let Some(rule) = rule {
+                            rule_hits.hit(rule);
+                        }
+                        debug!("row {}: {}", row_number, reason.as_str());
+                        continue 'next_piece;
+                    }
+                    if let Some(row_filter) = &row_filter {
+                        let values = row.iter().map(|v| &v[..]).collect::<Vec<_>>();
+                        if !row_filter.keeps(&values, &rule_hits) {
+                            filtered_rows += 1;
+                            continue 'next_piece;
+                        }
+                    }
+                    if !key_filters.iter().all(|f| f.keeps(&row, &rule_hits)) {
+                        filtered_rows += 1;
+                        continue 'next_piece;
+                    }
+                    if let Some(validator) = &validator {
+                        let values = row.iter().map(|v| &v[..]).collect::<Vec<_>>();
+                        let validation =
+                            validator.validate(row_number, &values, &rule_hits);
+                        validation_warnings += validation.warnings;
+                        if validation.errors > 0 {
+                            bad_rows += 1;
+                            if let Some(bad_row_output) = &mut bad_row_output {
+                                bad_row_output.write(
+                                    input_record.as_ref().unwrap_or(&record),
+                                    BadRowReason::ValidationFailed,
+                                )?;
+                            }
+                            continue 'next_piece;
+                        }
+                    }
+                    if let Some(sort_checker) = &mut sort_checker {
+                        if !sort_checker.check(&row, &rule_hits) {
+                            if sort_checker.policy() == OnUnsorted::Fail {
+                                return Err(format_err!(
+                                    "row {} is out of order for --assert-sorted-by",
+                                    row_number
+                                ));
+                            }
+                            bad_rows += 1;
+                            if let Some(bad_row_output) = &mut bad_row_output {
+                                bad_row_output.write(
+                                    input_record.as_ref().unwrap_or(&record),
+                                    BadRowReason::OutOfOrder,
+                                )?;
+                            }
+                            debug!("row {}: out of order", row_number);
+                            continue 'next_piece;
+                        }
+                    }
+                    if let Some(dedup) = &mut dedup {
+                        if dedup.is_duplicate(row.iter().map(|v| &v[..])) {
+                            continue 'next_piece;
+                        }
+                    }
+                    if let Some(dedup_by) = &mut dedup_by {
+                        let values = row.iter().map(|v| &v[..]).collect::<Vec<_>>();
+                        let input = input_record.as_ref().unwrap_or(&record);
+                        let (write_now, rejected) =
+                            match dedup_by.check(&values, input, row_changed.get())? {
+                                KeyCheck::Write => (true, None),
+                                KeyCheck::Duplicate => (false, Some(input.clone())),
+                                KeyCheck::Held { replaced } => {
+                                    (false, replaced.map(|held| held.input_record))
+                                }
+                            };
+                        if let Some(rejected) = rejected {
+                            bad_rows += 1;
+                            if let Some(bad_row_output) = &mut bad_row_output {
+                                bad_row_output
+                                    .write(&rejected, BadRowReason::DuplicateKey)?;
+                            }
+                            rule_hits.hit(
+                                dedup_by_rule.expect("should have --dedup-by rule"),
+                            );
+                            debug!("row {}: duplicate --dedup-by key", row_number);
+                        }
+                        if !write_now {
+                            continue 'next_piece;
+                        }
+                    }
+                    if let Some(row_window) = &mut row_window {
+                        if let Admit::Held { dropped } = row_window.admit(&row)? {
+                            if dropped {
+                                filtered_rows += 1;
+                            }
+                            continue 'next_piece;
+                        }
+                    }
+                    stage_times.start(Stage::Write);
+                    if let Some(splitter) = &mut splitter {
+                        splitter.before_row(
+                            &mut wtr,
+                            raw_writer.as_mut(),
+                            &mut shared_output,
+                            partition_col.map(|col| &row[col][..]),
+                        )?;
+                    }
+                    if opt.quote_leading_whitespace {
+                        write_record_quoting_edge_whitespace(
+                            &mut wtr,
+                            &mut shared_output,
+                            output_format,
+                            &row,
+                        )?;
+                    } else {
+                        wtr.write_record(&row).context("cannot write record")?;
+                    }
+                    if let Some(profiler) = &mut profiler {
+                        profiler.observe_row(row.iter().map(|value| &value[..]));
+                    }
+                    if let Some(duplicates) = &mut duplicates {
+                        duplicates.observe_row(row.iter().map(|value| &value[..]));
+                    }
+                }
+            }
+            if row_changed.get() {
+                changed_rows += 1;
+            }
+        }
+    }
+
+    // With `--keep last`, we've been holding on to our rows until we knew
+    // which was the last row with each key.
+    if let Some(dedup_by) = dedup_by {
+        stage_times.start(Stage::Write);
+        for held in dedup_by.into_held() {
+            let held = held?;
+            if let Some(splitter) = &mut splitter {
+                splitter.before_row(
+                    &mut wtr,
+                    raw_writer.as_mut(),
+                    &mut shared_output,
+                    partition_col.map(|col| &held.values[col][..]),
+                )?;
+            }
+
+            if opt.quote_leading_whitespace {
+                write_record_quoting_edge_whitespace(
+                    &mut wtr,
+                    &mut shared_output,
+                    output_format,
+                    &held.values,
+                )?;
+            } else {
+                wtr.write_record(&held.values)
+                    .context("cannot write record")?;
+            }
+            if let Some(profiler) = &mut profiler {
+                profiler.observe_row(held.values.iter().map(|value| &value[..]));
+            }
+            if let Some(duplicates) = &mut duplicates {
+                duplicates.observe_row(held.values.iter().map(|value| &value[..]));
+            }
+            if held.changed {
+                changed_rows += 1;
+            }
+        }
+    }
+
+    // With `--tail`, we've been holding on to our last rows.
+    if let Some(row_window) = row_window {
+        stage_times.start(Stage::Write);
+        for row in row_window.into_held() {
+            let row = row?;
+            if let Some(splitter) = &mut splitter {
+                splitter.before_row(
+                    &mut wtr,
+                    raw_writer.as_mut(),
+                    &mut shared_output,
+                    partition_col.map(|col| &row[col][..]),
+                )?;
+            }
+            if opt.quote_leading_whitespace {
+                write_record_quoting_edge_whitespace(
+                    &mut wtr,
+                    &mut shared_output,
+                    output_format,
+                    &row,
+                )?;
+            } else {
+                wtr.write_record(&row).context("cannot write record")?;
+            }
+            if let Some(profiler) = &mut profiler {
+                profiler.observe_row(row.iter().map(|value| &value[..]));
+            }
+            if let Some(duplicates) = &mut duplicates {
+                duplicates.observe_row(row.iter().map(|value| &value[..]));
+            }
+        }
+    }
+
+    clean_hits.record(&clean_rules, &rule_hits);
+
+    // Flush all our buffers.
+    stage_times.start(Stage::Write);
+    if let Some(raw_writer) = &mut raw_writer {
+        raw_writer
+            .write_buffered(&mut shared_output)
+            .context("error writing records")?;
+    }
+    wtr.flush().context("error writing records")?;
+    drop(wtr);
+    if let Some(bad_row_output) = &mut bad_row_output {
+        bad_row_output.flush()?;
+    }
+    if let Some(comments) = &comments {
+        comments
+            .borrow_mut()
+            .flush()
+            .context("cannot write comments")?;
+    }
+    stage_times.finish();
+
+    // Write out our profile, and compare it against our baseline.
+    let mut drift = vec![];
+    if let Some(profiler) = &profiler {
+        let profile = profiler.to_profile();
+        if let Some(path) = &opt.profile {
+            profile.write(path)?;
+        }
+        if let Some((format, path, table)) = &emit_schema {
+            fs::write(path, format.emit(&profile, table)?).with_context(|_| {
+                format!("cannot write schema to {}", path.display())
+            })?;
+        }
+        if let Some(path) = &opt.baseline_profile {
+            let baseline = Profile::read(path)?;
+            drift = profile.drift_from(&baseline, opt.max_null_rate_change);
+            for d in &drift {
+                eprintln!("Drift from baseline profile: {}", d);
+            }
+        }
+    }
+
+    // Report any duplicates we found.
+    if let Some(duplicates) = &duplicates {
+        eprintln!("{} duplicate rows", duplicates.duplicate_rows());
+        let top_keys = duplicates.top_keys(10);
+        if !top_keys.is_empty() {
+            eprintln!("Most duplicated keys:");
+            for (key, count) in top_keys {
+                let key = key
+                    .iter()
+                    .map(|value| String::from_utf8_lossy(value))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                eprintln!("  {:?}: {} rows", key, count);
+            }
+        }
+    }
+
+    // Print out some information about our run.
+    totals.add(&input);
+    for finished in sorted_merge.iter().flat_map(|merge| merge.finished()) {
+        totals.add(finished);
+    }
+    let rows = rows + extra_rows;
+    let ellapsed = (now() - start_time).as_seconds_f64();
+    let bytes_per_second = (totals.bytes as f64 / ellapsed) as i64;
+    if !opt.quiet {
+        eprintln!(
+            "{} rows ({} bad) in {:.2} seconds, {}/sec",
+            rows,
+            bad_rows,
+            ellapsed,
+            bytes_per_second.file_size(file_size_opts::BINARY)?,
+        );
+        if opt.stats {
+            stage_times.print_summary();
+        }
+        if changed_rows > 0 {
+            eprintln!("{} rows changed by cleanup", changed_rows);
+        }
+        if row_filter.is_some() || !key_filters.is_empty() || opt.tail.is_some() {
+            eprintln!("{} rows filtered out", filtered_rows);
+        }
+        if let (true, Some(dedup)) = (opt.dedup_report, &dedup) {
+            eprintln!("{} duplicate rows removed", dedup.duplicates());
+        }
+        if let Some(rate) = dedup.as_ref().and_then(|d| d.false_positive_rate()) {
+            eprintln!(
+                "--dedup-memory-limit reached, {:.4}% of new rows may be wrongly \
+                 removed as duplicates",
+                rate * 100.0,
+            );
+        }
+        if input.quote_repairs.is_some() {
+            eprintln!("{} stray quotes repaired", totals.quote_repairs);
+        }
+        if let (BareQuotePolicy::Strip, Some(_)) =
+            (opt.bare_quote_policy, &input.bare_quotes)
+        {
+            eprintln!("{} bare quotes stripped", totals.bare_quotes);
+        }
+        if validator.is_some() {
+            eprintln!("{} validation warnings", validation_warnings);
+        }
+        if recovery.is_some() {
+            eprintln!("{} runaway quoted fields recovered", runaway_quotes);
+        }
+        rule_hits.print_summary();
+    }
+
+    // Rows we filtered out or removed as duplicates were good, but we didn't
+    // write them.
+    let duplicate_rows = dedup.as_ref().map_or(0, |dedup| dedup.duplicates());
+    let filtered_rows = filtered_rows + duplicate_rows;
+    let good_rows = rows - header_rows - bad_rows - filtered_rows;
+
+    // Write a machine-readable report, if we were asked to.
+    if opt.report_path.is_some() || opt.report.is_some() {
+        let report = Report {
+            rows: rows - header_rows,
+            good_rows,
+            bad_rows,
+            filtered_rows,
+            changed_rows,
+            bytes_read: totals.bytes,
+            elapsed_seconds: ellapsed,
+            bytes_per_second: totals.bytes as f64 / ellapsed,
+            stage_seconds: opt.stats.then(|| (&stage_times).into()),
+            rules: RuleReport::from_hits(&rule_hits),
+        };
+        if let Some(path) = &opt.report_path {
+            report.write(path)?;
+        }
+        if let Some(ReportFormat::Json) = opt.report {
+            eprintln!("{}", report.to_json()?);
+        }
+    }
+
+    // Decide whether this run failed. We do this before we finish our output,
+    // so that a failed run never replaces an existing output file.
+    let stats = ScrubStats {
+        rows: rows - header_rows,
+        bad_rows,
+        filtered_rows,
+        changed_rows,
+    };
+    let failure = 'checks: {
+        // If too many rows are bad, assume something has gone horribly wrong.
+        // By default, we allow up to 10%.
+        let bad_row_policy = BadRowPolicy {
+            max_percent: match (opt.max_bad_rows, opt.max_bad_rows_count) {
+                (None, None) => BadRowPolicy::default().max_percent,
+                (pct, _) => pct,
+            },
+            max_count: opt.max_bad_rows_count,
+            fail_fast: opt.fail_fast,
+        };
+        if bad_row_policy.is_exceeded(bad_rows, rows) {
+            break 'checks Some(ScrubError::TooManyBadRows {
+                stats,
+                checked_rows: rows,
+            });
+        }
+
+        // Make sure we wrote a plausible number of rows, not counting the
+        // header.
+        if let Some(min) = opt.assert_min_rows {
+            if good_rows < min {
+                break 'checks Some(ScrubError::WrongRowCount(format!(
+                    "Too few good rows ({}, expected at least {})",
+                    good_rows, min
+                )));
+            }
+        }
+        if let Some(max) = opt.assert_max_rows {
+            if good_rows > max {
+                break 'checks Some(ScrubError::WrongRowCount(format!(
+                    "Too many good rows ({}, expected at most {})",
+                    good_rows, max
+                )));
+            }
+        }
+
+        // Check whether we changed suspiciously few or many rows.
+        if opt.fail_if_unchanged && changed_rows == 0 {
+            break 'checks Some(ScrubError::WrongChangeCount(
+                "No rows were changed by cleanup".to_owned(),
+            ));
+        }
+        if let Some(max_ratio) = opt.fail_if_changed_over {
+            let ratio = if good_rows == 0 {
+                0.0
+            } else {
+                changed_rows as f64 / good_rows as f64
+            };
+            if ratio > max_ratio {
+                break 'checks Some(ScrubError::WrongChangeCount(format!(
+                    "Too many rows ({} of {}) were changed by cleanup",
+                    changed_rows, good_rows,
+                )));
+            }
+        }
+
+        // Fail if our data has drifted from our baseline.
+        if opt.fail_on_drift && !drift.is_empty() {
+            break 'checks Some(ScrubError::Drift);
+        }
+        None
+    };
+
+    // Finish our output, unless we failed, in which case we throw it away.
+    let output = shared_output
+        .into_inner()
+        .expect("output should no longer be shared");
+    if let Some(err) = failure {
+        drop(output);
+        return Err(Box::new(err));
+    }
+    output.finish().context("error writing records")?;
+
+    Ok(stats)
+}
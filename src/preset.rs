@@ -0,0 +1,96 @@
+//! Combinations of options suited to loading our output into particular
+//! databases, for `--preset`.
+
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::Opt;
+
+/// The databases we have presets for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// `--ensure-utf8`, `--null` and `--replace-newlines`.
+    BigQuery,
+    /// `--ensure-utf8`, `--null`, `--max-cell-bytes 65535`, and
+    /// `--quote-leading-whitespace` without `--trim-whitespace`.
+    Redshift,
+    /// `--ensure-utf8`, `--null`, `--max-cell-bytes 16777216`, and
+    /// `--quote-leading-whitespace` without `--trim-whitespace`.
+    Snowflake,
+    /// `--ensure-utf8` and `--null`.
+    Postgres,
+}
+
+/// The values we treat as NULL for every preset. All our databases load
+/// empty values as NULL by default, but not the word "NULL" or `\N`.
+const NULL_REGEX: &str = r"(?i)NULL|\\N";
+
+/// The longest `VARCHAR` Redshift supports, in bytes.
+const REDSHIFT_MAX_CELL_BYTES: usize = 65_535;
+
+/// The longest `VARCHAR` Snowflake supports, in bytes.
+const SNOWFLAKE_MAX_CELL_BYTES: usize = 16 * 1024 * 1024;
+
+impl Preset {
+    /// Turn on the options in this preset. Any option which takes a value and
+    /// was passed explicitly is left alone.
+    pub fn apply(self, opt: &mut Opt) {
+        // Every database here wants valid UTF-8.
+        opt.ensure_utf8 = true;
+        if opt.null.is_none() {
+            opt.null = Some(NULL_REGEX.to_owned());
+        }
+        match self {
+            // BigQuery rejects quoted newlines unless `--allow_quoted_newlines`
+            // is passed, and allowing them makes loads much slower.
+            Preset::BigQuery => opt.replace_newlines = true,
+            // Redshift and Snowflake strip unquoted whitespace if asked to, so
+            // we make sure it's quoted.
+            Preset::Redshift | Preset::Snowflake => {
+                if !opt.trim_whitespace {
+                    opt.quote_leading_whitespace = true;
+                }
+            }
+            Preset::Postgres => {}
+        }
+        // Longer cells would fail the whole load.
+        let max_cell_bytes = match self {
+            Preset::Redshift => Some(REDSHIFT_MAX_CELL_BYTES),
+            Preset::Snowflake => Some(SNOWFLAKE_MAX_CELL_BYTES),
+            Preset::BigQuery | Preset::Postgres => None,
+        };
+        if opt.max_cell_bytes.is_none() {
+            opt.max_cell_bytes = max_cell_bytes;
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Preset> {
+        match s {
+            "bigquery" => Ok(Preset::BigQuery),
+            "redshift" => Ok(Preset::Redshift),
+            "snowflake" => Ok(Preset::Snowflake),
+            "postgres" => Ok(Preset::Postgres),
+            _ => Err(format_err!("unknown preset: '{}'", s)),
+        }
+    }
+}
+
+#[test]
+fn applies_presets() {
+    use structopt::StructOpt;
+
+    let mut opt = Opt::from_iter(["scrubcsv", "--null", "NA"]);
+    Preset::BigQuery.apply(&mut opt);
+    assert!(opt.replace_newlines && opt.ensure_utf8);
+    assert_eq!(opt.null.as_deref(), Some("NA"));
+
+    let mut opt = Opt::from_iter(["scrubcsv", "--trim-whitespace"]);
+    Preset::Redshift.apply(&mut opt);
+    assert!(!opt.replace_newlines && !opt.quote_leading_whitespace);
+    assert_eq!(opt.null.as_deref(), Some(NULL_REGEX));
+    assert_eq!(opt.max_cell_bytes, Some(REDSHIFT_MAX_CELL_BYTES));
+}
@@ -0,0 +1,688 @@
+//! Per-column statistics about the data we output, written as a JSON
+//! profile.
+
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BinaryHeap, HashMap},
+    fmt, fs,
+    hash::{Hash, Hasher},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::errors::*;
+
+/// A profile of an entire file.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Profile {
+    /// The number of data rows we saw.
+    #[serde(default)]
+    pub rows: u64,
+    /// Information about each column, in order.
+    pub columns: Vec<ColumnProfile>,
+}
+
+impl Profile {
+    /// Read a profile from `path`.
+    pub fn read(path: &Path) -> Result<Profile> {
+        let json = fs::read_to_string(path)
+            .with_context(|_| format!("cannot read profile {}", path.display()))?;
+        let profile = serde_json::from_str(&json)
+            .with_context(|_| format!("cannot parse profile {}", path.display()))?;
+        Ok(profile)
+    }
+
+    /// Write this profile to `path` as JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+            .with_context(|_| format!("cannot write profile to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Describe any significant differences between this profile and an
+    /// earlier `baseline`. A change in a column's fraction of empty values is
+    /// significant if it's larger than `max_null_rate_change`.
+    pub fn drift_from(
+        &self,
+        baseline: &Profile,
+        max_null_rate_change: f64,
+    ) -> Vec<String> {
+        let mut drift = vec![];
+        for column in &self.columns {
+            let old = match baseline.columns.iter().find(|c| c.name == column.name) {
+                Some(old) => old,
+                None => {
+                    drift.push(format!("new column {:?}", column.name));
+                    continue;
+                }
+            };
+            let old_rate = old.null_rate(baseline.rows);
+            let new_rate = column.null_rate(self.rows);
+            if (new_rate - old_rate).abs() > max_null_rate_change {
+                drift.push(format!(
+                    "column {:?} null rate changed from {:.1}% to {:.1}%",
+                    column.name,
+                    old_rate * 100.0,
+                    new_rate * 100.0,
+                ));
+            }
+            if let (Some(old_type), Some(new_type)) =
+                (old.value_type, column.value_type)
+            {
+                if old_type != new_type {
+                    drift.push(format!(
+                        "column {:?} type changed from {} to {}",
+                        column.name, old_type, new_type,
+                    ));
+                }
+            }
+        }
+        for old in &baseline.columns {
+            if !self.columns.iter().any(|c| c.name == old.name) {
+                drift.push(format!("missing column {:?}", old.name));
+            }
+        }
+        drift
+    }
+}
+
+/// A profile of a single column.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ColumnProfile {
+    /// The name of this column.
+    pub name: String,
+    /// The number of empty values in this column.
+    #[serde(default)]
+    pub null_count: u64,
+    /// The most specific type which fits all the non-empty values in this
+    /// column, if there were any.
+    #[serde(default, rename = "type")]
+    pub value_type: Option<ValueType>,
+    /// Approximately how many different non-empty values this column has.
+    #[serde(default)]
+    pub distinct_estimate: u64,
+    /// The length of the shortest non-empty value in this column, in
+    /// characters.
+    #[serde(default)]
+    pub min_length: usize,
+    /// The length of the longest value in this column, in characters.
+    #[serde(default)]
+    pub max_length: usize,
+    /// The smallest numeric value in this column, if there were any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    /// The largest numeric value in this column, if there were any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    /// The mean of the numeric values in this column, if there were any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean: Option<f64>,
+    /// The first few different non-empty values in this column.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<String>,
+    /// The most frequent values in this column, most frequent first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top_values: Vec<ValueCount>,
+}
+
+impl ColumnProfile {
+    /// What fraction of `rows` had an empty value in this column?
+    fn null_rate(&self, rows: u64) -> f64 {
+        if rows == 0 {
+            0.0
+        } else {
+            self.null_count as f64 / rows as f64
+        }
+    }
+}
+
+/// The type of a value, as best we can guess.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    /// `true` or `false`, in any case.
+    #[serde(alias = "bool")]
+    Boolean,
+    /// A whole number.
+    #[serde(alias = "int")]
+    Integer,
+    /// A number with a decimal point or exponent.
+    #[serde(alias = "float", alias = "number")]
+    Decimal,
+    /// A `YYYY-MM-DD` date.
+    Date,
+    /// Anything else.
+    String,
+}
+
+lazy_static! {
+    static ref INTEGER_RE: Regex =
+        Regex::new(r#"^[-+]?\d+$"#).expect("regex in source code is unparseable");
+    static ref DECIMAL_RE: Regex =
+        Regex::new(r#"^[-+]?(?:\d+\.\d*|\.\d+|\d+(?:\.\d*)?[eE][-+]?\d+)$"#)
+            .expect("regex in source code is unparseable");
+    static ref BOOLEAN_RE: Regex = Regex::new(r#"^(?i)(?:true|false)$"#)
+        .expect("regex in source code is unparseable");
+    static ref DATE_RE: Regex = Regex::new(r#"^\d{4}-\d{2}-\d{2}$"#)
+        .expect("regex in source code is unparseable");
+}
+
+impl ValueType {
+    /// Guess the type of a non-empty value.
+    pub fn of(value: &[u8]) -> ValueType {
+        if INTEGER_RE.is_match(value) {
+            ValueType::Integer
+        } else if DECIMAL_RE.is_match(value) {
+            ValueType::Decimal
+        } else if BOOLEAN_RE.is_match(value) {
+            ValueType::Boolean
+        } else if DATE_RE.is_match(value) {
+            ValueType::Date
+        } else {
+            ValueType::String
+        }
+    }
+
+    /// Return the most specific type which fits both `self` and `other`.
+    pub fn merge(self, other: ValueType) -> ValueType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ValueType::Integer, ValueType::Decimal)
+            | (ValueType::Decimal, ValueType::Integer) => ValueType::Decimal,
+            _ => ValueType::String,
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ValueType::Boolean => "boolean",
+            ValueType::Integer => "integer",
+            ValueType::Decimal => "decimal",
+            ValueType::Date => "date",
+            ValueType::String => "string",
+        };
+        name.fmt(f)
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ValueType> {
+        match s {
+            "boolean" | "bool" => Ok(ValueType::Boolean),
+            "integer" | "int" => Ok(ValueType::Integer),
+            "decimal" | "float" | "number" => Ok(ValueType::Decimal),
+            "date" => Ok(ValueType::Date),
+            "string" => Ok(ValueType::String),
+            _ => Err(format_err!("unknown type: '{}'", s)),
+        }
+    }
+}
+
+#[test]
+fn guesses_and_merges_types() {
+    assert_eq!(ValueType::of(b"-12"), ValueType::Integer);
+    assert_eq!(ValueType::of(b"1.5e3"), ValueType::Decimal);
+    assert_eq!(ValueType::of(b"TRUE"), ValueType::Boolean);
+    assert_eq!(ValueType::of(b"2024-01-03"), ValueType::Date);
+    assert_eq!(ValueType::of(b"x1"), ValueType::String);
+    assert_eq!(
+        ValueType::Integer.merge(ValueType::Decimal),
+        ValueType::Decimal
+    );
+    assert_eq!(ValueType::Date.merge(ValueType::Integer), ValueType::String);
+}
+
+/// A value and how often it occurred.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ValueCount {
+    /// The value.
+    pub value: String,
+    /// How many times we're sure we saw it.
+    pub count: u64,
+    /// How many more times we may have seen it without counting. This is only
+    /// non-zero for columns with many distinct values.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub error: u64,
+}
+
+/// Is `n` zero? For `skip_serializing_if`.
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Collects statistics about each column as we process rows.
+#[derive(Debug)]
+pub struct Profiler {
+    /// The number of rows we've seen.
+    rows: u64,
+    /// Our per-column statistics.
+    columns: Vec<ColumnProfiler>,
+}
+
+impl Profiler {
+    /// Create a profiler for columns named `names`, tracking the `top_values`
+    /// most frequent values in each, and keeping `samples` example values.
+    pub fn new<'a, I>(names: I, top_values: usize, samples: usize) -> Profiler
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let columns = names
+            .into_iter()
+            .map(|name| ColumnProfiler {
+                name: String::from_utf8_lossy(name).into_owned(),
+                null_count: 0,
+                value_type: None,
+                distinct: DistinctCounter::new(),
+                min_length: None,
+                max_length: 0,
+                numbers: None,
+                max_samples: samples,
+                samples: vec![],
+                top_values: TopValues::new(top_values),
+            })
+            .collect();
+        Profiler { rows: 0, columns }
+    }
+
+    /// Record the values in a single row.
+    pub fn observe_row<'a, I>(&mut self, row: I)
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        self.rows += 1;
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            if value.is_empty() {
+                column.null_count += 1;
+            } else {
+                let value_type = ValueType::of(value);
+                column.value_type = Some(match column.value_type {
+                    Some(existing) => existing.merge(value_type),
+                    None => value_type,
+                });
+                column.distinct.observe(value);
+                let length = String::from_utf8_lossy(value).chars().count();
+                column.min_length =
+                    Some(column.min_length.map_or(length, |min| min.min(length)));
+                column.max_length = column.max_length.max(length);
+                if let ValueType::Integer | ValueType::Decimal = value_type {
+                    if let Some(n) = std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|s| s.parse::<f64>().ok())
+                    {
+                        column.numbers = Some(match column.numbers {
+                            Some(NumericStats {
+                                min,
+                                max,
+                                sum,
+                                count,
+                            }) => NumericStats {
+                                min: min.min(n),
+                                max: max.max(n),
+                                sum: sum + n,
+                                count: count + 1,
+                            },
+                            None => NumericStats {
+                                min: n,
+                                max: n,
+                                sum: n,
+                                count: 1,
+                            },
+                        });
+                    }
+                }
+                if column.samples.len() < column.max_samples
+                    && !column.samples.iter().any(|s| s == value)
+                {
+                    column.samples.push(value.to_owned());
+                }
+            }
+            column.top_values.observe(value);
+        }
+    }
+
+    /// Summarize everything we've seen.
+    pub fn to_profile(&self) -> Profile {
+        Profile {
+            rows: self.rows,
+            columns: self
+                .columns
+                .iter()
+                .map(|column| ColumnProfile {
+                    name: column.name.clone(),
+                    null_count: column.null_count,
+                    value_type: column.value_type,
+                    distinct_estimate: column.distinct.estimate(),
+                    min_length: column.min_length.unwrap_or(0),
+                    max_length: column.max_length,
+                    min: column.numbers.map(|n| n.min),
+                    max: column.numbers.map(|n| n.max),
+                    mean: column.numbers.map(|n| n.sum / n.count as f64),
+                    samples: column
+                        .samples
+                        .iter()
+                        .map(|s| String::from_utf8_lossy(s).into_owned())
+                        .collect(),
+                    top_values: column.top_values.top(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Statistics for a single column.
+#[derive(Debug)]
+struct ColumnProfiler {
+    /// The name of this column.
+    name: String,
+    /// The number of empty values in this column.
+    null_count: u64,
+    /// The type of the non-empty values we've seen.
+    value_type: Option<ValueType>,
+    /// Estimates how many different values we've seen.
+    distinct: DistinctCounter,
+    /// The length of the shortest non-empty value we've seen, in characters.
+    min_length: Option<usize>,
+    /// The length of the longest value we've seen, in characters.
+    max_length: usize,
+    /// Statistics about the numbers we've seen.
+    numbers: Option<NumericStats>,
+    /// How many samples we want.
+    max_samples: usize,
+    /// The first different values we've seen.
+    samples: Vec<Vec<u8>>,
+    /// The most frequent values in this column.
+    top_values: TopValues,
+}
+
+/// Statistics about the numbers in a column.
+#[derive(Clone, Copy, Debug)]
+struct NumericStats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+
+/// How many bits of each hash a `DistinctCounter` uses to pick a register.
+const DISTINCT_INDEX_BITS: u32 = 12;
+
+/// Estimates the number of distinct values in a stream, using a fixed 4 KB
+/// of memory. This is the HyperLogLog algorithm of Flajolet et al., with
+/// linear counting for small numbers of values. Estimates are usually within
+/// a few percent.
+#[derive(Debug)]
+struct DistinctCounter {
+    /// For each register, the largest number of leading zeros, plus one, we've
+    /// seen in the hashes assigned to it.
+    registers: Vec<u8>,
+}
+
+impl DistinctCounter {
+    fn new() -> DistinctCounter {
+        DistinctCounter {
+            registers: vec![0; 1 << DISTINCT_INDEX_BITS],
+        }
+    }
+
+    /// Record that we saw `value`.
+    fn observe(&mut self, value: &[u8]) {
+        // `DefaultHasher::new` always uses the same keys, so our estimates
+        // are repeatable.
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let idx = (hash >> (64 - DISTINCT_INDEX_BITS)) as usize;
+        let rest = hash << DISTINCT_INDEX_BITS;
+        let rank = (rest.leading_zeros() + 1).min(64 - DISTINCT_INDEX_BITS + 1) as u8;
+        self.registers[idx] = self.registers[idx].max(rank);
+    }
+
+    /// Estimate how many distinct values we've seen.
+    fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let sum = self
+            .registers
+            .iter()
+            .map(|&r| 2f64.powi(-i32::from(r)))
+            .sum::<f64>();
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+#[test]
+fn distinct_counter_estimates_distinct_values() {
+    let mut counter = DistinctCounter::new();
+    assert_eq!(counter.estimate(), 0);
+    for value in &["a", "b", "a", "c"] {
+        counter.observe(value.as_bytes());
+    }
+    assert_eq!(counter.estimate(), 3);
+    for i in 0..100_000 {
+        counter.observe(format!("value{}", i % 50_000).as_bytes());
+    }
+    let estimate = counter.estimate() as f64;
+    assert!(
+        (estimate - 50_003.0).abs() / 50_003.0 < 0.05,
+        "{}",
+        estimate
+    );
+}
+
+#[test]
+fn profiler_collects_column_statistics() {
+    let mut profiler = Profiler::new(vec![&b"n"[..], b"s"], 0, 2);
+    for row in &[["1", "bb"], ["", "a"], ["2.5", "bb"], ["-3", "cccc"]] {
+        profiler.observe_row(row.iter().map(|v| v.as_bytes()));
+    }
+    let profile = profiler.to_profile();
+    let n = &profile.columns[0];
+    assert_eq!(n.null_count, 1);
+    assert_eq!(n.distinct_estimate, 3);
+    assert_eq!(
+        (n.min, n.max, n.mean),
+        (Some(-3.0), Some(2.5), Some(0.5 / 3.0))
+    );
+    assert_eq!((n.min_length, n.max_length), (1, 3));
+    let s = &profile.columns[1];
+    assert_eq!(s.samples, vec!["bb", "a"]);
+    assert_eq!(s.min, None);
+    assert_eq!((s.min_length, s.max_length), (1, 4));
+}
+
+/// How many counters to keep for each value we want to report. More counters
+/// give more accurate results.
+const COUNTERS_PER_TOP_VALUE: usize = 10;
+
+/// Tracks the most frequent values in a stream, using a fixed amount of
+/// memory. This is the "Space-Saving" algorithm of Metwally, Agrawal and El
+/// Abbadi: when we run out of counters, we evict the smallest one and let the
+/// new value inherit its count, remembering that the inherited part may be
+/// wrong.
+#[derive(Debug)]
+struct TopValues {
+    /// How many values we want to report.
+    k: usize,
+    /// Our counters.
+    counters: Vec<Counter>,
+    /// Which counter each value is in.
+    index: HashMap<Vec<u8>, usize>,
+    /// Our counters by count, smallest first. Counts only go up, so instead of
+    /// updating this on every value, we let it fall behind and fix it up when
+    /// we're looking for a counter to evict.
+    by_count: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+/// A single value we're counting.
+#[derive(Debug)]
+struct Counter {
+    /// The value.
+    value: Vec<u8>,
+    /// How many times we've seen it, including any count we inherited.
+    count: u64,
+    /// How much of `count` we inherited from the value we evicted.
+    error: u64,
+}
+
+impl TopValues {
+    /// Track the `k` most frequent values.
+    fn new(k: usize) -> TopValues {
+        TopValues {
+            k,
+            counters: vec![],
+            index: HashMap::new(),
+            by_count: BinaryHeap::new(),
+        }
+    }
+
+    /// Record that we saw `value`.
+    fn observe(&mut self, value: &[u8]) {
+        if self.k == 0 {
+            return;
+        }
+        if let Some(&i) = self.index.get(value) {
+            self.counters[i].count += 1;
+        } else if self.counters.len() < self.k * COUNTERS_PER_TOP_VALUE {
+            let i = self.counters.len();
+            self.counters.push(Counter {
+                value: value.to_owned(),
+                count: 1,
+                error: 0,
+            });
+            self.index.insert(value.to_owned(), i);
+            self.by_count.push(Reverse((1, i)));
+        } else {
+            let i = self.evict();
+            let counter = &mut self.counters[i];
+            self.index.remove(&counter.value);
+            counter.value = value.to_owned();
+            counter.error = counter.count;
+            counter.count += 1;
+            self.index.insert(value.to_owned(), i);
+            self.by_count.push(Reverse((counter.count, i)));
+        }
+    }
+
+    /// Find the counter with the smallest count, and remove it from
+    /// `by_count`.
+    fn evict(&mut self) -> usize {
+        loop {
+            let Reverse((count, i)) = self
+                .by_count
+                .pop()
+                .expect("should always have counters when full");
+            if count == self.counters[i].count {
+                return i;
+            }
+            // This counter has gone up since we last saw it, so put it back in
+            // the right place.
+            self.by_count.push(Reverse((self.counters[i].count, i)));
+        }
+    }
+
+    /// Return the `k` most frequent values, most frequent first. Ties are
+    /// broken by value, so our output is stable.
+    fn top(&self) -> Vec<ValueCount> {
+        let mut counters = self.counters.iter().collect::<Vec<_>>();
+        counters.sort_by(|a, b| {
+            let (a_count, b_count) = (a.count - a.error, b.count - b.error);
+            b_count
+                .cmp(&a_count)
+                .then_with(|| a.error.cmp(&b.error))
+                .then_with(|| a.value.cmp(&b.value))
+        });
+        counters
+            .into_iter()
+            .take(self.k)
+            .map(|counter| ValueCount {
+                value: String::from_utf8_lossy(&counter.value).into_owned(),
+                count: counter.count - counter.error,
+                error: counter.error,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn top_values_finds_frequent_values() {
+    let mut top = TopValues::new(2);
+    for i in 0..1000 {
+        top.observe(format!("unique{}", i).as_bytes());
+        if i % 3 == 0 {
+            top.observe(b"UNKNOWN");
+        }
+        if i % 5 == 0 {
+            top.observe(b"9999-12-31");
+        }
+    }
+    let found = top.top().into_iter().map(|vc| vc.value).collect::<Vec<_>>();
+    assert_eq!(found, vec!["UNKNOWN", "9999-12-31"]);
+}
+
+#[test]
+fn top_values_does_not_overcount_evicted_values() {
+    let mut top = TopValues::new(1);
+    for i in 0..1000 {
+        top.observe(format!("id{}", i).as_bytes());
+    }
+    for vc in top.top() {
+        assert_eq!(vc.count, 1, "{} seen once", vc.value);
+        assert!(vc.error > 0);
+        assert!(vc.count + vc.error <= 1000);
+    }
+}
+
+#[test]
+fn top_values_counts_exactly_when_there_is_room() {
+    let mut top = TopValues::new(3);
+    for value in &["a", "b", "a", "c", "a", "b"] {
+        top.observe(value.as_bytes());
+    }
+    let found = top
+        .top()
+        .into_iter()
+        .map(|vc| (vc.value, vc.count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        found,
+        vec![
+            ("a".to_owned(), 3),
+            ("b".to_owned(), 2),
+            ("c".to_owned(), 1)
+        ]
+    );
+}
+
+#[test]
+fn detects_drift() {
+    let mut old = Profiler::new(vec![&b"id"[..], b"email", b"gone"], 0, 0);
+    old.observe_row(vec![&b"1"[..], b"a@example.com", b""]);
+    old.observe_row(vec![&b"2"[..], b"b@example.com", b""]);
+    let mut new = Profiler::new(vec![&b"id"[..], b"email", b"extra"], 0, 0);
+    new.observe_row(vec![&b"x1"[..], b"", b""]);
+    new.observe_row(vec![&b"2"[..], b"", b""]);
+    let drift = new.to_profile().drift_from(&old.to_profile(), 0.1);
+    assert_eq!(
+        drift,
+        vec![
+            r#"column "id" type changed from integer to string"#,
+            r#"column "email" null rate changed from 0.0% to 100.0%"#,
+            r#"new column "extra""#,
+            r#"missing column "gone""#,
+        ]
+    );
+}
@@ -0,0 +1,219 @@
+//! Repair stray quotes inside quoted fields, like `"Broken " quotes"`.
+//!
+//! By the time the CSV parser has handed us a record, it has already thrown
+//! away the information we need, so we work on the raw input bytes instead.
+
+use log::debug;
+use std::{
+    cell::Cell,
+    io::{self, prelude::*},
+    rc::Rc,
+    str::FromStr,
+};
+
+use crate::errors::*;
+
+/// How should we interpret a quote in the middle of a quoted field?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuoteRepair {
+    /// Treat it as an embedded quote if the field is properly closed later on
+    /// the same line, and as a closing quote otherwise.
+    Smart,
+    /// Always treat it as an embedded quote.
+    Escape,
+    /// Always remove it.
+    Strip,
+}
+
+impl FromStr for QuoteRepair {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<QuoteRepair> {
+        match s {
+            "smart" => Ok(QuoteRepair::Smart),
+            "escape" => Ok(QuoteRepair::Escape),
+            "strip" => Ok(QuoteRepair::Strip),
+            _ => Err(format_err!("unknown quote repair strategy: '{}'", s)),
+        }
+    }
+}
+
+/// A reader which repairs stray quotes as it goes.
+pub struct QuoteRepairReader<R: BufRead> {
+    /// The reader we wrap.
+    inner: R,
+    /// Our repair strategy.
+    strategy: QuoteRepair,
+    /// Our field delimiter.
+    delimiter: u8,
+    /// Our quote character.
+    quote: u8,
+    /// The raw line we're working on.
+    line: Vec<u8>,
+    /// The repaired version of `line`.
+    repaired: Vec<u8>,
+    /// How much of `repaired` we've already returned.
+    pos: usize,
+    /// The 1-based number of the current line.
+    line_number: u64,
+    /// Are we inside a quoted field at the end of the current line?
+    in_quotes: bool,
+    /// How many repairs we've made. This is shared with the caller, so it can
+    /// still be read after we've been handed to the CSV parser.
+    repairs: Rc<Cell<u64>>,
+}
+
+impl<R: BufRead> QuoteRepairReader<R> {
+    /// Create a new reader. Returns the reader and a counter of repairs made.
+    pub fn new(
+        inner: R,
+        strategy: QuoteRepair,
+        delimiter: u8,
+        quote: u8,
+    ) -> (QuoteRepairReader<R>, Rc<Cell<u64>>) {
+        let repairs = Rc::new(Cell::new(0));
+        let rdr = QuoteRepairReader {
+            inner,
+            strategy,
+            delimiter,
+            quote,
+            line: vec![],
+            repaired: vec![],
+            pos: 0,
+            line_number: 0,
+            in_quotes: false,
+            repairs: repairs.clone(),
+        };
+        (rdr, repairs)
+    }
+
+    /// Can `b` legally follow a closing quote?
+    fn ends_field(&self, b: Option<&u8>) -> bool {
+        match b {
+            None | Some(b'\n') | Some(b'\r') => true,
+            Some(&b) => b == self.delimiter,
+        }
+    }
+
+    /// Repair `self.line` into `self.repaired`.
+    fn repair_line(&mut self) {
+        self.repaired.clear();
+        self.pos = 0;
+        let mut at_field_start = !self.in_quotes;
+        let mut i = 0;
+        while i < self.line.len() {
+            let b = self.line[i];
+            if !self.in_quotes {
+                if b == self.quote && at_field_start {
+                    self.in_quotes = true;
+                }
+                at_field_start = b == self.delimiter || b == b'\n' || b == b'\r';
+                self.repaired.push(b);
+            } else if b != self.quote {
+                self.repaired.push(b);
+            } else if self.line.get(i + 1) == Some(&self.quote) {
+                // A correctly escaped quote.
+                self.repaired.extend_from_slice(&[b, b]);
+                i += 1;
+            } else if self.ends_field(self.line.get(i + 1)) {
+                // A correctly placed closing quote.
+                self.in_quotes = false;
+                self.repaired.push(b);
+            } else {
+                // A stray quote. Figure out what it means.
+                let embedded = match self.strategy {
+                    QuoteRepair::Escape | QuoteRepair::Strip => true,
+                    QuoteRepair::Smart => self.closes_later(i + 1),
+                };
+                self.repairs.set(self.repairs.get() + 1);
+                if !embedded {
+                    debug!(
+                        "line {}: treating stray quote at byte {} as closing quote",
+                        self.line_number,
+                        i + 1,
+                    );
+                    self.in_quotes = false;
+                    self.repaired.push(b);
+                } else if self.strategy == QuoteRepair::Strip {
+                    debug!(
+                        "line {}: removing stray quote at byte {}",
+                        self.line_number,
+                        i + 1,
+                    );
+                } else {
+                    debug!(
+                        "line {}: treating stray quote at byte {} as embedded quote",
+                        self.line_number,
+                        i + 1,
+                    );
+                    self.repaired.extend_from_slice(&[b, b]);
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// Is there a properly placed closing quote at or after `start` on the
+    /// current line?
+    fn closes_later(&self, start: usize) -> bool {
+        (start..self.line.len()).any(|j| {
+            self.line[j] == self.quote && self.ends_field(self.line.get(j + 1))
+        })
+    }
+}
+
+impl<R: BufRead> Read for QuoteRepairReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.repaired.len() {
+            self.line.clear();
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            self.line_number += 1;
+            self.repair_line();
+        }
+        let count = buf.len().min(self.repaired.len() - self.pos);
+        buf[..count].copy_from_slice(&self.repaired[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+fn repair(input: &str, strategy: QuoteRepair) -> (String, u64) {
+    let (mut rdr, repairs) =
+        QuoteRepairReader::new(input.as_bytes(), strategy, b',', b'"');
+    let mut out = String::new();
+    rdr.read_to_string(&mut out).unwrap();
+    let repairs = repairs.get();
+    (out, repairs)
+}
+
+#[test]
+fn repairs_stray_quotes() {
+    let input = "a,\"Broken \" quotes\",\"ok \"\"x\"\"\"\n\"multi\nline\",\"x\" y\n";
+    assert_eq!(
+        repair(input, QuoteRepair::Escape),
+        (
+            "a,\"Broken \"\" quotes\",\"ok \"\"x\"\"\"\n\"multi\nline\",\"x\"\" y\n"
+                .to_owned(),
+            2
+        ),
+    );
+    assert_eq!(
+        repair(input, QuoteRepair::Strip),
+        (
+            "a,\"Broken  quotes\",\"ok \"\"x\"\"\"\n\"multi\nline\",\"x y\n"
+                .to_owned(),
+            2
+        ),
+    );
+    assert_eq!(
+        repair(input, QuoteRepair::Smart),
+        (
+            "a,\"Broken \"\" quotes\",\"ok \"\"x\"\"\"\n\"multi\nline\",\"x\" y\n"
+                .to_owned(),
+            2
+        ),
+    );
+}
@@ -0,0 +1,254 @@
+//! Extra quoting for values which other parsers might misread.
+
+use std::{
+    cell::RefCell,
+    io::{self, prelude::*},
+    rc::Rc,
+    str::FromStr,
+};
+
+use crate::errors::*;
+
+/// How should we escape quotes inside quoted values in our output?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputEscape {
+    /// Double them, as in `"a ""quoted"" word"`. This is standard CSV.
+    Doubled,
+    /// Put a backslash before them, as in `"a \"quoted\" word"`. Some Hive
+    /// SerDes and older Spark readers only understand this.
+    Backslash,
+}
+
+impl OutputEscape {
+    /// Configure `builder` to use this escape style.
+    pub fn configure(self, builder: &mut csv::WriterBuilder) {
+        match self {
+            OutputEscape::Doubled => builder.double_quote(true),
+            OutputEscape::Backslash => builder.double_quote(false).escape(b'\\'),
+        };
+    }
+
+    /// The byte we put before a quote to escape it.
+    fn escape_byte(self) -> u8 {
+        match self {
+            OutputEscape::Doubled => b'"',
+            OutputEscape::Backslash => b'\\',
+        }
+    }
+}
+
+impl FromStr for OutputEscape {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputEscape> {
+        match s {
+            "doubled" => Ok(OutputEscape::Doubled),
+            "backslash" => Ok(OutputEscape::Backslash),
+            _ => Err(format_err!("unknown output escape style: '{}'", s)),
+        }
+    }
+}
+
+/// How we format our output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputFormat {
+    /// How to escape quotes inside quoted values.
+    pub escape: OutputEscape,
+    /// The delimiter between values.
+    pub delimiter: u8,
+    /// End records with `\r\n` instead of `\n`.
+    pub crlf: bool,
+}
+
+impl OutputFormat {
+    /// Configure `builder` to write this format.
+    pub fn configure(self, builder: &mut csv::WriterBuilder) {
+        self.escape.configure(builder);
+        builder.delimiter(self.delimiter);
+        if self.crlf {
+            builder.terminator(csv::Terminator::CRLF);
+        }
+    }
+
+    /// Configure `builder` to read back what we wrote in this format.
+    pub fn configure_reader(self, builder: &mut csv::ReaderBuilder) {
+        if self.escape == OutputEscape::Backslash {
+            builder.double_quote(false).escape(Some(b'\\'));
+        }
+        builder.delimiter(self.delimiter);
+    }
+}
+
+impl Default for OutputFormat {
+    /// Standard CSV.
+    fn default() -> Self {
+        OutputFormat {
+            escape: OutputEscape::Doubled,
+            delimiter: b',',
+            crlf: false,
+        }
+    }
+}
+
+/// Does `val` start or end with whitespace? Some CSV parsers silently strip
+/// this unless the value is quoted.
+fn has_edge_whitespace(val: &[u8]) -> bool {
+    let is_space = |b: &u8| b.is_ascii_whitespace();
+    val.first().is_some_and(is_space) || val.last().is_some_and(is_space)
+}
+
+/// An output stream which can be shared between a `csv::Writer` and code
+/// which needs to write raw bytes.
+#[derive(Debug)]
+pub struct SharedOutput<W: Write>(Rc<RefCell<W>>);
+
+impl<W: Write> SharedOutput<W> {
+    /// Wrap `inner` so that it can be shared.
+    pub fn new(inner: W) -> SharedOutput<W> {
+        SharedOutput(Rc::new(RefCell::new(inner)))
+    }
+
+    /// Get our output back. Returns `None` if it's still being shared.
+    pub fn into_inner(self) -> Option<W> {
+        Rc::try_unwrap(self.0).ok().map(RefCell::into_inner)
+    }
+}
+
+impl<W: Write> Clone for SharedOutput<W> {
+    fn clone(&self) -> Self {
+        SharedOutput(self.0.clone())
+    }
+}
+
+impl<W: Write> Write for SharedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Write `record` to `wtr`, quoting any values with leading or trailing
+/// whitespace. `csv` can only choose a quoting style for a whole writer, so
+/// when we need to, we flush `wtr` and write the row to `output` ourselves,
+/// formatting it the same way as `wtr`.
+pub fn write_record_quoting_edge_whitespace<W, I, F>(
+    wtr: &mut csv::Writer<SharedOutput<W>>,
+    output: &mut SharedOutput<W>,
+    format: OutputFormat,
+    record: I,
+) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = F>,
+    F: AsRef<[u8]>,
+{
+    let record = record.into_iter().collect::<Vec<F>>();
+    if !record.iter().any(|val| has_edge_whitespace(val.as_ref())) {
+        wtr.write_record(&record).context("cannot write record")?;
+        return Ok(());
+    }
+
+    let mut line = vec![];
+    for (i, val) in record.iter().enumerate() {
+        let val = val.as_ref();
+        if i > 0 {
+            line.push(format.delimiter);
+        }
+        let needs_quotes = has_edge_whitespace(val)
+            || val
+                .iter()
+                .any(|&b| b == format.delimiter || matches!(b, b'"' | b'\n' | b'\r'));
+        if needs_quotes {
+            line.push(b'"');
+            for &b in val {
+                if b == b'"' {
+                    line.push(format.escape.escape_byte());
+                }
+                line.push(b);
+            }
+            line.push(b'"');
+        } else {
+            line.extend_from_slice(val);
+        }
+    }
+    if format.crlf {
+        line.push(b'\r');
+    }
+    line.push(b'\n');
+    wtr.flush().context("cannot write record")?;
+    output.write_all(&line).context("cannot write record")?;
+    Ok(())
+}
+
+#[test]
+fn quotes_edge_whitespace() {
+    let mut output = SharedOutput::new(vec![]);
+    let mut wtr = csv::Writer::from_writer(output.clone());
+    for record in [&["a", "b c"][..], &[" a", "b\"", "c "], &["x,y", "\t"]] {
+        write_record_quoting_edge_whitespace(
+            &mut wtr,
+            &mut output,
+            OutputFormat::default(),
+            record,
+        )
+        .unwrap();
+    }
+    wtr.flush().unwrap();
+    let written = output.0.borrow().clone();
+    assert_eq!(
+        String::from_utf8(written).unwrap(),
+        "a,b c\n\" a\",\"b\"\"\",\"c \"\n\"x,y\",\"\t\"\n",
+    );
+}
+
+#[test]
+fn escapes_quotes_with_backslashes() {
+    let mut output = SharedOutput::new(vec![]);
+    let mut builder = csv::WriterBuilder::new();
+    OutputEscape::Backslash.configure(&mut builder);
+    let mut wtr = builder.from_writer(output.clone());
+    for record in [&["a\"b"][..], &[" a\"b"]] {
+        write_record_quoting_edge_whitespace(
+            &mut wtr,
+            &mut output,
+            OutputFormat {
+                escape: OutputEscape::Backslash,
+                ..OutputFormat::default()
+            },
+            record,
+        )
+        .unwrap();
+    }
+    wtr.flush().unwrap();
+    let written = output.0.borrow().clone();
+    assert_eq!(
+        String::from_utf8(written).unwrap(),
+        "\"a\\\"b\"\n\" a\\\"b\"\n",
+    );
+}
+
+#[test]
+fn quotes_edge_whitespace_in_other_formats() {
+    let format = OutputFormat {
+        delimiter: b'\t',
+        crlf: true,
+        ..OutputFormat::default()
+    };
+    let mut output = SharedOutput::new(vec![]);
+    let mut builder = csv::WriterBuilder::new();
+    format.configure(&mut builder);
+    let mut wtr = builder.from_writer(output.clone());
+    for record in [&["a", "b,c"][..], &[" a", "b\tc"]] {
+        write_record_quoting_edge_whitespace(&mut wtr, &mut output, format, record)
+            .unwrap();
+    }
+    wtr.flush().unwrap();
+    let written = output.0.borrow().clone();
+    assert_eq!(
+        String::from_utf8(written).unwrap(),
+        "a\tb,c\r\n\" a\"\t\"b\tc\"\r\n",
+    );
+}
@@ -0,0 +1,157 @@
+//! Copying well-formed input records straight to our output, for
+//! `--preserve-formatting`.
+//!
+//! `csv` doesn't give us the raw bytes of each record, but it does tell us
+//! where each record starts and ends. So we keep a copy of everything it
+//! reads, and throw it away once we're done with it.
+
+use std::{
+    cell::RefCell,
+    io::{self, prelude::*},
+    rc::{Rc, Weak},
+};
+
+/// The raw bytes that our CSV reader has read, but that we haven't finished
+/// with yet.
+#[derive(Debug, Default)]
+struct RawData {
+    /// The bytes we've kept.
+    bytes: Vec<u8>,
+    /// The input offset of `bytes[0]`.
+    base: u64,
+    /// How many bytes at the start of `bytes` we no longer need.
+    discarded: usize,
+}
+
+/// A reader which remembers what it reads, so that we can look up the raw
+/// bytes of records using `RawRecords`. Once the `RawRecords` is dropped, we
+/// stop remembering anything.
+pub struct RawRecorder<R: Read> {
+    inner: R,
+    data: Weak<RefCell<RawData>>,
+}
+
+impl<R: Read> RawRecorder<R> {
+    /// Wrap `inner`, returning the reader and a handle for looking up the raw
+    /// bytes it reads.
+    pub fn new(inner: R) -> (RawRecorder<R>, RawRecords) {
+        let data = Rc::new(RefCell::new(RawData::default()));
+        let recorder = RawRecorder {
+            inner,
+            data: Rc::downgrade(&data),
+        };
+        (recorder, RawRecords(data))
+    }
+}
+
+impl<R: Read> Read for RawRecorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if let Some(data) = self.data.upgrade() {
+            data.borrow_mut().bytes.extend_from_slice(&buf[..count]);
+        }
+        Ok(count)
+    }
+}
+
+/// Looks up the raw bytes of records read through a `RawRecorder`.
+pub struct RawRecords(Rc<RefCell<RawData>>);
+
+impl RawRecords {
+    /// We'll never ask for anything before `offset` again, so forget it.
+    fn discard_to(&self, offset: u64) {
+        let mut data = self.0.borrow_mut();
+        let keep_from = (offset - data.base) as usize;
+        data.discarded = data.discarded.max(keep_from.min(data.bytes.len()));
+        // Only move our data once we've discarded a fair bit of it, so that
+        // we don't copy the whole buffer for every record.
+        if data.discarded > 64 * 1024 && data.discarded * 2 > data.bytes.len() {
+            let discarded = data.discarded;
+            data.bytes.drain(..discarded);
+            data.base += discarded as u64;
+            data.discarded = 0;
+        }
+    }
+
+    /// Append the record between `start` and `end` to `out`, always ending it
+    /// with a single LF, whatever it originally ended with.
+    ///
+    /// `csv` may include parts of line endings and blank lines in the range it
+    /// reports for a record, so we remove any CR and LF characters from both
+    /// ends. Those can only occur inside quotes, so this never changes the
+    /// record itself.
+    fn append_record(&self, start: u64, end: u64, out: &mut Vec<u8>) {
+        let data = self.0.borrow();
+        let from = (start - data.base) as usize;
+        let to = (end - data.base) as usize;
+        let raw = &data.bytes[from..to];
+        let is_content = |&b: &u8| b != b'\r' && b != b'\n';
+        let first = raw.iter().position(is_content).unwrap_or(raw.len());
+        let last = raw.iter().rposition(is_content).map_or(first, |i| i + 1);
+        out.extend_from_slice(&raw[first..last]);
+        out.push(b'\n');
+    }
+}
+
+/// Writes raw records to our output.
+pub struct RawWriter {
+    records: RawRecords,
+    buffer: Vec<u8>,
+    capacity: usize,
+}
+
+impl RawWriter {
+    /// Write records from `records`, buffering up to `capacity` bytes.
+    pub fn new(records: RawRecords, capacity: usize) -> RawWriter {
+        RawWriter {
+            records,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// We've started reading the record at `offset`, so we'll never need
+    /// anything before it.
+    pub fn discard_to(&self, offset: u64) {
+        self.records.discard_to(offset);
+    }
+
+    /// Write the record between `start` and `end` to `out`.
+    pub fn write_record<W: Write>(
+        &mut self,
+        start: u64,
+        end: u64,
+        out: &mut W,
+    ) -> io::Result<()> {
+        self.records.append_record(start, end, &mut self.buffer);
+        if self.buffer.len() >= self.capacity {
+            self.write_buffered(out)?;
+        }
+        Ok(())
+    }
+
+    /// Write any records we've buffered to `out`. This doesn't flush `out`.
+    pub fn write_buffered<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+#[test]
+fn records_raw_bytes() {
+    let input = "a,b\n\"1\",\"x\ny\"\r\n\n3,4";
+    let (recorder, raw) = RawRecorder::new(input.as_bytes());
+    let mut rdr = csv::Reader::from_reader(recorder);
+    let mut record = csv::ByteRecord::new();
+    let mut raw = RawWriter::new(raw, 4);
+    let mut out = vec![];
+    while rdr.read_byte_record(&mut record).unwrap() {
+        let start = record.position().unwrap().byte();
+        raw.discard_to(start);
+        raw.write_record(start, rdr.position().byte(), &mut out)
+            .unwrap();
+    }
+    raw.write_buffered(&mut out).unwrap();
+    assert_eq!(out, b"\"1\",\"x\ny\"\n3,4\n");
+}
@@ -0,0 +1,126 @@
+//! Recovery from unbalanced quotes.
+//!
+//! A single stray opening quote will cause the CSV parser to keep reading
+//! until it sees another quote, swallowing any number of following lines into
+//! one giant field. We try to detect this, close the field at the first line
+//! break, and re-parse whatever it swallowed.
+
+use csv::ByteRecord;
+
+use crate::errors::*;
+
+/// Settings for our recovery heuristics.
+#[derive(Debug)]
+pub struct RunawayQuoteRecovery {
+    /// Our field delimiter.
+    pub delimiter: u8,
+    /// Our quote character, or `None` if quoting is disabled.
+    pub quote: Option<u8>,
+    /// Any field spanning more than this many lines is always assumed to be a
+    /// runaway quoted field.
+    pub max_field_lines: usize,
+}
+
+impl RunawayQuoteRecovery {
+    /// Find the first field in `record` which looks like it was caused by an
+    /// unbalanced quote. Fields containing line breaks are suspicious if the
+    /// record has the wrong number of columns, or if they're very long.
+    pub fn find_runaway_field(
+        &self,
+        record: &ByteRecord,
+        expected_cols: usize,
+    ) -> Option<usize> {
+        record.iter().position(|field| {
+            let line_breaks = field.iter().filter(|&&b| b == b'\n').count();
+            line_breaks > 0
+                && (record.len() != expected_cols
+                    || line_breaks >= self.max_field_lines)
+        })
+    }
+
+    /// Split `record` at the first line break in field `idx`, returning the
+    /// repaired first record followed by any records we could rescue from the
+    /// swallowed lines.
+    pub fn split_runaway_field(
+        &self,
+        record: &ByteRecord,
+        idx: usize,
+    ) -> Result<Vec<ByteRecord>> {
+        let field = &record[idx];
+        let nl = field
+            .iter()
+            .position(|&b| b == b'\n')
+            .expect("runaway field should contain a newline");
+        let head = field[..nl].strip_suffix(b"\r").unwrap_or(&field[..nl]);
+
+        // The first record ends where the runaway field should have ended. The
+        // text before the line break was never really quoted, so split it on
+        // our delimiter.
+        let mut first = ByteRecord::new();
+        for prev in record.iter().take(idx) {
+            first.push_field(prev);
+        }
+        for piece in head.split(|&b| b == self.delimiter) {
+            first.push_field(piece);
+        }
+        let mut records = vec![first];
+
+        // Reassemble the swallowed text, plus any fields that followed it, and
+        // parse it again.
+        let mut rest = field[nl + 1..].to_owned();
+        for next in record.iter().skip(idx + 1) {
+            rest.push(self.delimiter);
+            rest.extend_from_slice(next);
+        }
+        let mut rdr_builder = csv::ReaderBuilder::new();
+        rdr_builder
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(self.delimiter);
+        if let Some(quote) = self.quote {
+            rdr_builder.quote(quote);
+        } else {
+            rdr_builder.quoting(false);
+        }
+        let mut rdr = rdr_builder.from_reader(&rest[..]);
+        for rescued in rdr.byte_records() {
+            records.push(rescued.context("cannot re-parse runaway quoted field")?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+fn recovery() -> RunawayQuoteRecovery {
+    RunawayQuoteRecovery {
+        delimiter: b',',
+        quote: Some(b'"'),
+        max_field_lines: 3,
+    }
+}
+
+#[test]
+fn finds_runaway_fields() {
+    let rec = recovery();
+    let ok = ByteRecord::from(vec!["1", "two\nlines", "3"]);
+    assert_eq!(rec.find_runaway_field(&ok, 3), None);
+    let ragged = ByteRecord::from(vec!["1", "two\nlines"]);
+    assert_eq!(rec.find_runaway_field(&ragged, 3), Some(1));
+    let long = ByteRecord::from(vec!["1", "a\nb\nc\nd", "3"]);
+    assert_eq!(rec.find_runaway_field(&long, 3), Some(1));
+}
+
+#[test]
+fn splits_runaway_fields() {
+    let rec = recovery();
+    let record = ByteRecord::from(vec!["1", "oops,3\n4,5,6\n7,8", "9"]);
+    let records = rec.split_runaway_field(&record, 1).unwrap();
+    assert_eq!(
+        records,
+        vec![
+            ByteRecord::from(vec!["1", "oops", "3"]),
+            ByteRecord::from(vec!["4", "5", "6"]),
+            ByteRecord::from(vec!["7", "8", "9"]),
+        ]
+    );
+}
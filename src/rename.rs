@@ -0,0 +1,91 @@
+//! Renaming columns before we clean them, for `--rename` and
+//! `--rename-file`.
+
+use csv::ByteRecord;
+use log::debug;
+use std::{path::Path, str::FromStr};
+
+use crate::errors::*;
+
+/// A column to rename, from `OLD=NEW`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rename {
+    /// The name in our input.
+    from: String,
+    /// The name we want.
+    to: String,
+}
+
+impl FromStr for Rename {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rename> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format_err!("expected OLD=NEW, found {:?}", s))?;
+        Ok(Rename {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+}
+
+/// Read renames from a CSV file with a header row and two columns, the old
+/// name and the new name.
+pub fn read_renames(path: &Path) -> Result<Vec<Rename>> {
+    let mut rdr = csv::Reader::from_path(path)
+        .with_context(|_| format!("cannot open rename file {}", path.display()))?;
+    let mut renames = vec![];
+    for record in rdr.records() {
+        let record = record
+            .with_context(|_| format!("cannot read rename file {}", path.display()))?;
+        if record.len() != 2 {
+            return Err(format_err!(
+                "expected 2 columns in rename file {}, found {}",
+                path.display(),
+                record.len()
+            ));
+        }
+        renames.push(Rename {
+            from: record[0].to_owned(),
+            to: record[1].to_owned(),
+        });
+    }
+    Ok(renames)
+}
+
+/// Rename the columns in `hdr`. Each column is renamed at most once, using
+/// the first matching entry in `renames`, so swapping two names works.
+/// Renames for columns we don't have are ignored, because the same renames
+/// are often used for files with slightly different columns.
+pub fn apply_renames(hdr: &ByteRecord, renames: &[Rename]) -> ByteRecord {
+    for rename in renames {
+        if !hdr.iter().any(|col| col == rename.from.as_bytes()) {
+            debug!("no column {:?} to rename", rename.from);
+        }
+    }
+    hdr.iter()
+        .map(|col| {
+            renames
+                .iter()
+                .find(|r| r.from.as_bytes() == col)
+                .map_or(col, |r| r.to.as_bytes())
+        })
+        .collect()
+}
+
+#[test]
+fn renames_columns() {
+    let hdr = ByteRecord::from(vec!["a", "b", "c"]);
+    let renames = vec![
+        "a=b".parse().unwrap(),
+        "b=a".parse().unwrap(),
+        "x=y".parse().unwrap(),
+        "a=z".parse().unwrap(),
+    ];
+    assert_eq!(
+        apply_renames(&hdr, &renames),
+        ByteRecord::from(vec!["b", "a", "c"])
+    );
+    assert!("ab".parse::<Rename>().is_err());
+}
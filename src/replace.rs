@@ -0,0 +1,156 @@
+//! Regex replacements in particular columns, for `--replace`.
+
+use csv::ByteRecord;
+use regex::bytes::Regex;
+use std::{borrow::Cow, str::FromStr};
+
+use crate::errors::*;
+use crate::stats::{RuleHits, RuleId};
+use crate::util::find_column;
+
+/// A replacement from `COL=/PATTERN/REPLACEMENT/`.
+#[derive(Clone, Debug)]
+pub struct Replacement {
+    /// The replacement as we were given it, for our stats.
+    spec: String,
+    /// The name of the column.
+    column: String,
+    /// What to look for.
+    regex: Regex,
+    /// What to replace it with, using `$1` or `${name}` for captures.
+    replacement: Vec<u8>,
+}
+
+/// Split `s` on `delim`, unless it's escaped with a backslash. Escaped
+/// delimiters are unescaped, and any other backslashes are left alone.
+fn split_unescaped(s: &str, delim: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&delim) {
+            parts.last_mut().expect("should have part").push(delim);
+            chars.next();
+        } else if c == delim {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().expect("should have part").push(c);
+        }
+    }
+    parts
+}
+
+impl FromStr for Replacement {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Replacement> {
+        let bad_spec =
+            || format_err!("expected COL=/PATTERN/REPLACEMENT/, found {:?}", s);
+        let (column, rest) = s.split_once('=').ok_or_else(bad_spec)?;
+        // Like sed, we use whatever comes first as our delimiter.
+        let delim = rest.chars().next().ok_or_else(bad_spec)?;
+        let parts = split_unescaped(&rest[delim.len_utf8()..], delim);
+        match &parts[..] {
+            [pattern, replacement, trailing] if trailing.is_empty() => {
+                let regex = Regex::new(pattern)
+                    .with_context(|_| format!("cannot parse regex {:?}", pattern))?;
+                Ok(Replacement {
+                    spec: s.to_owned(),
+                    column: column.to_owned(),
+                    regex,
+                    replacement: replacement.as_bytes().to_owned(),
+                })
+            }
+            _ => Err(bad_spec()),
+        }
+    }
+}
+
+/// Applies `--replace` to each row.
+pub struct Replacer {
+    /// Our replacements, the columns they apply to, and the rules which count
+    /// the changes they make.
+    replacements: Vec<(usize, Replacement, RuleId)>,
+}
+
+impl Replacer {
+    /// Prepare to apply `replacements` to rows with the header `hdr`,
+    /// registering our rules with `hits`. Returns `None` if we have no
+    /// replacements.
+    pub fn new(
+        hdr: &ByteRecord,
+        replacements: &[Replacement],
+        hits: &mut RuleHits,
+    ) -> Result<Option<Replacer>> {
+        if replacements.is_empty() {
+            return Ok(None);
+        }
+        let replacements = replacements
+            .iter()
+            .map(|r| {
+                let idx = find_column(hdr, &r.column).ok_or_else(|| {
+                    format_err!("cannot find --replace column {:?}", r.column)
+                })?;
+                let id =
+                    hits.register(format!("--replace {}", r.spec), "cells changed");
+                Ok((idx, r.clone(), id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Replacer { replacements }))
+    }
+
+    /// Apply our replacements to `row`, in order, counting them in `hits`.
+    /// Sets `changed` if we changed anything.
+    pub fn replace_row(
+        &self,
+        row: &mut [Cow<[u8]>],
+        changed: &mut bool,
+        hits: &RuleHits,
+    ) {
+        for (idx, r, id) in &self.replacements {
+            let replaced = r.regex.replace_all(&row[*idx], &r.replacement[..]);
+            if let Cow::Owned(replaced) = replaced {
+                if replaced != *row[*idx] {
+                    hits.hit(*id);
+                    row[*idx] = Cow::Owned(replaced);
+                    *changed = true;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn parses_replacements() {
+    let r = "id=/^ID-(\\d+)$/$1/".parse::<Replacement>().unwrap();
+    assert_eq!(r.column, "id");
+    assert_eq!(r.replacement, b"$1");
+    let r = "path=|a\\|b|/|".parse::<Replacement>().unwrap();
+    assert_eq!(r.regex.as_str(), "a|b");
+    assert_eq!(r.replacement, b"/");
+    assert!("id".parse::<Replacement>().is_err());
+    assert!("id=/a/b".parse::<Replacement>().is_err());
+    assert!("id=/a/b/c".parse::<Replacement>().is_err());
+    assert!("id=/(/b/".parse::<Replacement>().is_err());
+}
+
+#[test]
+fn replaces_in_columns() {
+    let hdr = ByteRecord::from(vec!["id", "name"]);
+    let replacements = vec![
+        "id=/^ID-0*//".parse().unwrap(),
+        "name=/(\\w+) (\\w+)/$2, $1/".parse().unwrap(),
+    ];
+    let mut hits = RuleHits::default();
+    let replacer = Replacer::new(&hdr, &replacements, &mut hits)
+        .unwrap()
+        .unwrap();
+    let mut row = vec![
+        Cow::Borrowed(&b"ID-007"[..]),
+        Cow::Borrowed(&b"Jane Doe"[..]),
+    ];
+    let mut changed = false;
+    replacer.replace_row(&mut row, &mut changed, &hits);
+    assert!(changed);
+    assert_eq!(row, vec![&b"7"[..], &b"Doe, Jane"[..]]);
+    assert!(Replacer::new(&hdr, &["x=/a/b/".parse().unwrap()], &mut hits).is_err());
+}
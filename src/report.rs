@@ -0,0 +1,128 @@
+//! A machine-readable report on each run, for orchestration systems.
+
+use serde::Serialize;
+use std::{fs, path::Path, str::FromStr};
+
+use crate::errors::*;
+use crate::stats::{RuleHits, Stage, StageTimes};
+
+/// The formats we can print a report in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Pretty-printed JSON.
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ReportFormat> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format_err!("unknown report format: '{}'", s)),
+        }
+    }
+}
+
+/// What happened during a run.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// The number of data rows we read, not counting any header.
+    pub rows: u64,
+    /// The number of rows we wrote.
+    pub good_rows: u64,
+    /// The number of rows we rejected.
+    pub bad_rows: u64,
+    /// The number of good rows we didn't write, because a row filter or
+    /// `--dedup` removed them.
+    pub filtered_rows: u64,
+    /// The number of good rows changed by cleanup.
+    pub changed_rows: u64,
+    /// The number of bytes of input we read.
+    pub bytes_read: u64,
+    /// How long the run took.
+    pub elapsed_seconds: f64,
+    /// How quickly we read our input.
+    pub bytes_per_second: f64,
+    /// How long we spent in each stage of our pipeline, if we were run with
+    /// `--stats`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage_seconds: Option<StageSeconds>,
+    /// What each of our cleanup and validation rules did. Rules which count
+    /// "rows rejected" add up to `bad_rows`.
+    pub rules: Vec<RuleReport>,
+}
+
+/// How long we spent in each stage of our pipeline, in seconds.
+#[derive(Debug, Serialize)]
+pub struct StageSeconds {
+    /// Reading and parsing records.
+    pub read: f64,
+    /// Checking and cleaning records.
+    pub clean: f64,
+    /// Writing records.
+    pub write: f64,
+}
+
+impl From<&StageTimes> for StageSeconds {
+    fn from(times: &StageTimes) -> StageSeconds {
+        StageSeconds {
+            read: times.time(Stage::Read).as_secs_f64(),
+            clean: times.time(Stage::Clean).as_secs_f64(),
+            write: times.time(Stage::Write).as_secs_f64(),
+        }
+    }
+}
+
+/// What one of our rules did.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct RuleReport {
+    /// A description of the rule.
+    pub rule: String,
+    /// What we counted, such as "cells changed" or "rows rejected".
+    pub unit: String,
+    /// How many times the rule did something.
+    pub count: u64,
+}
+
+impl RuleReport {
+    /// Build reports for all the rules in `hits`.
+    pub fn from_hits(hits: &RuleHits) -> Vec<RuleReport> {
+        hits.iter()
+            .map(|(rule, unit, count)| RuleReport {
+                rule: rule.to_owned(),
+                unit: unit.to_owned(),
+                count,
+            })
+            .collect()
+    }
+}
+
+impl Report {
+    /// Convert this report to JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Write this report to `path` as JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_json()?)
+            .with_context(|_| format!("cannot write report to {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn reports_rule_hits() {
+    let mut hits = RuleHits::default();
+    let id = hits.register("--a", "rows rejected");
+    hits.hit(id);
+    assert_eq!(
+        RuleReport::from_hits(&hits),
+        vec![RuleReport {
+            rule: "--a".to_owned(),
+            unit: "rows rejected".to_owned(),
+            count: 1,
+        }],
+    );
+}
@@ -0,0 +1,329 @@
+//! Expected schemas, and detecting when our input doesn't match them.
+
+use serde::Deserialize;
+use std::{fmt, fs, path::Path, str::FromStr};
+
+use crate::errors::*;
+use crate::profile::ValueType;
+use crate::validate::Rule;
+
+/// A schema describing the columns we expect to see, loaded from a YAML or
+/// JSON file like:
+///
+/// ```yaml
+/// ordered: true
+/// columns:
+///   - name: id
+///     type: integer
+///     nullable: false
+///   - name: email
+///     max_length: 100
+///     rules:
+///       - pattern: ".+@.+"
+///   - name: color
+///     allowed: [red, green, blue]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    /// Must our columns appear in this order?
+    #[serde(default)]
+    pub ordered: bool,
+    /// Our columns, in order.
+    pub columns: Vec<SchemaColumn>,
+}
+
+/// A single column in a `Schema`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaColumn {
+    /// The name of this column, after any cleaning.
+    pub name: String,
+    /// The type of this column. Unlike a `type` rule, we convert values to a
+    /// standard form, as with `--types`.
+    #[serde(rename = "type")]
+    pub value_type: Option<ValueType>,
+    /// May this column be empty?
+    #[serde(default = "default_nullable")]
+    pub nullable: bool,
+    /// The maximum length of a value, in characters.
+    pub max_length: Option<usize>,
+    /// The only values allowed in this column.
+    pub allowed: Option<Vec<String>>,
+    /// Validation rules for the values in this column.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Columns are nullable unless the schema says otherwise.
+fn default_nullable() -> bool {
+    true
+}
+
+/// A difference between our schema and the columns we actually found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SchemaChange {
+    /// A column appeared which isn't in the schema.
+    Added(String),
+    /// A column in the schema is missing.
+    Removed(String),
+    /// A column is out of order, in a schema with `ordered: true`.
+    Moved(String),
+    /// A column in the schema seems to have been replaced by another column
+    /// at the same position.
+    Renamed {
+        /// The name in the schema.
+        from: String,
+        /// The name we found.
+        to: String,
+    },
+}
+
+impl fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaChange::Added(name) => write!(f, "added column {:?}", name),
+            SchemaChange::Removed(name) => write!(f, "removed column {:?}", name),
+            SchemaChange::Moved(name) => write!(f, "moved column {:?}", name),
+            SchemaChange::Renamed { from, to } => {
+                write!(f, "renamed column {:?} to {:?}", from, to)
+            }
+        }
+    }
+}
+
+/// What to do when our input doesn't match our schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnSchemaChange {
+    /// Print a warning and continue.
+    Warn,
+    /// Exit with an error before writing any data.
+    Fail,
+    /// Print a warning and output the columns in the schema, leaving any
+    /// missing columns empty and discarding any new ones.
+    Adapt,
+}
+
+impl FromStr for OnSchemaChange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OnSchemaChange> {
+        match s {
+            "warn" => Ok(OnSchemaChange::Warn),
+            "fail" => Ok(OnSchemaChange::Fail),
+            "adapt" => Ok(OnSchemaChange::Adapt),
+            _ => Err(format_err!("unknown schema change policy: '{}'", s)),
+        }
+    }
+}
+
+impl Schema {
+    /// Read a schema from a YAML file, or a JSON file if `path` ends in
+    /// ".json".
+    pub fn read(path: &Path) -> Result<Schema> {
+        let text = fs::read_to_string(path)
+            .with_context(|_| format!("cannot read schema {}", path.display()))?;
+        let schema = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text)
+                .with_context(|_| format!("cannot parse schema {}", path.display()))?
+        } else {
+            serde_yaml::from_str(&text)
+                .with_context(|_| format!("cannot parse schema {}", path.display()))?
+        };
+        Ok(schema)
+    }
+
+    /// Find the position of the schema column we should output for each of
+    /// our `names`, taking renames into account.
+    fn matching_positions(&self, names: &[String]) -> Vec<Option<usize>> {
+        let mut matches = names
+            .iter()
+            .map(|name| self.columns.iter().position(|c| &c.name == name))
+            .collect::<Vec<_>>();
+        // Any unknown column sitting where a missing schema column should be
+        // is probably a rename.
+        for (i, m) in matches.iter_mut().enumerate() {
+            if m.is_none()
+                && i < self.columns.len()
+                && !names.contains(&self.columns[i].name)
+            {
+                *m = Some(i);
+            }
+        }
+        matches
+    }
+
+    /// Compare the column `names` we found against our schema.
+    pub fn changes(&self, names: &[String]) -> Vec<SchemaChange> {
+        let matches = self.matching_positions(names);
+        let mut changes = vec![];
+        for (name, m) in names.iter().zip(&matches) {
+            match m {
+                None => changes.push(SchemaChange::Added(name.clone())),
+                Some(idx) if &self.columns[*idx].name != name => {
+                    changes.push(SchemaChange::Renamed {
+                        from: self.columns[*idx].name.clone(),
+                        to: name.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        if self.ordered {
+            let mut last = None;
+            for (name, m) in names.iter().zip(&matches) {
+                if let Some(idx) = m {
+                    if last.is_some_and(|last| idx < last) {
+                        changes.push(SchemaChange::Moved(name.clone()));
+                    } else {
+                        last = Some(idx);
+                    }
+                }
+            }
+        }
+        for (idx, column) in self.columns.iter().enumerate() {
+            if !matches.contains(&Some(idx)) {
+                changes.push(SchemaChange::Removed(column.name.clone()));
+            }
+        }
+        changes
+    }
+
+    /// For each column in our schema, find the index of the input column we
+    /// should use to fill it in, if any.
+    pub fn projection(&self, names: &[String]) -> Vec<Option<usize>> {
+        let matches = self.matching_positions(names);
+        (0..self.columns.len())
+            .map(|idx| matches.iter().position(|&m| m == Some(idx)))
+            .collect()
+    }
+}
+
+/// Check our column `names` against `--expect-columns` and
+/// `--require-columns`, describing any problems.
+pub fn check_columns(
+    names: &[String],
+    expected: &[String],
+    required: &[String],
+) -> Vec<String> {
+    let mut problems = vec![];
+    if !expected.is_empty() && names != expected {
+        problems.push(format!(
+            "expected columns {:?}, found {:?}",
+            expected, names
+        ));
+    }
+    for name in required {
+        if !names.contains(name) {
+            problems.push(format!("missing required column {:?}", name));
+        }
+    }
+    problems
+}
+
+#[test]
+fn checks_columns() {
+    let found = names(&["a", "b"]);
+    assert!(check_columns(&found, &names(&["a", "b"]), &names(&["b"])).is_empty());
+    assert_eq!(
+        check_columns(&found, &names(&["b", "a"]), &names(&["c"])),
+        vec![
+            r#"expected columns ["b", "a"], found ["a", "b"]"#,
+            r#"missing required column "c""#,
+        ]
+    );
+}
+
+#[cfg(test)]
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|&n| n.to_owned()).collect()
+}
+
+#[test]
+fn detects_schema_changes() {
+    let schema: Schema =
+        serde_yaml::from_str("columns:\n- name: id\n- name: email\n- name: zip\n")
+            .unwrap();
+    assert!(schema.changes(&names(&["id", "email", "zip"])).is_empty());
+    assert_eq!(
+        schema.changes(&names(&["id", "e_mail", "extra"])),
+        vec![
+            SchemaChange::Renamed {
+                from: "email".to_owned(),
+                to: "e_mail".to_owned(),
+            },
+            SchemaChange::Renamed {
+                from: "zip".to_owned(),
+                to: "extra".to_owned(),
+            },
+        ]
+    );
+    assert_eq!(
+        schema.changes(&names(&["email", "id", "extra", "more"])),
+        vec![
+            SchemaChange::Renamed {
+                from: "zip".to_owned(),
+                to: "extra".to_owned(),
+            },
+            SchemaChange::Added("more".to_owned()),
+        ]
+    );
+    assert_eq!(
+        schema.changes(&names(&["zip", "id"])),
+        vec![SchemaChange::Removed("email".to_owned())]
+    );
+}
+
+#[test]
+fn parses_schemas_strictly() {
+    let schema: Schema = serde_yaml::from_str(
+        "columns:\n- name: id\n  type: int\n- name: ok\n  type: bool\n",
+    )
+    .unwrap();
+    assert_eq!(schema.columns[0].value_type, Some(ValueType::Integer));
+    assert_eq!(schema.columns[1].value_type, Some(ValueType::Boolean));
+
+    // Typos shouldn't be silently ignored.
+    assert!(serde_yaml::from_str::<Schema>(
+        "columns:\n- name: id\n  nulable: false\n"
+    )
+    .is_err());
+    assert!(serde_yaml::from_str::<Schema>("colums:\n- name: id\n").is_err());
+    assert!(serde_yaml::from_str::<Schema>(
+        "columns:\n- name: n\n  rules:\n  - range: { min: 0, maximum: 9 }\n"
+    )
+    .is_err());
+}
+
+#[test]
+fn detects_moved_columns() {
+    let mut schema: Schema = serde_json::from_str(
+        r#"{"columns": [{"name": "id"}, {"name": "email"}, {"name": "zip"}]}"#,
+    )
+    .unwrap();
+    assert!(schema.changes(&names(&["zip", "id", "email"])).is_empty());
+    schema.ordered = true;
+    assert_eq!(
+        schema.changes(&names(&["zip", "id", "email"])),
+        vec![
+            SchemaChange::Moved("id".to_owned()),
+            SchemaChange::Moved("email".to_owned()),
+        ]
+    );
+    assert!(schema.changes(&names(&["id", "email", "zip"])).is_empty());
+}
+
+#[test]
+fn builds_projections() {
+    let schema: Schema =
+        serde_yaml::from_str("columns:\n- name: id\n- name: email\n- name: zip\n")
+            .unwrap();
+    assert_eq!(
+        schema.projection(&names(&["email", "id", "extra", "more"])),
+        vec![Some(1), Some(0), Some(2)]
+    );
+    assert_eq!(
+        schema.projection(&names(&["zip", "id"])),
+        vec![Some(1), None, Some(0)]
+    );
+}
@@ -0,0 +1,375 @@
+//! Scrubbing CSV data from inside another Rust program.
+//!
+//! ```
+//! use scrubcsv::Scrubber;
+//!
+//! let input = "Name,Age\n Alice ,NULL\nBob\n";
+//! let mut output = vec![];
+//! let stats = Scrubber::new()
+//!     .null_regex("NULL")
+//!     .unwrap()
+//!     .trim_whitespace(true)
+//!     .clean_column_names(true)
+//!     .max_bad_rows_percent(Some(50.0))
+//!     .scrub(input.as_bytes(), &mut output)
+//!     .unwrap();
+//! assert_eq!(String::from_utf8(output).unwrap(), "name,age\nAlice,\n");
+//! assert_eq!(stats.bad_rows, 1);
+//! ```
+//!
+//! `Scrubber` has builder methods for the most commonly used cleanups. For
+//! everything else, build one from the `scrubcsv` command line's options
+//! with `Scrubber::from_opt`. Either way, we clean rows using exactly the
+//! same code as the command line tool.
+
+use log::debug;
+use regex::bytes::Regex;
+use std::{error, fmt, io};
+use structopt::StructOpt;
+
+use crate::cleanup::null_regex;
+use crate::errors::Error;
+use crate::options::{Command, Opt};
+use crate::pipeline::{self, Sink, Source};
+use crate::util::{CharSpecifier, DelimiterSpecifier};
+use crate::{generate, tui};
+
+/// An error which occurred while scrubbing.
+#[derive(Debug)]
+pub enum ScrubError {
+    /// A regular expression was invalid.
+    Regex(regex::Error),
+    /// We found a bad row, and our policy was to fail fast.
+    BadRow {
+        /// The line the row started on.
+        line: u64,
+    },
+    /// Too many rows were bad.
+    TooManyBadRows {
+        /// The scrubbing that we did before giving up.
+        stats: ScrubStats,
+        /// How many rows we checked, counting the header as a row, like
+        /// `--max-bad-rows` does.
+        checked_rows: u64,
+    },
+    /// We wrote too few or too many good rows, for `--assert-min-rows` or
+    /// `--assert-max-rows`.
+    WrongRowCount(String),
+    /// Cleanup changed too few or too many rows, for `--fail-if-unchanged`
+    /// or `--fail-if-changed-over`.
+    WrongChangeCount(String),
+    /// Our data drifted from `--baseline-profile`, and we were asked to
+    /// fail.
+    Drift,
+    /// Our columns weren't the ones we expected.
+    UnexpectedColumns(String),
+    /// Anything else which went wrong.
+    Other(Error),
+}
+
+impl ScrubError {
+    /// Did one of our checks on the data fail, instead of something going
+    /// wrong?
+    pub fn is_failed_check(&self) -> bool {
+        matches!(
+            self,
+            ScrubError::BadRow { .. }
+                | ScrubError::TooManyBadRows { .. }
+                | ScrubError::WrongRowCount(_)
+                | ScrubError::WrongChangeCount(_)
+                | ScrubError::Drift
+                | ScrubError::UnexpectedColumns(_)
+        )
+    }
+
+    /// The exit code `scrubcsv` uses for this error, as listed in its
+    /// `--help`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ScrubError::BadRow { .. } | ScrubError::TooManyBadRows { .. } => 2,
+            ScrubError::WrongRowCount(_) => 3,
+            ScrubError::WrongChangeCount(_) => 4,
+            ScrubError::Drift => 5,
+            ScrubError::UnexpectedColumns(_) => 6,
+            _ => {
+                // If we were waiting for input which never came, say so.
+                let mut source = error::Error::source(self);
+                while let Some(cause) = source {
+                    if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+                        if io_err.kind() == io::ErrorKind::TimedOut {
+                            return 7;
+                        }
+                    }
+                    source = cause.source();
+                }
+                1
+            }
+        }
+    }
+}
+
+impl fmt::Display for ScrubError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrubError::Regex(err) => write!(f, "invalid regex: {}", err),
+            ScrubError::BadRow { line } => write!(
+                f,
+                "Bad row at line {}, stopping because of --fail-fast",
+                line
+            ),
+            ScrubError::TooManyBadRows {
+                stats,
+                checked_rows,
+            } => write!(
+                f,
+                "Too many rows ({} of {}) were bad",
+                stats.bad_rows, checked_rows,
+            ),
+            ScrubError::WrongRowCount(message)
+            | ScrubError::WrongChangeCount(message)
+            | ScrubError::UnexpectedColumns(message) => message.fmt(f),
+            ScrubError::Drift => write!(f, "Found drift from baseline profile"),
+            ScrubError::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ScrubError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ScrubError::Regex(err) => Some(err),
+            // We print the same message as `err`, so skip ahead to its cause.
+            ScrubError::Other(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for ScrubError {
+    /// Our pipeline returns a `ScrubError` inside an `Error` when one of our
+    /// checks fails, so unwrap it again.
+    fn from(err: Error) -> Self {
+        match err.downcast::<ScrubError>() {
+            Ok(err) => *err,
+            Err(err) => ScrubError::Other(err),
+        }
+    }
+}
+
+/// What happened when we scrubbed some data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// Data rows read, not counting the header.
+    pub rows: u64,
+    /// Rows we rejected.
+    pub bad_rows: u64,
+    /// Good rows which we didn't write, because of options like `--where`,
+    /// `--tail` or `--dedup`.
+    pub filtered_rows: u64,
+    /// Good rows which we wrote in a different form than we read them.
+    pub changed_rows: u64,
+}
+
+impl ScrubStats {
+    /// How many rows did we write, not counting the header?
+    pub fn good_rows(&self) -> u64 {
+        self.rows - self.bad_rows - self.filtered_rows
+    }
+}
+
+/// Cleans up CSV data the same way as the `scrubcsv` command line tool.
+///
+/// Create one with `Scrubber::new` or `Scrubber::from_opt`, configure it, and
+/// call `scrub` as many times as you like.
+#[derive(Clone, Debug)]
+pub struct Scrubber {
+    /// Our options, exactly as if we'd parsed them from a command line.
+    opt: Opt,
+    /// A `--null` regex we were given already compiled, which we use as is.
+    null: Option<Regex>,
+}
+
+impl Default for Scrubber {
+    fn default() -> Self {
+        // Unlike the command line tool, we don't print a summary to standard
+        // error.
+        let opt = Opt::from_iter_safe(["scrubcsv", "--quiet"])
+            .expect("default options should parse");
+        Scrubber { opt, null: None }
+    }
+}
+
+impl Scrubber {
+    /// Create a `Scrubber` with the same defaults as `scrubcsv`, except that
+    /// we don't print a summary to standard error.
+    pub fn new() -> Scrubber {
+        Scrubber::default()
+    }
+
+    /// Create a `Scrubber` from `scrubcsv`'s command line options, as parsed
+    /// by `Opt::from_args`.
+    pub fn from_opt(mut opt: Opt) -> Scrubber {
+        if let Some(preset) = opt.preset {
+            preset.apply(&mut opt);
+        }
+        debug!("Options: {:#?}", opt);
+        Scrubber { opt, null: None }
+    }
+
+    /// The character which separates fields in our input. Our output always
+    /// uses commas.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.opt.delimiter =
+            DelimiterSpecifier::Char(CharSpecifier::new(Some(delimiter)));
+        self
+    }
+
+    /// The character used to quote fields in our input, or `None` to ignore
+    /// quotes.
+    pub fn quote(mut self, quote: Option<u8>) -> Self {
+        self.opt.quote = CharSpecifier::new(quote);
+        self
+    }
+
+    /// Convert values matching `null` to empty strings. Unlike `null_regex`,
+    /// this uses `null` exactly as given, so it should usually start with `^`
+    /// and end with `$`.
+    pub fn null(mut self, null: Regex) -> Self {
+        self.null = Some(null);
+        self
+    }
+
+    /// Convert values matching `pattern` to empty strings, like `--null`.
+    /// The pattern always has to match the whole value.
+    pub fn null_regex(self, pattern: &str) -> Result<Self, ScrubError> {
+        let re = null_regex(pattern).map_err(ScrubError::Regex)?;
+        Ok(self.null(re))
+    }
+
+    /// Remove whitespace at the beginning and end of each cell, like
+    /// `--trim-whitespace`.
+    pub fn trim_whitespace(mut self, trim_whitespace: bool) -> Self {
+        self.opt.trim_whitespace = trim_whitespace;
+        self
+    }
+
+    /// Replace newlines in values with spaces, like `--replace-newlines`.
+    pub fn replace_newlines(mut self, replace_newlines: bool) -> Self {
+        self.opt.replace_newlines = replace_newlines;
+        self
+    }
+
+    /// Make column names unique identifiers, like `--clean-column-names`.
+    pub fn clean_column_names(mut self, clean_column_names: bool) -> Self {
+        self.opt.clean_column_names = clean_column_names;
+        self
+    }
+
+    /// Reject any rows where `column` is empty after cleaning, like
+    /// `--drop-row-if-null`. Matches column names the same loose way.
+    pub fn drop_row_if_null<S: Into<String>>(mut self, column: S) -> Self {
+        self.opt.drop_row_if_null.push(column.into());
+        self
+    }
+
+    /// Fail if more than `percent` of rows are bad, like `--max-bad-rows`,
+    /// or never fail because of the percentage of bad rows if `percent` is
+    /// `None`. Defaults to 10, unless we have a `max_bad_rows_count`.
+    pub fn max_bad_rows_percent(mut self, percent: Option<f64>) -> Self {
+        // No more than all of our rows can be bad.
+        self.opt.max_bad_rows = Some(percent.unwrap_or(100.0));
+        self
+    }
+
+    /// Fail if more than `count` rows are bad, like `--max-bad-rows-count`.
+    pub fn max_bad_rows_count(mut self, count: Option<u64>) -> Self {
+        self.opt.max_bad_rows_count = count;
+        self
+    }
+
+    /// Fail as soon as we see a bad row, like `--fail-fast`.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.opt.fail_fast = fail_fast;
+        self
+    }
+
+    /// Read CSV data from `rdr`, clean it, and write it to `wtr`, instead of
+    /// using any input files or `--output` from our options. We read and
+    /// write on the current thread, even with `--io-threads`, and we don't
+    /// decompress our input.
+    ///
+    /// Like `scrubcsv`, we count the header as a row when checking whether
+    /// too many rows were bad, but not in the returned statistics.
+    pub fn scrub<'a, R, W>(&self, rdr: R, wtr: W) -> Result<ScrubStats, ScrubError>
+    where
+        R: io::Read + 'a,
+        W: io::Write + 'a,
+    {
+        let source = Source::Reader(Box::new(rdr));
+        let sink = Sink::Writer(Box::new(wtr));
+        Ok(pipeline::run(&self.opt, self.null.as_ref(), source, sink)?)
+    }
+
+    /// Do what the `scrubcsv` command line tool does: read our input files or
+    /// standard input, and write to `--output` or standard output. If our
+    /// options have a subcommand, like `generate`, we run that instead.
+    pub fn run(&self) -> Result<ScrubStats, ScrubError> {
+        match &self.opt.cmd {
+            Some(Command::Generate(generate_opt)) => generate::run(generate_opt)?,
+            Some(Command::Tui(tui_opt)) => tui::run(tui_opt)?,
+            None => {
+                let stats = pipeline::run(
+                    &self.opt,
+                    self.null.as_ref(),
+                    Source::Inputs,
+                    Sink::Output,
+                )?;
+                return Ok(stats);
+            }
+        }
+        Ok(ScrubStats::default())
+    }
+}
+
+#[test]
+fn scrubber_rejects_bad_rows() {
+    let input = "a,b\n1,2\n3\n,4\n";
+    let scrubber = Scrubber::new()
+        .drop_row_if_null("a")
+        .max_bad_rows_percent(None);
+    let mut output = vec![];
+    let stats = scrubber.scrub(input.as_bytes(), &mut output).unwrap();
+    assert_eq!(output, b"a,b\n1,2\n");
+    assert_eq!(
+        stats,
+        ScrubStats {
+            rows: 3,
+            bad_rows: 2,
+            filtered_rows: 0,
+            changed_rows: 0,
+        }
+    );
+
+    let err = Scrubber::new()
+        .fail_fast(true)
+        .scrub(input.as_bytes(), vec![])
+        .unwrap_err();
+    assert!(matches!(err, ScrubError::BadRow { line: 3 }));
+    let err = Scrubber::new().scrub(input.as_bytes(), vec![]).unwrap_err();
+    assert!(matches!(err, ScrubError::TooManyBadRows { .. }));
+}
+
+#[test]
+fn scrubber_matches_the_command_line() {
+    let input = "Customer ID,b\n1,Nullam\n,NULL\n";
+    let mut output = vec![];
+    let stats = Scrubber::new()
+        .null_regex("NULL|\\\\N")
+        .unwrap()
+        .drop_row_if_null("customer id")
+        .max_bad_rows_percent(None)
+        .scrub(input.as_bytes(), &mut output)
+        .unwrap();
+    assert_eq!(output, b"Customer ID,b\n1,Nullam\n");
+    assert_eq!(stats.bad_rows, 1);
+}
@@ -0,0 +1,180 @@
+//! Skipping over input we can't read, instead of giving up.
+//!
+//! Once `csv::Reader` sees an I/O error, it refuses to read anything else, so
+//! we need to handle errors before they reach it. We do this by wrapping our
+//! input, remembering any errors, and then ending the broken record and
+//! skipping to the next line of our input.
+
+use log::debug;
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, prelude::*},
+    rc::Rc,
+};
+
+/// How many times in a row we'll try reading after an error.
+const MAX_CONSECUTIVE_ERRORS: usize = 100;
+
+/// A read error which we skipped over.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SkippedError {
+    /// The byte offset in our output at which the error occurred. The record
+    /// containing this offset is broken.
+    pub byte: u64,
+    /// A description of the error.
+    pub error: String,
+}
+
+/// Errors which we have skipped, but which haven't yet been reported.
+pub type SkippedErrors = Rc<RefCell<VecDeque<SkippedError>>>;
+
+/// A reader which skips over read errors. This must be the last wrapper
+/// before `csv::Reader`, so that our byte offsets match the ones it reports.
+pub struct SkipUnparseableReader<R: Read> {
+    inner: R,
+    /// Our quote character, so that we can tell if we're inside a quoted
+    /// field.
+    quote: Option<u8>,
+    /// Are we inside a quoted field?
+    in_quotes: bool,
+    /// The number of bytes we've returned so far.
+    offset: u64,
+    /// Bytes we need to return before reading more from `inner`.
+    pending: VecDeque<u8>,
+    /// Where we record errors.
+    errors: SkippedErrors,
+}
+
+impl<R: Read> SkipUnparseableReader<R> {
+    /// Wrap `inner`. Returns our new reader, and a queue of skipped errors.
+    pub fn new(inner: R, quote: Option<u8>) -> (Self, SkippedErrors) {
+        let errors = SkippedErrors::default();
+        let rdr = SkipUnparseableReader {
+            inner,
+            quote,
+            in_quotes: false,
+            offset: 0,
+            pending: VecDeque::new(),
+            errors: errors.clone(),
+        };
+        (rdr, errors)
+    }
+
+    /// Skip over whatever is left of the current line in `inner`.
+    fn skip_line(&mut self) -> io::Result<()> {
+        let mut failures = 0;
+        let mut byte = [0];
+        loop {
+            match self.inner.read(&mut byte) {
+                Ok(0) => return Ok(()),
+                Ok(_) if byte[0] == b'\n' => return Ok(()),
+                Ok(_) => {}
+                Err(err) if failures < MAX_CONSECUTIVE_ERRORS => {
+                    debug!("error while skipping line: {}", err);
+                    failures += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for SkipUnparseableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.inner.read(buf) {
+                Ok(n) => {
+                    if let Some(quote) = self.quote {
+                        for &b in &buf[..n] {
+                            if b == quote {
+                                self.in_quotes = !self.in_quotes;
+                            }
+                        }
+                    }
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                // These aren't problems with our data, so let somebody else
+                // deal with them.
+                Err(err)
+                    if err.kind() == io::ErrorKind::Interrupted
+                        || err.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Err(err);
+                }
+                Err(err) => {
+                    self.errors.borrow_mut().push_back(SkippedError {
+                        byte: self.offset,
+                        error: err.to_string(),
+                    });
+                    self.skip_line()?;
+                    // End the broken record, closing any open quotes.
+                    if self.in_quotes {
+                        self.pending
+                            .push_back(self.quote.expect("should have quote"));
+                    }
+                    self.pending.push_back(b'\n');
+                    self.in_quotes = false;
+                }
+            }
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match self.pending.pop_front() {
+                Some(b) => {
+                    buf[n] = b;
+                    n += 1;
+                }
+                None => break,
+            }
+        }
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+/// A reader which returns each of its chunks in turn, and an error in place
+/// of each `None`.
+struct FlakyReader(VecDeque<Option<&'static [u8]>>);
+
+#[cfg(test)]
+impl Read for FlakyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.pop_front() {
+            Some(Some(chunk)) => {
+                let n = chunk.len().min(buf.len());
+                buf[..n].copy_from_slice(&chunk[..n]);
+                if n < chunk.len() {
+                    self.0.push_front(Some(&chunk[n..]));
+                }
+                Ok(n)
+            }
+            Some(None) => Err(io::Error::other("corrupt block")),
+            None => Ok(0),
+        }
+    }
+}
+
+#[test]
+fn skips_to_next_line_after_errors() {
+    let inner = FlakyReader(
+        vec![
+            Some(&b"a,b\n1,\"2"[..]),
+            None,
+            Some(b"lost\"\n3,4\n5,"),
+            None,
+            Some(b"6\n"),
+        ]
+        .into(),
+    );
+    let (mut rdr, errors) = SkipUnparseableReader::new(inner, Some(b'"'));
+    let mut output = String::new();
+    rdr.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "a,b\n1,\"2\"\n3,4\n5,\n");
+    assert_eq!(
+        errors.borrow().iter().map(|e| e.byte).collect::<Vec<_>>(),
+        vec![8, 16],
+    );
+}
@@ -0,0 +1,238 @@
+//! Guess things about our input by looking at a sample from the beginning of
+//! the file.
+
+use log::debug;
+use std::io::{self, prelude::*};
+
+use crate::encoding::UTF8_BOM;
+use crate::errors::*;
+
+/// Delimiters that we know how to detect, in order of preference when two of
+/// them look equally plausible.
+const CANDIDATE_DELIMITERS: &[u8] = b",\t;|";
+
+/// A sample read from the start of our input, which we use for guessing the
+/// format. The sampled bytes are handed back to the caller along with the
+/// rest of the input, so nothing is lost.
+pub struct Sample {
+    /// The raw bytes we read.
+    bytes: Vec<u8>,
+    /// The maximum number of lines to look at.
+    max_rows: usize,
+}
+
+impl Sample {
+    /// Read up to `max_bytes` from `input`, and return a sample plus a reader
+    /// which will return the entire input, including the sampled bytes.
+    pub fn read<'a>(
+        mut input: Box<dyn Read + 'a>,
+        max_bytes: usize,
+        max_rows: usize,
+    ) -> Result<(Sample, Box<dyn Read + 'a>)> {
+        let mut bytes = vec![];
+        input
+            .by_ref()
+            .take(max_bytes as u64)
+            .read_to_end(&mut bytes)
+            .context("cannot read sample from input")?;
+        let sample = Sample {
+            bytes: bytes.clone(),
+            max_rows,
+        };
+        let input = Box::new(io::Cursor::new(bytes).chain(input));
+        Ok((sample, input))
+    }
+
+    /// Return the complete lines in our sample, up to our row limit. We don't
+    /// try to understand quoted newlines here, because a rough guess is good
+    /// enough.
+    fn lines(&self) -> impl Iterator<Item = &[u8]> {
+        // Drop any partial line at the end, unless it's all we've got.
+        let complete = match self.bytes.iter().rposition(|&b| b == b'\n') {
+            Some(last_nl) => &self.bytes[..last_nl],
+            None => &self.bytes[..],
+        };
+        complete
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .take(self.max_rows)
+    }
+
+    /// Guess which delimiter this sample uses, falling back to `,`.
+    pub fn guess_delimiter(&self, quote: Option<u8>) -> u8 {
+        let mut best = (b',', 0);
+        for &delim in CANDIDATE_DELIMITERS {
+            let counts = self
+                .lines()
+                .map(|line| count_unquoted(line, delim, quote))
+                .collect::<Vec<_>>();
+            // Our score is the number of lines which agree on the most common
+            // non-zero number of delimiters.
+            let score = counts
+                .iter()
+                .filter(|&&c| c > 0)
+                .map(|&c| counts.iter().filter(|&&other| other == c).count())
+                .max()
+                .unwrap_or(0);
+            if score > best.1 {
+                best = (delim, score);
+            }
+        }
+        best.0
+    }
+}
+
+/// Remove anything before the CSV data itself from `input`. If `strip_bom`
+/// is true, that includes any UTF-8 byte order mark, which would otherwise
+/// end up glued to our first column name. After that, if `input` starts
+/// with a line like `sep=;`, which Excel uses to say which delimiter a file
+/// uses, we remove that line and return its delimiter. Returns a reader
+/// which will return the rest of the input.
+pub fn strip_prologue<'a>(
+    mut input: Box<dyn Read + 'a>,
+    strip_bom: bool,
+) -> Result<(Option<u8>, Box<dyn Read + 'a>)> {
+    // Read a byte at a time, so that we never wait for input we don't need.
+    let mut start = vec![];
+    let mut read_byte = |start: &mut Vec<u8>| -> Result<bool> {
+        let mut byte = [0];
+        loop {
+            match input.read(&mut byte) {
+                Ok(0) => return Ok(false),
+                Ok(_) => {
+                    start.push(byte[0]);
+                    return Ok(true);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err).context("cannot read start of input"),
+            }
+        }
+    };
+    if strip_bom {
+        while start.len() < UTF8_BOM.len()
+            && UTF8_BOM.starts_with(&start)
+            && read_byte(&mut start)?
+        {}
+        if start == UTF8_BOM {
+            debug!("stripping UTF-8 byte order mark");
+            start.clear();
+        }
+    }
+    while could_be_sep_line(&start)
+        && start.last() != Some(&b'\n')
+        && read_byte(&mut start)?
+    {}
+    let (delimiter, line_len) = match &start[..] {
+        [s, e, p, b'=', delim, rest @ ..]
+            if b"sep".eq_ignore_ascii_case(&[*s, *e, *p]) =>
+        {
+            match rest {
+                [] => (Some(*delim), 5),
+                [b'\n'] => (Some(*delim), 6),
+                [b'\r', b'\n'] => (Some(*delim), 7),
+                _ => (None, 0),
+            }
+        }
+        _ => (None, 0),
+    };
+    // Only wrap our input if we need to give back some bytes we read.
+    let rest = start.split_off(line_len);
+    if rest.is_empty() {
+        Ok((delimiter, input))
+    } else {
+        Ok((delimiter, Box::new(io::Cursor::new(rest).chain(input))))
+    }
+}
+
+/// Could `start` be the beginning of a `sep=` line?
+fn could_be_sep_line(start: &[u8]) -> bool {
+    let prefix = start.len().min(4);
+    start[..prefix].eq_ignore_ascii_case(&b"sep="[..prefix])
+        && matches!(
+            start.get(5..),
+            None | Some([] | [b'\r' | b'\n'] | [b'\r', b'\n'])
+        )
+}
+
+/// Count how many times `delim` appears in `line` outside of quotes.
+fn count_unquoted(line: &[u8], delim: u8, quote: Option<u8>) -> usize {
+    let mut in_quotes = false;
+    let mut count = 0;
+    for &b in line {
+        if Some(b) == quote {
+            in_quotes = !in_quotes;
+        } else if b == delim && !in_quotes {
+            count += 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+fn sample_for(bytes: &[u8], max_rows: usize) -> Sample {
+    Sample {
+        bytes: bytes.to_owned(),
+        max_rows,
+    }
+}
+
+#[test]
+fn guesses_delimiters() {
+    let examples: &[(&[u8], u8)] = &[
+        (b"a,b,c\n1,2,3\n", b','),
+        (b"a;b;c\n1;2,5;3\n", b';'),
+        (b"a\tb\n1\t2\n", b'\t'),
+        (b"a|b\n\"1|2\"|3\n", b'|'),
+        (b"a\n1\n", b','),
+        (b"", b','),
+    ];
+    for &(input, expected) in examples {
+        assert_eq!(sample_for(input, 100).guess_delimiter(Some(b'"')), expected);
+    }
+}
+
+#[test]
+fn respects_sample_row_limit() {
+    // The first line looks like it uses `;`, but later lines use `,`.
+    let input = b"a;b\n1,2,3\n4,5,6\n7,8,9\n";
+    assert_eq!(sample_for(input, 1).guess_delimiter(Some(b'"')), b';');
+    assert_eq!(sample_for(input, 4).guess_delimiter(Some(b'"')), b',');
+}
+
+#[test]
+fn strips_prologues() {
+    let examples: &[(&[u8], Option<u8>, &[u8])] = &[
+        (b"sep=;\r\na;b\n", Some(b';'), b"a;b\n"),
+        (b"SEP=|\na|b\n", Some(b'|'), b"a|b\n"),
+        (b"sep=\t", Some(b'\t'), b""),
+        (b"sep=;;\na\n", None, b"sep=;;\na\n"),
+        (b"a,b\n", None, b"a,b\n"),
+        (b"\xEF\xBB\xBFa,b\n", None, b"a,b\n"),
+        (b"\xEF\xBB\xBFsep=;\na\n", Some(b';'), b"a\n"),
+        (b"\xEF\xBB", None, b"\xEF\xBB"),
+        (b"", None, b""),
+    ];
+    for &(input, expected, rest) in examples {
+        let (delimiter, mut input) = strip_prologue(Box::new(input), true).unwrap();
+        assert_eq!(delimiter, expected);
+        let mut all = vec![];
+        input.read_to_end(&mut all).unwrap();
+        assert_eq!(all, rest);
+    }
+
+    // Byte order marks are left alone if we were asked to.
+    let input: Box<dyn Read> = Box::new(&b"\xEF\xBB\xBFa\n"[..]);
+    let (_, mut input) = strip_prologue(input, false).unwrap();
+    let mut all = vec![];
+    input.read_to_end(&mut all).unwrap();
+    assert_eq!(all, b"\xEF\xBB\xBFa\n");
+}
+
+#[test]
+fn sample_preserves_input() {
+    let input: Box<dyn Read> = Box::new(&b"a,b\n1,2\n"[..]);
+    let (_sample, mut input) = Sample::read(input, 3, 100).unwrap();
+    let mut all = vec![];
+    input.read_to_end(&mut all).unwrap();
+    assert_eq!(all, b"a,b\n1,2\n");
+}
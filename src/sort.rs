@@ -0,0 +1,664 @@
+//! Sorting our output by the values of some columns, for `--sort-by`,
+//! checking that it's already sorted, for `--assert-sorted-by`, and merging
+//! sorted inputs, for `--merge-sorted-by`.
+//!
+//! Our output may not fit in memory, so we spool it to a temporary file. When
+//! we finish, we read it back in chunks which do fit, sort each chunk into a
+//! temporary "run" file, and then merge the runs into our real output.
+
+use csv::ByteRecord;
+use log::debug;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    env, fs,
+    io::{self, prelude::*},
+    mem,
+    path::PathBuf,
+    process,
+    str::FromStr,
+};
+
+use crate::empty_columns::spooled_records;
+use crate::errors::*;
+use crate::output::FinishWrite;
+use crate::quoting::{
+    write_record_quoting_edge_whitespace, OutputFormat, SharedOutput,
+};
+use crate::stats::{RuleHits, RuleId};
+use crate::util::find_column;
+
+/// How much memory we use to sort, unless we're told otherwise.
+pub const DEFAULT_SORT_MEMORY: usize = 256 << 20;
+
+/// Roughly how much memory each record takes, not counting its values.
+const RECORD_OVERHEAD: usize = 64;
+
+/// A column to sort by, written as `COL[:num][:desc]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SortKey {
+    /// The name of the column.
+    column: String,
+    /// Should we compare values as numbers?
+    numeric: bool,
+    /// Should we sort from largest to smallest?
+    descending: bool,
+}
+
+impl FromStr for SortKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SortKey> {
+        let mut parts = s.split(':');
+        let column = parts.next().unwrap_or_default();
+        if column.is_empty() {
+            return Err(format_err!("expected COL[:num][:desc], found {:?}", s));
+        }
+        let mut key = SortKey {
+            column: column.to_owned(),
+            numeric: false,
+            descending: false,
+        };
+        for part in parts {
+            match part {
+                "asc" => key.descending = false,
+                "desc" => key.descending = true,
+                "num" => key.numeric = true,
+                _ => {
+                    return Err(format_err!(
+                        "unknown sort order {:?} in {:?}",
+                        part,
+                        s
+                    ))
+                }
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// A `SortKey` for a specific column index.
+#[derive(Clone, Copy, Debug)]
+struct ColumnKey {
+    idx: usize,
+    numeric: bool,
+    descending: bool,
+}
+
+/// Find the columns in `hdr` for each of `keys`, which were passed to
+/// `option`.
+fn column_keys(
+    hdr: &ByteRecord,
+    keys: &[SortKey],
+    option: &str,
+) -> Result<Vec<ColumnKey>> {
+    keys.iter()
+        .map(|key| {
+            let idx = find_column(hdr, &key.column).ok_or_else(|| {
+                format_err!("cannot find {} column {:?}", option, key.column)
+            })?;
+            Ok(ColumnKey {
+                idx,
+                numeric: key.numeric,
+                descending: key.descending,
+            })
+        })
+        .collect()
+}
+
+/// Make sure that every one of `keys` is a column in `hdr`, so that we fail
+/// before reading any rows.
+pub fn check_sort_keys(hdr: &ByteRecord, keys: &[SortKey]) -> Result<()> {
+    column_keys(hdr, keys, "--sort-by").map(|_| ())
+}
+
+/// Compare two values. Numeric values sort before anything which isn't a
+/// number. Everything else is sorted by its bytes. Values like "nan" and
+/// "inf" don't count as numbers, because "nan" can't be ordered, and our
+/// merges need a consistent order.
+fn compare_values(a: &[u8], b: &[u8], numeric: bool) -> Ordering {
+    let as_number = |v: &[u8]| -> Option<f64> {
+        let n = std::str::from_utf8(v).ok()?.trim().parse::<f64>().ok()?;
+        Some(n).filter(|n| n.is_finite())
+    };
+    if numeric {
+        match (as_number(a), as_number(b)) {
+            (Some(a), Some(b)) => {
+                return a.partial_cmp(&b).expect("finite numbers should be ordered")
+            }
+            (Some(_), None) => return Ordering::Less,
+            (None, Some(_)) => return Ordering::Greater,
+            (None, None) => {}
+        }
+    }
+    a.cmp(b)
+}
+
+/// Compare two values of the column for `key`.
+fn compare_key(key: &ColumnKey, a: &[u8], b: &[u8]) -> Ordering {
+    let ord = compare_values(a, b, key.numeric);
+    if key.descending {
+        ord.reverse()
+    } else {
+        ord
+    }
+}
+
+/// Compare two records using `keys`.
+fn compare_records(a: &ByteRecord, b: &ByteRecord, keys: &[ColumnKey]) -> Ordering {
+    keys.iter()
+        .map(|key| {
+            let a = a.get(key.idx).unwrap_or_default();
+            let b = b.get(key.idx).unwrap_or_default();
+            compare_key(key, a, b)
+        })
+        .find(|&ord| ord != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Spools our output, and writes it to `inner` in sorted order when we
+/// finish. The first row we're given is our header, which stays first.
+pub struct ExternalSorter<'a> {
+    /// Where our output should end up, until we finish.
+    inner: Option<Box<dyn FinishWrite + 'a>>,
+    /// How to sort our rows.
+    keys: Vec<SortKey>,
+    /// Roughly how much memory we may use to sort each run.
+    max_memory: usize,
+    /// Our spooled output.
+    spool: io::BufWriter<fs::File>,
+    /// The path of our spool file, which we remove when we're dropped.
+    spool_path: PathBuf,
+    /// Our sorted runs, which we remove when we're dropped.
+    run_paths: Vec<PathBuf>,
+    /// How our output is formatted.
+    format: OutputFormat,
+    /// Should we quote values with leading or trailing whitespace?
+    quote_edge_whitespace: bool,
+}
+
+impl<'a> ExternalSorter<'a> {
+    /// Spool our output before sorting it and writing it to `inner`.
+    pub fn new(
+        inner: Box<dyn FinishWrite + 'a>,
+        keys: Vec<SortKey>,
+        max_memory: usize,
+        format: OutputFormat,
+        quote_edge_whitespace: bool,
+    ) -> io::Result<ExternalSorter<'a>> {
+        let spool_path =
+            env::temp_dir().join(format!("scrubcsv-{}-sort.csv", process::id()));
+        debug!("spooling output to {} for sorting", spool_path.display());
+        let spool = io::BufWriter::new(fs::File::create(&spool_path)?);
+        Ok(ExternalSorter {
+            inner: Some(inner),
+            keys,
+            max_memory,
+            spool,
+            spool_path,
+            run_paths: vec![],
+            format,
+            quote_edge_whitespace,
+        })
+    }
+
+    /// Sort `chunk` and write it to a new run file.
+    fn write_run(
+        &mut self,
+        chunk: &mut Vec<ByteRecord>,
+        keys: &[ColumnKey],
+    ) -> io::Result<()> {
+        chunk.sort_by(|a, b| compare_records(a, b, keys));
+        let path = env::temp_dir().join(format!(
+            "scrubcsv-{}-sort-{}.csv",
+            process::id(),
+            self.run_paths.len()
+        ));
+        debug!("writing {} sorted rows to {}", chunk.len(), path.display());
+        self.run_paths.push(path.clone());
+        let mut wtr = csv::WriterBuilder::new().from_path(&path)?;
+        for record in chunk.drain(..) {
+            wtr.write_byte_record(&record)?;
+        }
+        wtr.flush()
+    }
+}
+
+impl Write for ExternalSorter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.spool.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}
+
+impl FinishWrite for ExternalSorter<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.spool.flush()?;
+        let format = self.format;
+        let mut records = spooled_records(&self.spool_path, format)?;
+        let hdr = records.next().transpose()?;
+        let keys = match &hdr {
+            Some(hdr) => column_keys(hdr, &self.keys, "--sort-by")
+                .map_err(|err| io::Error::other(err.to_string()))?,
+            None => vec![],
+        };
+
+        // Sort as many rows as we can in memory, spilling sorted runs to disk
+        // if there are too many.
+        let mut chunk = vec![];
+        let mut chunk_bytes = 0;
+        for record in records {
+            let record = record?;
+            chunk_bytes += record.as_slice().len() + RECORD_OVERHEAD;
+            chunk.push(record);
+            if chunk_bytes >= self.max_memory {
+                self.write_run(&mut chunk, &keys)?;
+                chunk_bytes = 0;
+            }
+        }
+        let mut sorted: Box<dyn Iterator<Item = io::Result<ByteRecord>>> =
+            if self.run_paths.is_empty() {
+                chunk.sort_by(|a, b| compare_records(a, b, &keys));
+                Box::new(chunk.into_iter().map(Ok))
+            } else {
+                if !chunk.is_empty() {
+                    self.write_run(&mut chunk, &keys)?;
+                }
+                Box::new(merge_runs(&self.run_paths, keys.clone())?)
+            };
+
+        let quote_edge_whitespace = self.quote_edge_whitespace;
+        let mut inner = self.inner.take().expect("should only finish once");
+        let mut output = SharedOutput::new(&mut inner);
+        let mut wtr_builder = csv::WriterBuilder::new();
+        format.configure(&mut wtr_builder);
+        let mut wtr = wtr_builder.from_writer(output.clone());
+        let rows = hdr.into_iter().map(Ok).chain(&mut sorted);
+        for record in rows {
+            let record = record?;
+            if quote_edge_whitespace {
+                write_record_quoting_edge_whitespace(
+                    &mut wtr,
+                    &mut output,
+                    format,
+                    record.iter(),
+                )
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            } else {
+                wtr.write_byte_record(&record)?;
+            }
+        }
+        wtr.flush()?;
+        drop(wtr);
+        drop(output);
+        drop(sorted);
+        inner.finish()
+    }
+}
+
+impl Drop for ExternalSorter<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.spool_path);
+        for path in &self.run_paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Merge the sorted runs in `paths`. When rows compare equal, we keep them
+/// in their original order.
+fn merge_runs(
+    paths: &[PathBuf],
+    keys: Vec<ColumnKey>,
+) -> io::Result<impl Iterator<Item = io::Result<ByteRecord>>> {
+    let mut runs = paths
+        .iter()
+        .map(|path| {
+            Ok(csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_path(path)?
+                .into_byte_records())
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut heads = runs
+        .iter_mut()
+        .map(|run| run.next().transpose())
+        .collect::<csv::Result<Vec<_>>>()?;
+    Ok(std::iter::from_fn(move || {
+        // We usually only have a few runs, so a linear scan is fine. `min_by`
+        // returns the first of several equal rows, which keeps us stable.
+        let (i, _) = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|head| (i, head)))
+            .min_by(|(_, a), (_, b)| compare_records(a, b, &keys))?;
+        let next = match runs[i].next().transpose() {
+            Ok(next) => next,
+            Err(err) => return Some(Err(err.into())),
+        };
+        std::mem::replace(&mut heads[i], next).map(Ok)
+    }))
+}
+
+/// What to do with rows which are out of order for `--assert-sorted-by`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnUnsorted {
+    /// Exit with an error.
+    Fail,
+    /// Reject the row as bad.
+    Reject,
+}
+
+impl FromStr for OnUnsorted {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OnUnsorted> {
+        match s {
+            "fail" => Ok(OnUnsorted::Fail),
+            "reject" => Ok(OnUnsorted::Reject),
+            _ => Err(format_err!("unknown unsorted row policy: '{}'", s)),
+        }
+    }
+}
+
+/// Checks that our rows are sorted, as they stream past.
+pub struct SortChecker {
+    /// How our rows should be sorted.
+    keys: Vec<ColumnKey>,
+    /// The key values of the last row in order, if any.
+    last: Option<Vec<Vec<u8>>>,
+    /// What to do with rows which are out of order.
+    policy: OnUnsorted,
+    /// Counts rows which are out of order.
+    rule: RuleId,
+}
+
+impl SortChecker {
+    /// Check that rows with the header `hdr` are sorted by `keys`. Registers
+    /// our rule with `hits`.
+    pub fn new(
+        hdr: &ByteRecord,
+        keys: &[SortKey],
+        policy: OnUnsorted,
+        hits: &mut RuleHits,
+    ) -> Result<SortChecker> {
+        Ok(SortChecker {
+            keys: column_keys(hdr, keys, "--assert-sorted-by")?,
+            last: None,
+            policy,
+            rule: hits.register("--assert-sorted-by", "rows out of order"),
+        })
+    }
+
+    /// What we do with rows which are out of order.
+    pub fn policy(&self) -> OnUnsorted {
+        self.policy
+    }
+
+    /// Does `row` belong after the last row which was in order? If not, we
+    /// count it in `hits`, and ignore it when checking later rows.
+    pub fn check(&mut self, row: &[Cow<[u8]>], hits: &RuleHits) -> bool {
+        if let Some(last) = &self.last {
+            let ord = self
+                .keys
+                .iter()
+                .zip(last)
+                .map(|(key, last)| compare_key(key, last, &row[key.idx]))
+                .find(|&ord| ord != Ordering::Equal)
+                .unwrap_or(Ordering::Equal);
+            if ord == Ordering::Greater {
+                hits.hit(self.rule);
+                return false;
+            }
+        }
+        self.last = Some(self.keys.iter().map(|key| row[key.idx].to_vec()).collect());
+        true
+    }
+}
+
+/// Merges the records of several inputs, each already sorted by the same
+/// keys. We read from one input at a time, which we call the current input,
+/// and keep the next record from each of the others, so that the current
+/// input is always the one our last record came from.
+pub struct SortedMerge<I> {
+    /// How our inputs are sorted.
+    keys: Vec<ColumnKey>,
+    /// The position of the current input in our list of inputs.
+    current: usize,
+    /// Have we read everything from the current input?
+    current_done: bool,
+    /// Our other inputs, with their positions and their next records.
+    waiting: Vec<(usize, I, ByteRecord)>,
+    /// Inputs we've read everything from.
+    finished: Vec<I>,
+}
+
+impl<I> SortedMerge<I> {
+    /// Merge the current input, which comes first, with `others`. Each input
+    /// has the header `hdr`, and is sorted by `keys`. We use `read` to read
+    /// records from an input.
+    pub fn new<R>(
+        hdr: &ByteRecord,
+        keys: &[SortKey],
+        others: Vec<I>,
+        mut read: R,
+    ) -> Result<SortedMerge<I>>
+    where
+        R: FnMut(&mut I, &mut ByteRecord) -> csv::Result<bool>,
+    {
+        let mut waiting = vec![];
+        let mut finished = vec![];
+        for (i, mut input) in others.into_iter().enumerate() {
+            let mut record = ByteRecord::new();
+            if read(&mut input, &mut record).context("cannot read record")? {
+                waiting.push((i + 1, input, record));
+            } else {
+                finished.push(input);
+            }
+        }
+        Ok(SortedMerge {
+            keys: column_keys(hdr, keys, "--merge-sorted-by")?,
+            current: 0,
+            current_done: false,
+            waiting,
+            finished,
+        })
+    }
+
+    /// The position of the input our last record came from.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// The inputs we've read everything from, not counting `current`.
+    pub fn finished(&self) -> &[I] {
+        &self.finished
+    }
+
+    /// Read our next record into `record`, using `read` to read from our
+    /// inputs. This may replace `current` with the input our record came
+    /// from. Returns `false` once we've read every record.
+    pub fn read_byte_record<R>(
+        &mut self,
+        current: &mut I,
+        record: &mut ByteRecord,
+        mut read: R,
+    ) -> csv::Result<bool>
+    where
+        R: FnMut(&mut I, &mut ByteRecord) -> csv::Result<bool>,
+    {
+        let has_record = !self.current_done && read(current, record)?;
+        self.current_done = !has_record;
+
+        // Find the waiting record which comes first, breaking ties using the
+        // order of our inputs.
+        let keys = &self.keys;
+        let compare = |(ia, a): (usize, &ByteRecord),
+                       (ib, b): (usize, &ByteRecord)| {
+            compare_records(a, b, keys).then(ia.cmp(&ib))
+        };
+        let best = self
+            .waiting
+            .iter()
+            .enumerate()
+            .min_by(|(_, (ia, _, a)), (_, (ib, _, b))| compare((*ia, a), (*ib, b)))
+            .map(|(j, _)| j);
+        let best = match best {
+            Some(j)
+                if !has_record
+                    || compare(
+                        (self.waiting[j].0, &self.waiting[j].2),
+                        (self.current, record),
+                    ) == Ordering::Less =>
+            {
+                j
+            }
+            _ => return Ok(has_record),
+        };
+
+        // Switch to the input with that record.
+        if has_record {
+            let (index, input, head) = &mut self.waiting[best];
+            mem::swap(current, input);
+            mem::swap(record, head);
+            mem::swap(&mut self.current, index);
+        } else {
+            let (index, input, head) = self.waiting.swap_remove(best);
+            self.finished.push(mem::replace(current, input));
+            *record = head;
+            self.current = index;
+            self.current_done = false;
+        }
+        Ok(true)
+    }
+}
+
+#[test]
+fn merges_sorted_inputs() {
+    let hdr = ByteRecord::from(vec!["n"]);
+    let keys = ["n:num".parse::<SortKey>().unwrap()];
+    let input = |values: &[&str]| {
+        values
+            .iter()
+            .map(|v| ByteRecord::from(vec![*v]))
+            .collect::<Vec<_>>()
+            .into_iter()
+    };
+    let read = |input: &mut std::vec::IntoIter<ByteRecord>,
+                record: &mut ByteRecord| {
+        Ok(match input.next() {
+            Some(next) => {
+                *record = next;
+                true
+            }
+            None => false,
+        })
+    };
+    let mut current = input(&["1", "5", "9"]);
+    let others = vec![input(&["2", "5", "10"]), input(&[]), input(&["0", "11"])];
+    let mut merge = SortedMerge::new(&hdr, &keys, others, read).unwrap();
+    let mut record = ByteRecord::new();
+    let mut merged = vec![];
+    while merge
+        .read_byte_record(&mut current, &mut record, read)
+        .unwrap()
+    {
+        merged.push((
+            String::from_utf8_lossy(&record[0]).into_owned(),
+            merge.current(),
+        ));
+    }
+    let expected = [
+        ("0", 3),
+        ("1", 0),
+        ("2", 1),
+        ("5", 0),
+        ("5", 1),
+        ("9", 0),
+        ("10", 1),
+        ("11", 3),
+    ];
+    assert_eq!(
+        merged,
+        expected
+            .iter()
+            .map(|(v, i)| (v.to_string(), *i))
+            .collect::<Vec<_>>(),
+    );
+    assert_eq!(merge.finished().len(), 3);
+}
+
+#[test]
+fn checks_sort_order() {
+    let hdr = ByteRecord::from(vec!["id", "n"]);
+    let keys = ["n:num".parse::<SortKey>().unwrap()];
+    let mut hits = RuleHits::default();
+    let mut checker =
+        SortChecker::new(&hdr, &keys, OnUnsorted::Reject, &mut hits).unwrap();
+    let row =
+        |n: &'static str| vec![Cow::Borrowed(&b"x"[..]), Cow::Borrowed(n.as_bytes())];
+    assert!(checker.check(&row("2"), &hits));
+    assert!(checker.check(&row("10"), &hits));
+    assert!(checker.check(&row("10"), &hits));
+    assert!(!checker.check(&row("9"), &hits));
+    assert!(checker.check(&row("11"), &hits));
+    assert!(SortChecker::new(
+        &hdr,
+        &["x".parse().unwrap()],
+        OnUnsorted::Fail,
+        &mut hits
+    )
+    .is_err());
+}
+
+#[test]
+fn parses_sort_keys() {
+    let key = "zip".parse::<SortKey>().unwrap();
+    assert!(!key.numeric && !key.descending);
+    let key = "amount:num:desc".parse::<SortKey>().unwrap();
+    assert_eq!(key.column, "amount");
+    assert!(key.numeric && key.descending);
+    assert!("".parse::<SortKey>().is_err());
+    assert!("zip:sideways".parse::<SortKey>().is_err());
+}
+
+#[test]
+fn compares_records() {
+    let keys = [
+        ColumnKey {
+            idx: 0,
+            numeric: true,
+            descending: false,
+        },
+        ColumnKey {
+            idx: 1,
+            numeric: false,
+            descending: true,
+        },
+    ];
+    let mut records = [
+        ByteRecord::from(vec!["10", "a"]),
+        ByteRecord::from(vec!["x", "a"]),
+        ByteRecord::from(vec!["9", "a"]),
+        ByteRecord::from(vec!["10", "b"]),
+    ];
+    records.sort_by(|a, b| compare_records(a, b, &keys));
+    let sorted = records
+        .iter()
+        .map(|r| String::from_utf8_lossy(r.as_slice()).into_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(sorted, vec!["9a", "10b", "10a", "xa"]);
+}
+
+#[test]
+fn sorts_non_finite_values_as_text() {
+    let mut values = vec!["1", "nan", "inf", "-inf", "2", "NaN", "1e999", "-3"];
+    values.sort_by(|a, b| compare_values(a.as_bytes(), b.as_bytes(), true));
+    assert_eq!(
+        values,
+        vec!["-3", "1", "2", "-inf", "1e999", "NaN", "inf", "nan"]
+    );
+}
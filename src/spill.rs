@@ -0,0 +1,329 @@
+//! Holding rows which we can't write yet, for `--dedup-by --keep last` and
+//! `--tail`.
+//!
+//! We keep rows in memory until they would take up more than
+//! `--memory-limit`, and then we append any more rows to a temporary file.
+//! Rows can be taken back out in any order, which lets `--dedup-by` reject
+//! a row when a later row replaces it, so memory use stays bounded even when
+//! most of our rows turn out to be bad.
+
+use log::debug;
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    env, fs,
+    io::{self, prelude::*, SeekFrom},
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::errors::*;
+use crate::util::ByteSize;
+
+/// Roughly how much memory each value takes, not counting its bytes.
+const VALUE_OVERHEAD: usize = 24;
+
+/// How many spool files we've created, so that each gets its own name.
+static SPOOL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Something we can write to a spool file and read back.
+pub trait Spill: Sized {
+    /// Roughly how many bytes of memory do we use?
+    fn memory_size(&self) -> usize;
+
+    /// Append our encoded form to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decode something written by `encode`. Returns `None` if `buf` is
+    /// corrupt.
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+impl Spill for Vec<Vec<u8>> {
+    fn memory_size(&self) -> usize {
+        values_memory_size(self.iter().map(|v| &v[..]))
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_values(buf, self.iter().map(|v| &v[..]));
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(buf);
+        let values = decoder.values()?;
+        decoder.finish().map(|()| values)
+    }
+}
+
+/// Roughly how many bytes of memory `values` use.
+pub fn values_memory_size<'a>(
+    values: impl ExactSizeIterator<Item = &'a [u8]>,
+) -> usize {
+    let overhead = (values.len() + 1) * VALUE_OVERHEAD;
+    overhead + values.map(|v| v.len()).sum::<usize>()
+}
+
+/// Append `n` to `buf`.
+pub fn encode_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Append `values` to `buf`, with their count and lengths.
+pub fn encode_values<'a>(
+    buf: &mut Vec<u8>,
+    values: impl ExactSizeIterator<Item = &'a [u8]>,
+) {
+    encode_u64(buf, values.len() as u64);
+    for value in values {
+        encode_u64(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+}
+
+/// Reads back what we wrote with `encode_u64` and `encode_values`.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Decode `buf`.
+    pub fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder { buf }
+    }
+
+    /// Decode the next number.
+    pub fn u64(&mut self) -> Option<u64> {
+        let (n, rest) = self.buf.split_first_chunk::<8>()?;
+        self.buf = rest;
+        Some(u64::from_le_bytes(*n))
+    }
+
+    /// Decode the next value.
+    pub fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = usize::try_from(self.u64()?).ok()?;
+        let (value, rest) = self.buf.split_at_checked(len)?;
+        self.buf = rest;
+        Some(value)
+    }
+
+    /// Decode the next list of values.
+    pub fn values(&mut self) -> Option<Vec<Vec<u8>>> {
+        let count = self.u64()?;
+        (0..count)
+            .map(|_| self.bytes().map(|v| v.to_vec()))
+            .collect()
+    }
+
+    /// Make sure we've decoded everything.
+    pub fn finish(self) -> Option<()> {
+        self.buf.is_empty().then_some(())
+    }
+}
+
+/// Where we put a row.
+#[derive(Debug)]
+enum Slot<T> {
+    /// In memory.
+    Memory(T),
+    /// In our spool file, at `offset`.
+    Spilled { offset: u64, len: usize },
+    /// Nowhere, because it was taken.
+    Taken,
+}
+
+/// A temporary file holding the rows which didn't fit in memory.
+#[derive(Debug)]
+struct Spool {
+    file: io::BufWriter<fs::File>,
+    /// The path of our spool file, which we remove when we're dropped.
+    path: PathBuf,
+    /// How many bytes we've written.
+    len: u64,
+}
+
+impl Spool {
+    fn create() -> Result<Spool> {
+        let path = env::temp_dir().join(format!(
+            "scrubcsv-{}-held-{}.bin",
+            process::id(),
+            SPOOL_COUNT.fetch_add(1, Ordering::Relaxed),
+        ));
+        debug!("spilling held rows to {}", path.display());
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|_| format!("cannot create {}", path.display()))?;
+        Ok(Spool {
+            file: io::BufWriter::new(file),
+            path,
+            len: 0,
+        })
+    }
+
+    /// Read `len` bytes at `offset`.
+    fn read(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf)?;
+        file.seek(SeekFrom::Start(self.len))?;
+        Ok(buf)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Decode a row we read back from our spool.
+fn decode<T: Spill>(buf: &[u8]) -> Result<T> {
+    T::decode(buf).ok_or_else(|| format_err!("held row file is corrupt"))
+}
+
+/// Rows we're holding on to, in order. Each row added gets an index, starting
+/// at 0, which can be used to take it back out.
+#[derive(Debug)]
+pub struct SpillBuffer<T> {
+    /// Our rows. Rows which we took or dropped from the front are gone.
+    slots: VecDeque<Slot<T>>,
+    /// The index of the row in `slots[0]`.
+    first: usize,
+    /// Roughly how much memory our in-memory rows use.
+    memory_used: usize,
+    /// How much memory our rows may use before we spill them, if limited.
+    memory_limit: Option<usize>,
+    /// Where we put rows which don't fit, once we need it.
+    spool: Option<Spool>,
+    /// A buffer for encoding rows.
+    scratch: Vec<u8>,
+}
+
+impl<T: Spill> SpillBuffer<T> {
+    /// Create a buffer which keeps at most `memory_limit` of rows in memory,
+    /// if specified.
+    pub fn new(memory_limit: Option<ByteSize>) -> SpillBuffer<T> {
+        SpillBuffer {
+            slots: VecDeque::new(),
+            first: 0,
+            memory_used: 0,
+            memory_limit: memory_limit.map(|ByteSize(bytes)| bytes),
+            spool: None,
+            scratch: vec![],
+        }
+    }
+
+    /// How many rows are we holding, including any which were taken from the
+    /// middle?
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Add `row` to the end of our buffer, and return its index.
+    pub fn push(&mut self, row: T) -> Result<usize> {
+        let size = row.memory_size();
+        let fits = self
+            .memory_limit
+            .is_none_or(|limit| self.memory_used + size <= limit);
+        let slot = if fits {
+            self.memory_used += size;
+            Slot::Memory(row)
+        } else {
+            if self.spool.is_none() {
+                self.spool = Some(Spool::create()?);
+            }
+            let spool = self.spool.as_mut().expect("created spool above");
+            self.scratch.clear();
+            row.encode(&mut self.scratch);
+            spool
+                .file
+                .write_all(&self.scratch)
+                .context("cannot write held row")?;
+            let offset = spool.len;
+            spool.len += self.scratch.len() as u64;
+            Slot::Spilled {
+                offset,
+                len: self.scratch.len(),
+            }
+        };
+        self.slots.push_back(slot);
+        Ok(self.first + self.slots.len() - 1)
+    }
+
+    /// Take the row at `idx`, if we still have it.
+    pub fn take(&mut self, idx: usize) -> Result<Option<T>> {
+        let slot = match idx
+            .checked_sub(self.first)
+            .and_then(|i| self.slots.get_mut(i))
+        {
+            Some(slot) => std::mem::replace(slot, Slot::Taken),
+            None => return Ok(None),
+        };
+        self.row_from(slot)
+    }
+
+    /// Forget our oldest row, without reading it back.
+    pub fn drop_first(&mut self) {
+        if let Some(Slot::Memory(row)) = self.slots.pop_front() {
+            self.memory_used -= row.memory_size();
+        }
+        self.first += 1;
+    }
+
+    /// Turn `slot` back into a row.
+    fn row_from(&mut self, slot: Slot<T>) -> Result<Option<T>> {
+        match slot {
+            Slot::Memory(row) => {
+                self.memory_used -= row.memory_size();
+                Ok(Some(row))
+            }
+            Slot::Spilled { offset, len } => {
+                let spool = self.spool.as_mut().expect("spilled row without spool");
+                let buf = spool.read(offset, len).context("cannot read held row")?;
+                decode(&buf).map(Some)
+            }
+            Slot::Taken => Ok(None),
+        }
+    }
+
+    /// Return the rows we're still holding, in order.
+    pub fn into_rows(mut self) -> impl Iterator<Item = Result<T>> {
+        let slots = std::mem::take(&mut self.slots);
+        slots
+            .into_iter()
+            .filter_map(move |slot| self.row_from(slot).transpose())
+    }
+}
+
+#[test]
+fn spills_rows_past_memory_limit() {
+    let row = |i: usize| vec![i.to_string().into_bytes(), vec![b'x'; i]];
+    let limit = (0..10).map(|i| row(i).memory_size()).sum::<usize>();
+    let mut buffer = SpillBuffer::new(Some(ByteSize(limit)));
+    for i in 0..100 {
+        assert_eq!(buffer.push(row(i)).unwrap(), i);
+    }
+    assert!(buffer.spool.is_some());
+    assert!(buffer.memory_used <= limit);
+
+    // We can take rows from memory or from our spool, in any order.
+    assert_eq!(buffer.take(50).unwrap(), Some(row(50)));
+    assert_eq!(buffer.take(50).unwrap(), None);
+    assert_eq!(buffer.take(3).unwrap(), Some(row(3)));
+    buffer.drop_first();
+    buffer.drop_first();
+    assert_eq!(buffer.take(0).unwrap(), None);
+    assert_eq!(buffer.len(), 98);
+
+    let expected = (2..100)
+        .filter(|&i| i != 3 && i != 50)
+        .map(row)
+        .collect::<Vec<_>>();
+    let rows = buffer.into_rows().collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(rows, expected);
+}
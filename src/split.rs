@@ -0,0 +1,298 @@
+//! Splitting our output into several files, for `--split-rows`,
+//! `--split-bytes` and `--partition-by`.
+
+use log::debug;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    io::{self, prelude::*},
+    path::PathBuf,
+    rc::Rc,
+};
+
+use crate::errors::*;
+use crate::output::{FinishWrite, OutputFile, OutputTemplate};
+use crate::raw::RawWriter;
+
+/// How many partition files we keep open at once, unless we're told
+/// otherwise.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 128;
+
+/// A file we're writing.
+struct SplitFile {
+    file: OutputFile,
+    /// Is `file` open right now?
+    open: bool,
+    /// When we last wrote to this file, for closing the least recently used.
+    last_used: u64,
+}
+
+/// The state shared by `Splitter` and `SplitWriter`.
+struct SplitState {
+    /// How to name our files.
+    template: OutputTemplate,
+    /// The files we're still writing.
+    files: HashMap<PathBuf, SplitFile>,
+    /// The file we're writing to now, if any.
+    current: Option<PathBuf>,
+    /// How many bytes we've written to the current file.
+    bytes: u64,
+    /// How many files we can keep open at once.
+    max_open: usize,
+    /// How many files we have open.
+    open: usize,
+    /// Counts the rows we've routed to files, for `SplitFile::last_used`.
+    clock: u64,
+    /// What we write at the start of each file, including our header.
+    preamble: Vec<u8>,
+    /// Are we still recording our preamble?
+    recording: bool,
+}
+
+impl SplitState {
+    /// Make `path` our current file, creating it if necessary.
+    fn switch_to(&mut self, path: PathBuf) -> Result<()> {
+        self.clock += 1;
+        if !self.files.contains_key(&path) {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty())
+            {
+                fs::create_dir_all(dir)
+                    .with_context(|_| format!("cannot create {}", dir.display()))?;
+            }
+            let mut file = OutputFile::create(&path)?;
+            file.write_all(&self.preamble)
+                .with_context(|_| format!("cannot write to {}", path.display()))?;
+            self.open += 1;
+            self.bytes = self.preamble.len() as u64;
+            self.files.insert(
+                path.clone(),
+                SplitFile {
+                    file,
+                    open: true,
+                    last_used: 0,
+                },
+            );
+        }
+        let split_file = self.files.get_mut(&path).expect("should have file");
+        split_file.last_used = self.clock;
+        if !split_file.open {
+            // Writing will reopen it.
+            split_file.open = true;
+            self.open += 1;
+        }
+        self.current = Some(path);
+        self.close_extra_files()
+    }
+
+    /// If we have too many files open, close the least recently used ones.
+    fn close_extra_files(&mut self) -> Result<()> {
+        while self.open > self.max_open {
+            let current = self.current.as_ref();
+            let (path, lru) = self
+                .files
+                .iter_mut()
+                .filter(|(path, f)| f.open && Some(*path) != current)
+                .min_by_key(|(_, f)| f.last_used)
+                .expect("should have a file to close");
+            debug!("closing {} for now", path.display());
+            lru.file
+                .suspend()
+                .with_context(|_| format!("cannot write to {}", path.display()))?;
+            lru.open = false;
+            self.open -= 1;
+        }
+        Ok(())
+    }
+
+    /// Finish writing `path`.
+    fn finish_file(&mut self, path: &PathBuf) -> Result<()> {
+        if let Some(split_file) = self.files.remove(path) {
+            if split_file.open {
+                self.open -= 1;
+            }
+            Box::new(split_file.file)
+                .finish()
+                .with_context(|_| format!("cannot finish {}", path.display()))?;
+        }
+        if self.current.as_ref() == Some(path) {
+            self.current = None;
+        }
+        Ok(())
+    }
+}
+
+/// Writes our output to a series of files.
+pub struct SplitWriter(Rc<RefCell<SplitState>>);
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let state = &mut *self.0.borrow_mut();
+        if state.recording {
+            state.preamble.extend_from_slice(buf);
+        }
+        if let Some(path) = &state.current {
+            let split_file = state.files.get_mut(path).expect("should have file");
+            split_file.file.write_all(buf)?;
+            state.bytes += buf.len() as u64;
+        } else if !state.recording && !buf.is_empty() {
+            panic!("wrote row without choosing an output file");
+        }
+        Ok(buf.len())
+    }
+
+    /// We don't pass this along, because `Splitter` may flush after every
+    /// row. Our files only appear once they're finished, anyway.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FinishWrite for SplitWriter {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let state = &mut *self.0.borrow_mut();
+        let mut paths = state.files.keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            state
+                .finish_file(&path)
+                .map_err(|err| io::Error::other(err.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// How we split our output.
+#[derive(Clone, Copy, Debug)]
+pub enum SplitBy {
+    /// Start a new file after `max_rows` rows, or once we've written
+    /// `max_bytes`.
+    Size {
+        max_rows: Option<u64>,
+        max_bytes: Option<u64>,
+    },
+    /// Write each row to a file named after one of its values, keeping up to
+    /// `max_open_files` open at once.
+    Value { max_open_files: usize },
+}
+
+/// Decides which file to write each row to.
+pub struct Splitter {
+    state: Rc<RefCell<SplitState>>,
+    split_by: SplitBy,
+    /// The number of the current file, when splitting by size.
+    number: u64,
+    /// How many rows we've written to the current file, when splitting by
+    /// size.
+    rows: u64,
+}
+
+impl Splitter {
+    /// Create a splitter. When splitting by size, we create our first file,
+    /// numbered 0, right away. Everything written to the returned writer
+    /// until we call `end_preamble` is copied to the start of each file.
+    pub fn new(
+        template: OutputTemplate,
+        split_by: SplitBy,
+    ) -> Result<(Splitter, SplitWriter)> {
+        let max_open = match split_by {
+            SplitBy::Size { .. } => 1,
+            SplitBy::Value { max_open_files } => max_open_files.max(1),
+        };
+        let mut state = SplitState {
+            template,
+            files: HashMap::new(),
+            current: None,
+            bytes: 0,
+            max_open,
+            open: 0,
+            clock: 0,
+            preamble: vec![],
+            recording: true,
+        };
+        if let SplitBy::Size { .. } = split_by {
+            let path = state.template.path(0);
+            state.switch_to(path)?;
+        }
+        let state = Rc::new(RefCell::new(state));
+        let splitter = Splitter {
+            state: state.clone(),
+            split_by,
+            number: 0,
+            rows: 0,
+        };
+        Ok((splitter, SplitWriter(state)))
+    }
+
+    /// We've written everything which goes at the start of each file.
+    pub fn end_preamble(&self) {
+        self.state.borrow_mut().recording = false;
+    }
+
+    /// Call this before writing each row to `wtr`, which writes to `out`.
+    /// When splitting by value, `value` is the value which chooses the file.
+    /// When splitting by size and our current file is full, we finish it and
+    /// start a new one.
+    pub fn before_row<W: Write>(
+        &mut self,
+        wtr: &mut csv::Writer<W>,
+        mut raw_writer: Option<&mut RawWriter>,
+        out: &mut W,
+        value: Option<&[u8]>,
+    ) -> Result<()> {
+        // Write out any rows we've buffered, so that they go to the right
+        // file, and so that we know how big our file is.
+        let mut write_rows = |out: &mut W| -> Result<()> {
+            if let Some(raw_writer) = &mut raw_writer {
+                raw_writer
+                    .write_buffered(out)
+                    .context("cannot write record")?;
+            }
+            wtr.flush().context("cannot write record")
+        };
+        match self.split_by {
+            SplitBy::Size {
+                max_rows,
+                max_bytes,
+            } => {
+                if max_bytes.is_some() {
+                    write_rows(out)?;
+                }
+                let mut state = self.state.borrow_mut();
+                let full = max_rows.is_some_and(|max| self.rows >= max)
+                    || max_bytes.is_some_and(|max| state.bytes >= max);
+                if self.rows > 0 && full {
+                    drop(state);
+                    write_rows(out)?;
+                    state = self.state.borrow_mut();
+                    let current = state.current.clone().expect("should have file");
+                    state.finish_file(&current)?;
+                    self.number += 1;
+                    let path = state.template.path(self.number);
+                    state.switch_to(path)?;
+                    self.rows = 0;
+                }
+                self.rows += 1;
+            }
+            SplitBy::Value { .. } => {
+                write_rows(out)?;
+                let mut state = self.state.borrow_mut();
+                let path = state
+                    .template
+                    .value_path(value.expect("should have partition value"));
+                if state.current.as_ref() == Some(&path) {
+                    state.clock += 1;
+                    let clock = state.clock;
+                    state
+                        .files
+                        .get_mut(&path)
+                        .expect("should have file")
+                        .last_used = clock;
+                } else {
+                    state.switch_to(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
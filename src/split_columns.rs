@@ -0,0 +1,195 @@
+//! Splitting one column into several, for `--split-column`.
+
+use csv::ByteRecord;
+use regex::bytes::Regex;
+use std::{borrow::Cow, str::FromStr};
+
+use crate::errors::*;
+use crate::util::{find_column, parse_option_words};
+
+/// How to split a value.
+#[derive(Clone, Debug)]
+enum SplitOn {
+    /// Split on each occurrence of a separator.
+    Separator(Vec<u8>),
+    /// Split on each match of a regex, or use its capture groups.
+    Regex(Regex),
+}
+
+/// A column to split, written as `COL=NEW1,NEW2 on=SEP` or
+/// `COL=NEW1,NEW2 re=REGEX`.
+#[derive(Clone, Debug)]
+pub struct ColumnSplit {
+    /// The column to split.
+    column: String,
+    /// The names of the columns which replace it.
+    into: Vec<String>,
+    /// How to split it.
+    on: SplitOn,
+}
+
+impl FromStr for ColumnSplit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ColumnSplit> {
+        let bad_spec = || {
+            format_err!(
+                "expected COL=NEW1,NEW2,... followed by on=SEP or re=REGEX, found {:?}",
+                s
+            )
+        };
+        let words = parse_option_words(s)?;
+        let (column, into) = match words.first() {
+            Some((column, Some(into))) => (column.to_owned(), into),
+            _ => return Err(bad_spec()),
+        };
+        let into = into.split(',').map(str::to_owned).collect::<Vec<_>>();
+        let on = match &words[1..] {
+            [(key, Some(sep))] if key == "on" && !sep.is_empty() => {
+                SplitOn::Separator(sep.as_bytes().to_owned())
+            }
+            [(key, Some(re))] if key == "re" => {
+                let re = Regex::new(re)
+                    .with_context(|_| format!("cannot parse regex {:?}", re))?;
+                let groups = re.captures_len() - 1;
+                if groups > 0 && groups != into.len() {
+                    return Err(format_err!(
+                        "regex {:?} has {} capture groups, but we need {}",
+                        re.as_str(),
+                        groups,
+                        into.len()
+                    ));
+                }
+                SplitOn::Regex(re)
+            }
+            _ => return Err(bad_spec()),
+        };
+        Ok(ColumnSplit { column, into, on })
+    }
+}
+
+impl ColumnSplit {
+    /// Split `val` into exactly `self.into.len()` pieces. If we find too many
+    /// pieces, the last one gets the rest of the value, and if we find too
+    /// few, we add empty pieces.
+    fn split<'a>(&self, val: &'a [u8]) -> Vec<&'a [u8]> {
+        let n = self.into.len();
+        let mut pieces = match &self.on {
+            SplitOn::Separator(sep) => {
+                let mut pieces = vec![];
+                let mut rest = val;
+                while pieces.len() + 1 < n {
+                    match rest.windows(sep.len()).position(|w| w == &sep[..]) {
+                        Some(i) => {
+                            pieces.push(&rest[..i]);
+                            rest = &rest[i + sep.len()..];
+                        }
+                        None => break,
+                    }
+                }
+                pieces.push(rest);
+                pieces
+            }
+            SplitOn::Regex(re) if re.captures_len() > 1 => match re.captures(val) {
+                Some(caps) => (1..=n)
+                    .map(|i| caps.get(i).map(|m| m.as_bytes()).unwrap_or(b""))
+                    .collect(),
+                // Don't lose values which don't match.
+                None => vec![val],
+            },
+            SplitOn::Regex(re) => re.splitn(val, n).collect(),
+        };
+        pieces.resize(n, b"");
+        pieces
+    }
+}
+
+/// Splits columns in each row.
+pub struct ColumnSplitter {
+    /// Our splits, and the columns they apply to at the time they run.
+    splits: Vec<(usize, ColumnSplit)>,
+}
+
+impl ColumnSplitter {
+    /// Apply `splits` to `hdr`, in order, and prepare to do the same to each
+    /// row. Returns `None` if we have nothing to split.
+    pub fn new(
+        hdr: &mut ByteRecord,
+        splits: &[ColumnSplit],
+    ) -> Result<Option<ColumnSplitter>> {
+        if splits.is_empty() {
+            return Ok(None);
+        }
+        let mut resolved = vec![];
+        for split in splits {
+            let idx = find_column(hdr, &split.column).ok_or_else(|| {
+                format_err!("cannot find --split-column column {:?}", split.column)
+            })?;
+            let mut names = hdr.iter().map(|name| name.to_owned()).collect::<Vec<_>>();
+            names.splice(
+                idx..=idx,
+                split.into.iter().map(|name| name.as_bytes().to_owned()),
+            );
+            for name in &split.into {
+                if names.iter().filter(|n| *n == name.as_bytes()).count() > 1 {
+                    return Err(format_err!("column {:?} already exists", name));
+                }
+            }
+            *hdr = ByteRecord::from(names);
+            resolved.push((idx, split.clone()));
+        }
+        Ok(Some(ColumnSplitter { splits: resolved }))
+    }
+
+    /// Split the columns of `row`.
+    pub fn split_row<'a>(&self, row: &mut Vec<Cow<'a, [u8]>>) {
+        for (idx, split) in &self.splits {
+            let pieces = split
+                .split(&row[*idx])
+                .into_iter()
+                .map(|piece| Cow::Owned(piece.to_owned()))
+                .collect::<Vec<_>>();
+            row.splice(*idx..=*idx, pieces);
+        }
+    }
+}
+
+#[test]
+fn splits_values() {
+    let split = r#"name=first,last on=" ""#.parse::<ColumnSplit>().unwrap();
+    assert_eq!(split.split(b"Jane Q Doe"), vec![&b"Jane"[..], b"Q Doe"]);
+    assert_eq!(split.split(b"Prince"), vec![&b"Prince"[..], b""]);
+
+    let split = r#"loc=city,state,zip re="^(.*), (\w\w) (\d+)$""#
+        .parse::<ColumnSplit>()
+        .unwrap();
+    assert_eq!(
+        split.split(b"Burlington, VT 05401"),
+        vec![&b"Burlington"[..], b"VT", b"05401"]
+    );
+    assert_eq!(split.split(b"nowhere"), vec![&b"nowhere"[..], b"", b""]);
+
+    let split = r#"x=a,b re=" *; *""#.parse::<ColumnSplit>().unwrap();
+    assert_eq!(split.split(b"1 ; 2;3"), vec![&b"1"[..], b"2;3"]);
+
+    assert!("name=a,b".parse::<ColumnSplit>().is_err());
+    assert!(r#"name=a,b re="(x)""#.parse::<ColumnSplit>().is_err());
+}
+
+#[test]
+fn splits_headers_and_rows() {
+    let mut hdr = ByteRecord::from(vec!["id", "name", "age"]);
+    let splits = vec![r#"name=first,last on=" ""#.parse().unwrap()];
+    let splitter = ColumnSplitter::new(&mut hdr, &splits).unwrap().unwrap();
+    assert_eq!(hdr, ByteRecord::from(vec!["id", "first", "last", "age"]));
+    let mut row = vec![
+        Cow::Borrowed(&b"1"[..]),
+        Cow::Borrowed(&b"Jane Doe"[..]),
+        Cow::Borrowed(&b"40"[..]),
+    ];
+    splitter.split_row(&mut row);
+    assert_eq!(row, vec![&b"1"[..], b"Jane", b"Doe", b"40"]);
+
+    let mut hdr = ByteRecord::from(vec!["name", "last"]);
+    assert!(ColumnSplitter::new(&mut hdr, &splits).is_err());
+}
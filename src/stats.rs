@@ -0,0 +1,175 @@
+//! Statistics about what our rules did, for our summary.
+
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+/// Identifies a rule registered with `RuleHits`.
+#[derive(Clone, Copy, Debug)]
+pub struct RuleId(usize);
+
+/// Counts how many rows or cells each of our cleanup and validation rules
+/// affected or rejected.
+#[derive(Debug, Default)]
+pub struct RuleHits {
+    /// A description of each rule, what we're counting, and the count. We use
+    /// `Cell` so that rules can be counted from inside closures that only
+    /// have shared access.
+    rules: Vec<(String, &'static str, Cell<u64>)>,
+}
+
+impl RuleHits {
+    /// Register a rule, described as `name`. Each hit counts one `unit`, such
+    /// as "cells changed" or "rows rejected".
+    pub fn register<S: Into<String>>(
+        &mut self,
+        name: S,
+        unit: &'static str,
+    ) -> RuleId {
+        self.rules.push((name.into(), unit, Cell::new(0)));
+        RuleId(self.rules.len() - 1)
+    }
+
+    /// Record that rule `id` did something.
+    pub fn hit(&self, id: RuleId) {
+        let count = &self.rules[id.0].2;
+        count.set(count.get() + 1);
+    }
+
+    /// Record that rule `id` did something `count` times.
+    pub fn add(&self, id: RuleId, count: u64) {
+        let total = &self.rules[id.0].2;
+        total.set(total.get() + count);
+    }
+
+    /// How many times has `id` been hit?
+    #[cfg(test)]
+    pub fn count(&self, id: RuleId) -> u64 {
+        self.rules[id.0].2.get()
+    }
+
+    /// Iterate over our rules, returning the name, unit and count of each.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &'static str, u64)> {
+        self.rules
+            .iter()
+            .map(|(name, unit, count)| (&name[..], *unit, count.get()))
+    }
+
+    /// Print a summary of the rules which did something to standard error.
+    /// Prints nothing if no rule did anything.
+    pub fn print_summary(&self) {
+        let mut hits = self.iter().filter(|&(_, _, count)| count > 0).peekable();
+        if hits.peek().is_some() {
+            eprintln!("Rule hits:");
+        }
+        for (name, unit, count) in hits {
+            eprintln!("  {}: {} {}", name, count, unit);
+        }
+    }
+}
+
+/// A stage of our pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading and parsing records.
+    Read,
+    /// Checking and cleaning records.
+    Clean,
+    /// Writing records.
+    Write,
+}
+
+/// How long we've spent in each `Stage`. We use a monotonic clock, because
+/// we're measuring lots of short intervals.
+#[derive(Debug, Default)]
+pub struct StageTimes {
+    /// Should we actually look at the clock?
+    enabled: bool,
+    /// Time spent in each stage, indexed by `Stage as usize`.
+    times: [Duration; 3],
+    /// The stage we're in now, and when we started it.
+    current: Option<(Stage, Instant)>,
+}
+
+impl StageTimes {
+    /// Create a new `StageTimes`. If `enabled` is false, we never look at the
+    /// clock, and every stage takes no time.
+    pub fn new(enabled: bool) -> StageTimes {
+        StageTimes {
+            enabled,
+            ..StageTimes::default()
+        }
+    }
+
+    /// Start timing `stage`, finishing whatever stage we were in before.
+    pub fn start(&mut self, stage: Stage) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        self.finish_at(now);
+        self.current = Some((stage, now));
+    }
+
+    /// Finish timing the current stage.
+    pub fn finish(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.finish_at(Instant::now());
+        self.current = None;
+    }
+
+    fn finish_at(&mut self, now: Instant) {
+        if let Some((stage, started)) = self.current {
+            self.times[stage as usize] += now - started;
+        }
+    }
+
+    /// How long have we spent in `stage`?
+    pub fn time(&self, stage: Stage) -> Duration {
+        self.times[stage as usize]
+    }
+
+    /// Print a summary of our times to standard error.
+    pub fn print_summary(&self) {
+        eprintln!(
+            "Time spent: {:.2}s reading, {:.2}s cleaning, {:.2}s writing",
+            self.time(Stage::Read).as_secs_f64(),
+            self.time(Stage::Clean).as_secs_f64(),
+            self.time(Stage::Write).as_secs_f64(),
+        );
+    }
+}
+
+#[test]
+fn times_stages() {
+    let mut times = StageTimes::new(true);
+    times.start(Stage::Read);
+    std::thread::sleep(Duration::from_millis(10));
+    times.start(Stage::Write);
+    times.finish();
+    times.finish();
+    assert!(times.time(Stage::Read) >= Duration::from_millis(10));
+    assert!(times.time(Stage::Read) > times.time(Stage::Write));
+    assert_eq!(times.time(Stage::Clean), Duration::ZERO);
+
+    let mut times = StageTimes::new(false);
+    times.start(Stage::Read);
+    std::thread::sleep(Duration::from_millis(10));
+    times.finish();
+    assert_eq!(times.time(Stage::Read), Duration::ZERO);
+}
+
+#[test]
+fn counts_rule_hits() {
+    let mut hits = RuleHits::default();
+    let a = hits.register("--a", "cells changed");
+    let b = hits.register("--b", "rows rejected");
+    hits.hit(a);
+    hits.hit(a);
+    hits.hit(b);
+    assert_eq!(hits.count(a), 2);
+    assert_eq!(hits.count(b), 1);
+}
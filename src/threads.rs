@@ -0,0 +1,275 @@
+//! Reading and writing on background threads, so that our I/O overlaps with
+//! parsing and cleaning.
+//!
+//! Each side passes chunks of data over a bounded channel, and hands empty
+//! buffers back to be reused, so we only ever allocate a few of them.
+
+use std::{
+    io::{self, prelude::*},
+    sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+    thread,
+};
+
+use crate::output::FinishWrite;
+
+/// How many chunks may be waiting in a channel at once. Two is enough for
+/// one side to fill a buffer while the other side drains one.
+const CHANNEL_DEPTH: usize = 2;
+
+/// A reader which reads `inner` on a background thread.
+pub struct ThreadedReader {
+    /// Chunks of data read by our background thread. When the thread reaches
+    /// the end of its input, it hangs up.
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    /// Where we send empty buffers to be refilled.
+    recycle: Sender<Vec<u8>>,
+    /// The chunk we're currently returning.
+    chunk: Vec<u8>,
+    /// How much of `chunk` we've returned so far.
+    pos: usize,
+}
+
+impl ThreadedReader {
+    /// Start reading from `inner` in the background, `chunk_size` bytes at a
+    /// time.
+    pub fn new<R>(mut inner: R, chunk_size: usize) -> ThreadedReader
+    where
+        R: Read + Send + 'static,
+    {
+        let (sender, chunks) = sync_channel(CHANNEL_DEPTH);
+        let (recycle, recycled) = channel::<Vec<u8>>();
+        thread::spawn(move || loop {
+            let mut buf = recycled.try_recv().unwrap_or_default();
+            buf.resize(chunk_size, 0);
+            let result = match inner.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Ok(buf)
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = result.is_err();
+            if sender.send(result).is_err() || failed {
+                return;
+            }
+        });
+        ThreadedReader {
+            chunks,
+            recycle,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ThreadedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    let used = std::mem::replace(&mut self.chunk, chunk?);
+                    // If our thread has exited, we don't need to recycle.
+                    let _ = self.recycle.send(used);
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// A message for our writer thread.
+enum WriterMessage {
+    /// Write this data.
+    Data(Vec<u8>),
+    /// Flush our output, and report how it went.
+    Flush(SyncSender<io::Result<()>>),
+    /// Finish our output. If we stop getting messages without this, we
+    /// drop our output without finishing it, like any other writer.
+    Finish,
+}
+
+/// A writer which writes to `inner` on a background thread.
+pub struct ThreadedWriter {
+    /// Where we send data to our thread. This is `None` once our thread has
+    /// exited.
+    sender: Option<SyncSender<WriterMessage>>,
+    /// Empty buffers returned by our thread.
+    recycled: Receiver<Vec<u8>>,
+    /// Data we haven't sent yet.
+    buf: Vec<u8>,
+    /// How much data we send at once.
+    chunk_size: usize,
+    /// Our background thread, which returns the first error it saw.
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl ThreadedWriter {
+    /// Start a thread which writes to `inner`, `chunk_size` bytes at a time.
+    pub fn new<W>(mut inner: W, chunk_size: usize) -> ThreadedWriter
+    where
+        W: FinishWrite + Send + 'static,
+    {
+        let (sender, messages) = sync_channel(CHANNEL_DEPTH);
+        let (recycle, recycled) = channel();
+        let thread = thread::spawn(move || -> io::Result<()> {
+            for message in messages {
+                match message {
+                    WriterMessage::Data(mut data) => {
+                        inner.write_all(&data)?;
+                        data.clear();
+                        let _ = recycle.send(data);
+                    }
+                    WriterMessage::Flush(reply) => {
+                        let result = inner.flush();
+                        let failed = result.is_err();
+                        let _ = reply.send(result);
+                        if failed {
+                            return Ok(());
+                        }
+                    }
+                    WriterMessage::Finish => return Box::new(inner).finish(),
+                }
+            }
+            Ok(())
+        });
+        ThreadedWriter {
+            sender: Some(sender),
+            recycled,
+            buf: Vec::with_capacity(chunk_size),
+            chunk_size,
+            thread: Some(thread),
+        }
+    }
+
+    /// Send a message to our thread. If it has exited, return its error.
+    fn send(&mut self, message: WriterMessage) -> io::Result<()> {
+        match &self.sender {
+            Some(sender) if sender.send(message).is_ok() => Ok(()),
+            _ => Err(self.thread_error()),
+        }
+    }
+
+    /// Our thread has exited, so find out why.
+    fn thread_error(&mut self) -> io::Error {
+        self.sender = None;
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(Err(err))) => err,
+            _ => io::Error::other("output thread exited unexpectedly"),
+        }
+    }
+
+    /// Send any data we've buffered.
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let mut next = self.recycled.try_recv().unwrap_or_default();
+        next.reserve(self.chunk_size);
+        let data = std::mem::replace(&mut self.buf, next);
+        self.send(WriterMessage::Data(data))
+    }
+}
+
+impl Write for ThreadedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= self.chunk_size {
+            self.send_buffered()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()?;
+        let (reply, result) = sync_channel(1);
+        self.send(WriterMessage::Flush(reply))?;
+        match result.recv() {
+            Ok(result) => result,
+            Err(_) => Err(self.thread_error()),
+        }
+    }
+}
+
+impl FinishWrite for ThreadedWriter {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.send_buffered()?;
+        self.send(WriterMessage::Finish)?;
+        self.sender = None;
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            _ => Err(io::Error::other("output thread exited unexpectedly")),
+        }
+    }
+}
+
+impl Drop for ThreadedWriter {
+    fn drop(&mut self) {
+        if self.sender.is_some() {
+            // We can't report errors here, so callers should flush first.
+            let _ = self.send_buffered();
+        }
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[test]
+fn reads_on_a_thread() {
+    let data = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut rdr = ThreadedReader::new(io::Cursor::new(data.clone()), 7);
+    let mut read = vec![];
+    rdr.read_to_end(&mut read).unwrap();
+    assert_eq!(read, data);
+}
+
+#[test]
+fn writes_on_a_thread() {
+    use std::sync::{Arc, Mutex};
+
+    /// A writer we can inspect after handing it to another thread.
+    #[derive(Clone, Default)]
+    struct SharedVec(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedVec {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl FinishWrite for SharedVec {
+        fn finish(self: Box<Self>) -> io::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(b"finished");
+            Ok(())
+        }
+    }
+
+    let output = SharedVec::default();
+    let mut wtr = ThreadedWriter::new(output.clone(), 7);
+    for i in 0..1000 {
+        write!(wtr, "{},", i).unwrap();
+    }
+    Box::new(wtr).finish().unwrap();
+    let mut expected = (0..1000).map(|i| format!("{},", i)).collect::<String>();
+    expected.push_str("finished");
+    assert_eq!(*output.0.lock().unwrap(), expected.as_bytes());
+
+    // If we're dropped without finishing, neither is our output.
+    let output = SharedVec::default();
+    let mut wtr = ThreadedWriter::new(output.clone(), 7);
+    write!(wtr, "partial").unwrap();
+    drop(wtr);
+    assert_eq!(*output.0.lock().unwrap(), b"partial");
+}
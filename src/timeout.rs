@@ -0,0 +1,115 @@
+//! Give up if our input stops arriving, instead of hanging forever.
+
+use std::{
+    io::{self, prelude::*},
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+/// How much data our background thread reads at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A reader which fails with `io::ErrorKind::TimedOut` if `inner` doesn't
+/// produce any data for `timeout`.
+///
+/// There's no portable way to time out a blocking read, so we read `inner`
+/// on a background thread and wait for its chunks with a timeout.
+pub struct TimeoutReader {
+    /// Chunks of data read by our background thread. When the thread reaches
+    /// the end of its input, it hangs up.
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    /// How long to wait for data.
+    timeout: Duration,
+    /// The chunk we're currently returning.
+    chunk: Vec<u8>,
+    /// How much of `chunk` we've returned so far.
+    pos: usize,
+}
+
+impl TimeoutReader {
+    /// Start reading from `inner` in the background.
+    pub fn new<R>(mut inner: R, timeout: Duration) -> TimeoutReader
+    where
+        R: Read + Send + 'static,
+    {
+        let (sender, chunks) = sync_channel(4);
+        thread::spawn(move || {
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let result = match inner.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => Ok(buf[..n].to_owned()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let failed = result.is_err();
+                if sender.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        TimeoutReader {
+            chunks,
+            timeout,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.chunk.len() {
+            match self.chunks.recv_timeout(self.timeout) {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "no input received for {} seconds",
+                            self.timeout.as_secs_f64()
+                        ),
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+/// A reader which sleeps before returning each read.
+struct SleepyReader(&'static [u8], Duration);
+
+#[cfg(test)]
+impl Read for SleepyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(self.1);
+        self.0.read(buf)
+    }
+}
+
+#[test]
+fn reads_input_which_arrives_in_time() {
+    let inner = SleepyReader(b"a,b\n1,2\n", Duration::from_millis(1));
+    let mut rdr = TimeoutReader::new(inner, Duration::from_secs(10));
+    let mut data = String::new();
+    rdr.read_to_string(&mut data).unwrap();
+    assert_eq!(data, "a,b\n1,2\n");
+}
+
+#[test]
+fn times_out_when_input_stops() {
+    let inner = SleepyReader(b"a,b\n", Duration::from_secs(10));
+    let mut rdr = TimeoutReader::new(inner, Duration::from_millis(10));
+    let err = rdr.read(&mut [0; 8]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
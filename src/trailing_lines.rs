@@ -0,0 +1,113 @@
+//! Skipping totals, summaries and other junk after the real data.
+
+use log::debug;
+use regex::bytes::Regex;
+use std::{
+    collections::VecDeque,
+    io::{self, prelude::*},
+};
+
+/// A reader which discards lines at the end of its input. We discard the
+/// last `count` lines, and then any lines matching `pattern` before them.
+/// Since we can't know which lines come last until we reach the end, we hold
+/// back any lines which might need to be discarded.
+pub struct TrailingLineSkipper<R: BufRead> {
+    inner: R,
+    /// How many lines to skip unconditionally.
+    count: usize,
+    /// Skip any trailing lines matching this pattern.
+    pattern: Option<Regex>,
+    /// Lines we're holding back. All but the last `count` match `pattern`.
+    held: VecDeque<Vec<u8>>,
+    /// The lines we're returning now.
+    line: Vec<u8>,
+    /// How much of `line` we've already returned.
+    pos: usize,
+    /// Have we reached the end of `inner`?
+    done: bool,
+}
+
+impl<R: BufRead> TrailingLineSkipper<R> {
+    /// Create a new reader.
+    pub fn new(inner: R, count: usize, pattern: Option<Regex>) -> Self {
+        TrailingLineSkipper {
+            inner,
+            count,
+            pattern,
+            held: VecDeque::new(),
+            line: vec![],
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Does `line` match our pattern?
+    fn matches(&self, line: &[u8]) -> bool {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        self.pattern.as_ref().is_some_and(|re| re.is_match(line))
+    }
+
+    /// Read more lines from `inner`, until we have some we know we can
+    /// return, or we reach the end.
+    fn fill_line(&mut self) -> io::Result<()> {
+        while !self.done && self.pos >= self.line.len() {
+            let mut line = vec![];
+            if self.inner.read_until(b'\n', &mut line)? == 0 {
+                self.done = true;
+                for _ in 0..self.count.min(self.held.len()) {
+                    let line = self.held.pop_back().expect("should have line");
+                    debug!(
+                        "skipping trailing line {:?}",
+                        String::from_utf8_lossy(&line)
+                    );
+                }
+                // Everything else we're holding matches our pattern.
+                for line in self.held.drain(..) {
+                    debug!(
+                        "skipping trailing line {:?}",
+                        String::from_utf8_lossy(&line)
+                    );
+                }
+                break;
+            }
+            self.held.push_back(line);
+            if self.held.len() <= self.count {
+                continue;
+            }
+            // This line won't be one of our last `count`, so if it doesn't
+            // match, neither it nor any lines before it are trailing lines.
+            let idx = self.held.len() - self.count - 1;
+            if !self.matches(&self.held[idx]) {
+                self.line = self.held.drain(..=idx).flatten().collect();
+                self.pos = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for TrailingLineSkipper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_line()?;
+        let count = buf.len().min(self.line.len() - self.pos);
+        buf[..count].copy_from_slice(&self.line[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+#[test]
+fn skips_trailing_lines() {
+    let input = "a,b\n1,2\n\n3,4\nTotals,10\n\r\nExported by Example\n";
+    let pattern = Regex::new("^(Totals,.*)?$").unwrap();
+    let mut rdr = TrailingLineSkipper::new(input.as_bytes(), 1, Some(pattern));
+    let mut output = String::new();
+    rdr.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "a,b\n1,2\n\n3,4\n");
+
+    let mut rdr = TrailingLineSkipper::new("a\n1\n2".as_bytes(), 5, None);
+    let mut output = String::new();
+    rdr.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "");
+}
@@ -0,0 +1,328 @@
+//! An interactive preview of how we'd parse a file, for working out which
+//! options a new feed needs.
+//!
+//! This is deliberately simple: we print a page of parsed rows, read a
+//! one-line command from standard input, and repeat. That works in any
+//! terminal and over any pipe.
+
+use csv::ByteRecord;
+use std::{
+    fs,
+    io::{self, prelude::*},
+    path::PathBuf,
+    str::FromStr,
+};
+use structopt::StructOpt;
+
+use crate::cleanup;
+use crate::errors::*;
+use crate::uniquifier::Uniquifier;
+use crate::util::CharSpecifier;
+
+/// Options for `scrubcsv tui`.
+#[derive(Clone, Debug, StructOpt)]
+pub struct TuiOpt {
+    /// The file to preview.
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Number of data rows to load for the preview.
+    #[structopt(value_name = "N", long = "rows", default_value = "100")]
+    rows: usize,
+
+    /// Number of rows to show on each page.
+    #[structopt(value_name = "N", long = "page-size", default_value = "20")]
+    page_size: usize,
+}
+
+/// The maximum width of a column in our table.
+const MAX_COLUMN_WIDTH: usize = 20;
+
+/// How many lines to load for each row we want to preview. Rows may contain
+/// quoted newlines, so we load a few extra.
+const LINES_PER_ROW: usize = 4;
+
+/// The most input we'll load for a preview, no matter how many rows we want.
+const MAX_PREVIEW_BYTES: usize = 16 * 1024 * 1024;
+
+/// Help for our commands.
+const HELP: &str = "\
+Commands:
+  n / p       next / previous page
+  d CHAR      set delimiter (\"tab\" for tabs)
+  q CHAR      set quote character (\"none\" to disable quoting)
+  t           toggle --trim-whitespace
+  l           toggle --replace-newlines
+  c           toggle --clean-column-names
+  w           print the matching scrubcsv command and exit
+  x           exit without printing anything
+";
+
+/// The options we're previewing.
+#[derive(Debug)]
+struct PreviewState {
+    delimiter: u8,
+    quote: Option<u8>,
+    trim_whitespace: bool,
+    replace_newlines: bool,
+    clean_column_names: bool,
+    page: usize,
+}
+
+impl Default for PreviewState {
+    fn default() -> PreviewState {
+        PreviewState {
+            delimiter: b',',
+            quote: Some(b'"'),
+            trim_whitespace: false,
+            replace_newlines: false,
+            clean_column_names: false,
+            page: 0,
+        }
+    }
+}
+
+impl PreviewState {
+    /// Parse the start of `data` using our current settings, returning the
+    /// header and up to `max_rows` data rows.
+    fn parse(
+        &self,
+        data: &[u8],
+        max_rows: usize,
+    ) -> Result<(ByteRecord, Vec<ByteRecord>)> {
+        let mut rdr_builder = csv::ReaderBuilder::new();
+        rdr_builder
+            .has_headers(true)
+            .flexible(true)
+            .delimiter(self.delimiter);
+        if let Some(quote) = self.quote {
+            rdr_builder.quote(quote);
+        } else {
+            rdr_builder.quoting(false);
+        }
+        let mut rdr = rdr_builder.from_reader(data);
+        let mut hdr = rdr.byte_headers().context("cannot read headers")?.clone();
+        if self.clean_column_names {
+            let mut uniquifier = Uniquifier::default();
+            let mut new_hdr = ByteRecord::default();
+            for col in hdr.iter() {
+                let col = String::from_utf8_lossy(col);
+                new_hdr.push_field(uniquifier.unique_id_for(&col)?.as_bytes());
+            }
+            hdr = new_hdr;
+        }
+        let mut rows = vec![];
+        for record in rdr.byte_records().take(max_rows) {
+            let record = record.context("cannot read record")?;
+            let mut cleaned = ByteRecord::new();
+            for val in record.iter() {
+                let val = if self.trim_whitespace {
+                    cleanup::trim_whitespace(val)
+                } else {
+                    val
+                };
+                if self.replace_newlines {
+                    cleaned.push_field(&cleanup::replace_newlines(val));
+                } else {
+                    cleaned.push_field(val);
+                }
+            }
+            rows.push(cleaned);
+        }
+        Ok((hdr, rows))
+    }
+
+    /// Render one page of `rows` as a table. Rows with the wrong number of
+    /// columns are marked with `!`.
+    fn render(
+        &self,
+        hdr: &ByteRecord,
+        rows: &[ByteRecord],
+        page_size: usize,
+    ) -> String {
+        let start = (self.page * page_size).min(rows.len());
+        let page = &rows[start..(start + page_size).min(rows.len())];
+        let bad = rows.iter().filter(|r| r.len() != hdr.len()).count();
+
+        let mut out = String::new();
+        out.push_str(&format!(
+            "delimiter {:?}, quote {}, rows {}-{} of {} loaded ({} bad)\n",
+            char::from(self.delimiter),
+            self.quote
+                .map(|q| format!("{:?}", char::from(q)))
+                .unwrap_or_else(|| "none".to_owned()),
+            start + 1,
+            start + page.len(),
+            rows.len(),
+            bad,
+        ));
+        out.push_str(&render_row("  ", hdr));
+        for row in page {
+            let marker = if row.len() != hdr.len() { "! " } else { "  " };
+            out.push_str(&render_row(marker, row));
+        }
+        out
+    }
+
+    /// Return a `scrubcsv` command line which uses our current settings.
+    fn command_line(&self, input: &str) -> String {
+        let mut args = vec!["scrubcsv".to_owned()];
+        if self.delimiter != b',' {
+            args.push("-d".to_owned());
+            args.push(shell_quote(&char_name(self.delimiter)));
+        }
+        match self.quote {
+            Some(b'"') => {}
+            Some(q) => {
+                args.push("--quote".to_owned());
+                args.push(shell_quote(&char_name(q)));
+            }
+            None => {
+                args.push("--quote".to_owned());
+                args.push("none".to_owned());
+            }
+        }
+        if self.trim_whitespace {
+            args.push("--trim-whitespace".to_owned());
+        }
+        if self.replace_newlines {
+            args.push("--replace-newlines".to_owned());
+        }
+        if self.clean_column_names {
+            args.push("--clean-column-names".to_owned());
+        }
+        args.push(shell_quote(input));
+        args.join(" ")
+    }
+}
+
+/// Render a single row of our table.
+fn render_row(marker: &str, row: &ByteRecord) -> String {
+    let cells = row
+        .iter()
+        .map(|val| {
+            let val = String::from_utf8_lossy(val).replace(['\n', '\r'], "⏎");
+            let mut cell = val.chars().take(MAX_COLUMN_WIDTH).collect::<String>();
+            if val.chars().count() > MAX_COLUMN_WIDTH {
+                cell.pop();
+                cell.push('…');
+            }
+            format!("{:<width$}", cell, width = MAX_COLUMN_WIDTH)
+        })
+        .collect::<Vec<_>>();
+    format!("{}{}\n", marker, cells.join(" | ").trim_end())
+}
+
+/// Describe a delimiter or quote character the way our CLI expects it.
+fn char_name(c: u8) -> String {
+    match c {
+        b'\t' => "tab".to_owned(),
+        _ => char::from(c).to_string(),
+    }
+}
+
+/// Quote `s` for a POSIX shell if necessary.
+fn shell_quote(s: &str) -> String {
+    let safe = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:".contains(c));
+    if safe {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+/// Read enough of `rdr` to preview `rows` rows, without loading the whole
+/// file.
+fn read_prefix<R: BufRead>(mut rdr: R, rows: usize) -> io::Result<Vec<u8>> {
+    let max_lines = rows.saturating_add(1).saturating_mul(LINES_PER_ROW);
+    let mut data = vec![];
+    for _ in 0..max_lines {
+        if data.len() >= MAX_PREVIEW_BYTES || rdr.read_until(b'\n', &mut data)? == 0 {
+            break;
+        }
+    }
+    Ok(data)
+}
+
+/// Run our interactive preview.
+pub fn run(opt: &TuiOpt) -> Result<()> {
+    let data = fs::File::open(&opt.input)
+        .and_then(|f| read_prefix(io::BufReader::new(f), opt.rows))
+        .with_context(|_| format!("cannot read {}", opt.input.display()))?;
+    let input_name = opt.input.display().to_string();
+    let mut state = PreviewState::default();
+    let stdin = io::stdin();
+    let mut commands = stdin.lock().lines();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    loop {
+        match state.parse(&data, opt.rows) {
+            Ok((hdr, rows)) => {
+                write!(out, "{}", state.render(&hdr, &rows, opt.page_size))?;
+            }
+            Err(err) => writeln!(out, "cannot parse with these settings: {}", err)?,
+        }
+        write!(out, "command (h for help)> ")?;
+        out.flush()?;
+
+        let line = match commands.next() {
+            Some(line) => line.context("cannot read command")?,
+            None => return Ok(()),
+        };
+        let mut words = line.trim().splitn(2, ' ');
+        let (cmd, arg) = (words.next().unwrap_or(""), words.next().map(str::trim));
+        match (cmd, arg) {
+            ("n", _) => state.page += 1,
+            ("p", _) => state.page = state.page.saturating_sub(1),
+            ("d", Some(arg)) => match CharSpecifier::from_str(arg) {
+                Ok(d) => match d.char() {
+                    Some(d) => state.delimiter = d,
+                    None => writeln!(out, "a delimiter is required")?,
+                },
+                Err(err) => writeln!(out, "{}", err)?,
+            },
+            ("q", Some(arg)) => match CharSpecifier::from_str(arg) {
+                Ok(q) => state.quote = q.char(),
+                Err(err) => writeln!(out, "{}", err)?,
+            },
+            ("t", _) => state.trim_whitespace = !state.trim_whitespace,
+            ("l", _) => state.replace_newlines = !state.replace_newlines,
+            ("c", _) => state.clean_column_names = !state.clean_column_names,
+            ("w", _) => {
+                writeln!(out, "\n{}", state.command_line(&input_name))?;
+                return Ok(());
+            }
+            ("x", _) => return Ok(()),
+            _ => write!(out, "{}", HELP)?,
+        }
+    }
+}
+
+#[test]
+fn renders_bad_rows_and_command_lines() {
+    let state = PreviewState {
+        delimiter: b';',
+        trim_whitespace: true,
+        ..PreviewState::default()
+    };
+    let (hdr, rows) = state.parse(b"a;b\n 1 ;2\n3\n", 10).unwrap();
+    let table = state.render(&hdr, &rows, 10);
+    assert!(table.contains("(1 bad)"));
+    assert!(table.lines().any(|l| l.starts_with("  1 ")));
+    assert!(table.lines().any(|l| l.starts_with("! 3")));
+    assert_eq!(
+        state.command_line("my file.csv"),
+        "scrubcsv -d ';' --trim-whitespace 'my file.csv'"
+    );
+}
+
+#[test]
+fn reads_only_a_prefix() {
+    let input = "a\n".repeat(1000);
+    let data = read_prefix(input.as_bytes(), 9).unwrap();
+    assert_eq!(data.len(), 2 * 10 * LINES_PER_ROW);
+    let data = read_prefix(&b"a\nb"[..], 9).unwrap();
+    assert_eq!(data, b"a\nb");
+}
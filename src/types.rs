@@ -0,0 +1,243 @@
+//! Checking and converting the values in typed columns, for `--types`.
+
+use std::{borrow::Cow, str::FromStr};
+
+use crate::errors::*;
+use crate::profile::ValueType;
+use crate::stats::{RuleHits, RuleId};
+use crate::util::find_column;
+
+/// The type of a column, from `COL:TYPE`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnType {
+    /// The name of the column.
+    column: String,
+    /// The type its values should have.
+    value_type: ValueType,
+}
+
+impl ColumnType {
+    /// Expect `column` to have values of type `value_type`.
+    pub fn new(column: &str, value_type: ValueType) -> ColumnType {
+        ColumnType {
+            column: column.to_owned(),
+            value_type,
+        }
+    }
+}
+
+impl FromStr for ColumnType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ColumnType> {
+        let (column, value_type) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format_err!("expected COL:TYPE, found {:?}", s))?;
+        Ok(ColumnType {
+            column: column.to_owned(),
+            value_type: value_type.parse()?,
+        })
+    }
+}
+
+/// Convert `value` to the standard form of `value_type`, or return `None` if
+/// it doesn't have that type.
+///
+/// - Integers lose any whitespace, leading "+" and leading zeros, and may
+///   be written with a zero fractional part, like "12.0".
+/// - Decimals lose any whitespace and leading "+", and gain a "0" before a
+///   leading decimal point.
+/// - Booleans can be written as true/false, t/f, yes/no, y/n or 1/0, in any
+///   case, and become "true" or "false".
+/// - Dates can be written as YYYY-MM-DD, YYYY/MM/DD or YYYYMMDD, and must
+///   exist. They become YYYY-MM-DD.
+pub fn coerce(value: &[u8], value_type: ValueType) -> Option<Cow<'_, [u8]>> {
+    let trimmed = value.trim_ascii();
+    let unsigned = trimmed.strip_prefix(b"+").unwrap_or(trimmed);
+    let coerced: Vec<u8> = match value_type {
+        ValueType::String => return Some(Cow::Borrowed(value)),
+        ValueType::Integer => {
+            let s = std::str::from_utf8(unsigned).ok()?;
+            let whole = match s.split_once('.') {
+                Some((whole, frac)) if frac.bytes().all(|b| b == b'0') => whole,
+                Some(_) => return None,
+                None => s,
+            };
+            whole.parse::<i64>().ok()?.to_string().into_bytes()
+        }
+        ValueType::Decimal => {
+            if !matches!(
+                ValueType::of(unsigned),
+                ValueType::Integer | ValueType::Decimal
+            ) {
+                return None;
+            }
+            let (sign, digits) = match unsigned.strip_prefix(b"-") {
+                Some(digits) => (&b"-"[..], digits),
+                None => (&b""[..], unsigned),
+            };
+            let mut coerced = sign.to_owned();
+            if digits.starts_with(b".") {
+                coerced.push(b'0');
+            }
+            coerced.extend_from_slice(digits);
+            coerced
+        }
+        ValueType::Boolean => match &unsigned.to_ascii_lowercase()[..] {
+            b"true" | b"t" | b"yes" | b"y" | b"1" => b"true".to_vec(),
+            b"false" | b"f" | b"no" | b"n" | b"0" => b"false".to_vec(),
+            _ => return None,
+        },
+        ValueType::Date => {
+            let s = std::str::from_utf8(trimmed).ok()?;
+            let (y, m, d) = if s.len() == 8 && s.bytes().all(|b| b.is_ascii_digit()) {
+                (&s[..4], &s[4..6], &s[6..])
+            } else {
+                let sep = if s.contains('/') { '/' } else { '-' };
+                let mut parts = s.split(sep);
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(y), Some(m), Some(d), None) => (y, m, d),
+                    _ => return None,
+                }
+            };
+            let digits = |s: &str, len: usize| {
+                (s.len() == len && s.bytes().all(|b| b.is_ascii_digit()))
+                    .then(|| s.parse::<u32>().ok())
+                    .flatten()
+            };
+            let (y, m, d) = (digits(y, 4)?, digits(m, 2)?, digits(d, 2)?);
+            let leap = (y % 4 == 0 && y % 100 != 0) || y % 400 == 0;
+            let days = match m {
+                1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+                4 | 6 | 9 | 11 => 30,
+                2 if leap => 29,
+                2 => 28,
+                _ => return None,
+            };
+            if d < 1 || d > days {
+                return None;
+            }
+            format!("{:04}-{:02}-{:02}", y, m, d).into_bytes()
+        }
+    };
+    if coerced == value {
+        Some(Cow::Borrowed(value))
+    } else {
+        Some(Cow::Owned(coerced))
+    }
+}
+
+/// Checks and converts the values in our typed columns.
+#[derive(Debug)]
+pub struct TypeCoercer {
+    /// For each typed column, its index, its type, and the ID we use to count
+    /// rejected values.
+    columns: Vec<(usize, ValueType, RuleId)>,
+    /// Counts the values we converted.
+    coerced_rule: RuleId,
+}
+
+impl TypeCoercer {
+    /// Prepare to check the columns in `types`, using the header `hdr`, and
+    /// registering our rules with `hits`. Returns `None` if we have no types.
+    pub fn new(
+        hdr: &csv::ByteRecord,
+        types: &[ColumnType],
+        hits: &mut RuleHits,
+    ) -> Result<Option<TypeCoercer>> {
+        if types.is_empty() {
+            return Ok(None);
+        }
+        let mut columns = vec![];
+        for t in types {
+            let idx = find_column(hdr, &t.column).ok_or_else(|| {
+                format_err!("cannot find --types column {:?}", t.column)
+            })?;
+            let id = hits.register(
+                format!("--types {}:{}", t.column, t.value_type),
+                "cells rejected",
+            );
+            columns.push((idx, t.value_type, id));
+        }
+        let coerced_rule = hits.register("--types", "cells converted");
+        Ok(Some(TypeCoercer {
+            columns,
+            coerced_rule,
+        }))
+    }
+
+    /// Convert the typed values in `row`, counting them in `hits`. Returns
+    /// `false` if a value doesn't have its column's type. Returns `true` and
+    /// sets `changed` if we converted anything. Empty values are always
+    /// allowed.
+    pub fn coerce_row(
+        &self,
+        row: &mut [Cow<[u8]>],
+        changed: &mut bool,
+        hits: &RuleHits,
+    ) -> bool {
+        for &(idx, value_type, id) in &self.columns {
+            if row[idx].is_empty() {
+                continue;
+            }
+            match coerce(&row[idx], value_type) {
+                Some(Cow::Borrowed(_)) => {}
+                Some(Cow::Owned(coerced)) => {
+                    hits.hit(self.coerced_rule);
+                    row[idx] = Cow::Owned(coerced);
+                    *changed = true;
+                }
+                None => {
+                    hits.hit(id);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+#[test]
+fn coerces_values() {
+    let check = |value: &str, value_type: ValueType| {
+        coerce(value.as_bytes(), value_type)
+            .map(|v| String::from_utf8(v.into_owned()).unwrap())
+    };
+    let some = |s: &str| Some(s.to_owned());
+    assert_eq!(check(" +007 ", ValueType::Integer), some("7"));
+    assert_eq!(check("-12.00", ValueType::Integer), some("-12"));
+    assert_eq!(check("12.5", ValueType::Integer), None);
+    assert_eq!(check("1e3", ValueType::Integer), None);
+    assert_eq!(check(".5", ValueType::Decimal), some("0.5"));
+    assert_eq!(check("-.5", ValueType::Decimal), some("-0.5"));
+    assert_eq!(check("+1.5e3", ValueType::Decimal), some("1.5e3"));
+    assert_eq!(check("12", ValueType::Decimal), some("12"));
+    assert_eq!(check("1,5", ValueType::Decimal), None);
+    assert_eq!(check("Yes", ValueType::Boolean), some("true"));
+    assert_eq!(check("0", ValueType::Boolean), some("false"));
+    assert_eq!(check("maybe", ValueType::Boolean), None);
+    assert_eq!(check("2024/02/29", ValueType::Date), some("2024-02-29"));
+    assert_eq!(check("20240131", ValueType::Date), some("2024-01-31"));
+    assert_eq!(check("2023-02-29", ValueType::Date), None);
+    assert_eq!(check("2023-13-01", ValueType::Date), None);
+    assert_eq!(check("01/02/2023", ValueType::Date), None);
+    assert_eq!(check(" x ", ValueType::String), some(" x "));
+}
+
+#[test]
+fn coerces_rows() {
+    let hdr = csv::ByteRecord::from(vec!["id", "ok"]);
+    let types = vec!["id:int".parse().unwrap(), "ok:bool".parse().unwrap()];
+    let mut hits = RuleHits::default();
+    let coercer = TypeCoercer::new(&hdr, &types, &mut hits).unwrap().unwrap();
+    let mut changed = false;
+    let mut row = vec![Cow::Borrowed(&b"01"[..]), Cow::Borrowed(&b""[..])];
+    assert!(coercer.coerce_row(&mut row, &mut changed, &hits));
+    assert!(changed);
+    assert_eq!(row[0], &b"1"[..]);
+    let mut row = vec![Cow::Borrowed(&b"1"[..]), Cow::Borrowed(&b"x"[..])];
+    assert!(!coercer.coerce_row(&mut row, &mut changed, &hits));
+    assert!("id".parse::<ColumnType>().is_err());
+    assert!("id:blob".parse::<ColumnType>().is_err());
+    assert!(TypeCoercer::new(&hdr, &["x:int".parse().unwrap()], &mut hits).is_err());
+}
@@ -0,0 +1,256 @@
+//! Unicode cleanups, for `--normalize-unicode` and `--ascii-punctuation`.
+//!
+//! We don't carry the full Unicode normalization tables. Instead, we compose
+//! Latin letters with the accents in `COMPOSITIONS`, and apply the
+//! compatibility mappings we actually see in our data, like fullwidth
+//! letters, ligatures and fancy spaces. Anything else is left alone.
+
+use std::{borrow::Cow, str::FromStr};
+
+use crate::errors::*;
+
+/// Which Unicode normalization form to use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnicodeForm {
+    /// Canonical composition, which changes how characters are encoded, but
+    /// not how they look.
+    Nfc,
+    /// Compatibility composition, which also replaces characters like `ﬁ`
+    /// and `Ａ` with their plain equivalents.
+    Nfkc,
+}
+
+impl FromStr for UnicodeForm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<UnicodeForm> {
+        match s {
+            "nfc" => Ok(UnicodeForm::Nfc),
+            "nfkc" => Ok(UnicodeForm::Nfkc),
+            _ => Err(format_err!("unknown Unicode normalization form: '{}'", s)),
+        }
+    }
+}
+
+/// For each combining mark, pairs of base letters and the letter they
+/// compose into.
+const COMPOSITIONS: &[(char, &str)] = &[
+    // grave accent
+    ('\u{300}', "AÀEÈIÌOÒUÙaàeèiìoòuùÜǛüǜNǸnǹ"),
+    // acute accent
+    ('\u{301}', "AÁEÉIÍOÓUÚYÝaáeéiíoóuúyýCĆcćLĹlĺNŃnńRŔrŕ"),
+    ('\u{301}', "SŚsśZŹzźÜǗüǘGǴgǵÅǺåǻÆǼæǽØǾøǿ"),
+    // circumflex accent
+    ('\u{302}', "AÂEÊIÎOÔUÛaâeêiîoôuûCĈcĉGĜgĝHĤhĥJĴjĵSŜsŝ"),
+    ('\u{302}', "WŴwŵYŶyŷ"),
+    // tilde
+    ('\u{303}', "AÃNÑOÕaãnñoõIĨiĩUŨuũ"),
+    // macron
+    ('\u{304}', "AĀaāEĒeēIĪiīOŌoōUŪuūÜǕüǖÄǞäǟȦǠȧǡÆǢæǣǪǬǫǭ"),
+    ('\u{304}', "ÖȪöȫÕȬõȭȮȰȯȱYȲyȳ"),
+    // breve
+    ('\u{306}', "AĂaăEĔeĕGĞgğIĬiĭOŎoŏUŬuŭ"),
+    // dot above
+    ('\u{307}', "CĊcċEĖeėGĠgġIİZŻzżAȦaȧOȮoȯ"),
+    // diaeresis
+    ('\u{308}', "AÄEËIÏOÖUÜaäeëiïoöuüyÿYŸ"),
+    // ring above
+    ('\u{30a}', "AÅaåUŮuů"),
+    // double acute accent
+    ('\u{30b}', "OŐoőUŰuű"),
+    // caron
+    ('\u{30c}', "CČcčDĎdďEĚeěLĽlľNŇnňRŘrřSŠsšTŤtťZŽzžAǍaǎ"),
+    ('\u{30c}', "IǏiǐOǑoǒUǓuǔÜǙüǚGǦgǧKǨkǩƷǮʒǯjǰHȞhȟ"),
+    // double grave accent
+    ('\u{30f}', "AȀaȁEȄeȅIȈiȉOȌoȍRȐrȑUȔuȕ"),
+    // inverted breve
+    ('\u{311}', "AȂaȃEȆeȇIȊiȋOȎoȏRȒrȓUȖuȗ"),
+    // horn
+    ('\u{31b}', "OƠoơUƯuư"),
+    // comma below
+    ('\u{326}', "SȘsșTȚtț"),
+    // cedilla
+    ('\u{327}', "CÇcçGĢgģKĶkķLĻlļNŅnņRŖrŗSŞsşTŢtţEȨeȩ"),
+    // ogonek
+    ('\u{328}', "AĄaąEĘeęIĮiįUŲuųOǪoǫ"),
+];
+
+/// Compatibility characters and their replacements, in addition to the
+/// fullwidth ASCII characters.
+const COMPATIBILITY: &[(char, &str)] = &[
+    ('\u{a0}', " "),
+    ('\u{aa}', "a"),
+    ('\u{b2}', "2"),
+    ('\u{b3}', "3"),
+    ('\u{b5}', "\u{3bc}"),
+    ('\u{b9}', "1"),
+    ('\u{ba}', "o"),
+    ('\u{bc}', "1\u{2044}4"),
+    ('\u{bd}', "1\u{2044}2"),
+    ('\u{be}', "3\u{2044}4"),
+    ('\u{132}', "IJ"),
+    ('\u{133}', "ij"),
+    ('\u{17f}', "s"),
+    ('\u{2002}', " "),
+    ('\u{2003}', " "),
+    ('\u{2004}', " "),
+    ('\u{2005}', " "),
+    ('\u{2006}', " "),
+    ('\u{2007}', " "),
+    ('\u{2008}', " "),
+    ('\u{2009}', " "),
+    ('\u{200a}', " "),
+    ('\u{2024}', "."),
+    ('\u{2025}', ".."),
+    ('\u{2026}', "..."),
+    ('\u{202f}', " "),
+    ('\u{205f}', " "),
+    ('\u{2070}', "0"),
+    ('\u{2074}', "4"),
+    ('\u{2075}', "5"),
+    ('\u{2076}', "6"),
+    ('\u{2077}', "7"),
+    ('\u{2078}', "8"),
+    ('\u{2079}', "9"),
+    ('\u{2080}', "0"),
+    ('\u{2081}', "1"),
+    ('\u{2082}', "2"),
+    ('\u{2083}', "3"),
+    ('\u{2084}', "4"),
+    ('\u{2085}', "5"),
+    ('\u{2086}', "6"),
+    ('\u{2087}', "7"),
+    ('\u{2088}', "8"),
+    ('\u{2089}', "9"),
+    ('\u{2116}', "No"),
+    ('\u{2120}', "SM"),
+    ('\u{2122}', "TM"),
+    ('\u{3000}', " "),
+    ('\u{fb00}', "ff"),
+    ('\u{fb01}', "fi"),
+    ('\u{fb02}', "fl"),
+    ('\u{fb03}', "ffi"),
+    ('\u{fb04}', "ffl"),
+    ('\u{fb05}', "st"),
+    ('\u{fb06}', "st"),
+];
+
+/// The character `base` followed by `mark` composes into, if any.
+fn compose(base: char, mark: char) -> Option<char> {
+    COMPOSITIONS
+        .iter()
+        .filter(|(m, _)| *m == mark)
+        .find_map(|(_, pairs)| {
+            let mut chars = pairs.chars();
+            while let (Some(b), Some(composed)) = (chars.next(), chars.next()) {
+                if b == base {
+                    return Some(composed);
+                }
+            }
+            None
+        })
+}
+
+/// Characters which NFC always replaces with another character.
+fn canonical_singleton(c: char) -> Option<char> {
+    match c {
+        '\u{2000}' => Some('\u{2002}'),
+        '\u{2001}' => Some('\u{2003}'),
+        '\u{2126}' => Some('\u{3a9}'),
+        '\u{212a}' => Some('K'),
+        '\u{212b}' => Some('\u{c5}'),
+        _ => None,
+    }
+}
+
+/// The NFKC replacement for `c`, if it has one.
+fn compatibility(c: char) -> Option<Cow<'static, str>> {
+    if let '\u{ff01}'..='\u{ff5e}' = c {
+        let ascii = char::from_u32(c as u32 - 0xfee0).expect("should be ASCII");
+        return Some(Cow::Owned(ascii.to_string()));
+    }
+    COMPATIBILITY
+        .iter()
+        .find(|(from, _)| *from == c)
+        .map(|(_, to)| Cow::Borrowed(*to))
+}
+
+/// Normalize `val` to `form`.
+pub fn normalize(val: &str, form: UnicodeForm) -> Cow<'_, str> {
+    // ASCII is always normalized.
+    if val.is_ascii() {
+        return Cow::Borrowed(val);
+    }
+    let mut normalized = String::with_capacity(val.len());
+    for c in val.chars() {
+        let c = canonical_singleton(c).unwrap_or(c);
+        if form == UnicodeForm::Nfkc {
+            if let Some(replacement) = compatibility(c) {
+                normalized.push_str(&replacement);
+                continue;
+            }
+        }
+        let composed = normalized
+            .chars()
+            .next_back()
+            .and_then(|base| compose(base, c));
+        if let Some(composed) = composed {
+            normalized.pop();
+            normalized.push(composed);
+        } else {
+            normalized.push(c);
+        }
+    }
+    if normalized == val {
+        Cow::Borrowed(val)
+    } else {
+        Cow::Owned(normalized)
+    }
+}
+
+/// The ASCII replacement for the "smart" punctuation character `c`, if any.
+fn ascii_replacement(c: char) -> Option<char> {
+    match c {
+        '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' | '\u{2032}' => Some('\''),
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{201f}' | '\u{2033}' => Some('"'),
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => Some('-'),
+        '\u{a0}' | '\u{2007}' | '\u{202f}' => Some(' '),
+        _ => None,
+    }
+}
+
+/// Replace curly quotes, dashes and non-breaking spaces in `val` with plain
+/// ASCII.
+pub fn ascii_punctuation(val: &str) -> Cow<'_, str> {
+    if !val.chars().any(|c| ascii_replacement(c).is_some()) {
+        return Cow::Borrowed(val);
+    }
+    Cow::Owned(
+        val.chars()
+            .map(|c| ascii_replacement(c).unwrap_or(c))
+            .collect(),
+    )
+}
+
+#[test]
+fn normalizes_unicode() {
+    assert_eq!(normalize("plain", UnicodeForm::Nfc), "plain");
+    assert_eq!(normalize("Jose\u{301}", UnicodeForm::Nfc), "Jos\u{e9}");
+    assert_eq!(normalize("U\u{308}\u{304}", UnicodeForm::Nfc), "\u{1d5}");
+    assert_eq!(normalize("\u{212b}", UnicodeForm::Nfc), "\u{c5}");
+    assert_eq!(
+        normalize("\u{fb01}x\u{ff21}", UnicodeForm::Nfc),
+        "\u{fb01}x\u{ff21}"
+    );
+    assert_eq!(normalize("\u{fb01}x\u{ff21}", UnicodeForm::Nfkc), "fixA");
+    assert_eq!(normalize("a\u{a0}b", UnicodeForm::Nfkc), "a b");
+}
+
+#[test]
+fn replaces_smart_punctuation() {
+    assert_eq!(ascii_punctuation("plain"), "plain");
+    assert_eq!(
+        ascii_punctuation("\u{201c}It\u{2019}s\u{201d} \u{2013} 9\u{a0}AM"),
+        "\"It's\" - 9 AM",
+    );
+}
@@ -2,31 +2,120 @@
 //!
 //! Imported from `dbcrossbar`, another open source Faraday project with
 //! the same copyright holder and license.
+//!
+//! ## Stability
+//!
+//! Given the same options and the same sequence of names, a `Uniquifier` will
+//! always return the same identifiers, including across releases of this
+//! crate. `UniquifierOptions::default()` matches `scrubcsv
+//! --clean-column-names`. If we ever need to change how identifiers are
+//! generated, we'll add a new option and leave the existing behavior alone.
+
+use std::{collections::HashSet, error, fmt};
+
+/// Which characters may appear in identifiers?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Charset {
+    /// Only ASCII letters, digits and underscores.
+    Ascii,
+    /// Any Unicode letters and digits, and underscores.
+    Unicode,
+}
+
+/// How should we change the case of letters?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseStyle {
+    /// Convert letters to lowercase.
+    Lower,
+    /// Convert letters to uppercase.
+    Upper,
+    /// Leave letters alone.
+    Preserve,
+}
+
+/// Options controlling how a `Uniquifier` generates identifiers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UniquifierOptions {
+    /// Replaces characters which aren't allowed in identifiers, and separates
+    /// an identifier from the number we add to make it unique.
+    pub separator: String,
+    /// Which characters we allow.
+    pub charset: Charset,
+    /// How we change the case of letters.
+    pub case: CaseStyle,
+    /// The maximum length of an identifier, in characters, including any
+    /// number we add to make it unique.
+    pub max_len: Option<usize>,
+}
+
+impl Default for UniquifierOptions {
+    fn default() -> Self {
+        UniquifierOptions {
+            separator: "_".to_owned(),
+            charset: Charset::Ascii,
+            case: CaseStyle::Lower,
+            max_len: None,
+        }
+    }
+}
+
+/// Returned when we can't make a unique identifier for a name.
+#[derive(Debug)]
+pub struct TooManyCollisions {
+    /// The name we were trying to make an identifier for.
+    pub name: String,
+}
 
-use std::collections::HashSet;
+impl fmt::Display for TooManyCollisions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "too many column name collisions for {:?}", self.name)
+    }
+}
 
-use crate::Result;
+impl error::Error for TooManyCollisions {}
 
-/// Turns arbitrary Unicode names into unique, lowercase ASCII identifiers. All
-/// identifiers start with an underscore or a lowercase ASCII letter, followed
-/// by zero or more underscores, lowercase ASCII letters and digits.
+/// Turns arbitrary Unicode names into unique identifiers. With the default
+/// options, all identifiers start with an underscore or a lowercase ASCII
+/// letter, followed by zero or more underscores, lowercase ASCII letters and
+/// digits.
+///
+/// Identifiers never start with a digit, and are never empty.
 #[derive(Debug, Default)]
-pub(crate) struct Uniquifier {
+pub struct Uniquifier {
+    /// How we generate identifiers.
+    options: UniquifierOptions,
     /// Identifiers that we have already generated.
     used: HashSet<String>,
 }
 
 impl Uniquifier {
-    /// Given a `name`, return an idenfitier
-    pub(crate) fn unique_id_for<'a>(&mut self, name: &'a str) -> Result<&str> {
-        let id = name_to_lowercase_id(name);
+    /// Create a `Uniquifier` with the specified options.
+    pub fn new(options: UniquifierOptions) -> Uniquifier {
+        Uniquifier {
+            options,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Given a `name`, return an identifier which we haven't returned before.
+    pub fn unique_id_for(&mut self, name: &str) -> Result<&str, TooManyCollisions> {
+        let id = self.to_id(name);
+        let id = truncate_chars(&id, self.options.max_len);
         if self.used.insert(id.to_owned()) {
-            Ok(&self.used.get(&id).expect("just verified id was present")[..])
+            Ok(&self.used.get(id).expect("just verified id was present")[..])
         } else {
             let mut offset = 1;
             while offset < 50 {
                 offset += 1;
-                let alt_id = format!("{}_{}", id, offset);
+                let suffix = format!("{}{}", self.options.separator, offset);
+                let base_len = self
+                    .options
+                    .max_len
+                    .map(|max_len| max_len.saturating_sub(suffix.chars().count()));
+                if base_len == Some(0) {
+                    break;
+                }
+                let alt_id = format!("{}{}", truncate_chars(id, base_len), suffix);
                 if self.used.insert(alt_id.to_owned()) {
                     return Ok(&self
                         .used
@@ -34,8 +123,42 @@ impl Uniquifier {
                         .expect("just verified alt_id was present")[..]);
                 }
             }
-            Err(format_err!("too many column name collisions"))
+            Err(TooManyCollisions {
+                name: name.to_owned(),
+            })
+        }
+    }
+
+    /// Given a string, turn it into an identifier, which may not be unique.
+    fn to_id(&self, name: &str) -> String {
+        if name.is_empty() {
+            return self.options.separator.clone();
+        }
+        let mut id = String::with_capacity(name.len());
+        for (idx, c) in name.chars().enumerate() {
+            let allowed = match self.options.charset {
+                Charset::Ascii => c.is_ascii_alphanumeric(),
+                Charset::Unicode => c.is_alphanumeric(),
+            };
+            if c == '_' || (allowed && (idx != 0 || !c.is_numeric())) {
+                match self.options.case {
+                    CaseStyle::Lower => id.extend(c.to_lowercase()),
+                    CaseStyle::Upper => id.extend(c.to_uppercase()),
+                    CaseStyle::Preserve => id.push(c),
+                }
+            } else {
+                id.push_str(&self.options.separator);
+            }
         }
+        id
+    }
+}
+
+/// Truncate `s` to at most `max_len` characters.
+fn truncate_chars(s: &str, max_len: Option<usize>) -> &str {
+    match max_len.and_then(|max_len| s.char_indices().nth(max_len)) {
+        Some((idx, _)) => &s[..idx],
+        None => s,
     }
 }
 
@@ -53,31 +176,37 @@ fn uniquifier_generates_unique_ids() {
     }
 }
 
-/// Given a unique string, turn it into a lower-case identifier.
-fn name_to_lowercase_id(name: &str) -> String {
-    if name.is_empty() {
-        "_".to_owned()
-    } else {
-        name.char_indices()
-            .map(|(idx, c)| {
-                if c == '_' || c.is_ascii_lowercase() {
-                    c
-                } else if c.is_ascii_uppercase() {
-                    c.to_ascii_lowercase()
-                } else if idx != 0 && c.is_ascii_digit() {
-                    c
-                } else {
-                    '_'
-                }
-            })
-            .collect::<String>()
+#[test]
+fn uniquifier_cleans_non_id_characters() {
+    let examples = &[("", "_"), ("_aA1?", "_aa1_"), ("1", "_"), ("é", "_")];
+    for &(input, expected) in examples {
+        let mut uniqifier = Uniquifier::default();
+        assert_eq!(uniqifier.unique_id_for(input).unwrap(), expected);
     }
 }
 
 #[test]
-fn name_to_lowercase_id_cleans_non_id_characters() {
-    let examples = &[("", "_"), ("_aA1?", "_aa1_"), ("1", "_")];
+fn uniquifier_honors_options() {
+    let mut uniqifier = Uniquifier::new(UniquifierOptions {
+        separator: "-".to_owned(),
+        charset: Charset::Unicode,
+        case: CaseStyle::Upper,
+        max_len: Some(5),
+    });
+    let examples = &[
+        ("café au lait", "CAFÉ-"),
+        ("café", "CAFÉ"),
+        ("Café", "CAF-2"),
+        ("1x", "-X"),
+    ];
     for &(input, expected) in examples {
-        assert_eq!(name_to_lowercase_id(input), expected);
+        assert_eq!(uniqifier.unique_id_for(input).unwrap(), expected);
     }
+
+    let mut uniqifier = Uniquifier::new(UniquifierOptions {
+        max_len: Some(2),
+        ..UniquifierOptions::default()
+    });
+    assert_eq!(uniqifier.unique_id_for("a").unwrap(), "a");
+    assert!(uniqifier.unique_id_for("a").is_err());
 }
@@ -1,8 +1,10 @@
 //! Miscellaneous utilities.
 
+use csv::ByteRecord;
 use std::str::FromStr;
 use time::{Duration, OffsetDateTime};
 
+use crate::columns::{self, ColumnLookupError};
 use crate::errors::*;
 
 /// Get the current time relative to the Unix epoch, as suggested by the `time`
@@ -12,12 +14,136 @@ pub fn now() -> Duration {
     OffsetDateTime::now_utc() - OffsetDateTime::UNIX_EPOCH
 }
 
+/// A size in bytes specified on the command line, like "512M".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteSize(pub usize);
+
+impl FromStr for ByteSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ByteSize> {
+        let (digits, multiplier) = match s.as_bytes().last() {
+            Some(b'K' | b'k') => (&s[..s.len() - 1], 1 << 10),
+            Some(b'M' | b'm') => (&s[..s.len() - 1], 1 << 20),
+            Some(b'G' | b'g') => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+        digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .filter(|&bytes| bytes > 0)
+            .map(ByteSize)
+            .ok_or_else(|| format_err!("cannot parse size: '{}'", s))
+    }
+}
+
+/// Find the column `name` in `hdr`. This is the shared resolver for every
+/// option which takes a column name.
+pub use crate::columns::find_column;
+
+/// Like `find_column`, but for the command-line option `option`. See
+/// `crate::columns::find_column_loosely` for the names we accept.
+pub fn find_column_loosely(
+    hdr: &ByteRecord,
+    name: &str,
+    option: &str,
+) -> Result<usize> {
+    columns::find_column_loosely(hdr, name).map_err(|err| match err {
+        ColumnLookupError::Missing => {
+            format_err!("cannot find {} column {:?}", option, name)
+        }
+        ColumnLookupError::Ambiguous => {
+            format_err!("{} column {:?} is ambiguous", option, name)
+        }
+    })
+}
+
+#[test]
+fn finds_columns_loosely() {
+    let hdr = ByteRecord::from(vec!["id", " Email ", "EMAIL"]);
+    assert_eq!(find_column_loosely(&hdr, " ID", "--test").unwrap(), 0);
+    let err = find_column_loosely(&hdr, "email", "--test").unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("--test column \"email\" is ambiguous"));
+    let err = find_column_loosely(&hdr, "state", "--test").unwrap_err();
+    assert!(err.to_string().contains("cannot find --test column"));
+}
+
+/// Copy `record` into a new record which is exactly the right size. Records
+/// read by `csv` have room to spare, which makes them slow to clone.
+pub fn copy_record(record: &ByteRecord) -> ByteRecord {
+    let mut copy = ByteRecord::with_capacity(record.as_slice().len(), record.len());
+    copy.extend(record);
+    copy.set_position(record.position().cloned());
+    copy
+}
+
+/// Build a new record containing the fields of `record` listed in
+/// `projection`. A `None` produces an empty field.
+pub fn project_record(
+    record: &ByteRecord,
+    projection: &[Option<usize>],
+) -> ByteRecord {
+    let mut projected =
+        ByteRecord::with_capacity(record.as_slice().len(), projection.len());
+    for idx in projection {
+        projected.push_field(idx.and_then(|idx| record.get(idx)).unwrap_or(b""));
+    }
+    projected
+}
+
+/// Choose the columns listed in `columns` from the output of `projection`,
+/// returning a single projection which does both. If `projection` is `None`,
+/// we assume it passes columns through unchanged.
+pub fn compose_projection(
+    projection: Option<&[Option<usize>]>,
+    columns: &[usize],
+) -> Vec<Option<usize>> {
+    columns
+        .iter()
+        .map(|&i| match projection {
+            Some(projection) => projection[i],
+            None => Some(i),
+        })
+        .collect()
+}
+
+/// Build a new header containing the columns listed in `columns`.
+pub fn select_columns(hdr: &ByteRecord, columns: &[usize]) -> ByteRecord {
+    columns.iter().map(|&i| &hdr[i]).collect::<Vec<_>>().into()
+}
+
+#[test]
+fn composes_projections() {
+    assert_eq!(compose_projection(None, &[2, 0]), vec![Some(2), Some(0)],);
+    assert_eq!(
+        compose_projection(Some(&[None, Some(3), Some(1)]), &[2, 0]),
+        vec![Some(1), None],
+    );
+}
+
+#[test]
+fn projects_records() {
+    let record = ByteRecord::from(vec!["a", "b", "c"]);
+    assert_eq!(
+        project_record(&record, &[Some(2), None, Some(0)]),
+        ByteRecord::from(vec!["c", "", "a"])
+    );
+}
+
 /// Specifies an optional single-byte character used to configure our CSV
 /// parser.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CharSpecifier(Option<u8>);
 
 impl CharSpecifier {
+    /// Specify `c`, or no character at all.
+    pub fn new(c: Option<u8>) -> CharSpecifier {
+        CharSpecifier(c)
+    }
+
     /// Return the specified character, if any.
     pub fn char(&self) -> Option<u8> {
         self.0
@@ -28,7 +154,7 @@ impl FromStr for CharSpecifier {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<CharSpecifier> {
-        if s.as_bytes().len() == 1 {
+        if s.len() == 1 {
             Ok(CharSpecifier(Some(s.as_bytes()[0])))
         } else {
             match s {
@@ -36,14 +162,79 @@ impl FromStr for CharSpecifier {
                 // instead of trying to type a tab literal. `xsv` supports this,
                 // too.
                 r"\t" => Ok(CharSpecifier(Some(b'\t'))),
+                r"\r" => Ok(CharSpecifier(Some(b'\r'))),
+                r"\n" => Ok(CharSpecifier(Some(b'\n'))),
+                r"\0" => Ok(CharSpecifier(Some(0))),
                 "tab" => Ok(CharSpecifier(Some(b'\t'))),
+                "comma" => Ok(CharSpecifier(Some(b','))),
+                "semicolon" => Ok(CharSpecifier(Some(b';'))),
+                "pipe" => Ok(CharSpecifier(Some(b'|'))),
+                "caret" => Ok(CharSpecifier(Some(b'^'))),
                 "none" => Ok(CharSpecifier(None)),
+                // Allow any byte to be given in hex, like `0x1F` or `\x1F`,
+                // which is handy for ASCII-delimited files.
+                _ if s.len() == 4 && (s.starts_with("0x") || s.starts_with(r"\x")) => {
+                    u8::from_str_radix(&s[2..], 16)
+                        .map(|b| CharSpecifier(Some(b)))
+                        .map_err(|_| format_err!("cannot parse character specifier: '{}'", s))
+                }
+                // We only support single-byte characters, so explain why
+                // something like `§` doesn't work.
+                _ if s.chars().count() == 1 => Err(format_err!(
+                    "'{}' is {} bytes long in UTF-8, but only single-byte (ASCII) characters are supported",
+                    s,
+                    s.len()
+                )),
                 _ => Err(format_err!("cannot parse character specifier: '{}'", s)),
             }
         }
     }
 }
 
+/// Specifies the field delimiter, which may be guessed from the input.
+#[derive(Clone, Debug)]
+pub enum DelimiterSpecifier {
+    /// Use the specified character.
+    Char(CharSpecifier),
+    /// Guess the delimiter by looking at the start of the input.
+    Auto,
+    /// Split fields on runs of spaces and tabs.
+    Whitespace,
+    /// Use a delimiter which is longer than one byte, like `||`.
+    Sequence(Vec<u8>),
+}
+
+impl FromStr for DelimiterSpecifier {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DelimiterSpecifier> {
+        match s {
+            "auto" => Ok(DelimiterSpecifier::Auto),
+            "whitespace" => Ok(DelimiterSpecifier::Whitespace),
+            // Longer delimiters must be asked for explicitly, so that typos
+            // like "TAB" don't quietly turn into one.
+            _ => match s.strip_prefix("seq:") {
+                Some(seq) if !seq.is_empty() && !seq.contains(['\n', '\r']) => {
+                    Ok(DelimiterSpecifier::Sequence(seq.as_bytes().to_owned()))
+                }
+                Some(_) => Err(format_err!(
+                    "delimiter sequence cannot be empty or contain newlines: '{}'",
+                    s
+                )),
+                None => match CharSpecifier::from_str(s) {
+                    Ok(c) => Ok(DelimiterSpecifier::Char(c)),
+                    Err(err) if s.chars().count() > 1 => Err(format_err!(
+                        "{} (use \"seq:{}\" for a delimiter longer than one byte)",
+                        err,
+                        s
+                    )),
+                    Err(err) => Err(err),
+                },
+            },
+        }
+    }
+}
+
 #[test]
 fn parses_char_specifiers() {
     assert_eq!(CharSpecifier::from_str(",").unwrap().char(), Some(b','));
@@ -51,4 +242,116 @@ fn parses_char_specifiers() {
     assert_eq!(CharSpecifier::from_str(r"\t").unwrap().char(), Some(b'\t'));
     assert_eq!(CharSpecifier::from_str(r"tab").unwrap().char(), Some(b'\t'));
     assert_eq!(CharSpecifier::from_str(r"none").unwrap().char(), None);
+    assert_eq!(CharSpecifier::from_str("pipe").unwrap().char(), Some(b'|'));
+    assert_eq!(
+        CharSpecifier::from_str("semicolon").unwrap().char(),
+        Some(b';')
+    );
+    assert_eq!(CharSpecifier::from_str("comma").unwrap().char(), Some(b','));
+    assert_eq!(CharSpecifier::from_str("caret").unwrap().char(), Some(b'^'));
+    assert_eq!(CharSpecifier::from_str("0x1F").unwrap().char(), Some(0x1F));
+    assert_eq!(CharSpecifier::from_str(r"\x1e").unwrap().char(), Some(0x1E));
+    assert_eq!(CharSpecifier::from_str(r"\0").unwrap().char(), Some(0));
+    assert_eq!(CharSpecifier::from_str(r"\r").unwrap().char(), Some(b'\r'));
+    assert!(CharSpecifier::from_str("0xZZ").is_err());
+    let err = CharSpecifier::from_str("§").unwrap_err().to_string();
+    assert!(err.contains("2 bytes long"), "unexpected error: {}", err);
+}
+
+#[test]
+fn parses_delimiter_specifiers() {
+    match DelimiterSpecifier::from_str("auto").unwrap() {
+        DelimiterSpecifier::Auto => {}
+        other => panic!("expected auto, got {:?}", other),
+    }
+    match DelimiterSpecifier::from_str("whitespace").unwrap() {
+        DelimiterSpecifier::Whitespace => {}
+        other => panic!("expected whitespace, got {:?}", other),
+    }
+    match DelimiterSpecifier::from_str(";").unwrap() {
+        DelimiterSpecifier::Char(c) => assert_eq!(c.char(), Some(b';')),
+        other => panic!("expected char, got {:?}", other),
+    }
+    match DelimiterSpecifier::from_str("seq:~|~").unwrap() {
+        DelimiterSpecifier::Sequence(seq) => assert_eq!(seq, b"~|~"),
+        other => panic!("expected sequence, got {:?}", other),
+    }
+    match DelimiterSpecifier::from_str("seq:§").unwrap() {
+        DelimiterSpecifier::Sequence(seq) => assert_eq!(seq, "§".as_bytes()),
+        other => panic!("expected sequence, got {:?}", other),
+    }
+    for typo in ["TAB", "semicolin", "~|~", "seq:"] {
+        assert!(DelimiterSpecifier::from_str(typo).is_err(), "{}", typo);
+    }
+    let err = DelimiterSpecifier::from_str("||").unwrap_err().to_string();
+    assert!(err.contains("seq:||"), "unexpected error: {}", err);
+    let err = DelimiterSpecifier::from_str("§").unwrap_err().to_string();
+    assert!(err.contains("2 bytes long"), "unexpected error: {}", err);
+}
+
+#[test]
+fn parses_byte_sizes() {
+    assert_eq!(ByteSize::from_str("2k").unwrap(), ByteSize(2048));
+    assert_eq!(ByteSize::from_str("1G").unwrap(), ByteSize(1 << 30));
+    assert_eq!(ByteSize::from_str("100").unwrap(), ByteSize(100));
+    assert!(ByteSize::from_str("0").is_err());
+    assert!(ByteSize::from_str("M").is_err());
+}
+
+/// Split an option like `a=b,c on=" " drop` into words, each with an optional
+/// value after the first `=`. Words are separated by whitespace, and double
+/// quotes can be used to include whitespace or `=` in a word. Inside quotes,
+/// `\"` is a quote, and other backslashes are left alone for regexes.
+pub fn parse_option_words(s: &str) -> Result<Vec<(String, Option<String>)>> {
+    let mut words = vec![];
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Ok(words);
+        }
+        let mut key = String::new();
+        let mut value = None::<String>;
+        let mut in_quotes = false;
+        while let Some(c) = chars.next() {
+            if c == '=' && !in_quotes && value.is_none() {
+                value = Some(String::new());
+                continue;
+            } else if c.is_whitespace() && !in_quotes {
+                break;
+            }
+            let target = value.as_mut().unwrap_or(&mut key);
+            match c {
+                '"' => in_quotes = !in_quotes,
+                '\\' if in_quotes && chars.peek() == Some(&'"') => {
+                    target.push('"');
+                    chars.next();
+                }
+                c => target.push(c),
+            }
+        }
+        if in_quotes {
+            return Err(format_err!("unterminated '\"' in {:?}", s));
+        }
+        words.push((key, value));
+    }
+}
+
+#[test]
+fn parses_option_words() {
+    let words = parse_option_words(r#"a=b,c  on=" " sep="=\"\d" drop"#).unwrap();
+    let expected = [
+        ("a", Some("b,c")),
+        ("on", Some(" ")),
+        ("sep", Some("=\"\\d")),
+        ("drop", None),
+    ];
+    assert_eq!(words.len(), expected.len());
+    for ((key, value), (expected_key, expected_value)) in words.iter().zip(&expected) {
+        assert_eq!(key, expected_key);
+        assert_eq!(value.as_deref(), *expected_value);
+    }
+    assert!(parse_option_words(r#"on=" "#).is_err());
 }
@@ -0,0 +1,331 @@
+//! Validation rules for individual cells, configured in our schema.
+
+use log::debug;
+use regex::bytes::Regex;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::errors::*;
+use crate::profile::ValueType;
+use crate::schema::Schema;
+use crate::stats::{RuleHits, RuleId};
+
+/// How seriously should we take a validation failure?
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Count the failure and report it, but keep the row.
+    Warn,
+    /// Treat the row as bad.
+    #[default]
+    Error,
+}
+
+/// A validation rule for a column, as written in a schema:
+///
+/// ```yaml
+/// rules:
+///   - type: integer
+///   - range: { min: 0, max: 150 }
+///     severity: warn
+///   - pattern: "^[A-Z]{2}$"
+///   - allowed: [red, green, blue]
+///   - max_length: 20
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    /// What we check.
+    #[serde(flatten)]
+    pub check: Check,
+    /// What happens if the check fails.
+    #[serde(default)]
+    pub severity: Severity,
+}
+
+/// The different checks we support. Empty values are only checked by
+/// `NotNull`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Check {
+    /// The value must have this type. Integers count as decimals, and any
+    /// value counts as a string.
+    Type(ValueType),
+    /// The value must be a number within this range.
+    Range {
+        /// The smallest allowed value.
+        min: Option<f64>,
+        /// The largest allowed value.
+        max: Option<f64>,
+    },
+    /// The value must match this regular expression.
+    Pattern(String),
+    /// The value must be one of these strings.
+    Allowed(Vec<String>),
+    /// The value must have at most this many characters.
+    #[serde(rename = "max_length")]
+    MaxLength(usize),
+    /// The value must not be empty. Written as `nullable: false` on a column.
+    #[serde(skip_deserializing)]
+    NotNull,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Check::Type(t) => write!(f, "type {}", t),
+            Check::Range { min, max } => {
+                write!(f, "range ")?;
+                if let Some(min) = min {
+                    write!(f, "{}", min)?;
+                }
+                write!(f, "..")?;
+                if let Some(max) = max {
+                    write!(f, "{}", max)?;
+                }
+                Ok(())
+            }
+            Check::Pattern(pattern) => write!(f, "pattern {}", pattern),
+            Check::Allowed(values) => write!(f, "allowed {}", values.join(",")),
+            Check::MaxLength(max) => write!(f, "max_length {}", max),
+            Check::NotNull => write!(f, "not null"),
+        }
+    }
+}
+
+/// A compiled version of `Check`.
+#[derive(Debug)]
+enum CompiledCheck {
+    Type(ValueType),
+    Range { min: Option<f64>, max: Option<f64> },
+    Pattern(Regex),
+    Allowed(Vec<Vec<u8>>),
+    MaxLength(usize),
+    NotNull,
+}
+
+impl CompiledCheck {
+    /// Compile a `Check`.
+    fn new(check: &Check) -> Result<CompiledCheck> {
+        Ok(match check {
+            Check::Type(t) => CompiledCheck::Type(*t),
+            Check::Range { min, max } => CompiledCheck::Range {
+                min: *min,
+                max: *max,
+            },
+            Check::Pattern(pattern) => {
+                // Always match the full value, like `--null`.
+                let re = Regex::new(&format!("^(?:{})$", pattern))
+                    .context("can't compile regular expression in schema")?;
+                CompiledCheck::Pattern(re)
+            }
+            Check::Allowed(values) => CompiledCheck::Allowed(
+                values.iter().map(|v| v.as_bytes().to_owned()).collect(),
+            ),
+            Check::MaxLength(max) => CompiledCheck::MaxLength(*max),
+            Check::NotNull => CompiledCheck::NotNull,
+        })
+    }
+
+    /// Does `value` pass this check?
+    fn passes(&self, value: &[u8]) -> bool {
+        match self {
+            CompiledCheck::Type(expected) => {
+                let actual = ValueType::of(value);
+                *expected == ValueType::String
+                    || actual == *expected
+                    || (*expected == ValueType::Decimal
+                        && actual == ValueType::Integer)
+            }
+            CompiledCheck::Range { min, max } => {
+                let n = match std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|s| s.parse::<f64>().ok())
+                {
+                    Some(n) => n,
+                    None => return false,
+                };
+                min.is_none_or(|min| n >= min) && max.is_none_or(|max| n <= max)
+            }
+            CompiledCheck::Pattern(re) => re.is_match(value),
+            CompiledCheck::Allowed(values) => values.iter().any(|v| v == value),
+            CompiledCheck::MaxLength(max) => {
+                String::from_utf8_lossy(value).chars().count() <= *max
+            }
+            CompiledCheck::NotNull => !value.is_empty(),
+        }
+    }
+}
+
+/// The result of validating a row.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RowValidation {
+    /// The number of failed rules with `Severity::Warn`.
+    pub warnings: u64,
+    /// The number of failed rules with `Severity::Error`.
+    pub errors: u64,
+}
+
+/// Validates rows against the rules in a schema.
+#[derive(Debug)]
+pub struct Validator {
+    /// For each rule, the output column it applies to, its severity, the
+    /// compiled check, and the ID we use to count failures.
+    rules: Vec<(usize, Severity, CompiledCheck, RuleId)>,
+}
+
+impl Validator {
+    /// Build a validator for the rules in `schema`, for output columns named
+    /// `names`, registering each rule with `hits`. Returns `None` if there are
+    /// no rules to check.
+    pub fn new(
+        schema: &Schema,
+        names: &[String],
+        hits: &mut RuleHits,
+    ) -> Result<Option<Validator>> {
+        let mut rules = vec![];
+        for column in &schema.columns {
+            // Turn any checks written as column properties into rules.
+            let mut column_rules = vec![];
+            if !column.nullable {
+                column_rules.push(Check::NotNull);
+            }
+            if let Some(max) = column.max_length {
+                column_rules.push(Check::MaxLength(max));
+            }
+            if let Some(allowed) = &column.allowed {
+                column_rules.push(Check::Allowed(allowed.clone()));
+            }
+            let column_rules = column_rules
+                .into_iter()
+                .map(|check| Rule {
+                    check,
+                    severity: Severity::Error,
+                })
+                .collect::<Vec<_>>();
+            if column.rules.is_empty() && column_rules.is_empty() {
+                continue;
+            }
+            let idx = match names.iter().position(|n| n == &column.name) {
+                Some(idx) => idx,
+                None => {
+                    debug!("not validating missing column {:?}", column.name);
+                    continue;
+                }
+            };
+            for rule in column_rules.iter().chain(&column.rules) {
+                let check = CompiledCheck::new(&rule.check)?;
+                let unit = match rule.severity {
+                    Severity::Warn => "cells warned",
+                    Severity::Error => "cells rejected",
+                };
+                let id = hits.register(
+                    format!("schema rule {} for {:?}", rule.check, column.name),
+                    unit,
+                );
+                rules.push((idx, rule.severity, check, id));
+            }
+        }
+        if rules.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Validator { rules }))
+        }
+    }
+
+    /// Check the values in `row`, counting failures in `hits`. `row_number`
+    /// is only used for logging.
+    pub fn validate(
+        &self,
+        row_number: u64,
+        row: &[&[u8]],
+        hits: &RuleHits,
+    ) -> RowValidation {
+        let mut result = RowValidation::default();
+        for (idx, severity, check, id) in &self.rules {
+            let value = row.get(*idx).copied().unwrap_or(b"");
+            let skip_empty = !matches!(check, CompiledCheck::NotNull);
+            if (value.is_empty() && skip_empty) || check.passes(value) {
+                continue;
+            }
+            debug!(
+                "row {}: column {} failed {:?} ({:?})",
+                row_number,
+                idx + 1,
+                check,
+                severity,
+            );
+            hits.hit(*id);
+            match severity {
+                Severity::Warn => result.warnings += 1,
+                Severity::Error => result.errors += 1,
+            }
+        }
+        result
+    }
+}
+
+#[test]
+fn validates_rows() {
+    let schema: Schema = serde_yaml::from_str(
+        r#"
+columns:
+  - name: age
+    rules:
+      - type: integer
+      - range: { min: 0, max: 150 }
+        severity: warn
+  - name: state
+    rules:
+      - pattern: "[A-Z]{2}"
+      - allowed: [OR, WA]
+        severity: warn
+"#,
+    )
+    .unwrap();
+    let names = vec!["age".to_owned(), "state".to_owned()];
+    let mut hits = RuleHits::default();
+    let validator = Validator::new(&schema, &names, &mut hits).unwrap().unwrap();
+    let check = |row: &[&[u8]]| {
+        let v = validator.validate(2, row, &hits);
+        (v.warnings, v.errors)
+    };
+    assert_eq!(check(&[b"42", b"OR"]), (0, 0));
+    assert_eq!(check(&[b"", b""]), (0, 0));
+    assert_eq!(check(&[b"200", b"CA"]), (2, 0));
+    assert_eq!(check(&[b"x", b"oregon"]), (2, 2));
+
+    let labels = hits.iter().map(|(label, _, _)| label).collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        vec![
+            r#"schema rule type integer for "age""#,
+            r#"schema rule range 0..150 for "age""#,
+            r#"schema rule pattern [A-Z]{2} for "state""#,
+            r#"schema rule allowed OR,WA for "state""#,
+        ]
+    );
+}
+
+#[test]
+fn validates_column_properties() {
+    let schema: Schema = serde_yaml::from_str(
+        r#"
+columns:
+  - name: id
+    nullable: false
+  - name: color
+    max_length: 5
+    allowed: [red, green, purple]
+"#,
+    )
+    .unwrap();
+    let names = vec!["id".to_owned(), "color".to_owned()];
+    let mut hits = RuleHits::default();
+    let validator = Validator::new(&schema, &names, &mut hits).unwrap().unwrap();
+    let errors = |row: &[&[u8]]| validator.validate(2, row, &hits).errors;
+    assert_eq!(errors(&[b"1", b"red"]), 0);
+    assert_eq!(errors(&[b"1", b""]), 0);
+    assert_eq!(errors(&[b"", b"red"]), 1);
+    assert_eq!(errors(&[b"1", b"purple"]), 1);
+    assert_eq!(errors(&[b"1", b"blue"]), 1);
+}
@@ -0,0 +1,109 @@
+//! Keeping only the first or last of our output rows, for `--head` and
+//! `--tail`.
+
+use std::borrow::Cow;
+
+use crate::errors::*;
+use crate::spill::SpillBuffer;
+use crate::util::ByteSize;
+
+/// What to do with a row we're about to write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admit {
+    /// Write it now.
+    Write,
+    /// We're holding on to it until we've seen every row. If this made us
+    /// forget an older row, `dropped` is true.
+    Held { dropped: bool },
+}
+
+/// Decides which of our output rows to write.
+#[derive(Debug)]
+pub struct RowWindow {
+    /// Stop after this many rows.
+    head: Option<u64>,
+    /// Only write this many rows, from the end of our output.
+    tail: Option<usize>,
+    /// How many rows we've admitted.
+    admitted: u64,
+    /// The last `tail` rows.
+    held: SpillBuffer<Vec<Vec<u8>>>,
+}
+
+impl RowWindow {
+    /// Keep the first `head` rows, and then the last `tail` of those, which
+    /// are spilled to disk past `memory_limit`. Returns `None` if we should
+    /// keep every row.
+    pub fn new(
+        head: Option<u64>,
+        tail: Option<usize>,
+        memory_limit: Option<ByteSize>,
+    ) -> Option<RowWindow> {
+        if head.is_none() && tail.is_none() {
+            return None;
+        }
+        Some(RowWindow {
+            head,
+            tail,
+            admitted: 0,
+            held: SpillBuffer::new(memory_limit),
+        })
+    }
+
+    /// Have we seen every row we need? If so, we can stop reading.
+    pub fn is_full(&self) -> bool {
+        self.head.is_some_and(|head| self.admitted >= head)
+    }
+
+    /// Decide what to do with `row`, which should come before any rows we
+    /// admit later.
+    pub fn admit(&mut self, row: &[Cow<[u8]>]) -> Result<Admit> {
+        self.admitted += 1;
+        let tail = match self.tail {
+            Some(tail) => tail,
+            None => return Ok(Admit::Write),
+        };
+        self.held.push(row.iter().map(|v| v.to_vec()).collect())?;
+        let dropped = self.held.len() > tail;
+        if dropped {
+            self.held.drop_first();
+        }
+        Ok(Admit::Held { dropped })
+    }
+
+    /// The rows we're holding, which should be written at the end.
+    pub fn into_held(self) -> impl Iterator<Item = Result<Vec<Vec<u8>>>> {
+        self.held.into_rows()
+    }
+}
+
+#[test]
+fn keeps_windows() {
+    let row = |v: &'static str| vec![Cow::Borrowed(v.as_bytes())];
+    let mut head = RowWindow::new(Some(2), None, None).unwrap();
+    assert!(!head.is_full());
+    assert_eq!(head.admit(&row("a")).unwrap(), Admit::Write);
+    assert_eq!(head.admit(&row("b")).unwrap(), Admit::Write);
+    assert!(head.is_full());
+
+    // Only our first row fits in memory.
+    let mut tail = RowWindow::new(Some(3), Some(2), Some(ByteSize(64))).unwrap();
+    assert_eq!(
+        tail.admit(&row("a")).unwrap(),
+        Admit::Held { dropped: false }
+    );
+    assert_eq!(
+        tail.admit(&row("b")).unwrap(),
+        Admit::Held { dropped: false }
+    );
+    assert_eq!(
+        tail.admit(&row("c")).unwrap(),
+        Admit::Held { dropped: true }
+    );
+    assert!(tail.is_full());
+    assert_eq!(
+        tail.into_held().collect::<Result<Vec<_>>>().unwrap(),
+        vec![vec![b"b".to_vec()], vec![b"c".to_vec()]]
+    );
+    assert!(RowWindow::new(None, None, None).is_none());
+}
@@ -10,6 +10,9 @@ fn help_flag() {
     let output = testdir.cmd().arg("--help").expect_success();
     assert!(output.stdout_str().contains("scrubcsv"));
     assert!(output.stdout_str().contains("--help"));
+    assert!(output
+        .stdout_str()
+        .contains("Clean and normalize a CSV file."));
 }
 
 #[test]
@@ -49,7 +52,7 @@ fn stdin_and_delimiter_and_quiet() {
     let testdir = TestDir::new("scrubcsv", "stdin_and_delimiter_and_quiet");
     let output = testdir
         .cmd()
-        .args(&["-d", "|"])
+        .args(["-d", "|"])
         .arg("-q")
         .output_with_stdin(
             "\
@@ -80,8 +83,8 @@ a\tb\tc
     );
     let output = testdir
         .cmd()
-        .args(&["-d", r"\t"])
-        .args(&["--quote", "none"])
+        .args(["-d", r"\t"])
+        .args(["--quote", "none"])
         .arg("in.csv")
         .expect_success();
     assert_eq!(
@@ -111,6 +114,29 @@ fn bad_rows() {
     assert!(output.stderr_str().contains("102 rows (1 bad)"));
 }
 
+#[test]
+fn show_bad_rows() {
+    let mut input = "a,b\n".to_owned();
+    for _ in 0..100 {
+        input.push_str("1,2\n");
+    }
+    input.push_str("1,2,3\n4\n5\n");
+
+    let testdir = TestDir::new("scrubcsv", "show_bad_rows");
+    let output = testdir
+        .cmd()
+        .args(["--show-bad-rows", "2"])
+        .output_with_stdin(&input)
+        .expect_success();
+    let stderr = output.stderr_str();
+    assert!(stderr.contains(
+        "Bad row at line 102: expected 2 columns, found 3\n  1,2,3\n     ^ unexpected delimiter\n"
+    ));
+    assert!(stderr.contains("Bad row at line 103: expected 2 columns, found 1\n"));
+    assert!(!stderr.contains("line 104"));
+    assert!(stderr.contains("(not showing any more bad rows)"));
+}
+
 #[test]
 fn too_many_bad_rows() {
     let testdir = TestDir::new("scrubcsv", "too_many_bad_rows");
@@ -135,7 +161,7 @@ fn null_normalization() {
     let testdir = TestDir::new("scrubcsv", "null_normalization");
     let output = testdir
         .cmd()
-        .args(&["--null", "(?i)null|NIL"])
+        .args(["--null", "(?i)null|NIL"])
         .output_with_stdin("a,b,c,d,e\nnull,NIL,nil,,not null\n")
         .expect_success();
     assert_eq!(output.stdout_str(), "a,b,c,d,e\n,,,,not null\n")
@@ -146,7 +172,7 @@ fn null_normalization_of_null_bytes() {
     let testdir = TestDir::new("scrubcsv", "null_normalization_of_null_bytes");
     let output = testdir
         .cmd()
-        .args(&["--null", "\\x00"])
+        .args(["--null", "\\x00"])
         .output_with_stdin("a,b\n\0,\n")
         .expect_success();
     assert_eq!(output.stdout_str(), "a,b\n,\n")
@@ -174,6 +200,17 @@ fn trim_whitespace() {
     assert_eq!(output.stdout_str(), "a,b,c,d\n1,2,,\n");
 }
 
+#[test]
+fn collapse_whitespace() {
+    let testdir = TestDir::new("scrubcsv", "collapse_whitespace");
+    let output = testdir
+        .cmd()
+        .args(["--collapse-whitespace", "--trim-whitespace"])
+        .output_with_stdin("name,addr\n Jane  Doe ,\"1\t Main   St\"\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "name,addr\nJane Doe,1 Main St\n");
+}
+
 #[test]
 fn clean_column_names() {
     let testdir = TestDir::new("scrubcsv", "clean_column_names");
@@ -185,6 +222,56 @@ fn clean_column_names() {
     assert_eq!(output.stdout_str(), "_,__2,a,a_2\n");
 }
 
+#[test]
+fn header_maps() {
+    let testdir = TestDir::new("scrubcsv", "header_maps");
+    testdir.create_file("day1.csv", "Zip Code,A,a\n97201,1,2\n");
+    testdir
+        .cmd()
+        .args([
+            "--clean-column-names",
+            "--write-header-map",
+            "map.csv",
+            "day1.csv",
+        ])
+        .output()
+        .expect("could not run scrubcsv")
+        .expect_success();
+    testdir.expect_file_contents(
+        "map.csv",
+        "original,cleaned\nZip Code,zip_code\nA,a\na,a_2\n",
+    );
+
+    // The same names come out even if the columns are reordered.
+    let output = testdir
+        .cmd()
+        .args(["--apply-header-map", "map.csv"])
+        .output_with_stdin("a,Zip Code,A\n2,97201,1\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a_2,zip_code,a\n2,97201,1\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--apply-header-map", "map.csv"])
+        .output_with_stdin("a,Email\n")
+        .expect("could not run scrubcsv");
+    assert!(!output.status.success());
+    assert!(output
+        .stderr_str()
+        .contains("\"Email\" is not in the header map"));
+}
+
+#[test]
+fn sort_columns() {
+    let testdir = TestDir::new("scrubcsv", "sort_columns");
+    let output = testdir
+        .cmd()
+        .args(["--sort-columns", "--clean-column-names"])
+        .output_with_stdin("Zip,Name,Age\n97201,Jo,30\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "age,name,zip\n30,Jo,97201\n");
+}
+
 #[test]
 fn drop_row_if_null() {
     let testdir = TestDir::new("scrubcsv", "replace_newlines");
@@ -192,7 +279,7 @@ fn drop_row_if_null() {
         .cmd()
         .arg("--drop-row-if-null=c1")
         .arg("--drop-row-if-null=c2")
-        .args(&["--null", "NULL"])
+        .args(["--null", "NULL"])
         .output_with_stdin(
             r#"c1,c2,c3
 1,,
@@ -211,3 +298,2352 @@ a,b,c
 "#
     );
 }
+
+#[test]
+fn strip_thousands_separators_and_decimal_comma_output() {
+    let testdir = TestDir::new("scrubcsv", "decimal_comma_output");
+    let output = testdir
+        .cmd()
+        .arg("--strip-thousands-separators")
+        .arg("--decimal-comma-output")
+        .output_with_stdin("a,b,c,d\n\"1,234.56\",7.5,12345,x.y\n")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "a,b,c,d\n\"1234,56\",\"7,5\",12345,x.y\n"
+    );
+}
+
+#[test]
+fn auto_delimiter() {
+    let testdir = TestDir::new("scrubcsv", "auto_delimiter");
+    let output = testdir
+        .cmd()
+        .args(["-d", "auto"])
+        .args(["--detect-sample-rows", "2"])
+        .output_with_stdin("a;b;c\n1;\"2;5\";3\n4,5;6;7\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b,c\n1,2;5,3\n\"4,5\",6,7\n");
+}
+
+#[test]
+fn assert_min_and_max_rows() {
+    let testdir = TestDir::new("scrubcsv", "assert_min_and_max_rows");
+    let input = "a,b\n1,2\n3,4\n";
+
+    testdir
+        .cmd()
+        .args(["--assert-min-rows", "2", "--assert-max-rows", "2"])
+        .output_with_stdin(input)
+        .expect_success();
+
+    let output = testdir
+        .cmd()
+        .args(["--assert-min-rows", "3"])
+        .output_with_stdin(input)
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output
+        .stderr_str()
+        .contains("Too few good rows (2, expected at least 3)"));
+
+    let output = testdir
+        .cmd()
+        .args(["--assert-max-rows", "1"])
+        .output_with_stdin(input)
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output
+        .stderr_str()
+        .contains("Too many good rows (2, expected at most 1)"));
+}
+
+#[test]
+fn changed_rows() {
+    let testdir = TestDir::new("scrubcsv", "changed_rows");
+    let input = "a,b\n 1,2\n3,4\n";
+
+    let output = testdir
+        .cmd()
+        .arg("--trim-whitespace")
+        .output_with_stdin(input)
+        .expect_success();
+    assert!(output.stderr_str().contains("1 rows changed by cleanup"));
+
+    let output = testdir
+        .cmd()
+        .arg("--fail-if-unchanged")
+        .output_with_stdin(input)
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(4));
+
+    let output = testdir
+        .cmd()
+        .arg("--trim-whitespace")
+        .args(["--fail-if-changed-over", "0.25"])
+        .output_with_stdin(input)
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(4));
+    assert!(output
+        .stderr_str()
+        .contains("Too many rows (1 of 2) were changed by cleanup"));
+}
+
+#[test]
+fn generate_dirty_data() {
+    let testdir = TestDir::new("scrubcsv", "generate_dirty_data");
+    let output = testdir
+        .cmd()
+        .args(["generate", "--rows", "100", "--cols", "4", "--seed", "3"])
+        .expect_success();
+    assert!(output.stdout.starts_with(b"col1,col2,col3,col4\n"));
+    std::fs::write(testdir.path("dirty.csv"), &output.stdout).unwrap();
+
+    let output = testdir
+        .cmd()
+        .arg("dirty.csv")
+        .output()
+        .expect("could not run scrubcsv");
+    assert!(output.stderr_str().contains(" rows ("));
+}
+
+#[test]
+fn recover_runaway_quotes() {
+    let testdir = TestDir::new("scrubcsv", "recover_runaway_quotes");
+    let input = "a,b,c\n1,\"oops,3\n4,5,6\n7,8,9\n";
+
+    let output = testdir
+        .cmd()
+        .output_with_stdin(input)
+        .expect("could not run scrubcsv");
+    assert_eq!(output.stdout_str(), "a,b,c\n");
+
+    let output = testdir
+        .cmd()
+        .arg("--recover-runaway-quotes")
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b,c\n1,oops,3\n4,5,6\n7,8,9\n");
+    assert!(output
+        .stderr_str()
+        .contains("1 runaway quoted fields recovered"));
+}
+
+#[test]
+fn quote_repair() {
+    let testdir = TestDir::new("scrubcsv", "quote_repair");
+    let input = "a,b\n\"Broken \" quotes\",2\n";
+    let expected = &[
+        ("smart", "a,b\n\"Broken \"\" quotes\",2\n"),
+        ("escape", "a,b\n\"Broken \"\" quotes\",2\n"),
+        ("strip", "a,b\nBroken  quotes,2\n"),
+    ];
+    for &(strategy, expected) in expected {
+        let output = testdir
+            .cmd()
+            .args(["--quote-repair", strategy])
+            .output_with_stdin(input)
+            .expect_success();
+        assert_eq!(output.stdout_str(), expected);
+        assert!(output.stderr_str().contains("1 stray quotes repaired"));
+    }
+}
+
+#[test]
+fn allow_trailing_delimiter() {
+    let testdir = TestDir::new("scrubcsv", "allow_trailing_delimiter");
+    let output = testdir
+        .cmd()
+        .arg("--allow-trailing-delimiter")
+        .output_with_stdin("a,b,\n1,2,\n3,4,\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n3,4\n");
+
+    // Rows without the trailing delimiter are bad.
+    let output = testdir
+        .cmd()
+        .arg("--allow-trailing-delimiter")
+        .output_with_stdin("a,b,\n1,2,\n3,4\n")
+        .expect("could not run scrubcsv");
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+    assert!(output.stderr_str().contains("3 rows (1 bad)"));
+
+    // Without a trailing delimiter in the header, we do nothing.
+    let output = testdir
+        .cmd()
+        .arg("--allow-trailing-delimiter")
+        .output_with_stdin("a,b\n1,\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,\n");
+}
+
+#[test]
+fn merge_delimiters() {
+    let testdir = TestDir::new("scrubcsv", "merge_delimiters");
+    let output = testdir
+        .cmd()
+        .args(["-d", ";", "--merge-delimiters"])
+        .output_with_stdin("a;;;b;c\n;1;\"2;;x\";;;3;\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b,c\n1,2;;x,3\n");
+}
+
+#[test]
+fn whitespace_delimiter() {
+    let testdir = TestDir::new("scrubcsv", "whitespace_delimiter");
+    let output = testdir
+        .cmd()
+        .args(["-d", "whitespace"])
+        .output_with_stdin("name     city        count\n  Alice  \"New York\"  3\nBob\tParis\t\t12  \n")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "name,city,count\nAlice,New York,3\nBob,Paris,12\n"
+    );
+}
+
+#[test]
+fn profile_top_values() {
+    let testdir = TestDir::new("scrubcsv", "profile_top_values");
+    testdir
+        .cmd()
+        .args(["--profile", "profile.json", "--top-values", "1"])
+        .output_with_stdin("a,b\nUNKNOWN,1\nx,2\nUNKNOWN,2\n")
+        .expect_success();
+    testdir.expect_file_contents(
+        "profile.json",
+        r#"{
+  "rows": 3,
+  "columns": [
+    {
+      "name": "a",
+      "null_count": 0,
+      "type": "string",
+      "distinct_estimate": 2,
+      "min_length": 1,
+      "max_length": 7,
+      "samples": [
+        "UNKNOWN",
+        "x"
+      ],
+      "top_values": [
+        {
+          "value": "UNKNOWN",
+          "count": 2
+        }
+      ]
+    },
+    {
+      "name": "b",
+      "null_count": 0,
+      "type": "integer",
+      "distinct_estimate": 2,
+      "min_length": 1,
+      "max_length": 1,
+      "min": 1.0,
+      "max": 2.0,
+      "mean": 1.6666666666666667,
+      "samples": [
+        "1",
+        "2"
+      ],
+      "top_values": [
+        {
+          "value": "2",
+          "count": 2
+        }
+      ]
+    }
+  ]
+}"#,
+    );
+}
+
+#[test]
+fn baseline_profile_drift() {
+    let testdir = TestDir::new("scrubcsv", "baseline_profile_drift");
+    testdir
+        .cmd()
+        .args(["--profile", "baseline.json"])
+        .output_with_stdin("id,name\n1,a\n2,b\n")
+        .expect_success();
+
+    let output = testdir
+        .cmd()
+        .args(["--baseline-profile", "baseline.json"])
+        .output_with_stdin("id,name\n1,a\n2,b\n")
+        .expect_success();
+    assert!(!output.stderr_str().contains("Drift"));
+
+    let output = testdir
+        .cmd()
+        .args(["--baseline-profile", "baseline.json", "--fail-on-drift"])
+        .output_with_stdin("id,name,extra\n1,,x\n2,,y\n")
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(5));
+    assert!(output
+        .stderr_str()
+        .contains("column \"name\" null rate changed from 0.0% to 100.0%"));
+    assert!(output.stderr_str().contains("new column \"extra\""));
+}
+
+#[test]
+fn expect_schema() {
+    let testdir = TestDir::new("scrubcsv", "expect_schema");
+    testdir.create_file(
+        "schema.yml",
+        "columns:\n- name: id\n- name: email\n- name: zip\n",
+    );
+    let input = "id,e_mail,state\n1,a@example.com,OR\n";
+
+    let output = testdir
+        .cmd()
+        .args(["--expect-schema", "schema.yml"])
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(output.stdout_str(), input);
+    assert!(output
+        .stderr_str()
+        .contains("Schema change: renamed column \"email\" to \"e_mail\""));
+
+    let output = testdir
+        .cmd()
+        .args([
+            "--expect-schema",
+            "schema.yml",
+            "--on-schema-change",
+            "fail",
+        ])
+        .output_with_stdin(input)
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(output.stdout_str(), "");
+
+    let output = testdir
+        .cmd()
+        .args([
+            "--expect-schema",
+            "schema.yml",
+            "--on-schema-change",
+            "adapt",
+        ])
+        .output_with_stdin("email,id,extra,more\na@example.com,1,97201,x\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,email,zip\n1,a@example.com,97201\n");
+}
+
+#[test]
+fn schema_file_validation() {
+    let testdir = TestDir::new("scrubcsv", "schema_file_validation");
+    testdir.create_file(
+        "schema.json",
+        r#"{
+  "ordered": true,
+  "columns": [
+    {"name": "id", "type": "integer", "nullable": false},
+    {"name": "color", "max_length": 5, "allowed": ["red", "green", "purple"]}
+  ]
+}"#,
+    );
+    testdir.create_file("in.csv", "id,color\n01,red\n,green\nx,red\n3,purple\n4,\n");
+    let output = testdir
+        .cmd()
+        .args(["--schema", "schema.json"])
+        .args(["--bad-rows-path", "bad.csv", "--annotate-bad-rows"])
+        .args(["--max-bad-rows", "100"])
+        .arg("in.csv")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,color\n1,red\n4,\n");
+    testdir.expect_file_contents(
+        "bad.csv",
+        "_line,_reason,id,color\n3,validation_failed,,green\n\
+         4,type_mismatch,x,red\n5,validation_failed,3,purple\n",
+    );
+
+    testdir.create_file("moved.csv", "color,id\nred,1\n");
+    let output = testdir
+        .cmd()
+        .args(["--schema", "schema.json", "--on-schema-change", "adapt"])
+        .arg("moved.csv")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,color\n1,red\n");
+    assert!(output
+        .stderr_str()
+        .contains("Schema change: moved column \"id\""));
+}
+
+#[test]
+fn emit_schema() {
+    let testdir = TestDir::new("scrubcsv", "emit_schema");
+    testdir.create_file(
+        "in.csv",
+        "id,name,joined\n1,Ann,2024-01-02\n2,,2024-02-03\n",
+    );
+    testdir
+        .cmd()
+        .args(["--emit-schema", "postgres", "-o", "people.csv", "in.csv"])
+        .expect_success();
+    testdir.expect_file_contents(
+        "people.sql",
+        "CREATE TABLE \"people\" (\n    \"id\" BIGINT NOT NULL,\n    \
+         \"name\" VARCHAR(3),\n    \"joined\" DATE NOT NULL\n);\n",
+    );
+
+    // Our JSON schemas can be used to check later files.
+    testdir
+        .cmd()
+        .args(["--emit-schema", "json", "--emit-schema-path", "schema.json"])
+        .arg("in.csv")
+        .expect_success();
+    testdir.create_file("later.csv", "id,name,joined\n3,Carol,2024-03-04\n");
+    let output = testdir
+        .cmd()
+        .args(["--schema", "schema.json", "later.csv"])
+        .expect_failure();
+    assert!(output.stderr_str().contains("1 bad"));
+
+    let output = testdir
+        .cmd()
+        .args(["--emit-schema", "bigquery", "in.csv"])
+        .expect_failure();
+    assert!(output.stderr_str().contains("--emit-schema needs --output"));
+}
+
+#[test]
+fn schema_validation_severity() {
+    let testdir = TestDir::new("scrubcsv", "schema_validation_severity");
+    testdir.create_file(
+        "schema.yml",
+        r#"
+columns:
+  - name: id
+    rules:
+      - type: integer
+  - name: age
+    rules:
+      - range: { min: 0, max: 150 }
+        severity: warn
+"#,
+    );
+    let mut input = "id,age\n".to_owned();
+    for i in 0..20 {
+        input.push_str(&format!("{},30\n", i));
+    }
+    input.push_str("x,30\n99,200\n");
+    let output = testdir
+        .cmd()
+        .args(["--expect-schema", "schema.yml"])
+        .output_with_stdin(&input)
+        .expect_success();
+    assert!(output.stdout_str().ends_with("19,30\n99,200\n"));
+    assert!(output.stderr_str().contains("23 rows (1 bad)"));
+    assert!(output.stderr_str().contains("1 validation warnings"));
+}
+
+#[test]
+fn rule_hits_summary() {
+    let testdir = TestDir::new("scrubcsv", "rule_hits_summary");
+    let mut input = "a,b\n".to_owned();
+    for _ in 0..20 {
+        input.push_str("1,2\n");
+    }
+    input.push_str(" x ,NULL\n,y\nbad\n");
+    let output = testdir
+        .cmd()
+        .args([
+            "--trim-whitespace",
+            "--null",
+            "NULL",
+            "--drop-row-if-null",
+            "a",
+        ])
+        .output_with_stdin(&input)
+        .expect_success();
+    let stderr = output.stderr_str();
+    assert!(stderr.contains("  wrong number of columns: 1 rows rejected\n"));
+    assert!(stderr.contains("  --null: 1 cells changed\n"));
+    assert!(stderr.contains("  --trim-whitespace: 1 cells changed\n"));
+    assert!(stderr.contains("  --drop-row-if-null: 1 rows rejected\n"));
+
+    // Rules which did nothing aren't worth mentioning.
+    let output = testdir
+        .cmd()
+        .args(["--trim-whitespace", "--null", "NULL"])
+        .output_with_stdin("a,b\n1,2\n x ,3\n")
+        .expect_success();
+    let stderr = output.stderr_str();
+    assert!(stderr.contains("Rule hits:\n  --trim-whitespace: 1 cells changed\n"));
+    assert!(!stderr.contains("--null"));
+    let output = testdir
+        .cmd()
+        .args(["--trim-whitespace"])
+        .output_with_stdin("a,b\n1,2\n")
+        .expect_success();
+    let stderr = output.stderr_str();
+    assert!(!stderr.contains("Rule hits"));
+    assert!(!stderr.contains("changed by cleanup"));
+}
+
+#[test]
+fn tui_preview() {
+    let testdir = TestDir::new("scrubcsv", "tui_preview");
+    testdir.create_file("in.csv", "a;b\n 1 ;2\n3\n");
+    let output = testdir
+        .cmd()
+        .args(["tui", "in.csv"])
+        .output_with_stdin("d ;;\nd ;\nt\nw\n")
+        .expect_success();
+    let stdout = output.stdout_str();
+    assert!(stdout.contains("cannot parse character specifier: ';;'"));
+    assert!(stdout.contains("(1 bad)"));
+    assert!(stdout.ends_with("scrubcsv -d ';' --trim-whitespace in.csv\n"));
+}
+
+#[test]
+fn follow_growing_file() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::Stdio;
+
+    let testdir = TestDir::new("scrubcsv", "follow_growing_file");
+    testdir.create_file("in.csv", "a,b\n1,2\n");
+    let mut child = testdir
+        .cmd()
+        .args(["--follow", "in.csv"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("could not run scrubcsv");
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "a,b\n1,2\n");
+
+    // Append a row with a bad row before it, and make sure it shows up.
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(testdir.path("in.csv"))
+        .unwrap();
+    file.write_all(b"bad\n3,4\n").unwrap();
+    line.clear();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "3,4\n");
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn stdin_timeout() {
+    use std::process::Stdio;
+
+    let testdir = TestDir::new("scrubcsv", "stdin_timeout");
+    let mut child = testdir
+        .cmd()
+        .args(["--stdin-timeout", "0.2"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run scrubcsv");
+    // Hold on to stdin without writing anything.
+    let _stdin = child.stdin.take();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(7));
+    assert!(output
+        .stderr_str()
+        .contains("no input received for 0.2 seconds"));
+}
+
+#[test]
+fn stdin_timeout_conflicts_with_inputs() {
+    let testdir = TestDir::new("scrubcsv", "stdin_timeout_conflicts_with_inputs");
+    testdir.create_file("in.csv", "a\n1\n");
+    let output = testdir
+        .cmd()
+        .args(["--stdin-timeout", "1", "in.csv"])
+        .expect_failure();
+    assert!(output.stderr_str().contains("cannot be used with"));
+}
+
+#[test]
+fn utf8_fallback() {
+    let testdir = TestDir::new("scrubcsv", "utf8_fallback");
+    std::fs::write(testdir.path("in.csv"), b"a,b\ndon\x92t,caf\xc3\xa9\n").unwrap();
+    let output = testdir
+        .cmd()
+        .args(["--utf8-fallback", "windows-1252", "in.csv"])
+        .output()
+        .expect("could not run scrubcsv")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\ndon\u{2019}t,caf\u{e9}\n");
+    assert!(output
+        .stderr_str()
+        .contains("  --utf8-fallback: 1 cells changed\n"));
+}
+
+#[test]
+fn excel_friendly() {
+    let testdir = TestDir::new("scrubcsv", "excel_friendly");
+    let output = testdir
+        .cmd()
+        .arg("--excel-friendly")
+        .output_with_stdin("a,b\n1,2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "\u{feff}a,b\n1,2\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--excel-friendly", "--excel-sep-line"])
+        .output_with_stdin("a,b\n1,2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "\u{feff}sep=,\r\na,b\n1,2\n");
+}
+
+#[test]
+fn quote_leading_whitespace() {
+    let testdir = TestDir::new("scrubcsv", "quote_leading_whitespace");
+    let output = testdir
+        .cmd()
+        .arg("--quote-leading-whitespace")
+        .output_with_stdin("a,b\n  1,2\n3,4 \n5,6\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n\"  1\",2\n3,\"4 \"\n5,6\n");
+}
+
+#[test]
+fn read_errors_include_position() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let testdir = TestDir::new("scrubcsv", "read_errors_include_position");
+    let mut child = testdir
+        .cmd()
+        .args(["--stdin-timeout", "0.2"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run scrubcsv");
+    // Send a couple of lines, then stall so that the read fails.
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"a,b\n1,2\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output
+        .stderr_str()
+        .contains("cannot read record (at line 3, record 2, byte offset 8)"));
+    drop(stdin);
+}
+
+#[test]
+fn bad_rows_path() {
+    let testdir = TestDir::new("scrubcsv", "bad_rows_path");
+    let mut input = "a,b\n".to_owned();
+    for _ in 0..20 {
+        input.push_str("1,2\n");
+    }
+    input.push_str("1,2,3\n,4\n");
+    let output = testdir
+        .cmd()
+        .args(["--bad-rows-path", "bad.csv", "--drop-row-if-null", "a"])
+        .output_with_stdin(&input)
+        .expect_success();
+    assert!(output.stderr_str().contains("23 rows (2 bad)"));
+    testdir.expect_file_contents("bad.csv", "a,b\n1,2,3\n,4\n");
+}
+
+#[test]
+fn output_escape_backslash() {
+    let testdir = TestDir::new("scrubcsv", "output_escape_backslash");
+    let output = testdir
+        .cmd()
+        .args(["--output-escape", "backslash"])
+        .output_with_stdin("a,b\n\"say \"\"hi\"\"\",2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n\"say \\\"hi\\\"\",2\n");
+}
+
+#[test]
+fn bare_quote_policy() {
+    let testdir = TestDir::new("scrubcsv", "bare_quote_policy");
+    let mut input = "a,b\n".to_owned();
+    for _ in 0..10 {
+        input.push_str("1,2\n");
+    }
+    input.push_str("5 1/2\",6\n\"x\ny\",\"ok \"\"z\"\"\"\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--bare-quote-policy", "strip"])
+        .output_with_stdin(&input)
+        .expect_success();
+    assert!(output
+        .stdout_str()
+        .ends_with("5 1/2,6\n\"x\ny\",\"ok \"\"z\"\"\"\n"));
+    assert!(output.stderr_str().contains("1 bare quotes stripped"));
+
+    let output = testdir
+        .cmd()
+        .args(["--bare-quote-policy", "reject"])
+        .output_with_stdin(&input)
+        .expect_success();
+    assert!(output
+        .stdout_str()
+        .ends_with("1,2\n\"x\ny\",\"ok \"\"z\"\"\"\n"));
+    assert!(output.stderr_str().contains("13 rows (1 bad)"));
+}
+
+#[test]
+fn count_duplicates() {
+    let testdir = TestDir::new("scrubcsv", "count_duplicates");
+    let output = testdir
+        .cmd()
+        .args(["--count-duplicates", "--duplicate-key", "id"])
+        .output_with_stdin("id,name\n1,a\n2,b\n1,a\n1,c\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,name\n1,a\n2,b\n1,a\n1,c\n");
+    assert!(output.stderr_str().contains("1 duplicate rows"));
+    assert!(output.stderr_str().contains("\"1\": 3 rows"));
+}
+
+#[test]
+fn add_completeness_column() {
+    let testdir = TestDir::new("scrubcsv", "add_completeness_column");
+    let output = testdir
+        .cmd()
+        .args(["--add-completeness-column", "filled"])
+        .output_with_stdin("a,b,c\n1,,3\n,,\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b,c,filled\n1,,3,2\n,,,0\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--add-completeness-column", "filled"])
+        .arg("--completeness-as-fraction")
+        .output_with_stdin("a,b,c\n1,,3\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b,c,filled\n1,,3,0.667\n");
+}
+
+#[test]
+fn stage_times() {
+    let testdir = TestDir::new("scrubcsv", "stage_times");
+    let output = testdir
+        .cmd()
+        .arg("--trim-whitespace")
+        .arg("--stats")
+        .output_with_stdin("a,b\n 1,2\n")
+        .expect_success();
+    assert!(output.stderr_str().contains("Time spent: "));
+    assert!(output.stderr_str().contains("s reading, "));
+
+    // We only check the clock when asked to.
+    let output = testdir
+        .cmd()
+        .arg("--trim-whitespace")
+        .output_with_stdin("a,b\n 1,2\n")
+        .expect_success();
+    assert!(!output.stderr_str().contains("Time spent: "));
+}
+
+#[test]
+fn named_delimiters() {
+    let testdir = TestDir::new("scrubcsv", "named_delimiters");
+    let output = testdir
+        .cmd()
+        .args(["-d", "pipe"])
+        .output_with_stdin("a|b\n1|2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+
+    let output = testdir
+        .cmd()
+        .args(["-d", "seq:§"])
+        .output_with_stdin("a§b\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n");
+
+    let output = testdir
+        .cmd()
+        .args(["-d", "§"])
+        .output_with_stdin("a§b\n")
+        .expect_failure();
+    assert!(output.stderr_str().contains("2 bytes long"));
+}
+
+#[test]
+fn early_header_flush() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::Stdio;
+
+    let testdir = TestDir::new("scrubcsv", "early_header_flush");
+    let mut child = testdir
+        .cmd()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("could not run scrubcsv");
+    // Send only the header, and make sure we see it before sending any more.
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"a,b\n").unwrap();
+    stdin.flush().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "a,b\n");
+    drop(stdin);
+    child.wait().unwrap();
+}
+
+#[test]
+fn buffer_sizes() {
+    let testdir = TestDir::new("scrubcsv", "buffer_sizes");
+    for args in [["--read-buffer", "1"], ["--write-buffer", "auto"]] {
+        let output = testdir
+            .cmd()
+            .args(args)
+            .arg("--merge-delimiters")
+            .output_with_stdin("a,b\n1,,2\n")
+            .expect_success();
+        assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+    }
+
+    let output = testdir
+        .cmd()
+        .args(["--read-buffer", "lots"])
+        .expect_failure();
+    assert!(output.stderr_str().contains("cannot parse buffer size"));
+}
+
+#[test]
+fn io_threads() {
+    let testdir = TestDir::new("scrubcsv", "io_threads");
+    let input = (0..10_000)
+        .map(|i| format!("{},{}\n", i, i * 2))
+        .collect::<String>();
+    let output = testdir
+        .cmd()
+        .args([
+            "--io-threads",
+            "--read-buffer",
+            "1K",
+            "--write-buffer",
+            "1K",
+        ])
+        .output_with_stdin(format!("a,b\n{}", input))
+        .expect_success();
+    assert_eq!(output.stdout_str(), format!("a,b\n{}", input));
+}
+
+/// Compress `data` with gzip.
+fn gzip(data: &str) -> Vec<u8> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn gzip_input() {
+    let testdir = TestDir::new("scrubcsv", "gzip_input");
+    testdir.create_file("in.csv.gz", gzip("a,b\n1,2\n"));
+    let output = testdir.cmd().arg("in.csv.gz").expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--gzip", "--io-threads"])
+        .output_with_stdin(gzip("a,b\n3,4\n"))
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n3,4\n");
+}
+
+#[test]
+fn output_path() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let testdir = TestDir::new("scrubcsv", "output_path");
+    let output = testdir
+        .cmd()
+        .args(["-o", "out.csv"])
+        .output_with_stdin("a,b\n1,2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "");
+    testdir.expect_file_contents("out.csv", "a,b\n1,2\n");
+
+    testdir
+        .cmd()
+        .args(["--output", "out.csv.gz", "--io-threads"])
+        .output_with_stdin("a,b\n3,4\n")
+        .expect_success();
+    let compressed = std::fs::File::open(testdir.path("out.csv.gz")).unwrap();
+    let mut decompressed = String::new();
+    GzDecoder::new(compressed)
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, "a,b\n3,4\n");
+
+    // If we fail, we shouldn't leave anything behind.
+    testdir
+        .cmd()
+        .args(["-o", "failed.csv", "--apply-header-map", "missing.csv"])
+        .output_with_stdin("a,b\n3,4\n")
+        .expect_failure();
+    let leftovers = std::fs::read_dir(testdir.path("."))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("failed.csv"))
+        .collect::<Vec<_>>();
+    assert!(leftovers.is_empty(), "found {:?}", leftovers);
+
+    // If a check fails after we've read everything, our old output survives.
+    testdir.create_file("in.csv", "a,b\n1,2\n3\n4\n5\n6,7\n");
+    for extra in [&[][..], &["--io-threads"][..]] {
+        testdir.create_file("kept.csv", "old\n");
+        let output = testdir
+            .cmd()
+            .args(["-o", "kept.csv", "in.csv"])
+            .args(extra)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        testdir.expect_file_contents("kept.csv", "old\n");
+    }
+    let leftovers = std::fs::read_dir(testdir.path("."))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("kept.csv."))
+        .collect::<Vec<_>>();
+    assert!(leftovers.is_empty(), "found {:?}", leftovers);
+}
+
+#[test]
+fn select_columns() {
+    let testdir = TestDir::new("scrubcsv", "select_columns");
+    let output = testdir
+        .cmd()
+        .args(["--select", "c,a"])
+        .output_with_stdin("a,b,c\n1,2,3\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "c,a\n3,1\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--select", "a,nope"])
+        .output_with_stdin("a,b,c\n1,2,3\n")
+        .expect_failure();
+    assert!(output.stderr_str().contains("cannot select missing column"));
+}
+
+#[test]
+fn drop_columns() {
+    let testdir = TestDir::new("scrubcsv", "drop_columns");
+    let output = testdir
+        .cmd()
+        .args(["--clean-column-names", "--drop-column", "Junk"])
+        .args(["--drop-column-matching", "^Unnamed: "])
+        .output_with_stdin("Id,Junk,Unnamed: 12,Name\n1,x,,a\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,name\n1,a\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--drop-column", "id", "--drop-row-if-null", "id"])
+        .output_with_stdin("id,name\n1,a\n")
+        .expect_failure();
+    assert!(output.stderr_str().contains("used by --drop-row-if-null"));
+
+    // Our options shouldn't swallow the input path that follows them.
+    testdir.create_file("in.csv", "a,b,c\n1,2,3\n");
+    let output = testdir
+        .cmd()
+        .args([
+            "--drop-column-matching",
+            "^a$",
+            "--drop-column",
+            "b",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "c\n3\n");
+}
+
+#[test]
+fn drop_row_if_null_matching() {
+    let testdir = TestDir::new("scrubcsv", "drop_row_if_null_matching");
+    testdir.create_file("in.csv", "Customer ID,Email\n1,a\n,b\n2,\n3,c\n4,d\n5,e\n");
+    let output = testdir
+        .cmd()
+        .args(["--clean-column-names", "--drop-row-if-null", "Customer ID"])
+        .args([
+            "--drop-row-if-null",
+            "EMAIL",
+            "--max-bad-rows",
+            "50",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "customer_id,email\n1,a\n3,c\n4,d\n5,e\n",
+    );
+
+    let output = testdir
+        .cmd()
+        .args(["--drop-row-if-null", "zip", "in.csv"])
+        .expect_failure();
+    assert!(output
+        .stderr_str()
+        .contains("cannot find --drop-row-if-null column \"zip\""));
+}
+
+#[test]
+fn column_positions() {
+    let testdir = TestDir::new("scrubcsv", "column_positions");
+    testdir.create_file("in.csv", "id,,\n1,a,x\n2,,y\n3,c,z\n4,d,w\n5,e,v\n6,f,u\n");
+    let output = testdir
+        .cmd()
+        .args(["--drop-row-if-null", "@2", "--drop-column", "#3"])
+        .args(["--max-bad-rows", "50", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,\n1,a\n3,c\n4,d\n5,e\n6,f\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--select", "@3,@1", "in.csv"])
+        .expect_success();
+    assert!(output.stdout_str().starts_with(",id\nx,1\n"));
+    // Dropping a column that --drop-row-if-null needs is still an error, no
+    // matter how we refer to it.
+    testdir.create_file("named.csv", "Id,Email,Extra\n1,a,x\n");
+    for (drop, required) in [("@2", "Email"), ("Email", "#2"), ("Email", "EMAIL")] {
+        let output = testdir
+            .cmd()
+            .args(["--drop-column", drop, "--drop-row-if-null", required])
+            .arg("named.csv")
+            .expect_failure();
+        assert!(output.stderr_str().contains("used by --drop-row-if-null"));
+    }
+
+    // --output-columns resolves names the same way, and positions take the
+    // name of the column they refer to.
+    let output = testdir
+        .cmd()
+        .args(["--output-columns", "email,@1,state", "named.csv"])
+        .args(["--fail-on-extra-columns"])
+        .output()
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(6));
+    assert!(output.stderr_str().contains("[\"Extra\"]"));
+    let output = testdir
+        .cmd()
+        .args(["--output-columns", "email,@1,state", "named.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "email,Id,state\na,1,\n");
+}
+
+#[test]
+fn drop_null_rows() {
+    let testdir = TestDir::new("scrubcsv", "drop_null_rows");
+    let mut input = "a,b\n".to_owned();
+    for _ in 0..20 {
+        input.push_str("1,2\n");
+    }
+    input.push_str("1,\n,\n");
+    testdir.create_file("in.csv", &input);
+    let output = testdir
+        .cmd()
+        .args(["--drop-row-if-all-null", "--add-constant-column", "c=x"])
+        .args([
+            "--bad-rows-path",
+            "bad.csv",
+            "--annotate-bad-rows",
+            "in.csv",
+        ])
+        .expect_success();
+    assert!(output.stdout_str().ends_with("1,2,x\n1,,x\n"));
+    testdir.expect_file_contents("bad.csv", "_line,_reason,a,b\n23,blank_row,,\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--drop-row-if-any-null", "--drop-row-if-all-null"])
+        .args([
+            "--bad-rows-path",
+            "bad.csv",
+            "--annotate-bad-rows",
+            "in.csv",
+        ])
+        .expect_success();
+    assert!(output.stdout_str().ends_with("1,2\n1,2\n"));
+    assert!(output.stderr_str().contains("23 rows (2 bad)"));
+    testdir.expect_file_contents(
+        "bad.csv",
+        "_line,_reason,a,b\n22,any_column_null,1,\n23,blank_row,,\n",
+    );
+}
+
+#[test]
+fn replace() {
+    let testdir = TestDir::new("scrubcsv", "replace");
+    let output = testdir
+        .cmd()
+        .args([
+            "--replace",
+            "id=/^ID-0*//",
+            "--replace",
+            "name=|(\\w+) (\\w+)|$2, $1|",
+        ])
+        .args(["--add-constant-column", "src=a-b", "--replace", "src=/-/_/"])
+        .output_with_stdin("id,name\nID-007,Jane Doe\n12,Prince\n")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,name,src\n7,\"Doe, Jane\",a_b\n12,Prince,a_b\n"
+    );
+    assert!(output.stderr_str().contains("--replace id=/^ID-0*//"));
+
+    testdir.create_file("in.csv", "id\n1\n");
+    testdir
+        .cmd()
+        .arg("in.csv")
+        .args(["--replace", "nope=/a/b/"])
+        .expect_failure();
+}
+
+#[test]
+fn split_column() {
+    let testdir = TestDir::new("scrubcsv", "split_column");
+    testdir.create_file(
+        "in.csv",
+        "id,name,loc\n1,Jane Doe,\"Burlington, VT 05401\"\n2,Prince,Paisley Park\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--split-column", r#"name=first,last on=" ""#])
+        .args([
+            "--split-column",
+            r#"loc=city,state,zip re="^(.*), (\w\w) (\d+)$""#,
+        ])
+        .args([
+            "--drop-row-if-null",
+            "last",
+            "--max-bad-rows",
+            "100",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,first,last,city,state,zip\n1,Jane,Doe,Burlington,VT,05401\n",
+    );
+}
+
+#[test]
+fn merge_columns() {
+    let testdir = TestDir::new("scrubcsv", "merge_columns");
+    testdir.create_file("in.csv", "id,street,city,zip\n1,1 Main St,Springfield,\n");
+    let output = testdir
+        .cmd()
+        .args([
+            "--merge-columns",
+            r#"address=street,city,zip sep=", " drop"#,
+        ])
+        .args(["--merge-columns", "label=id,address", "in.csv"])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,address,label\n1,\"1 Main St, Springfield\",\"1 1 Main St, Springfield\"\n",
+    );
+}
+
+#[test]
+fn explode() {
+    let testdir = TestDir::new("scrubcsv", "explode");
+    let output = testdir
+        .cmd()
+        .args([
+            "--explode",
+            r#"tag on=";""#,
+            "--drop-row-if-match",
+            "tag=^skip$",
+        ])
+        .args(["--add-row-number-column", "n"])
+        .output_with_stdin("id,tag\n1,a;skip;b\n2,\n3,c\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,tag,n\n1,a,1\n1,b,1\n2,,2\n3,c,3\n");
+    assert!(output.stderr_str().contains("6 rows (0 bad)"));
+}
+
+#[test]
+fn melt() {
+    let testdir = TestDir::new("scrubcsv", "melt");
+    let output = testdir
+        .cmd()
+        .args([
+            "--melt",
+            "--id-columns",
+            "id,date",
+            "--variable-name",
+            "metric",
+        ])
+        .output_with_stdin("id,temp,date,rain\n1,20,2024-01-01,0.5\n")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,date,metric,value\n1,2024-01-01,temp,20\n1,2024-01-01,rain,0.5\n",
+    );
+}
+
+#[test]
+fn lookup() {
+    let testdir = TestDir::new("scrubcsv", "lookup");
+    testdir.create_file("zips.csv", "postal,city,state\n05401,Burlington,VT\n");
+    testdir.create_file("in.csv", "id,zip\n1,05401\n2,99999\n");
+    let output = testdir
+        .cmd()
+        .args([
+            "--lookup",
+            "zips.csv on=zip:postal add=state,city",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,zip,state,city\n1,05401,VT,Burlington\n2,99999,,\n"
+    );
+
+    let output = testdir
+        .cmd()
+        .args(["--lookup", "zips.csv on=zip:postal miss=reject"])
+        .args([
+            "--max-bad-rows",
+            "100",
+            "--bad-rows-path",
+            "bad.csv",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,zip,city,state\n1,05401,Burlington,VT\n"
+    );
+    testdir.expect_file_contents("bad.csv", "id,zip\n2,99999\n");
+}
+
+#[test]
+fn sort_by() {
+    let testdir = TestDir::new("scrubcsv", "sort_by");
+    testdir.create_file("in.csv", "state,amount\nNY,10\nCA,9\nNY,100\nCA,20\nNY,9\n");
+    // With one byte of sort memory, every row gets its own run, so this
+    // tests merging, too.
+    for memory in ["1", "1M"] {
+        let output = testdir
+            .cmd()
+            .args(["--sort-by", "state,amount:num:desc"])
+            .args(["--sort-memory", memory, "in.csv"])
+            .expect_success();
+        assert_eq!(
+            output.stdout_str(),
+            "state,amount\nCA,20\nCA,9\nNY,100\nNY,10\nNY,9\n"
+        );
+    }
+    testdir
+        .cmd()
+        .args(["--sort-by", "nope", "in.csv"])
+        .expect_failure();
+
+    // Values like "nan" can't be ordered as numbers, so they sort as text.
+    for memory in ["1", "1M"] {
+        let output = testdir
+            .cmd()
+            .args(["-q", "--sort-by", "n:num", "--sort-memory", memory])
+            .output_with_stdin("n\n1\nnan\ninf\n-inf\n2\n")
+            .expect_success();
+        assert_eq!(output.stdout_str(), "n\n1\n2\n-inf\ninf\nnan\n");
+    }
+}
+
+#[test]
+fn assert_sorted_by() {
+    let testdir = TestDir::new("scrubcsv", "assert_sorted_by");
+    testdir.create_file(
+        "in.csv",
+        "id,day\n1,2024-01-01\n2,2024-01-02\n3,2024-01-01\n4,2024-01-03\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--assert-sorted-by", "day", "in.csv"])
+        .expect_failure();
+    assert!(output.stderr_str().contains("row 4 is out of order"));
+    let output = testdir
+        .cmd()
+        .args(["--assert-sorted-by", "day", "--on-unsorted", "reject"])
+        .args(["--max-bad-rows", "100", "in.csv"])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,day\n1,2024-01-01\n2,2024-01-02\n4,2024-01-03\n"
+    );
+}
+
+#[test]
+fn merge_sorted_by() {
+    let testdir = TestDir::new("scrubcsv", "merge_sorted_by");
+    testdir.create_file("a.csv", "id,day\n1,2024-01-01\n2,2024-01-03\n");
+    testdir.create_file(
+        "b.csv",
+        "id,day\n3,2024-01-02\n4,2024-01-03\n5,2024-01-04\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--merge-sorted-by", "day", "a.csv", "b.csv"])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,day\n1,2024-01-01\n3,2024-01-02\n2,2024-01-03\n4,2024-01-03\n5,2024-01-04\n"
+    );
+    assert!(output.stderr_str().contains("6 rows (0 bad)"));
+}
+
+#[test]
+fn head_tail_and_skip_rows() {
+    let testdir = TestDir::new("scrubcsv", "head_tail_and_skip_rows");
+    testdir.create_file("in.csv", "n,note\n1,a\n2,\"b\nb\"\n3,c\n4,d\n5,e\n");
+    let output = testdir
+        .cmd()
+        .args(["--skip-rows", "1", "--head", "3", "--tail", "2", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "n,note\n3,c\n4,d\n");
+    assert!(output.stderr_str().contains("1 rows filtered out"));
+    let output = testdir
+        .cmd()
+        .args(["--head", "2", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "n,note\n1,a\n2,\"b\nb\"\n");
+}
+
+#[test]
+fn skip_footer() {
+    let testdir = TestDir::new("scrubcsv", "skip_footer");
+    testdir.create_file(
+        "in.csv",
+        "item,amount\nA,1\nB,2\nTotals,3,\n\n\"Exported 2024-01-01\n",
+    );
+    let output = testdir
+        .cmd()
+        .args([
+            "--skip-footer",
+            "1",
+            "--drop-trailer-matching",
+            "^(Totals,.*)?$",
+        ])
+        .arg("in.csv")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "item,amount\nA,1\nB,2\n");
+    assert!(output.stderr_str().contains("3 rows (0 bad)"));
+}
+
+#[test]
+fn comment_char() {
+    let testdir = TestDir::new("scrubcsv", "comment_char");
+    testdir.create_file(
+        "in.csv",
+        "# instrument 7\na,b\n1,\"x\n#y\"\n# calibrated\n2,3\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--comment-char", "#", "--comments-path", "comments.txt"])
+        .arg("in.csv")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,\"x\n#y\"\n2,3\n");
+    assert!(output.stderr_str().contains("3 rows (0 bad)"));
+    testdir.expect_file_contents("comments.txt", "# instrument 7\n# calibrated\n");
+}
+
+#[test]
+fn excel_sep_line_input() {
+    let testdir = TestDir::new("scrubcsv", "excel_sep_line_input");
+    testdir.create_file("in.csv", "sep=;\r\na;b\r\n1,5;2\r\n");
+    let output = testdir.cmd().arg("in.csv").expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n\"1,5\",2\n");
+    let output = testdir.cmd().args(["-d", "|", "in.csv"]).expect_success();
+    assert_eq!(output.stdout_str(), "a;b\n\"1,5;2\"\n");
+}
+
+#[test]
+fn long_delimiters() {
+    let testdir = TestDir::new("scrubcsv", "long_delimiters");
+    testdir.create_file("in.txt", "a~|~b\n1|2~|~\"x~|~y\"\n");
+    let output = testdir
+        .cmd()
+        .args(["-d", "seq:~|~", "in.txt"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1|2,x~|~y\n");
+
+    // Typos shouldn't be mistaken for long delimiters.
+    let output = testdir.cmd().args(["-d", "TAB", "in.txt"]).expect_failure();
+    assert!(output
+        .stderr_str()
+        .contains("cannot parse character specifier"));
+}
+
+#[test]
+fn ascii_delimited_input() {
+    let testdir = TestDir::new("scrubcsv", "ascii_delimited_input");
+    testdir.create_file("in.txt", "a\x1Fb\x1E1\x1Fx\ny\x1E");
+    let output = testdir
+        .cmd()
+        .args(["-d", "0x1F", "--record-terminator", "0x1E", "in.txt"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,\"x\ny\"\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--record-terminator", r"\0"])
+        .output_with_stdin("a,b\x001,2\x00")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+}
+
+#[test]
+fn out_delimiter_and_crlf() {
+    let testdir = TestDir::new("scrubcsv", "out_delimiter_and_crlf");
+    let output = testdir
+        .cmd()
+        .args(["--out-delimiter", "tab", "--out-crlf"])
+        .output_with_stdin("a,b\n1,\"x\ty\"\n\"2,3\",4\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a\tb\r\n1\t\"x\ty\"\r\n2,3\t4\r\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--out-delimiter", ";", "--drop-empty-columns"])
+        .output_with_stdin("a,b,c\n1,,x;y\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a;c\n1;\"x;y\"\n");
+}
+
+#[test]
+fn backslash_escaped_quotes() {
+    let testdir = TestDir::new("scrubcsv", "backslash_escaped_quotes");
+    let input = "a,b\n\"Broken \\\" quotes\",\"x\"\"y\"\n";
+    let output = testdir
+        .cmd()
+        .args(["--escape", "\\"])
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "a,b\n\"Broken \"\" quotes\",\"x\"\"y\"\n"
+    );
+
+    let output = testdir
+        .cmd()
+        .args(["--escape", "\\", "--double-quote", "false"])
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "a,b\n\"Broken \"\" quotes\",\"x\"\"y\"\"\"\n"
+    );
+}
+
+#[test]
+fn list_options_before_inputs() {
+    let testdir = TestDir::new("scrubcsv", "list_options_before_inputs");
+    testdir.create_file("in.csv", "a,b\n1,2\n");
+    testdir.create_file("keys.csv", "a\n9\n");
+    // Each of these can be passed more than once, but should only ever take
+    // one value at a time.
+    let options: &[&[&str]] = &[
+        &["--rename", "a=a"],
+        &["--drop-column", "b"],
+        &["--drop-column-matching", "^b$"],
+        &["--select", "a"],
+        &["--output-columns", "a"],
+        &["--drop-row-if-null", "a"],
+        &["--sort-by", "a"],
+        &["--assert-sorted-by", "a"],
+        &["--drop-row-if-match", "a=x"],
+        &["--keep-row-if-match", "a=1"],
+        &["--drop-if-in", "keys.csv on=a"],
+        &["--types", "a:int"],
+        &["--add-column", "c = a"],
+        &["--add-constant-column", "c=x"],
+        &["--replace", "a=/x/y/"],
+        &["--normalize-numbers", "a"],
+        &["--require-columns", "a"],
+        &["--count-duplicates", "--duplicate-key", "a"],
+        &["--dedup-by", "a"],
+    ];
+    for option in options {
+        let output = testdir
+            .cmd()
+            .args(*option)
+            .arg("in.csv")
+            .output_with_stdin("")
+            .expect_success();
+        assert!(
+            output.stdout_str().starts_with('a'),
+            "{:?} swallowed our input path",
+            option,
+        );
+    }
+}
+
+#[test]
+fn per_file_input_settings() {
+    let testdir = TestDir::new("scrubcsv", "per_file_input_settings");
+    testdir.create_file("a.csv", "id,name\n1,a\n");
+    testdir.create_file("b.txt", "id|name\n2|'b|c'\n");
+    std::fs::write(testdir.path("c.csv"), b"id;name\n3;\xE9\n").unwrap();
+    let output = testdir
+        .cmd()
+        .args([
+            "a.csv",
+            "b.txt;delimiter=pipe;quote='",
+            "c.csv;delimiter=semicolon;encoding=latin1",
+        ])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,name\n1,a\n2,b|c\n3,é\n");
+
+    let output = testdir.cmd().arg("a.csv;delim=|").expect_failure();
+    assert!(output.stderr_str().contains("unknown input setting"));
+}
+
+#[test]
+fn memory_limit() {
+    let testdir = TestDir::new("scrubcsv", "memory_limit");
+    let mut input = "id,name\n".to_owned();
+    for i in 0..200 {
+        input.push_str(&format!("{},name {}\n", i % 30, i));
+    }
+    testdir.create_file("in.csv", &input);
+    let tmp = testdir.path("tmp");
+    std::fs::create_dir(&tmp).unwrap();
+
+    let run = |limit: &[&str], args: &[&str]| {
+        let output = testdir
+            .cmd()
+            .env("TMPDIR", &tmp)
+            .args(limit)
+            .args(args)
+            .args(["--bad-rows-path", "bad.csv", "--annotate-bad-rows"])
+            .args(["--max-bad-rows", "100", "in.csv"])
+            .expect_success();
+        let bad_rows = std::fs::read_to_string(testdir.path("bad.csv")).unwrap();
+        (output.stdout_str().to_owned(), bad_rows)
+    };
+    for args in [
+        &["--dedup-by", "id", "--keep", "last"][..],
+        &["--tail", "50"][..],
+    ] {
+        // Spilling every held row shouldn't change what we write.
+        let expected = run(&[], args);
+        assert_eq!(run(&["--memory-limit", "1K"], args), expected);
+        assert_eq!(run(&["--memory-limit", "1"], args), expected);
+        assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 0);
+    }
+}
+
+#[test]
+fn library_matches_binary() {
+    let testdir = TestDir::new("scrubcsv", "library_matches_binary");
+    let input = "\
+Customer ID,Name,Notes
+1, Alice ,NULL
+2,Bob
+,Carol,\"two
+lines\"
+3,NULL,ok
+4,Dave,\"bad\"quote\"
+";
+    testdir.create_file("in.csv", input);
+    let args = [
+        "--null",
+        "NULL",
+        "--trim-whitespace",
+        "--replace-newlines",
+        "--clean-column-names",
+        "--drop-row-if-null",
+        "customer id",
+        "--max-bad-rows",
+        "80",
+    ];
+    let output = testdir.cmd().args(args).arg("in.csv").expect_success();
+    let summary = output.stderr_str();
+    let bad_rows = summary
+        [summary.find('(').unwrap() + 1..summary.find(" bad)").unwrap()]
+        .parse::<u64>()
+        .unwrap();
+
+    // Build our `Scrubber` with its builder methods.
+    let mut wtr = vec![];
+    let stats = scrubcsv::Scrubber::new()
+        .null_regex("NULL")
+        .unwrap()
+        .trim_whitespace(true)
+        .replace_newlines(true)
+        .clean_column_names(true)
+        .drop_row_if_null("customer id")
+        .max_bad_rows_percent(Some(80.0))
+        .scrub(input.as_bytes(), &mut wtr)
+        .unwrap();
+    assert_eq!(String::from_utf8(wtr).unwrap(), output.stdout_str());
+    assert_eq!(stats.bad_rows, bad_rows);
+    assert_eq!(stats.rows, 5);
+
+    // And from the same command line.
+    let opt = <scrubcsv::Opt as structopt::StructOpt>::from_iter_safe(
+        std::iter::once("scrubcsv").chain(args),
+    )
+    .unwrap();
+    let mut wtr = vec![];
+    let from_opt = scrubcsv::Scrubber::from_opt(opt)
+        .scrub(input.as_bytes(), &mut wtr)
+        .unwrap();
+    assert_eq!(String::from_utf8(wtr).unwrap(), output.stdout_str());
+    assert_eq!(from_opt, stats);
+}
+
+#[test]
+fn key_list_filters() {
+    let testdir = TestDir::new("scrubcsv", "key_list_filters");
+    testdir.create_file("suppress.csv", "address\nB@example.com\n");
+    testdir.create_file("allow.csv", "id\n1\n2\n");
+    testdir.create_file(
+        "in.csv",
+        "id,email\n1,a@example.com\n2,b@example.com\n3,c@example.com\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--drop-if-in", "suppress.csv on=email:address ignore-case"])
+        .args(["--keep-if-in", "allow.csv on=id", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,email\n1,a@example.com\n");
+    assert!(output.stderr_str().contains("2 rows filtered out"));
+}
+
+#[test]
+fn normalize_unicode() {
+    let testdir = TestDir::new("scrubcsv", "normalize_unicode");
+    testdir.create_file(
+        "in.csv",
+        "name,note\nJose\u{301},\u{201c}hi\u{201d} \u{2014} \u{fb01}ne\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--normalize-unicode", "nfc", "in.csv"])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "name,note\nJos\u{e9},\u{201c}hi\u{201d} \u{2014} \u{fb01}ne\n",
+    );
+
+    let output = testdir
+        .cmd()
+        .args([
+            "--normalize-unicode",
+            "nfkc",
+            "--ascii-punctuation",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "name,note\nJos\u{e9},\"\"\"hi\"\" - fine\"\n"
+    );
+}
+
+#[test]
+fn strip_control_chars() {
+    let testdir = TestDir::new("scrubcsv", "strip_control_chars");
+    testdir.create_file("in.csv", "a,b\n\"x\u{0}y\",\"1\t2\u{b}3\u{c}\"\n");
+    let output = testdir
+        .cmd()
+        .args(["--strip-control-chars", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\nxy,1\t23\n");
+
+    let output = testdir
+        .cmd()
+        .args([
+            "--strip-control-chars",
+            "--control-chars-to-spaces",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\nx y,1\t2 3 \n");
+}
+
+#[test]
+fn max_cell_bytes() {
+    let testdir = TestDir::new("scrubcsv", "max_cell_bytes");
+    testdir.create_file("in.csv", "a,b\nabcd,x\nab,\"\u{e9}\u{e9}!\"\n");
+    let output = testdir
+        .cmd()
+        .args(["--max-cell-bytes", "3", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\nabc,x\nab,\u{e9}\n");
+
+    let output = testdir
+        .cmd()
+        .args([
+            "--max-cell-bytes",
+            "3",
+            "--long-cell-policy",
+            "null",
+            "in.csv",
+        ])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n,x\nab,\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--max-cell-bytes", "4", "--long-cell-policy", "drop-row"])
+        .args(["--max-bad-rows", "100", "--bad-rows-path", "bad.csv"])
+        .args(["--annotate-bad-rows", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\nabcd,x\n");
+    testdir.expect_file_contents(
+        "bad.csv",
+        "_line,_reason,a,b\n3,cell_too_long,ab,\u{e9}\u{e9}!\n",
+    );
+}
+
+#[test]
+fn drop_empty_columns() {
+    let testdir = TestDir::new("scrubcsv", "drop_empty_columns");
+    let output = testdir
+        .cmd()
+        .args(["--drop-empty-columns", "--null", "NULL"])
+        .output_with_stdin("a,b,c,d\n1,,\" x\",NULL\n\"multi\nline\",,,\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,c\n1, x\n\"multi\nline\",\n");
+
+    testdir
+        .cmd()
+        .args(["--drop-empty-columns", "-o", "out.csv"])
+        .output_with_stdin("a,b\n")
+        .expect_success();
+    testdir.expect_file_contents("out.csv", "a,b\n");
+}
+
+#[test]
+fn no_headers() {
+    let testdir = TestDir::new("scrubcsv", "no_headers");
+    let output = testdir
+        .cmd()
+        .args(["--no-headers", "--drop-row-if-null", "c2"])
+        .output_with_stdin("1,a\n2,\n3,c\n4,d\n5,e\n6,f\n7,g\n8,h\n9,i\n10,j\n11,k\n")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "1,a\n3,c\n4,d\n5,e\n6,f\n7,g\n8,h\n9,i\n10,j\n11,k\n",
+    );
+
+    let output = testdir
+        .cmd()
+        .args(["--no-headers", "--add-header"])
+        .output_with_stdin("1,a\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "c1,c2\n1,a\n");
+}
+
+#[test]
+fn skip_leading_lines() {
+    let testdir = TestDir::new("scrubcsv", "skip_leading_lines");
+    let output = testdir
+        .cmd()
+        .args(["--skip-lines", "1", "--skip-lines-matching", "^(sep=.*)?$"])
+        .args(["--delimiter", "auto"])
+        .output_with_stdin("Report generated 2024-01-03\n\nsep=;\na;b\n1;2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+}
+
+#[test]
+fn json_report() {
+    let testdir = TestDir::new("scrubcsv", "json_report");
+    testdir
+        .cmd()
+        .args(["--report-path", "report.json", "--drop-row-if-null", "b"])
+        .output_with_stdin(
+            "a,b\n1,2\n3,4\n5,6\n7,8\n9,10\n11,12\n13,14\n15,16\n17,\n19,20,21\n",
+        )
+        .expect_failure();
+    let report: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(testdir.path("report.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(report["rows"], 10);
+    assert_eq!(report["good_rows"], 8);
+    assert_eq!(report["bad_rows"], 2);
+    let rules = report["rules"].as_array().unwrap();
+    assert!(rules
+        .iter()
+        .any(|r| r["rule"] == "wrong number of columns" && r["count"] == 1));
+    assert!(rules
+        .iter()
+        .any(|r| r["rule"] == "--drop-row-if-null" && r["count"] == 1));
+}
+
+#[test]
+fn bad_row_thresholds() {
+    let testdir = TestDir::new("scrubcsv", "bad_row_thresholds");
+    let input = "a,b\n1,2\n3\n4,5\n6\n";
+    testdir
+        .cmd()
+        .args(["--max-bad-rows", "50"])
+        .output_with_stdin(input)
+        .expect_success();
+    testdir
+        .cmd()
+        .args(["--max-bad-rows-count", "1"])
+        .output_with_stdin(input)
+        .expect_failure();
+
+    let output = testdir
+        .cmd()
+        .args(["--fail-fast", "--max-bad-rows", "100"])
+        .output_with_stdin(input)
+        .expect_failure();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stderr_str().contains("Bad row at line 3, stopping"));
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+}
+
+#[test]
+fn annotate_bad_rows() {
+    let testdir = TestDir::new("scrubcsv", "annotate_bad_rows");
+    let mut input = "a,b\n".to_owned();
+    for _ in 0..20 {
+        input.push_str("1,2\n");
+    }
+    input.push_str("1,2,3\n,4\n");
+    testdir
+        .cmd()
+        .args(["--bad-rows-path", "bad.csv", "--annotate-bad-rows"])
+        .args(["--drop-row-if-null", "a"])
+        .output_with_stdin(&input)
+        .expect_success();
+    testdir.expect_file_contents(
+        "bad.csv",
+        "_line,_reason,a,b\n22,wrong_column_count,1,2,3\n23,required_column_null,,4\n",
+    );
+}
+
+#[test]
+fn repair_long_rows() {
+    let testdir = TestDir::new("scrubcsv", "repair_long_rows");
+    let input = "a,b\n1,2\n3,4,5,6\n";
+    let output = testdir
+        .cmd()
+        .arg("--truncate-long-rows")
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n3,4\n");
+    let output = testdir
+        .cmd()
+        .arg("--merge-overflow-into-last")
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n3,\"4,5,6\"\n");
+}
+
+#[test]
+fn input_encoding() {
+    let testdir = TestDir::new("scrubcsv", "input_encoding");
+    let utf16 = "a,b\n1,é\n"
+        .encode_utf16()
+        .flat_map(|c| c.to_le_bytes())
+        .collect::<Vec<u8>>();
+    std::fs::write(testdir.path("in.csv"), utf16).unwrap();
+    let output = testdir
+        .cmd()
+        .args(["--input-encoding", "utf-16le", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,é\n");
+}
+
+#[test]
+fn byte_order_marks() {
+    let testdir = TestDir::new("scrubcsv", "byte_order_marks");
+    let output = testdir
+        .cmd()
+        .args(["--drop-row-if-null", "a"])
+        .output_with_stdin("\u{feff}a,b\n1,2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+
+    let output = testdir
+        .cmd()
+        .arg("--write-bom")
+        .output_with_stdin("\u{feff}a,b\n1,2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "\u{feff}a,b\n1,2\n");
+}
+
+#[test]
+fn ensure_utf8() {
+    let testdir = TestDir::new("scrubcsv", "ensure_utf8");
+    let mut input = b"a,b\n".to_vec();
+    for _ in 0..10 {
+        input.extend_from_slice(b"1,2\n");
+    }
+    input.extend_from_slice(b"caf\xe9,3\n");
+    std::fs::write(testdir.path("in.csv"), &input).unwrap();
+    let output = testdir
+        .cmd()
+        .args(["--ensure-utf8", "in.csv"])
+        .expect_success();
+    assert!(output.stdout_str().ends_with("1,2\ncaf\u{fffd},3\n"));
+    assert!(output
+        .stderr_str()
+        .contains("  --ensure-utf8: 1 cells changed\n"));
+
+    let output = testdir
+        .cmd()
+        .args(["--ensure-utf8", "--invalid-utf8", "drop", "in.csv"])
+        .args(["--bad-rows-path", "bad.csv"])
+        .expect_success();
+    assert!(output.stdout_str().ends_with("1,2\n1,2\n"));
+    testdir.expect_file_contents("bad.csv", b"a,b\ncaf\xe9,3\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--ensure-utf8", "--invalid-utf8", "fail", "in.csv"])
+        .expect_failure();
+    assert!(output
+        .stderr_str()
+        .contains("invalid UTF-8 in row at line 12"));
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn detect_compressed_input() {
+    let testdir = TestDir::new("scrubcsv", "detect_compressed_input");
+    let compressed = zstd::encode_all(&b"a,b\n1,2\n"[..], 0).unwrap();
+    let output = testdir
+        .cmd()
+        .output_with_stdin(&compressed)
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+    testdir.create_file("in.csv.zst", &compressed);
+    let output = testdir.cmd().arg("in.csv.zst").expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+}
+
+#[test]
+fn parallel_jobs() {
+    let testdir = TestDir::new("scrubcsv", "parallel_jobs");
+    let mut input = "a,b\n".to_owned();
+    let mut expected = input.clone();
+    for i in 0..10_000 {
+        if i % 1000 == 0 {
+            input.push_str(",missing\n");
+        } else {
+            input.push_str(&format!(" {} ,NULL\n", i));
+            expected.push_str(&format!("{},\n", i));
+        }
+    }
+    let output = testdir
+        .cmd()
+        .args(["--jobs", "4", "--trim-whitespace", "--null", "NULL"])
+        .args(["--drop-row-if-null", "a"])
+        .output_with_stdin(&input)
+        .expect_success();
+    assert_eq!(output.stdout_str(), expected);
+    let stderr = output.stderr_str();
+    assert!(stderr.contains("10001 rows (10 bad)"), "{}", stderr);
+    assert!(stderr.contains("  --trim-whitespace: 9990 cells changed\n"));
+    assert!(stderr.contains("  --null: 9990 cells changed\n"));
+}
+
+#[test]
+fn preserve_formatting() {
+    let testdir = TestDir::new("scrubcsv", "preserve_formatting");
+    let input = "a,b\r\n\"1\",\"x\ny\"\r\n3\n4,\"a \"\"q\"\"\"\n";
+    let output = testdir
+        .cmd()
+        .args(["--preserve-formatting", "--max-bad-rows", "50"])
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "a,b\n\"1\",\"x\ny\"\n4,\"a \"\"q\"\"\"\n"
+    );
+
+    // We fall back to writing rows normally when we need to clean them.
+    let output = testdir
+        .cmd()
+        .args(["--preserve-formatting", "--trim-whitespace"])
+        .output_with_stdin("a,b\n\"1\", 2\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n1,2\n");
+}
+
+#[test]
+fn dedup() {
+    let testdir = TestDir::new("scrubcsv", "dedup");
+    let output = testdir
+        .cmd()
+        .args(["--dedup", "--dedup-report", "--trim-whitespace"])
+        .output_with_stdin("a,b\n2,x\n1,y\n 2 ,x\n1,y\n3,x\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\n2,x\n1,y\n3,x\n");
+    assert!(output.stderr_str().contains("2 duplicate rows removed"));
+
+    // With a memory limit, we fall back to a Bloom filter, and warn about it.
+    let mut input = "a\n".to_owned();
+    for i in 0..1000 {
+        input.push_str(&format!("{}\n{}\n", i, i));
+    }
+    let output = testdir
+        .cmd()
+        .args(["--dedup", "--dedup-memory-limit", "1K"])
+        .output_with_stdin(&input)
+        .expect_success();
+    assert!(output.stdout_str().lines().count() <= 1001);
+    assert!(output.stderr_str().contains("--dedup-memory-limit reached"));
+}
+
+#[test]
+fn dedup_by_key() {
+    let testdir = TestDir::new("scrubcsv", "dedup_by_key");
+    let input = "id,kind,name\n1,a,x\n2,a,y\n1,a,z\n1,b,w\n";
+    let output = testdir
+        .cmd()
+        .args(["--dedup-by", "id,kind", "--max-bad-rows", "50"])
+        .args(["--bad-rows-path", "bad.csv"])
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,kind,name\n1,a,x\n2,a,y\n1,b,w\n");
+    testdir.expect_file_contents("bad.csv", "id,kind,name\n1,a,z\n");
+
+    let output = testdir
+        .cmd()
+        .args([
+            "--dedup-by",
+            "id,kind",
+            "--keep",
+            "last",
+            "--max-bad-rows",
+            "50",
+        ])
+        .args(["--bad-rows-path", "bad.csv"])
+        .output_with_stdin(input)
+        .expect_success();
+    assert_eq!(output.stdout_str(), "id,kind,name\n2,a,y\n1,a,z\n1,b,w\n");
+    testdir.expect_file_contents("bad.csv", "id,kind,name\n1,a,x\n");
+    assert!(output
+        .stderr_str()
+        .contains("  --dedup-by: 1 rows rejected\n"));
+}
+
+#[test]
+fn filter_rows_by_regex() {
+    let testdir = TestDir::new("scrubcsv", "filter_rows_by_regex");
+    let output = testdir
+        .cmd()
+        .args(["--drop-row-if-match", "email=@example\\.com$"])
+        .args(["--keep-row-if-match", "country=^US$", "--trim-whitespace"])
+        .args(["--report", "json"])
+        .output_with_stdin(
+            "email,country\na@b.org, US\ntest@example.com,US\nc@d.org,CA\n",
+        )
+        .expect_success();
+    assert_eq!(output.stdout_str(), "email,country\na@b.org,US\n");
+    let stderr = output.stderr_str();
+    assert!(stderr.contains("2 rows filtered out"), "{}", stderr);
+    assert!(stderr.contains("  --drop-row-if-match: 1 rows filtered\n"));
+    assert!(stderr.contains("\"filtered_rows\": 2"));
+    assert!(stderr.contains("\"bad_rows\": 0"));
+}
+
+#[test]
+fn where_expression() {
+    let testdir = TestDir::new("scrubcsv", "where_expression");
+    let output = testdir
+        .cmd()
+        .args(["--where", "age >= 18 && state != \"\""])
+        .output_with_stdin("age,state\n18,NY\n17,NY\n30,\nn/a,MA\n40,MA\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "age,state\n18,NY\n40,MA\n");
+    assert!(output.stderr_str().contains("  --where: 3 rows filtered\n"));
+
+    let output = testdir
+        .cmd()
+        .args(["--where", "nope > 1"])
+        .output_with_stdin("age,state\n")
+        .expect_failure();
+    assert!(output.stderr_str().contains("cannot find --where column"));
+}
+
+#[test]
+fn add_computed_columns() {
+    let testdir = TestDir::new("scrubcsv", "add_computed_columns");
+    let output = testdir
+        .cmd()
+        .args(["--add-column", "full_name = concat(first, \" \", last)"])
+        .args(["--add-column", "code = upper(substr(last, 1, 3))"])
+        .output_with_stdin("first,last\nAda,Lovelace\nAlan,Turing\n")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "first,last,full_name,code\nAda,Lovelace,Ada Lovelace,LOV\nAlan,Turing,Alan Turing,TUR\n",
+    );
+}
+
+#[test]
+fn add_metadata_columns() {
+    let testdir = TestDir::new("scrubcsv", "add_metadata_columns");
+    testdir.create_file("in.csv", "a\nx\ny\n");
+    let output = testdir
+        .cmd()
+        .args(["--add-constant-column", "batch=b1"])
+        .args(["--add-row-number-column", "row"])
+        .args(["--add-source-file-column", "file"])
+        .arg("in.csv")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "a,batch,row,file\nx,b1,1,in.csv\ny,b1,2,in.csv\n",
+    );
+}
+
+#[test]
+fn type_coercion() {
+    let testdir = TestDir::new("scrubcsv", "type_coercion");
+    testdir.create_file(
+        "in.csv",
+        "id,amount,created_at,ok\n007,.5,2024/02/29,Y\n2,x,2024-01-01,n\n3,,20230228,\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--types", "id:int,amount:decimal,created_at:date,ok:bool"])
+        .args(["--bad-rows-path", "bad.csv", "--annotate-bad-rows"])
+        .args(["--max-bad-rows", "50"])
+        .arg("in.csv")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,amount,created_at,ok\n7,0.5,2024-02-29,true\n3,,2023-02-28,\n",
+    );
+    testdir.expect_file_contents(
+        "bad.csv",
+        "_line,_reason,id,amount,created_at,ok\n3,type_mismatch,2,x,2024-01-01,n\n",
+    );
+
+    let output = testdir
+        .cmd()
+        .args(["--types", "id:blob"])
+        .arg("in.csv")
+        .expect_failure();
+    assert!(output.stderr_str().contains("unknown type"));
+}
+
+#[test]
+fn normalize_numbers() {
+    let testdir = TestDir::new("scrubcsv", "normalize_numbers");
+    testdir.create_file(
+        "in.csv",
+        "id,amount\n\"1,000\",\"$1,234.56\"\n2,(12.50)\n3,n/a\n4,\n",
+    );
+    let output = testdir
+        .cmd()
+        .args(["--normalize-numbers", "amount"])
+        .args(["--bad-rows-path", "bad.csv", "--annotate-bad-rows"])
+        .args(["--max-bad-rows", "50"])
+        .arg("in.csv")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,amount\n\"1,000\",1234.56\n2,-12.50\n4,\n",
+    );
+    testdir.expect_file_contents(
+        "bad.csv",
+        "_line,_reason,id,amount\n4,invalid_number,3,n/a\n",
+    );
+
+    testdir.create_file("eu.csv", "amount\n\"1.234,5 €\"\n");
+    let output = testdir
+        .cmd()
+        .args(["--decimal-comma", "--normalize-numbers", "amount", "eu.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "amount\n1234.5\n");
+}
+
+#[test]
+fn warehouse_presets() {
+    let testdir = TestDir::new("scrubcsv", "warehouse_presets");
+    let output = testdir
+        .cmd()
+        .args(["--preset", "bigquery"])
+        .output_with_stdin(&b"a,b\n\"x\ny\",NULL\n\\N,\"\xff\"\n"[..])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\nx y,\n,\u{fffd}\n");
+
+    let output = testdir
+        .cmd()
+        .args(["--preset", "redshift"])
+        .output_with_stdin("a\n\"x\ny\"\n\" z\"\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a\n\"x\ny\"\n\" z\"\n");
+
+    // Values which only start or end like a NULL are left alone.
+    let output = testdir
+        .cmd()
+        .args(["--preset", "postgres"])
+        .output_with_stdin("a,b\nNullam dolor,x\\N\nnull,\\N\n")
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b\nNullam dolor,x\\N\n,\n");
+}
+
+#[test]
+fn rename_columns() {
+    let testdir = TestDir::new("scrubcsv", "rename_columns");
+    testdir.create_file("renames.csv", "old,new\nE-Mail,email\nZip,Postal Code\n");
+    let output = testdir
+        .cmd()
+        .args(["--rename", "Cust ID=id", "--rename-file", "renames.csv"])
+        .arg("--clean-column-names")
+        .output_with_stdin("Cust ID,E-Mail,Zip\n1,a@example.com,97201\n")
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,email,postal_code\n1,a@example.com,97201\n",
+    );
+}
+
+#[test]
+fn output_columns() {
+    let testdir = TestDir::new("scrubcsv", "output_columns");
+    testdir.create_file("in.csv", "b,extra,a\n2,x,1\n");
+    let output = testdir
+        .cmd()
+        .args(["--output-columns", "a,b,c", "in.csv"])
+        .expect_success();
+    assert_eq!(output.stdout_str(), "a,b,c\n1,2,\n");
+
+    let output = testdir
+        .cmd()
+        .args([
+            "--output-columns",
+            "a,b,c",
+            "--fail-on-extra-columns",
+            "in.csv",
+        ])
+        .output()
+        .expect("could not run scrubcsv");
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(output.stdout_str(), "");
+    assert!(output.stderr_str().contains("\"extra\""));
+}
+
+#[test]
+fn header_assertions() {
+    let testdir = TestDir::new("scrubcsv", "header_assertions");
+    testdir.create_file("in.csv", "Id,Email\n1,a@example.com\n");
+    testdir
+        .cmd()
+        .args(["--clean-column-names", "--expect-columns", "id,email"])
+        .args(["--require-columns", "email", "in.csv"])
+        .expect_success();
+
+    for args in [
+        ["--expect-columns", "email,id"],
+        ["--require-columns", "id,zip"],
+    ] {
+        let output = testdir
+            .cmd()
+            .arg("--clean-column-names")
+            .args(args)
+            .arg("in.csv")
+            .output()
+            .expect("could not run scrubcsv");
+        assert_eq!(output.status.code(), Some(6));
+        assert_eq!(output.stdout_str(), "");
+        assert!(output.stderr_str().contains("Column check failed"));
+    }
+}
+
+#[test]
+fn multiple_inputs() {
+    let testdir = TestDir::new("scrubcsv", "multiple_inputs");
+    testdir.create_file("a.csv", "id,name\n1,\"multi\nline\"\n");
+    testdir.create_file("b.csv", "id,name\n2,b");
+    testdir.create_file("c.csv", "name,email\nc,c@example.com\n");
+    let output = testdir
+        .cmd()
+        .args(["--add-source-file-column", "file"])
+        .args(["a.csv", "b.csv"])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "id,name,file\n1,\"multi\nline\",a.csv\n2,b,b.csv\n",
+    );
+
+    let output = testdir.cmd().args(["a.csv", "c.csv"]).expect_failure();
+    assert!(output.stderr_str().contains("do not match"));
+
+    let output = testdir
+        .cmd()
+        .arg("--union-columns")
+        .args(["c.csv", "*.csv"])
+        .expect_success();
+    assert_eq!(
+        output.stdout_str(),
+        "name,email,id\nc,c@example.com,\n\"multi\nline\",,1\nb,,2\nc,c@example.com,\n",
+    );
+}
+
+#[test]
+fn split_output() {
+    let testdir = TestDir::new("scrubcsv", "split_output");
+    let input = "a,b\n1,x\n2,y\n3,z\n";
+    testdir
+        .cmd()
+        .args(["--split-rows", "2", "--output-template", "rows-{:02}.csv"])
+        .output_with_stdin(input)
+        .expect_success();
+    testdir.expect_file_contents("rows-00.csv", "a,b\n1,x\n2,y\n");
+    testdir.expect_file_contents("rows-01.csv", "a,b\n3,z\n");
+    testdir.expect_no_such_path("rows-02.csv");
+
+    testdir
+        .cmd()
+        .args(["--split-bytes", "8", "--output-template", "bytes-{}.csv"])
+        .output_with_stdin(input)
+        .expect_success();
+    testdir.expect_file_contents("bytes-0.csv", "a,b\n1,x\n");
+    testdir.expect_file_contents("bytes-1.csv", "a,b\n2,y\n");
+    testdir.expect_file_contents("bytes-2.csv", "a,b\n3,z\n");
+
+    testdir.create_file("in.csv", input);
+    testdir
+        .cmd()
+        .args(["--output-template", "x-{}.csv", "in.csv"])
+        .expect_failure();
+}
+
+#[test]
+fn partition_output() {
+    let testdir = TestDir::new("scrubcsv", "partition_output");
+    testdir
+        .cmd()
+        .args(["--partition-by", "country", "--max-open-files", "1"])
+        .args(["--output-template", "out/{value}.csv"])
+        .output_with_stdin("id,country\n1,US\n2,CA\n3,US\n4,\n5,CA\n")
+        .expect_success();
+    testdir.expect_file_contents("out/US.csv", "id,country\n1,US\n3,US\n");
+    testdir.expect_file_contents("out/CA.csv", "id,country\n2,CA\n5,CA\n");
+    testdir.expect_file_contents("out/_.csv", "id,country\n4,\n");
+
+    testdir.create_file("in.csv", "id,country\n1,US\n");
+    testdir
+        .cmd()
+        .args(["--partition-by", "country", "--output-template", "{}.csv"])
+        .arg("in.csv")
+        .expect_failure();
+}